@@ -11,8 +11,12 @@ def system_demo_rust_toolchain():
             "prelude//os/constraints:linux": "x86_64-unknown-linux-gnu",
             "prelude//os/constraints:macos": "aarch64-apple-darwin",
             "prelude//os/constraints:windows": "x86_64-pc-windows-msvc",
+            "prelude//os/constraints:iphoneos": "aarch64-apple-ios",
             "DEFAULT": "x86_64-unknown-linux-gnu",
         }),
         default_edition = "2021",
+        # Set by `cargo buckal test --coverage`, as a build-time switch rather than in the
+        # generated rules, so that coverage builds don't need regenerating the BUCK files
+        rustc_flags = ["-Cinstrument-coverage"] if read_root_config("buckal", "coverage", "false") == "true" else [],
         visibility = ["PUBLIC"],
     )
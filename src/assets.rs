@@ -64,20 +64,9 @@ fn extract_dir(dest: &Path, dir: &Dir) -> io::Result<()> {
 #[cfg(test)]
 mod tests {
     use super::extract_buck2_assets;
-    use std::time::{SystemTime, UNIX_EPOCH};
 
     fn unique_temp_dir() -> std::path::PathBuf {
-        let mut path = std::env::temp_dir();
-        let nanos = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_nanos();
-        path.push(format!(
-            "cargo-buckal-assets-{}-{}",
-            std::process::id(),
-            nanos
-        ));
-        path
+        crate::testing::temp_dir("assets").into_std_path_buf()
     }
 
     #[test]
@@ -1,14 +1,16 @@
 use std::collections::{BTreeMap as Map, BTreeSet as Set};
 use std::ffi::CString;
 
+use anyhow::{Context, Result, anyhow, bail};
 use cargo_metadata::camino::Utf8PathBuf;
 use pyo3::prelude::*;
-use pyo3::types::{PyDict, PyList, PyString, PyTuple};
-use pyo3_ffi::c_str;
+use pyo3::types::{PyBool, PyDict, PyList, PyString, PyTuple};
 use serde::ser::{Serialize, SerializeStruct, SerializeTupleStruct, Serializer};
 use serde_derive::Serialize;
 
-#[derive(Serialize, Debug)]
+mod starlark;
+
+#[derive(Serialize, Debug, PartialEq)]
 #[serde(untagged)]
 pub enum Rule {
     Load(Load),
@@ -28,6 +30,21 @@ pub struct Alias {
     pub visibility: Set<String>,
 }
 impl Rule {
+    /// The `(kind, name)` key of the rule in parsed BUCK files; `load` statements have none.
+    pub fn key(&self) -> Option<RuleKey> {
+        let (kind, name) = match self {
+            Rule::Load(_) => return None,
+            Rule::HttpArchive(r) => ("http_archive", &r.name),
+            Rule::FileGroup(r) => ("filegroup", &r.name),
+            Rule::CargoManifest(r) => ("cargo_manifest", &r.name),
+            Rule::RustLibrary(r) => ("rust_library", &r.name),
+            Rule::RustBinary(r) => ("rust_binary", &r.name),
+            Rule::RustTest(r) => ("rust_test", &r.name),
+            Rule::BuildscriptRun(r) => ("buildscript_run", &r.name),
+        };
+        Some(rule_key(kind, name))
+    }
+
     pub fn as_rust_rule_mut(&mut self) -> Option<&mut dyn RustRule> {
         match self {
             Rule::RustLibrary(inner) => Some(inner),
@@ -54,13 +71,13 @@ pub enum CargoTargetKind {
     Test,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Load {
     pub bzl: String,
     pub items: Set<String>,
 }
 
-#[derive(Serialize, Default, Debug)]
+#[derive(Serialize, Default, Debug, PartialEq)]
 #[serde(rename = "http_archive")]
 pub struct HttpArchive {
     pub name: String,
@@ -73,18 +90,21 @@ pub struct HttpArchive {
     pub out: Option<String>,
 }
 
-#[derive(Serialize, Default, Debug)]
+#[derive(Serialize, Default, Debug, PartialEq)]
 #[serde(rename = "cargo_manifest")]
 pub struct CargoManifest {
     pub name: String,
     pub vendor: String,
 }
 
-#[derive(Serialize, Default, Debug)]
+#[derive(Serialize, Default, Debug, PartialEq)]
 #[serde(rename = "rust_library")]
 pub struct RustLibrary {
     pub name: String,
     pub srcs: Set<String>,
+    /// Extra sources, from a label to their path within the crate
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub mapped_srcs: Map<String, String>,
     #[serde(rename = "crate")]
     pub crate_name: String,
     pub crate_root: String,
@@ -101,8 +121,19 @@ pub struct RustLibrary {
     pub features: Set<String>,
     #[serde(skip_serializing_if = "Set::is_empty")]
     pub rustc_flags: Set<String>,
+    #[serde(skip_serializing_if = "Set::is_empty")]
+    pub rustdoc_flags: Set<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub proc_macro: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_linkage: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub doctests: Option<bool>,
+    /// Extra dependencies of doctests, i.e. dev-dependencies
+    #[serde(skip_serializing_if = "Set::is_empty")]
+    pub doc_deps: Set<String>,
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub doc_named_deps: Map<String, String>,
     #[serde(skip_serializing_if = "Map::is_empty")]
     pub named_deps: Map<String, String>,
     #[serde(skip_serializing_if = "Map::is_empty")]
@@ -114,7 +145,7 @@ pub struct RustLibrary {
     pub deps: Set<String>,
 }
 
-#[derive(Serialize, Default, Debug)]
+#[derive(Serialize, Default, Debug, PartialEq)]
 #[serde(rename = "rust_binary")]
 pub struct RustBinary {
     pub name: String,
@@ -129,6 +160,9 @@ pub struct RustBinary {
     pub compatible_with: Set<String>,
     #[serde(skip_serializing_if = "Set::is_empty")]
     pub exec_compatible_with: Set<String>,
+    /// Platform `buck2 run` and `buck2 build` use without `--target-platforms`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_target_platform: Option<String>,
     #[serde(skip_serializing_if = "Map::is_empty")]
     pub env: Map<String, String>,
     #[serde(skip_serializing_if = "Set::is_empty")]
@@ -144,9 +178,12 @@ pub struct RustBinary {
     pub visibility: Set<String>,
     #[serde(skip_serializing_if = "Set::is_empty")]
     pub deps: Set<String>,
+    /// Dependencies built for the exec platform, i.e. the build-dependencies of a build script
+    #[serde(skip_serializing_if = "Set::is_empty")]
+    pub exec_deps: Set<String>,
 }
 
-#[derive(Serialize, Default, Debug)]
+#[derive(Serialize, Default, Debug, PartialEq)]
 #[serde(rename = "rust_test")]
 pub struct RustTest {
     pub name: String,
@@ -163,6 +200,9 @@ pub struct RustTest {
     pub exec_compatible_with: Set<String>,
     #[serde(skip_serializing_if = "Map::is_empty")]
     pub env: Map<String, String>,
+    /// Arguments passed to the test runner
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
     #[serde(skip_serializing_if = "Set::is_empty")]
     pub features: Set<String>,
     #[serde(skip_serializing_if = "Set::is_empty")]
@@ -173,12 +213,16 @@ pub struct RustTest {
     pub os_named_deps: Map<String, Map<String, String>>,
     #[serde(skip_serializing_if = "Map::is_empty")]
     pub os_deps: Map<String, Set<String>>,
+    #[serde(skip_serializing_if = "Set::is_empty")]
+    pub resources: Set<String>,
+    #[serde(skip_serializing_if = "Set::is_empty")]
+    pub labels: Set<String>,
     pub visibility: Set<String>,
     #[serde(skip_serializing_if = "Set::is_empty")]
     pub deps: Set<String>,
 }
 
-#[derive(Serialize, Default, Debug)]
+#[derive(Serialize, Default, Debug, PartialEq)]
 #[serde(rename = "buildscript_run")]
 pub struct BuildscriptRun {
     pub name: String,
@@ -196,13 +240,13 @@ pub struct BuildscriptRun {
     pub visibility: Set<String>,
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone, PartialEq)]
 pub struct Glob {
     pub include: Set<String>,
     pub exclude: Set<String>,
 }
 
-#[derive(Serialize, Default, Debug)]
+#[derive(Serialize, Default, Debug, PartialEq)]
 #[serde(rename = "filegroup")]
 pub struct FileGroup {
     pub name: String,
@@ -242,31 +286,17 @@ impl Serialize for Glob {
 }
 
 impl Glob {
-    fn from_py_tuple(tuple: &Bound<'_, PyTuple>) -> PyResult<Self> {
-        let func_binding = tuple.get_item(0).unwrap();
-        let func = func_binding.downcast::<PyString>().unwrap();
-        assert_eq!(func.to_str().unwrap(), "glob");
-        let args_binding = tuple.get_item(1).unwrap();
-        let args = args_binding.downcast::<PyTuple>().unwrap();
+    fn from_call(args: &[Value], kwargs: &Kwargs) -> Result<Self> {
         if args.len() > 1 {
-            Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
-                "glob only supports one positional argument",
-            ))
-        } else if args.len() == 1 {
-            let include_vec: Vec<String> = args
-                .get_item(0)
-                .expect("Expected one positional argument")
-                .extract()
-                .ok()
-                .unwrap_or_default();
+            bail!("glob only supports one positional argument");
+        } else if let Some(include) = args.first() {
+            let include_vec: Vec<String> = FromValue::from_value(include).unwrap_or_default();
             let include: Set<String> = include_vec.into_iter().collect();
             Ok(Glob {
                 include,
                 exclude: Set::new(),
             })
         } else {
-            let kwargs_binding = tuple.get_item(2).unwrap();
-            let kwargs = kwargs_binding.downcast::<PyDict>().unwrap();
             let include_vec: Vec<String> = get_arg(kwargs, "include");
             let include: Set<String> = include_vec.into_iter().collect();
             let exclude_vec: Vec<String> = get_arg(kwargs, "exclude");
@@ -274,6 +304,19 @@ impl Glob {
             Ok(Glob { include, exclude })
         }
     }
+
+    fn from_py_tuple(tuple: &Bound<'_, PyTuple>) -> Result<Self> {
+        let args_binding = tuple.get_item(1)?;
+        let args = args_binding
+            .downcast::<PyTuple>()
+            .map_err(PyErr::from)?
+            .iter()
+            .map(|arg| value_from_py(&arg))
+            .collect::<Result<Vec<_>>>()?;
+        let kwargs_binding = tuple.get_item(2)?;
+        let kwargs = kwargs_from_py(kwargs_binding.downcast::<PyDict>().map_err(PyErr::from)?)?;
+        Glob::from_call(&args, &kwargs)
+    }
 }
 
 impl RustRule for RustLibrary {
@@ -393,22 +436,49 @@ struct DepFieldsRef<'a> {
     os_named_deps: &'a Map<String, Map<String, String>>,
 }
 
+/// Attributes whose manual edits can be carried over, i.e. the valid `patch_fields` entries.
+pub const PATCHABLE_FIELDS: [&str; 14] = [
+    "compatible_with",
+    "deps",
+    "env",
+    "exec_compatible_with",
+    "exec_deps",
+    "features",
+    "mapped_srcs",
+    "named_deps",
+    "os_deps",
+    "os_named_deps",
+    "resources",
+    "rustc_flags",
+    "target_compatible_with",
+    "visibility",
+];
+
+/// Whether manual edits of `field` are carried over.
+fn patches(patch_fields: &Set<String>, field: &str) -> bool {
+    debug_assert!(
+        PATCHABLE_FIELDS.contains(&field),
+        "`{field}` isn't patchable"
+    );
+    patch_fields.contains(field)
+}
+
 fn patch_deps_fields(patch_fields: &Set<String>, dst: &mut DepFieldsMut, src: &DepFieldsRef) {
-    if patch_fields.contains("deps") {
+    if patches(patch_fields, "deps") {
         patch_set(dst.deps, src.deps);
     }
 
-    if patch_fields.contains("os_deps") {
+    if patches(patch_fields, "os_deps") {
         for (plat, deps) in src.os_deps {
             patch_set(dst.os_deps.entry(plat.clone()).or_default(), deps);
         }
     }
 
-    if patch_fields.contains("named_deps") {
+    if patches(patch_fields, "named_deps") {
         patch_map(dst.named_deps, src.named_deps);
     }
 
-    if patch_fields.contains("os_named_deps") {
+    if patches(patch_fields, "os_named_deps") {
         for (alias, plat_map) in src.os_named_deps {
             let entry = dst.os_named_deps.entry(alias.clone()).or_default();
             patch_map(entry, plat_map);
@@ -417,9 +487,10 @@ fn patch_deps_fields(patch_fields: &Set<String>, dst: &mut DepFieldsMut, src: &D
 }
 
 impl RustLibrary {
-    fn from_py_dict(kwargs: &Bound<'_, PyDict>) -> PyResult<Self> {
+    fn from_kwargs(kwargs: &Kwargs) -> Result<Self> {
         let name: String = get_arg(kwargs, "name");
         let srcs: Set<String> = extract_set!(kwargs, "srcs");
+        let mapped_srcs: Map<String, String> = get_arg(kwargs, "mapped_srcs");
         let crate_name: String = get_arg(kwargs, "crate");
         let crate_root: String = get_arg(kwargs, "crate_root");
         let edition: String = get_arg(kwargs, "edition");
@@ -429,7 +500,12 @@ impl RustLibrary {
         let env: Map<String, String> = get_arg(kwargs, "env");
         let features: Set<String> = extract_set!(kwargs, "features");
         let rustc_flags: Set<String> = extract_set!(kwargs, "rustc_flags");
+        let rustdoc_flags: Set<String> = extract_set!(kwargs, "rustdoc_flags");
         let proc_macro: Option<bool> = get_arg(kwargs, "proc_macro");
+        let preferred_linkage: Option<String> = get_arg(kwargs, "preferred_linkage");
+        let doctests: Option<bool> = get_arg(kwargs, "doctests");
+        let doc_deps: Set<String> = extract_set!(kwargs, "doc_deps");
+        let doc_named_deps: Map<String, String> = get_arg(kwargs, "doc_named_deps");
         let named_deps: Map<String, String> = get_arg(kwargs, "named_deps");
         let os_named_deps: Map<String, Map<String, String>> = get_arg(kwargs, "os_named_deps");
         let os_deps: Map<String, Set<String>> = get_arg(kwargs, "os_deps");
@@ -438,6 +514,7 @@ impl RustLibrary {
         Ok(RustLibrary {
             name,
             srcs,
+            mapped_srcs,
             crate_name,
             crate_root,
             edition,
@@ -447,7 +524,12 @@ impl RustLibrary {
             env,
             features,
             rustc_flags,
+            rustdoc_flags,
             proc_macro,
+            preferred_linkage,
+            doctests,
+            doc_deps,
+            doc_named_deps,
             named_deps,
             os_named_deps,
             os_deps,
@@ -458,34 +540,38 @@ impl RustLibrary {
 
     fn patch_from(&mut self, other: &RustLibrary, patch_fields: &Set<String>) {
         // Patch target_compatible_with set
-        if patch_fields.contains("target_compatible_with") {
+        if patches(patch_fields, "target_compatible_with") {
             patch_set(
                 &mut self.target_compatible_with,
                 &other.target_compatible_with,
             );
         }
         // Patch compatible_with set
-        if patch_fields.contains("compatible_with") {
+        if patches(patch_fields, "compatible_with") {
             patch_set(&mut self.compatible_with, &other.compatible_with);
         }
         // Patch exec_compatible_with set
-        if patch_fields.contains("exec_compatible_with") {
+        if patches(patch_fields, "exec_compatible_with") {
             patch_set(&mut self.exec_compatible_with, &other.exec_compatible_with);
         }
+        // Patch mapped_srcs map
+        if patches(patch_fields, "mapped_srcs") {
+            patch_map(&mut self.mapped_srcs, &other.mapped_srcs);
+        }
         // Patch env map
-        if patch_fields.contains("env") {
+        if patches(patch_fields, "env") {
             patch_map(&mut self.env, &other.env);
         }
         // Patch features set
-        if patch_fields.contains("features") {
+        if patches(patch_fields, "features") {
             patch_set(&mut self.features, &other.features);
         }
         // Patch rustc_flags set
-        if patch_fields.contains("rustc_flags") {
+        if patches(patch_fields, "rustc_flags") {
             patch_set(&mut self.rustc_flags, &other.rustc_flags);
         }
         // Patch visibility set
-        if patch_fields.contains("visibility") {
+        if patches(patch_fields, "visibility") {
             patch_set(&mut self.visibility, &other.visibility);
         }
 
@@ -506,7 +592,7 @@ impl RustLibrary {
 }
 
 impl RustBinary {
-    fn from_py_dict(kwargs: &Bound<'_, PyDict>) -> PyResult<Self> {
+    fn from_kwargs(kwargs: &Kwargs) -> Result<Self> {
         let name: String = get_arg(kwargs, "name");
         let srcs: Set<String> = extract_set!(kwargs, "srcs");
         let crate_name: String = get_arg(kwargs, "crate");
@@ -515,6 +601,7 @@ impl RustBinary {
         let target_compatible_with: Set<String> = extract_set!(kwargs, "target_compatible_with");
         let compatible_with: Set<String> = extract_set!(kwargs, "compatible_with");
         let exec_compatible_with: Set<String> = extract_set!(kwargs, "exec_compatible_with");
+        let default_target_platform: Option<String> = get_arg(kwargs, "default_target_platform");
         let env: Map<String, String> = get_arg(kwargs, "env");
         let features: Set<String> = extract_set!(kwargs, "features");
         let rustc_flags: Set<String> = extract_set!(kwargs, "rustc_flags");
@@ -523,6 +610,7 @@ impl RustBinary {
         let os_deps: Map<String, Set<String>> = get_arg(kwargs, "os_deps");
         let visibility: Set<String> = extract_set!(kwargs, "visibility");
         let deps: Set<String> = extract_set!(kwargs, "deps");
+        let exec_deps: Set<String> = extract_set!(kwargs, "exec_deps");
         Ok(RustBinary {
             name,
             srcs,
@@ -532,6 +620,7 @@ impl RustBinary {
             target_compatible_with,
             compatible_with,
             exec_compatible_with,
+            default_target_platform,
             env,
             features,
             rustc_flags,
@@ -540,41 +629,46 @@ impl RustBinary {
             os_deps,
             visibility,
             deps,
+            exec_deps,
         })
     }
 
     fn patch_from(&mut self, other: &RustBinary, patch_fields: &Set<String>) {
         // Patch target_compatible_with set
-        if patch_fields.contains("target_compatible_with") {
+        if patches(patch_fields, "target_compatible_with") {
             patch_set(
                 &mut self.target_compatible_with,
                 &other.target_compatible_with,
             );
         }
         // Patch compatible_with set
-        if patch_fields.contains("compatible_with") {
+        if patches(patch_fields, "compatible_with") {
             patch_set(&mut self.compatible_with, &other.compatible_with);
         }
         // Patch exec_compatible_with set
-        if patch_fields.contains("exec_compatible_with") {
+        if patches(patch_fields, "exec_compatible_with") {
             patch_set(&mut self.exec_compatible_with, &other.exec_compatible_with);
         }
         // Patch env map
-        if patch_fields.contains("env") {
+        if patches(patch_fields, "env") {
             patch_map(&mut self.env, &other.env);
         }
         // Patch features set
-        if patch_fields.contains("features") {
+        if patches(patch_fields, "features") {
             patch_set(&mut self.features, &other.features);
         }
         // Patch rustc_flags set
-        if patch_fields.contains("rustc_flags") {
+        if patches(patch_fields, "rustc_flags") {
             patch_set(&mut self.rustc_flags, &other.rustc_flags);
         }
         // Patch visibility set
-        if patch_fields.contains("visibility") {
+        if patches(patch_fields, "visibility") {
             patch_set(&mut self.visibility, &other.visibility);
         }
+        // Patch exec_deps set
+        if patches(patch_fields, "exec_deps") {
+            patch_set(&mut self.exec_deps, &other.exec_deps);
+        }
 
         let mut dst = DepFieldsMut {
             deps: &mut self.deps,
@@ -593,7 +687,7 @@ impl RustBinary {
 }
 
 impl RustTest {
-    fn from_py_dict(kwargs: &Bound<'_, PyDict>) -> PyResult<Self> {
+    fn from_kwargs(kwargs: &Kwargs) -> Result<Self> {
         let name: String = get_arg(kwargs, "name");
         let srcs: Set<String> = extract_set!(kwargs, "srcs");
         let crate_name: String = get_arg(kwargs, "crate");
@@ -603,11 +697,14 @@ impl RustTest {
         let compatible_with: Set<String> = extract_set!(kwargs, "compatible_with");
         let exec_compatible_with: Set<String> = extract_set!(kwargs, "exec_compatible_with");
         let env: Map<String, String> = get_arg(kwargs, "env");
+        let args: Vec<String> = get_arg(kwargs, "args");
         let features: Set<String> = extract_set!(kwargs, "features");
         let rustc_flags: Set<String> = extract_set!(kwargs, "rustc_flags");
         let named_deps: Map<String, String> = get_arg(kwargs, "named_deps");
         let os_named_deps: Map<String, Map<String, String>> = get_arg(kwargs, "os_named_deps");
         let os_deps: Map<String, Set<String>> = get_arg(kwargs, "os_deps");
+        let resources: Set<String> = extract_set!(kwargs, "resources");
+        let labels: Set<String> = extract_set!(kwargs, "labels");
         let visibility: Set<String> = extract_set!(kwargs, "visibility");
         let deps: Set<String> = extract_set!(kwargs, "deps");
         Ok(RustTest {
@@ -620,11 +717,14 @@ impl RustTest {
             compatible_with,
             exec_compatible_with,
             env,
+            args,
             features,
             rustc_flags,
             named_deps,
             os_named_deps,
             os_deps,
+            resources,
+            labels,
             visibility,
             deps,
         })
@@ -632,34 +732,38 @@ impl RustTest {
 
     fn patch_from(&mut self, other: &RustTest, patch_fields: &Set<String>) {
         // Patch target_compatible_with set
-        if patch_fields.contains("target_compatible_with") {
+        if patches(patch_fields, "target_compatible_with") {
             patch_set(
                 &mut self.target_compatible_with,
                 &other.target_compatible_with,
             );
         }
         // Patch compatible_with set
-        if patch_fields.contains("compatible_with") {
+        if patches(patch_fields, "compatible_with") {
             patch_set(&mut self.compatible_with, &other.compatible_with);
         }
         // Patch exec_compatible_with set
-        if patch_fields.contains("exec_compatible_with") {
+        if patches(patch_fields, "exec_compatible_with") {
             patch_set(&mut self.exec_compatible_with, &other.exec_compatible_with);
         }
         // Patch env map
-        if patch_fields.contains("env") {
+        if patches(patch_fields, "env") {
             patch_map(&mut self.env, &other.env);
         }
         // Patch features set
-        if patch_fields.contains("features") {
+        if patches(patch_fields, "features") {
             patch_set(&mut self.features, &other.features);
         }
         // Patch rustc_flags set
-        if patch_fields.contains("rustc_flags") {
+        if patches(patch_fields, "rustc_flags") {
             patch_set(&mut self.rustc_flags, &other.rustc_flags);
         }
+        // Patch resources set
+        if patches(patch_fields, "resources") {
+            patch_set(&mut self.resources, &other.resources);
+        }
         // Patch visibility set
-        if patch_fields.contains("visibility") {
+        if patches(patch_fields, "visibility") {
             patch_set(&mut self.visibility, &other.visibility);
         }
 
@@ -680,7 +784,7 @@ impl RustTest {
 }
 
 impl BuildscriptRun {
-    fn from_py_dict(kwargs: &Bound<'_, PyDict>) -> PyResult<Self> {
+    fn from_kwargs(kwargs: &Kwargs) -> Result<Self> {
         let name: String = get_arg(kwargs, "name");
         let package_name: String = get_arg(kwargs, "package_name");
         let buildscript_rule: String = get_arg(kwargs, "buildscript_rule");
@@ -705,22 +809,22 @@ impl BuildscriptRun {
 
     fn patch_from(&mut self, other: &BuildscriptRun, patch_fields: &Set<String>) {
         // Patch env map
-        if patch_fields.contains("env") {
+        if patches(patch_fields, "env") {
             patch_map(&mut self.env, &other.env);
         }
         // Patch features set
-        if patch_fields.contains("features") {
+        if patches(patch_fields, "features") {
             patch_set(&mut self.features, &other.features);
         }
         // Patch visibility set
-        if patch_fields.contains("visibility") {
+        if patches(patch_fields, "visibility") {
             patch_set(&mut self.visibility, &other.visibility);
         }
     }
 }
 
 impl HttpArchive {
-    fn from_py_dict(kwargs: &Bound<'_, PyDict>) -> PyResult<Self> {
+    fn from_kwargs(kwargs: &Kwargs) -> Result<Self> {
         let name: String = get_arg(kwargs, "name");
         let urls_vec: Vec<String> = get_arg(kwargs, "urls");
         let urls: Set<String> = urls_vec.into_iter().collect();
@@ -740,30 +844,52 @@ impl HttpArchive {
 }
 
 impl FileGroup {
-    fn from_py_dict(kwargs: &Bound<'_, PyDict>) -> PyResult<Self> {
+    fn from_kwargs(kwargs: &Kwargs) -> Result<Self> {
         let name: String = get_arg(kwargs, "name");
-        let srcs_tuple_binding = kwargs
-            .get_item("srcs")
-            .expect("Expected 'srcs' argument")
-            .unwrap();
-        let srcs_tuple = srcs_tuple_binding.downcast::<PyTuple>().unwrap();
-        let srcs = Glob::from_py_tuple(srcs_tuple)?;
+        let srcs = match kwargs.get("srcs") {
+            Some(Value::Glob(glob)) => glob.clone(),
+            _ => bail!(
+                "Expected 'srcs' argument of filegroup `{}` to be a glob",
+                name
+            ),
+        };
         let out: Option<String> = get_arg(kwargs, "out");
         Ok(FileGroup { name, srcs, out })
     }
 }
 
 impl CargoManifest {
-    fn from_py_dict(kwargs: &Bound<'_, PyDict>) -> PyResult<Self> {
+    fn from_kwargs(kwargs: &Kwargs) -> Result<Self> {
         let name: String = get_arg(kwargs, "name");
         let vendor: String = get_arg(kwargs, "vendor");
         Ok(CargoManifest { name, vendor })
     }
 }
 
-pub fn parse_buck_file(file: &Utf8PathBuf) -> PyResult<Map<String, Rule>> {
-    Python::attach(|py| {
-        let buck = std::fs::read_to_string(file).expect("Failed to read BUCK file");
+/// Rule kind (the function name, e.g. `rust_binary`) and target name of a parsed rule.
+pub type RuleKey = (String, String);
+
+pub fn parse_buck_file(file: &Utf8PathBuf) -> Result<Map<RuleKey, Rule>> {
+    let buck =
+        std::fs::read_to_string(file).with_context(|| format!("failed to read `{}`", file))?;
+    if cfg!(feature = "starlark-parser") {
+        starlark::parse_buck_content(&buck)
+    } else {
+        parse_buck_content(&buck)
+    }
+}
+
+/// Strip a leading UTF-8 BOM and normalize CRLF line endings, as left behind by some Windows
+/// editors, so the content can be evaluated as Python source.
+fn normalize_buck_content(content: &str) -> String {
+    content
+        .strip_prefix('\u{feff}')
+        .unwrap_or(content)
+        .replace("\r\n", "\n")
+}
+
+fn parse_buck_content(buck: &str) -> Result<Map<RuleKey, Rule>> {
+    let calls = Python::attach(|py| -> Result<Vec<(String, Kwargs)>> {
         let python_code = format!(
             r#"
 call_kwargs_list = []
@@ -806,101 +932,109 @@ def cargo_manifest(*args, **kwargs):
 def glob(*args, **kwargs):
     return (glob.__name__, args, kwargs)
 
+class _Select(dict):
+    # `select` branches are dropped when concatenated, keeping the unconditional operand
+    def __add__(self, other):
+        return other
+
+    def __radd__(self, other):
+        return other
+
 def select(arg):
-    return arg
+    return _Select(arg)
 
 def load(*args, **kwargs):
     pass
 
-        {}
+def read_config(section, key, default=None):
+    return default
+
+{}
 "#,
-            buck
+            normalize_buck_content(buck)
         );
 
-        let mut buck_rules: Map<String, Rule> = Map::new();
-
-        let c_str = CString::new(python_code).unwrap();
-
-        py.run(c_str.as_c_str(), None, None)?;
+        let c_str = CString::new(python_code)?;
 
-        let globals_binding = py.eval(c_str!("__import__('builtins').globals()"), None, None)?;
-        let globals = globals_binding.downcast::<PyDict>()?;
+        // Evaluate in a fresh namespace so concurrent parses don't share `call_kwargs_list`
+        let globals = PyDict::new(py);
+        py.run(c_str.as_c_str(), Some(&globals), None)?;
 
         let kwargs_binding = globals
-            .get_item("call_kwargs_list")
-            .expect("call_kwargs_list not found")
-            .unwrap();
-        let kwargs_list = kwargs_binding.downcast::<PyList>()?;
+            .get_item("call_kwargs_list")?
+            .ok_or_else(|| anyhow!("call_kwargs_list not found"))?;
+        let kwargs_list = kwargs_binding.downcast::<PyList>().map_err(PyErr::from)?;
 
+        let mut calls = Vec::new();
         for tuple in kwargs_list.iter() {
-            let tuple = tuple.downcast::<PyTuple>()?;
-            let binding = tuple.get_item(0).unwrap();
-            let func_name = binding.downcast::<PyString>()?;
-            let func_name: &str = func_name.extract().unwrap();
-            let binding = tuple.get_item(1).unwrap();
-            let kwargs = binding.downcast::<PyDict>()?;
-
-            match func_name {
-                "rust_library" => {
-                    let rule = RustLibrary::from_py_dict(kwargs)?;
-                    buck_rules.insert(func_name.to_string(), Rule::RustLibrary(rule));
-                }
-                "rust_binary" => {
-                    let rule = RustBinary::from_py_dict(kwargs)?;
-                    buck_rules.insert(func_name.to_string(), Rule::RustBinary(rule));
-                }
-                "rust_test" => {
-                    let rule = RustTest::from_py_dict(kwargs)?;
-                    buck_rules.insert(func_name.to_string(), Rule::RustTest(rule));
-                }
-                "buildscript_run" => {
-                    let rule = BuildscriptRun::from_py_dict(kwargs)?;
-                    buck_rules.insert(func_name.to_string(), Rule::BuildscriptRun(rule));
-                }
-                "http_archive" => {
-                    let rule = HttpArchive::from_py_dict(kwargs)?;
-                    buck_rules.insert(func_name.to_string(), Rule::HttpArchive(rule));
-                }
-                "filegroup" => {
-                    let rule = FileGroup::from_py_dict(kwargs)?;
-                    buck_rules.insert(func_name.to_string(), Rule::FileGroup(rule));
-                }
-                "cargo_manifest" => {
-                    let rule = CargoManifest::from_py_dict(kwargs)?;
-                    buck_rules.insert(func_name.to_string(), Rule::CargoManifest(rule));
-                }
-                _ => panic!("Unknown function name: {}", func_name),
-            }
+            let tuple = tuple.downcast::<PyTuple>().map_err(PyErr::from)?;
+            let func_name: String = tuple.get_item(0)?.extract()?;
+            let binding = tuple.get_item(1)?;
+            let kwargs = binding.downcast::<PyDict>().map_err(PyErr::from)?;
+            calls.push((func_name, kwargs_from_py(kwargs)?));
         }
+        Ok(calls)
+    })?;
 
-        Ok(buck_rules)
-    })
+    rules_from_calls(calls)
+}
+
+/// Build the rule map from the `(function name, kwargs)` of every rule call in a BUCK file,
+/// independently of which parser evaluated it. Rules are keyed by `(kind, name)` so a file with
+/// several rules of the same kind (e.g. multiple binaries) keeps all of them.
+fn rules_from_calls(calls: Vec<(String, Kwargs)>) -> Result<Map<RuleKey, Rule>> {
+    let mut buck_rules: Map<RuleKey, Rule> = Map::new();
+
+    for (func_name, kwargs) in calls {
+        let name: String = get_arg(&kwargs, "name");
+        let rule = match func_name.as_str() {
+            "rust_library" => Rule::RustLibrary(RustLibrary::from_kwargs(&kwargs)?),
+            "rust_binary" => Rule::RustBinary(RustBinary::from_kwargs(&kwargs)?),
+            "rust_test" => Rule::RustTest(RustTest::from_kwargs(&kwargs)?),
+            "buildscript_run" => Rule::BuildscriptRun(BuildscriptRun::from_kwargs(&kwargs)?),
+            "http_archive" => Rule::HttpArchive(HttpArchive::from_kwargs(&kwargs)?),
+            "filegroup" => Rule::FileGroup(FileGroup::from_kwargs(&kwargs)?),
+            "cargo_manifest" => Rule::CargoManifest(CargoManifest::from_kwargs(&kwargs)?),
+            _ => bail!("Unknown function name: {}", func_name),
+        };
+        buck_rules.insert((func_name, name), rule);
+    }
+
+    Ok(buck_rules)
 }
 
 pub fn patch_buck_rules(
-    existing: &Map<String, Rule>,
+    existing: &Map<RuleKey, Rule>,
     to_patch: &mut [Rule],
     patch_fields: &Set<String>,
 ) {
     for rule in to_patch.iter_mut() {
         match rule {
             Rule::RustLibrary(new_rule) => {
-                if let Some(Rule::RustLibrary(existing_rule)) = existing.get("rust_library") {
+                if let Some(Rule::RustLibrary(existing_rule)) =
+                    existing.get(&rule_key("rust_library", &new_rule.name))
+                {
                     new_rule.patch_from(existing_rule, patch_fields);
                 }
             }
             Rule::RustBinary(new_rule) => {
-                if let Some(Rule::RustBinary(existing_rule)) = existing.get("rust_binary") {
+                if let Some(Rule::RustBinary(existing_rule)) =
+                    existing.get(&rule_key("rust_binary", &new_rule.name))
+                {
                     new_rule.patch_from(existing_rule, patch_fields);
                 }
             }
             Rule::RustTest(new_rule) => {
-                if let Some(Rule::RustTest(existing_rule)) = existing.get("rust_test") {
+                if let Some(Rule::RustTest(existing_rule)) =
+                    existing.get(&rule_key("rust_test", &new_rule.name))
+                {
                     new_rule.patch_from(existing_rule, patch_fields);
                 }
             }
             Rule::BuildscriptRun(new_rule) => {
-                if let Some(Rule::BuildscriptRun(existing_rule)) = existing.get("buildscript_run") {
+                if let Some(Rule::BuildscriptRun(existing_rule)) =
+                    existing.get(&rule_key("buildscript_run", &new_rule.name))
+                {
                     new_rule.patch_from(existing_rule, patch_fields);
                 }
             }
@@ -909,13 +1043,460 @@ pub fn patch_buck_rules(
     }
 }
 
-fn get_arg<'a, T>(kwargs: &Bound<'a, PyDict>, key: &str) -> T
+/// A keyword argument value of a rule call, as evaluated from a BUCK file.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    None,
+    Bool(bool),
+    Str(String),
+    List(Vec<Value>),
+    Dict(Map<String, Value>),
+    Glob(Glob),
+    /// Anything the rule structs never read (numbers, non-string dict keys, ...)
+    Unsupported,
+}
+
+type Kwargs = Map<String, Value>;
+
+trait FromValue: Sized {
+    fn from_value(value: &Value) -> Option<Self>;
+}
+
+impl FromValue for String {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Str(s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::None => Some(None),
+            _ => T::from_value(value).map(Some),
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::List(items) => items.iter().map(T::from_value).collect(),
+            _ => None,
+        }
+    }
+}
+
+impl<T: FromValue + Ord> FromValue for Set<T> {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::List(items) => items.iter().map(T::from_value).collect(),
+            _ => None,
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Map<String, T> {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Dict(entries) => entries
+                .iter()
+                .map(|(k, v)| T::from_value(v).map(|v| (k.clone(), v)))
+                .collect(),
+            _ => None,
+        }
+    }
+}
+
+fn rule_key(kind: &str, name: &str) -> RuleKey {
+    (kind.to_owned(), name.to_owned())
+}
+
+fn get_arg<T>(kwargs: &Kwargs, key: &str) -> T
 where
-    T: Default + FromPyObject<'a>,
+    T: Default + FromValue,
 {
-    kwargs
-        .get_item(key)
-        .unwrap_or_else(|_| panic!("Expected '{}' argument", key))
-        .and_then(|v| v.extract().ok())
-        .unwrap_or_default()
+    kwargs.get(key).and_then(T::from_value).unwrap_or_default()
+}
+
+fn kwargs_from_py(dict: &Bound<'_, PyDict>) -> Result<Kwargs> {
+    let mut kwargs = Kwargs::new();
+    for (key, value) in dict.iter() {
+        kwargs.insert(key.extract()?, value_from_py(&value)?);
+    }
+    Ok(kwargs)
+}
+
+fn value_from_py(obj: &Bound<'_, PyAny>) -> Result<Value> {
+    if obj.is_none() {
+        Ok(Value::None)
+    } else if let Ok(b) = obj.downcast::<PyBool>() {
+        Ok(Value::Bool(b.is_true()))
+    } else if let Ok(s) = obj.downcast::<PyString>() {
+        Ok(Value::Str(s.to_str()?.to_owned()))
+    } else if let Ok(list) = obj.downcast::<PyList>() {
+        let items = list.iter().map(|item| value_from_py(&item));
+        Ok(Value::List(items.collect::<Result<_>>()?))
+    } else if let Ok(tuple) = obj.downcast::<PyTuple>() {
+        // `glob(...)` evaluates to a `("glob", args, kwargs)` tuple
+        let is_glob = tuple.len() == 3
+            && tuple
+                .get_item(0)?
+                .extract::<String>()
+                .is_ok_and(|func| func == "glob");
+        if is_glob {
+            Ok(Value::Glob(Glob::from_py_tuple(tuple)?))
+        } else {
+            let items = tuple.iter().map(|item| value_from_py(&item));
+            Ok(Value::List(items.collect::<Result<_>>()?))
+        }
+    } else if let Ok(dict) = obj.downcast::<PyDict>() {
+        let mut entries = Map::new();
+        for (key, value) in dict.iter() {
+            let Ok(key) = key.extract::<String>() else {
+                return Ok(Value::Unsupported);
+            };
+            entries.insert(key, value_from_py(&value)?);
+        }
+        Ok(Value::Dict(entries))
+    } else {
+        Ok(Value::Unsupported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUCK_CONTENT: &str = r#"# @generated by `cargo buckal`
+
+load("@buckal//:wrapper.bzl", "rust_library")
+
+rust_library(
+    name = "foo",
+    srcs = [":foo-vendor"],
+    crate = "foo",
+    crate_root = "vendor/src/lib.rs",
+    edition = "2021",
+    features = [
+        "default",
+        "std",
+    ],
+    visibility = ["PUBLIC"],
+)
+"#;
+
+    fn parse_library(content: &str) -> RustLibrary {
+        let mut rules = parse_buck_content(content).expect("failed to parse BUCK content");
+        match rules.remove(&rule_key("rust_library", "foo")) {
+            Some(Rule::RustLibrary(rule)) => rule,
+            other => panic!("expected rust_library, got {other:?}"),
+        }
+    }
+
+    fn assert_round_trip(content: &str) {
+        let parsed = parse_library(content);
+        assert_eq!(parsed.name, "foo");
+        assert_eq!(parsed.crate_root, "vendor/src/lib.rs");
+        assert_eq!(
+            parsed.features,
+            Set::from(["default".to_owned(), "std".to_owned()])
+        );
+
+        let rendered = serde_starlark::to_string(&parsed).expect("failed to serialize rule");
+        assert!(!rendered.contains('\r'));
+        assert!(!rendered.starts_with('\u{feff}'));
+
+        let reparsed = parse_library(&rendered);
+        assert_eq!(reparsed.name, parsed.name);
+        assert_eq!(reparsed.crate_root, parsed.crate_root);
+        assert_eq!(reparsed.features, parsed.features);
+        assert_eq!(reparsed.visibility, parsed.visibility);
+    }
+
+    #[test]
+    fn test_normalize_buck_content() {
+        assert_eq!(normalize_buck_content("\u{feff}a\r\nb\r\n"), "a\nb\n");
+        assert_eq!(normalize_buck_content("a\nb\n"), "a\nb\n");
+    }
+
+    #[test]
+    fn test_parse_buck_content_with_bom() {
+        assert_round_trip(&format!("\u{feff}{BUCK_CONTENT}"));
+    }
+
+    #[test]
+    fn test_parse_buck_content_with_crlf() {
+        assert_round_trip(&BUCK_CONTENT.replace('\n', "\r\n"));
+        assert_round_trip(&format!("\u{feff}{}", BUCK_CONTENT.replace('\n', "\r\n")));
+    }
+
+    #[test]
+    fn test_parse_buck_content_without_leading_comment() {
+        // Content used to be spliced in with indentation, which only worked if the first line
+        // was a comment.
+        let content = BUCK_CONTENT.trim_start_matches("# @generated by `cargo buckal`\n\n");
+        assert_round_trip(content);
+    }
+
+    const MIXED_BUCK_CONTENT: &str = r#"# @generated by `cargo buckal`
+
+load("@buckal//:cargo_manifest.bzl", "cargo_manifest")
+load("@buckal//:wrapper.bzl", "buildscript_run", "rust_binary", "rust_library", "rust_test")
+
+http_archive(
+    name = "foo-vendor",
+    urls = ["https://static.crates.io/crates/foo/foo-1.0.0.crate"],
+    sha256 = "abc",
+    type = "tar.gz",
+    strip_prefix = "foo-1.0.0",
+    out = "vendor",
+)
+
+filegroup(
+    name = "foo-srcs",
+    srcs = glob(
+        include = ["**/**"],
+        exclude = ["target/**"],
+    ),
+    out = "vendor",
+)
+
+cargo_manifest(
+    name = "foo-manifest",
+    vendor = ":foo-vendor",
+)
+
+rust_library(
+    name = "foo",
+    srcs = [":foo-vendor"],
+    crate = "foo",
+    crate_root = "vendor/src/lib.rs",
+    edition = "2021",
+    target_compatible_with = ["prelude//os/constraints:linux"],
+    env = {
+        "CARGO_PKG_NAME": "foo",
+        "OUT_DIR": "$(location :foo-build-script-run[out_dir])",
+    },
+    features = ["default", "std"],
+    rustc_flags = ["@$(location :foo-build-script-run[rustc_flags])"] + ["--cfg=foo"],
+    proc_macro = True,
+    preferred_linkage = None,
+    named_deps = {"bar_alias": "//third-party/rust/crates/bar/1.0.0:bar"},
+    os_named_deps = {"baz": {"windows": "//third-party/rust/crates/baz/0.1.0:baz"}},
+    os_deps = {
+        "linux": ["//third-party/rust/crates/libc/0.2.0:libc"],
+        "macos": [],
+    },
+    visibility = ["PUBLIC"],
+    deps = select({
+        "DEFAULT": ["//third-party/rust/crates/qux/2.0.0:qux"],
+    }),
+)
+
+rust_binary(
+    name = "foo-bin",
+    srcs = [":foo-vendor"],
+    crate = "foo",
+    crate_root = "vendor/src/main.rs",
+    edition = "2021",
+    visibility = ["PUBLIC"],
+    deps = [":foo"],
+)
+
+rust_test(
+    name = "foo-unittest",
+    srcs = glob(["**/*.rs"]),
+    crate = "foo",
+    crate_root = "vendor/src/lib.rs",
+    edition = "2021",
+    visibility = ["PUBLIC"],
+)
+
+buildscript_run(
+    name = "foo-build-script-run",
+    package_name = "foo",
+    buildscript_rule = ":foo-build-script-build",
+    env_srcs = [":foo-manifest[env_dict]"],
+    features = ["default"],
+    version = "1.0.0",
+    manifest_dir = ":foo-vendor",
+)
+"#;
+
+    #[test]
+    fn test_parsers_agree() {
+        let from_python = parse_buck_content(MIXED_BUCK_CONTENT).expect("python parser failed");
+        let from_starlark =
+            starlark::parse_buck_content(MIXED_BUCK_CONTENT).expect("starlark parser failed");
+        assert_eq!(from_python, from_starlark);
+        assert_eq!(from_python.len(), 7);
+
+        let Some(Rule::RustLibrary(lib)) = from_starlark.get(&rule_key("rust_library", "foo"))
+        else {
+            panic!("expected rust_library");
+        };
+        assert_eq!(lib.proc_macro, Some(true));
+        assert_eq!(lib.rustc_flags.len(), 2);
+        assert_eq!(
+            lib.os_deps["linux"],
+            Set::from(["//third-party/rust/crates/libc/0.2.0:libc".to_owned()])
+        );
+        assert_eq!(
+            lib.os_named_deps["baz"]["windows"],
+            "//third-party/rust/crates/baz/0.1.0:baz"
+        );
+        // `select` evaluates to its dict, which is not a valid `deps` list
+        assert!(lib.deps.is_empty());
+
+        let Some(Rule::FileGroup(filegroup)) =
+            from_starlark.get(&rule_key("filegroup", "foo-srcs"))
+        else {
+            panic!("expected filegroup");
+        };
+        assert_eq!(filegroup.srcs.exclude, Set::from(["target/**".to_owned()]));
+    }
+
+    #[test]
+    fn test_parsers_agree_on_normalized_content() {
+        let content = format!("\u{feff}{}", BUCK_CONTENT.replace('\n', "\r\n"));
+        assert_eq!(
+            parse_buck_content(&content).unwrap(),
+            starlark::parse_buck_content(&content).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_starlark_parser_rejects_unknown_rules() {
+        let err = starlark::parse_buck_content("genrule(name = \"x\")\n").unwrap_err();
+        assert!(err.to_string().contains("genrule"));
+        assert!(parse_buck_content("genrule(name = \"x\")\n").is_err());
+    }
+
+    #[test]
+    fn test_parsers_drop_concatenated_select() {
+        let content = indoc::indoc! {r#"
+            rust_library(
+                name = "foo",
+                rustc_flags = ["--cfg=foo"] + select({
+                    "buckal//config/mode:release": ["-Copt-level=0"],
+                    "DEFAULT": [],
+                }),
+            )
+        "#};
+        let from_python = parse_buck_content(content).expect("python parser failed");
+        let from_starlark = starlark::parse_buck_content(content).expect("starlark parser failed");
+        assert_eq!(from_python, from_starlark);
+
+        let Some(Rule::RustLibrary(lib)) = from_python.get(&rule_key("rust_library", "foo")) else {
+            panic!("expected rust_library");
+        };
+        assert_eq!(lib.rustc_flags, Set::from(["--cfg=foo".to_owned()]));
+    }
+
+    #[test]
+    fn test_parsers_read_forwarded_env() {
+        let content = indoc::indoc! {r#"
+            buildscript_run(
+                name = "openssl-sys-build-script-run",
+                package_name = "openssl-sys",
+                buildscript_rule = ":openssl-sys-build-script-build",
+                env = {k: v for k, v in {"OPENSSL_DIR": read_config("sys_crate_env", "OPENSSL_DIR", ""), "OPENSSL_NO_VENDOR": "1"}.items() if v},
+                version = "0.9.0",
+                manifest_dir = ":openssl-sys-vendor",
+            )
+        "#};
+        let from_python = parse_buck_content(content).expect("python parser failed");
+        let from_starlark = starlark::parse_buck_content(content).expect("starlark parser failed");
+        assert_eq!(from_python, from_starlark);
+
+        let Some(Rule::BuildscriptRun(run)) =
+            from_python.get(&rule_key("buildscript_run", "openssl-sys-build-script-run"))
+        else {
+            panic!("expected buildscript_run");
+        };
+        assert_eq!(
+            run.env,
+            Map::from([("OPENSSL_NO_VENDOR".to_owned(), "1".to_owned())])
+        );
+    }
+
+    #[test]
+    fn test_parsers_read_gated_flags() {
+        let content = indoc::indoc! {r#"
+            rust_library(
+                name = "foo",
+                rustc_flags = ["@$(location :foo-build-script-run[rustc_flags])"] + (["@$(location :foo-build-script-run[check_cfg])"] if read_config("buckal", "check_cfg", "") == "true" else []),
+            )
+        "#};
+        let from_python = parse_buck_content(content).expect("python parser failed");
+        let from_starlark = starlark::parse_buck_content(content).expect("starlark parser failed");
+        assert_eq!(from_python, from_starlark);
+
+        // Without a buckconfig the gated flags are left out, so they never merge into the rule
+        let Some(Rule::RustLibrary(lib)) = from_python.get(&rule_key("rust_library", "foo")) else {
+            panic!("expected rust_library");
+        };
+        assert_eq!(
+            lib.rustc_flags,
+            Set::from(["@$(location :foo-build-script-run[rustc_flags])".to_owned()])
+        );
+    }
+
+    #[test]
+    fn test_patch_multiple_binaries_by_name() {
+        let existing = parse_buck_content(indoc::indoc! {r#"
+            rust_binary(
+                name = "foo",
+                crate_root = "src/main.rs",
+                env = {"MANUAL_FOO": "1"},
+                deps = ["//manual:foo_dep"],
+            )
+
+            rust_binary(
+                name = "foo-cli",
+                crate_root = "src/bin/cli.rs",
+                env = {"MANUAL_CLI": "1"},
+                deps = ["//manual:cli_dep"],
+            )
+        "#})
+        .unwrap();
+        assert_eq!(existing.len(), 2);
+
+        let mut generated = vec![
+            Rule::RustBinary(RustBinary {
+                name: "foo".to_owned(),
+                deps: Set::from([":foo-lib".to_owned()]),
+                ..Default::default()
+            }),
+            Rule::RustBinary(RustBinary {
+                name: "foo-cli".to_owned(),
+                deps: Set::from([":foo-lib".to_owned()]),
+                ..Default::default()
+            }),
+        ];
+        let patch_fields = Set::from(["env".to_owned(), "deps".to_owned()]);
+        patch_buck_rules(&existing, &mut generated, &patch_fields);
+
+        let [Rule::RustBinary(foo), Rule::RustBinary(cli)] = generated.as_slice() else {
+            panic!("expected two rust_binary rules");
+        };
+        assert_eq!(foo.env.keys().collect::<Vec<_>>(), ["MANUAL_FOO"]);
+        assert!(foo.deps.contains("//manual:foo_dep"));
+        assert!(!foo.deps.contains("//manual:cli_dep"));
+        assert_eq!(cli.env.keys().collect::<Vec<_>>(), ["MANUAL_CLI"]);
+        assert!(cli.deps.contains("//manual:cli_dep"));
+        assert!(cli.deps.contains(":foo-lib"));
+    }
 }
@@ -0,0 +1,180 @@
+use std::collections::BTreeMap as Map;
+
+use anyhow::{Result, anyhow, bail};
+use starlark_syntax::codemap::Spanned;
+use starlark_syntax::syntax::ast::{
+    ArgumentP, AstExpr, AstLiteral, AstNoPayload, AstStmt, BinOp, ExprP, Stmt,
+};
+use starlark_syntax::syntax::module::AstModuleFields;
+use starlark_syntax::syntax::{AstModule, Dialect};
+
+use super::{Glob, Kwargs, Rule, RuleKey, Value, normalize_buck_content, rules_from_calls};
+
+/// Parse a BUCK file by walking its Starlark AST, without embedding a Python interpreter.
+///
+/// Only the subset of Starlark that `cargo buckal` emits (rule calls with literal arguments,
+/// `glob`, `select`, `read_config`, list concatenation and the comprehensions and conditionals
+/// around buckconfig lookups) is understood; anything else is reported as an error
+/// rather than silently ignored.
+pub(super) fn parse_buck_content(buck: &str) -> Result<Map<RuleKey, Rule>> {
+    let ast = AstModule::parse("BUCK", normalize_buck_content(buck), &Dialect::Extended)
+        .map_err(|e| anyhow!("failed to parse BUCK file: {e}"))?;
+
+    let mut calls = Vec::new();
+    collect_rule_calls(ast.statement(), &mut calls)?;
+
+    rules_from_calls(calls)
+}
+
+fn collect_rule_calls(stmt: &AstStmt, out: &mut Vec<(String, Kwargs)>) -> Result<()> {
+    match &stmt.node {
+        Stmt::Statements(stmts) => {
+            for s in stmts {
+                collect_rule_calls(s, out)?;
+            }
+        }
+        Stmt::Load(_) | Stmt::Pass => {}
+        Stmt::Expression(expr) => {
+            if let ExprP::Call(callee, args) = &expr.node
+                && let ExprP::Identifier(ident) = &callee.node
+                && !matches!(ident.node.ident.as_str(), "glob" | "select")
+            {
+                let (_, kwargs) = eval_args(&args.args)?;
+                out.push((ident.node.ident.clone(), kwargs));
+            } else {
+                eval_expr(expr)?;
+            }
+        }
+        _ => bail!("unsupported statement in BUCK file"),
+    }
+    Ok(())
+}
+
+fn eval_args(args: &[Spanned<ArgumentP<AstNoPayload>>]) -> Result<(Vec<Value>, Kwargs)> {
+    let mut positional = Vec::new();
+    let mut kwargs = Kwargs::new();
+    for arg in args {
+        match &arg.node {
+            ArgumentP::Positional(expr) => positional.push(eval_expr(expr)?),
+            ArgumentP::Named(name, expr) => {
+                kwargs.insert(name.node.clone(), eval_expr(expr)?);
+            }
+            ArgumentP::Args(_) | ArgumentP::KwArgs(_) => {
+                bail!("`*args` and `**kwargs` are not supported in BUCK files")
+            }
+        }
+    }
+    Ok((positional, kwargs))
+}
+
+fn eval_expr(expr: &AstExpr) -> Result<Value> {
+    match &expr.node {
+        ExprP::Literal(AstLiteral::String(s)) => Ok(Value::Str(s.node.clone())),
+        ExprP::Literal(_) => Ok(Value::Unsupported),
+        ExprP::Identifier(ident) => match ident.node.ident.as_str() {
+            "True" => Ok(Value::Bool(true)),
+            "False" => Ok(Value::Bool(false)),
+            "None" => Ok(Value::None),
+            name => bail!("undefined variable `{}` in BUCK file", name),
+        },
+        ExprP::List(items) | ExprP::Tuple(items) => items
+            .iter()
+            .map(eval_expr)
+            .collect::<Result<_>>()
+            .map(Value::List),
+        ExprP::Dict(entries) => {
+            let mut dict = Map::new();
+            let mut string_keys = true;
+            for (key, value) in entries {
+                let value = eval_expr(value)?;
+                match eval_expr(key)? {
+                    Value::Str(key) => {
+                        dict.insert(key, value);
+                    }
+                    _ => string_keys = false,
+                }
+            }
+            Ok(if string_keys {
+                Value::Dict(dict)
+            } else {
+                Value::Unsupported
+            })
+        }
+        ExprP::Call(callee, args) => {
+            let ExprP::Identifier(ident) = &callee.node else {
+                bail!("unsupported call in BUCK file");
+            };
+            let (positional, kwargs) = eval_args(&args.args)?;
+            match ident.node.ident.as_str() {
+                "glob" => Ok(Value::Glob(Glob::from_call(&positional, &kwargs)?)),
+                "select" => match <[Value; 1]>::try_from(positional) {
+                    Ok([arg]) if kwargs.is_empty() => Ok(arg),
+                    _ => bail!("select() takes exactly one positional argument"),
+                },
+                // Evaluated without a buckconfig, so every key is unset
+                "read_config" => Ok(positional
+                    .into_iter()
+                    .nth(2)
+                    .or_else(|| kwargs.get("default").cloned())
+                    .unwrap_or(Value::None)),
+                name => bail!("unsupported function `{}` in BUCK file", name),
+            }
+        }
+        ExprP::Op(lhs, BinOp::Add, rhs) => match (eval_expr(lhs)?, eval_expr(rhs)?) {
+            // `select` branches are dropped when concatenated, keeping the unconditional operand
+            (lhs, _) if is_select_call(rhs) => Ok(lhs),
+            (_, rhs) if is_select_call(lhs) => Ok(rhs),
+            (Value::List(mut lhs), Value::List(rhs)) => {
+                lhs.extend(rhs);
+                Ok(Value::List(lhs))
+            }
+            (Value::Str(lhs), Value::Str(rhs)) => Ok(Value::Str(lhs + &rhs)),
+            _ => bail!("unsupported operands for `+` in BUCK file"),
+        },
+        ExprP::Op(lhs, BinOp::Equal, rhs) => Ok(Value::Bool(eval_expr(lhs)? == eval_expr(rhs)?)),
+        // `x if cond else y`, as around flags gated on a buckconfig key
+        ExprP::If(branches) => {
+            let (cond, then, otherwise) = &**branches;
+            let cond = match eval_expr(cond)? {
+                Value::None => false,
+                Value::Bool(b) => b,
+                Value::Str(s) => !s.is_empty(),
+                Value::List(items) => !items.is_empty(),
+                Value::Dict(entries) => !entries.is_empty(),
+                Value::Glob(_) | Value::Unsupported => {
+                    bail!("unsupported condition in BUCK file")
+                }
+            };
+            eval_expr(if cond { then } else { otherwise })
+        }
+        // `{k: v for k, v in {...}.items() if v}`, as around forwarded env: the non-empty entries
+        ExprP::DictComprehension(_, for_clause, clauses) => {
+            let ExprP::Call(callee, args) = &for_clause.over.node else {
+                bail!("unsupported comprehension in BUCK file");
+            };
+            let ExprP::Dot(dict, method) = &callee.node else {
+                bail!("unsupported comprehension in BUCK file");
+            };
+            if method.node != "items" || !args.args.is_empty() || clauses.len() != 1 {
+                bail!("unsupported comprehension in BUCK file");
+            }
+            match eval_expr(dict)? {
+                Value::Dict(entries) => Ok(Value::Dict(
+                    entries
+                        .into_iter()
+                        .filter(|(_, value)| {
+                            !matches!(value, Value::None) && *value != Value::Str(String::new())
+                        })
+                        .collect(),
+                )),
+                _ => bail!("unsupported comprehension in BUCK file"),
+            }
+        }
+        _ => bail!("unsupported expression in BUCK file"),
+    }
+}
+
+fn is_select_call(expr: &AstExpr) -> bool {
+    matches!(&expr.node, ExprP::Call(callee, _)
+        if matches!(&callee.node, ExprP::Identifier(ident) if ident.node.ident == "select"))
+}
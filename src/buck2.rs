@@ -91,6 +91,11 @@ impl Buck2Command {
     pub fn uquery() -> Self {
         Self::new().subcommand("uquery")
     }
+
+    /// Create a cquery command
+    pub fn cquery() -> Self {
+        Self::new().subcommand("cquery")
+    }
 }
 
 impl Default for Buck2Command {
@@ -1,9 +1,33 @@
 mod actions;
+mod buildozer;
+mod check_cfg;
 mod cross;
 mod deps;
+mod drops;
 mod emit;
+mod graph;
+mod profile;
+mod project;
+mod report;
 mod rules;
+mod stats;
+mod sys_env;
+mod unittests;
 mod windows;
 
-pub use actions::flush_root;
-pub use rules::{buckify_dep_node, buckify_root_node, gen_buck_content, vendor_package};
+pub use actions::{
+    dependents_of, ensure_generated_buck_files, flush_members, flush_packages, flush_root,
+    generate_checksums_manifest, generate_third_party_aliases, generate_vendor_gitignore,
+    refresh_cargo_manifests, render_buck_files,
+};
+pub use buildozer::buildozer_script;
+pub use deps::dependent_oses_in;
+pub use drops::print_dropped_deps;
+pub use graph::{check_cycles, dependency_graph_dot};
+pub use project::{ProjectToolchain, rust_project_json};
+pub use report::BuckalReport;
+pub use rules::{
+    buckify_dep_node, buckify_root_node, check_crate_root, gen_buck_content, vendor_package,
+};
+pub use stats::BuckifyStats;
+pub use unittests::UNITTESTS_CONFIG;
@@ -1,158 +1,711 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     io::{BufWriter, Write},
+    panic::AssertUnwindSafe,
+    sync::{
+        LazyLock,
+        atomic::{AtomicUsize, Ordering},
+    },
 };
 
+use anyhow::{Context, Result, anyhow, bail};
 use regex::Regex;
 
-use cargo_metadata::camino::Utf8PathBuf;
+use cargo_metadata::{Node, Package, PackageId, camino::Utf8PathBuf};
 
 use crate::{
     RUST_CRATES_ROOT,
-    buck::{Alias, parse_buck_file, patch_buck_rules},
-    buckal_log,
+    buck::{Alias, Rule, parse_buck_file, patch_buck_rules},
+    buckal_log, buckal_warn,
     cache::{BuckalChange, ChangeType},
-    context::BuckalContext,
-    utils::{UnwrapOrExit, get_buck2_root, get_vendor_dir},
+    context::{BuckalContext, ChecksumKey},
+    utils::{
+        PackageSource, UnwrapOrExit, get_buck2_root, get_vendor_dir, remove_vendor_dir,
+        source_segment,
+    },
 };
 
 use super::{
-    buckify_dep_node, buckify_root_node, cross, gen_buck_content, vendor_package, windows,
+    buckify_dep_node, buckify_root_node, check_cfg, cross,
+    deps::third_party_label,
+    emit::{emit_cargo_manifest, forwarded_sys_crate_env},
+    gen_buck_content,
+    profile::ProfileOverrides,
+    stats::record_rules,
+    sys_env, unittests, vendor_package, windows,
 };
 
 impl BuckalChange {
-    pub fn apply(&self, ctx: &BuckalContext) {
-        // This function applies changes to the BUCK files of detected packages in the cache diff, but skips the root package.
-        let re = Regex::new(r"^([^+#]+)\+([^#]+)#([^@]+)@([^+#]+)(?:\+(.+))?$")
-            .expect("error creating regex");
-        let skip_pattern = format!("path+file://{}", ctx.workspace_root);
+    /// Apply the changes and return the BUCK files written.
+    ///
+    /// Removals run first, so a crate re-added under the same name and version (e.g. from
+    /// another source) isn't removed after being written. BUCK files are then rendered by up
+    /// to `ctx.jobs` threads, and returned in package order regardless of the job count.
+    pub fn apply(&self, ctx: &BuckalContext) -> Vec<Utf8PathBuf> {
+        let (written, _) = self.apply_with(ctx, false);
+        written
+    }
+
+    /// Apply the changes like [`apply`](Self::apply), but record the packages that fail to be
+    /// buckified (e.g. a failed download or a missing checksum) and carry on with the others,
+    /// for `--keep-going`. Returns the BUCK files written and the failures.
+    pub fn apply_keep_going(&self, ctx: &BuckalContext) -> (Vec<Utf8PathBuf>, Vec<BuckifyFailure>) {
+        self.apply_with(ctx, true)
+    }
 
+    fn apply_with(
+        &self,
+        ctx: &BuckalContext,
+        keep_going: bool,
+    ) -> (Vec<Utf8PathBuf>, Vec<BuckifyFailure>) {
+        let mut failures = Vec::new();
+        // This function applies changes to the BUCK files of detected packages in the cache diff, but skips workspace members.
+        let mut pending = Vec::new();
         for (id, change_type) in &self.changes {
             match change_type {
                 ChangeType::Added | ChangeType::Changed => {
-                    // Skip root package
-                    if let Some(root) = &ctx.root
-                        && id == &root.id
-                    {
+                    // Skip workspace members, which are always regenerated by `flush_root`, and
+                    // first-party packages under `--separate`
+                    if ctx.workspace_members.contains(id) || self.is_separated(id, ctx) {
                         continue;
                     }
 
                     if let Some(node) = ctx.nodes_map.get(id) {
                         let package = ctx.packages_map.get(id).unwrap();
-
-                        buckal_log!(
-                            if let ChangeType::Added = change_type {
-                                "Adding"
-                            } else {
-                                "Flushing"
-                            },
-                            format!("{} v{}", package.name, package.version)
-                        );
-
-                        // Vendor package sources
-                        let vendor_dir = if package.source.is_none() {
-                            package.manifest_path.parent().unwrap().to_owned()
-                        } else {
-                            vendor_package(package)
-                        };
-
-                        // Generate BUCK rules
-                        let mut buck_rules = if package.source.is_none() {
-                            buckify_root_node(node, ctx)
-                        } else {
-                            buckify_dep_node(node, ctx)
-                        };
-
-                        // Patch BUCK Rules
-                        let buck_path = vendor_dir.join("BUCK");
-                        if buck_path.exists() {
-                            // Skip merging manual changes if `--no-merge` is set
-                            if !ctx.no_merge && !ctx.repo_config.patch_fields.is_empty() {
-                                let existing_rules = parse_buck_file(&buck_path)
-                                    .expect("Failed to parse existing BUCK file");
-                                patch_buck_rules(
-                                    &existing_rules,
-                                    &mut buck_rules,
-                                    &ctx.repo_config.patch_fields,
-                                );
-                            }
-                        } else {
-                            std::fs::File::create(&buck_path).expect("Failed to create BUCK file");
+                        // Its BUCK file is written by the package whose vendored copy it shares
+                        if ctx.is_linked_vendor(package) {
+                            continue;
                         }
-
-                        // Generate the BUCK file
-                        let mut buck_content = gen_buck_content(&buck_rules);
-                        buck_content = cross::patch_rust_test_target_compatible_with(buck_content);
-                        std::fs::write(&buck_path, buck_content)
-                            .expect("Failed to write BUCK file");
+                        pending.push((node, package, change_type));
                     }
                 }
                 ChangeType::Removed => {
-                    // Skip workspace_root package
-                    if id.repr.starts_with(skip_pattern.as_str()) {
+                    // Skip first-party packages: they are never vendored, and a workspace crate
+                    // may share its name (and even version) with a vendored registry package.
+                    let (name, version) = match try_buckify(id.repr.clone(), keep_going, || {
+                        vendored_name_version(id)
+                    }) {
+                        Ok(Some(name_version)) => name_version,
+                        Ok(None) => continue,
+                        Err(failure) => {
+                            failures.push(failure);
+                            continue;
+                        }
+                    };
+
+                    // Another source of the same crate version may still use its directory, or
+                    // hold its own copy within it
+                    let vendor_dir =
+                        get_vendor_dir(&name, &version).context("failed to get vendor directory");
+                    let source_dir = vendor_dir
+                        .as_ref()
+                        .ok()
+                        .map(|dir| dir.join(source_segment(id)))
+                        .filter(|dir| dir.exists());
+                    let still_vendored = ctx.packages_map.values().any(|p| {
+                        p.source.is_some()
+                            && p.name.as_str() == name
+                            && p.version.to_string() == version
+                    });
+                    if source_dir.is_none() && still_vendored {
                         continue;
                     }
 
-                    let caps = re.captures(&id.repr).expect("Failed to parse package ID");
-                    let name = &caps[3];
-                    let version = &caps[4];
-
                     buckal_log!("Removing", format!("{} v{}", name, version));
-                    let vendor_dir = get_vendor_dir(name, version)
-                        .unwrap_or_exit_ctx("failed to get vendor directory");
-                    if vendor_dir.exists() {
-                        std::fs::remove_dir_all(&vendor_dir)
-                            .expect("Failed to remove vendor directory");
-                    }
-                    if let Some(package_dir) = vendor_dir.parent()
-                        && package_dir.exists()
-                        && package_dir.read_dir().unwrap().next().is_none()
-                    {
-                        std::fs::remove_dir_all(package_dir)
-                            .expect("Failed to remove empty package directory");
+                    let removed = try_buckify(format!("{} v{}", name, version), keep_going, || {
+                        let vendor_dir = source_dir.map_or(vendor_dir, Ok)?;
+                        remove_vendor_dir(&vendor_dir).context("failed to remove vendor directory")
+                    });
+                    if let Err(failure) = removed {
+                        failures.push(failure);
                     }
                 }
             }
         }
+
+        let profile_overrides = ProfileOverrides::load(&ctx.workspace_root);
+        let results = parallel_map(&pending, ctx.jobs, |(node, package, change_type)| {
+            buckal_log!(
+                if let ChangeType::Added = change_type {
+                    "Adding"
+                } else {
+                    "Flushing"
+                },
+                format!("{} v{}", package.name, package.version)
+            );
+            try_buckify(
+                format!("{} v{}", package.name, package.version),
+                keep_going,
+                || write_buck_file(node, package, ctx, &profile_overrides),
+            )
+        });
+
+        let mut written = Vec::new();
+        for result in results {
+            match result {
+                Ok(buck_path) => written.push(buck_path),
+                Err(failure) => failures.push(failure),
+            }
+        }
+        (written, failures)
+    }
+
+    /// Names of the added or changed first-party packages that `apply` leaves untouched because
+    /// of `--separate`, workspace members excluded.
+    pub fn separated_packages(&self, ctx: &BuckalContext) -> Vec<String> {
+        self.changes
+            .iter()
+            .filter(|(id, change_type)| {
+                !matches!(change_type, ChangeType::Removed)
+                    && !ctx.workspace_members.contains(id)
+                    && self.is_separated(id, ctx)
+            })
+            .filter_map(|(id, _)| ctx.packages_map.get(id))
+            .map(|package| package.name.to_string())
+            .collect()
+    }
+
+    /// Whether any third-party crate is added, changed or removed.
+    pub fn has_third_party_changes(&self, ctx: &BuckalContext) -> bool {
+        self.changes
+            .keys()
+            .any(|id| match ctx.packages_map.get(id) {
+                Some(package) => package.source.is_some(),
+                // Removed packages are no longer in the metadata; those whose ID can't be parsed
+                // are counted, as they may well be
+                None => !matches!(vendored_name_version(id), Ok(None)),
+            })
+    }
+
+    fn is_separated(&self, id: &PackageId, ctx: &BuckalContext) -> bool {
+        ctx.separate
+            && ctx
+                .packages_map
+                .get(id)
+                .is_some_and(|package| package.source.is_none())
+    }
+}
+
+/// A package that failed to be buckified under `--keep-going`.
+#[derive(Debug)]
+pub struct BuckifyFailure {
+    /// Package name and version, e.g. `foo v1.0.0`, or the directory of a shared BUCK file
+    pub package: String,
+    pub error: String,
+}
+
+/// Run `buckify` for `package`, returning its error as a failure under `keep_going`, and exiting
+/// with it otherwise.
+fn try_buckify<T>(
+    package: String,
+    keep_going: bool,
+    buckify: impl FnOnce() -> Result<T>,
+) -> Result<T, BuckifyFailure> {
+    if !keep_going {
+        return Ok(buckify().unwrap_or_exit());
     }
+    // Much of buckify still panics on unexpected input, which must not take down the other
+    // packages either
+    std::panic::catch_unwind(AssertUnwindSafe(buckify))
+        .unwrap_or_else(|panic| Err(anyhow!(panic_message(panic.as_ref()))))
+        .map_err(|error| BuckifyFailure {
+            package,
+            error: format!("{error:#}"),
+        })
 }
 
-pub fn flush_root(ctx: &BuckalContext) {
-    // Generate BUCK file for root package
-    // Skip if root package is not found (in virtual workspace)
-    if let Some(root) = &ctx.root {
-        buckal_log!("Flushing", format!("{} v{}", root.name, root.version));
-        let root_node = ctx.nodes_map.get(&root.id).expect("Root node not found");
+/// The message a panic was raised with.
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "panicked".to_owned()
+    }
+}
 
-        if ctx.repo_config.inherit_workspace_deps {
-            buckal_log!(
-                "Generating",
-                "third-party alias rules (inherit_workspace_deps=true)"
+/// Flush every first-party workspace member and return the BUCK files written, with the
+/// failures recorded under `keep_going` (otherwise the first one exits).
+///
+/// Members are iterated rather than assuming a single root package, so virtual workspaces (which
+/// have no root package) get a BUCK file for each of their members as well.
+pub fn flush_root(
+    ctx: &BuckalContext,
+    keep_going: bool,
+) -> (Vec<Utf8PathBuf>, Vec<BuckifyFailure>) {
+    let mut written = Vec::new();
+    let mut failures = Vec::new();
+
+    if ctx.repo_config.inherit_workspace_deps {
+        buckal_log!(
+            "Generating",
+            "third-party alias rules (inherit_workspace_deps=true)"
+        );
+        match try_buckify("third-party/rust".to_owned(), keep_going, || {
+            generate_third_party_aliases(ctx)
+        }) {
+            Ok(buck_file) => written.push(buck_file),
+            Err(failure) => failures.push(failure),
+        }
+    }
+
+    let (members, member_failures) = flush_members(ctx, keep_going);
+    written.extend(members);
+    failures.extend(member_failures);
+    (written, failures)
+}
+
+/// Flush every first-party workspace member like [`flush_root`], leaving the third-party alias
+/// rules untouched.
+pub fn flush_members(
+    ctx: &BuckalContext,
+    keep_going: bool,
+) -> (Vec<Utf8PathBuf>, Vec<BuckifyFailure>) {
+    let mut written = Vec::new();
+    let mut failures = Vec::new();
+    let profile_overrides = ProfileOverrides::load(&ctx.workspace_root);
+    for member_id in &ctx.workspace_members {
+        let Some(member) = ctx.packages_map.get(member_id) else {
+            continue;
+        };
+        buckal_log!("Flushing", format!("{} v{}", member.name, member.version));
+        let flushed = try_buckify(
+            format!("{} v{}", member.name, member.version),
+            keep_going,
+            || {
+                let member_node = ctx
+                    .nodes_map
+                    .get(member_id)
+                    .context("workspace member is not in the dependency graph")?;
+                write_buck_file(member_node, member, ctx, &profile_overrides)
+            },
+        );
+        match flushed {
+            Ok(buck_path) => written.push(buck_path),
+            Err(failure) => failures.push(failure),
+        }
+    }
+    (written, failures)
+}
+
+/// The packages named `name` and, transitively, every package depending on them.
+pub fn dependents_of(ctx: &BuckalContext, name: &str) -> Result<Vec<PackageId>> {
+    let mut affected: BTreeSet<&PackageId> = ctx
+        .packages_map
+        .values()
+        .filter(|package| package.name.as_str() == name)
+        .map(|package| &package.id)
+        .filter(|id| ctx.nodes_map.contains_key(*id))
+        .collect();
+    if affected.is_empty() {
+        bail!("package `{}` is not in the dependency graph", name);
+    }
+
+    let reverse_deps = ctx.reverse_deps();
+    let mut queue: Vec<&PackageId> = affected.iter().copied().collect();
+    while let Some(id) = queue.pop() {
+        for (dependent, _) in reverse_deps.get(id).into_iter().flatten() {
+            if affected.insert(dependent) {
+                queue.push(dependent);
+            }
+        }
+    }
+    Ok(affected.into_iter().cloned().collect())
+}
+
+/// Regenerate the BUCK files of exactly the given packages and return the files written.
+pub fn flush_packages(ctx: &BuckalContext, ids: &[PackageId]) -> Vec<Utf8PathBuf> {
+    let profile_overrides = ProfileOverrides::load(&ctx.workspace_root);
+    let mut written = Vec::new();
+    for id in ids {
+        let (Some(node), Some(package)) = (ctx.nodes_map.get(id), ctx.packages_map.get(id)) else {
+            continue;
+        };
+        buckal_log!("Flushing", format!("{} v{}", package.name, package.version));
+        written.push(write_buck_file(node, package, ctx, &profile_overrides).unwrap_or_exit());
+    }
+    written
+}
+
+/// Regenerate only the `cargo_manifest` rule of the packages named `name` and the `env` of their
+/// Rust rules, leaving the rest of their BUCK files byte-identical, and return the files written.
+pub fn refresh_cargo_manifests(ctx: &BuckalContext, name: &str) -> Result<Vec<Utf8PathBuf>> {
+    let packages = package_buck_paths(ctx, &[name.to_owned()])?;
+    if packages.is_empty() {
+        bail!("package `{}` is not in the dependency graph", name);
+    }
+
+    let mut written = Vec::new();
+    for (node, package, buck_path) in packages {
+        buckal_log!(
+            "Refreshing",
+            format!("{} v{} manifest", package.name, package.version)
+        );
+        let buck_content = std::fs::read_to_string(&buck_path)
+            .with_context(|| format!("failed to read {}", buck_path))?;
+        let buck_rules = generate_rules(node, package, &buck_path, ctx)?;
+        let buck_content = refresh_cargo_manifest_in(&buck_content, package, &buck_rules, ctx)
+            .with_context(|| format!("failed to refresh the manifest in {}", buck_path))?;
+        std::fs::write(&buck_path, buck_content)
+            .with_context(|| format!("failed to write {}", buck_path))?;
+        record_rules(&buck_path, &buck_rules);
+        written.push(buck_path);
+    }
+    Ok(written)
+}
+
+/// Replace the `cargo_manifest` rule of `package` in `buck_content` with a freshly emitted one,
+/// and the `env` of its Rust rules with the one of `buck_rules`, the rules a full sync would emit,
+/// which picks up changes to the package's `[package.metadata.buckal]`.
+fn refresh_cargo_manifest_in(
+    buck_content: &str,
+    package: &Package,
+    buck_rules: &[Rule],
+    ctx: &BuckalContext,
+) -> Result<String> {
+    let manifest = emit_cargo_manifest(package, ctx);
+    let Some(range) = windows::rule_call_range(buck_content, "cargo_manifest", &manifest.name)
+    else {
+        bail!("no `cargo_manifest` rule named `{}`", manifest.name);
+    };
+    let rendered = serde_starlark::to_string(&Rule::CargoManifest(manifest))?;
+
+    let mut refreshed = String::with_capacity(buck_content.len());
+    refreshed.push_str(&buck_content[..range.start]);
+    refreshed.push_str(rendered.trim_end());
+    refreshed.push_str(&buck_content[range.end..]);
+
+    for rule in buck_rules {
+        let (kind, name, env) = match rule {
+            Rule::RustLibrary(rule) => ("rust_library", &rule.name, &rule.env),
+            Rule::RustBinary(rule) => ("rust_binary", &rule.name, &rule.env),
+            Rule::RustTest(rule) => ("rust_test", &rule.name, &rule.env),
+            _ => continue,
+        };
+        let env = (!env.is_empty()).then(|| render_env(env));
+        if let Some(patched) =
+            windows::set_rule_kwarg(&refreshed, kind, name, ENV_KWARG, env.as_deref())
+        {
+            refreshed = patched;
+        }
+    }
+    Ok(refreshed)
+}
+
+/// The `env` attribute of the Rust rules, with the attributes emitted before it.
+const ENV_KWARG: (&str, &[&str]) = (
+    "env",
+    &[
+        "name",
+        "srcs",
+        "mapped_srcs",
+        "crate",
+        "crate_root",
+        "edition",
+        "target_compatible_with",
+        "compatible_with",
+        "exec_compatible_with",
+        "default_target_platform",
+    ],
+);
+
+/// Render an `env` dict the way the generated rules are written.
+fn render_env(env: &BTreeMap<String, String>) -> String {
+    let mut out = String::from("{\n");
+    for (key, value) in env {
+        out.push_str("        ");
+        windows::write_string_literal(&mut out, key);
+        out.push_str(": ");
+        windows::write_string_literal(&mut out, value);
+        out.push_str(",\n");
+    }
+    out.push_str("    }");
+    out
+}
+
+/// Run `f` over `items` on up to `jobs` threads, returning the results in the order of `items`.
+fn parallel_map<T, R, F>(items: &[T], jobs: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let jobs = jobs.clamp(1, items.len().max(1));
+    if jobs == 1 {
+        return items.iter().map(f).collect();
+    }
+
+    // Workers pull the next item from a shared counter, so a slow package doesn't hold up a
+    // whole batch
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, R)> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..jobs)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(i) else {
+                            break done;
+                        };
+                        done.push((i, f(item)));
+                    }
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("buckify worker panicked"))
+            .collect()
+    });
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Vendor the sources of a package if needed and write its BUCK file.
+fn write_buck_file(
+    node: &Node,
+    package: &Package,
+    ctx: &BuckalContext,
+    profile_overrides: &ProfileOverrides,
+) -> Result<Utf8PathBuf> {
+    let package_dir = if package.source.is_none() {
+        package.manifest_path.parent().unwrap().to_owned()
+    } else {
+        // Checked before anything is vendored, as the `http_archive` can't be generated without
+        if !ctx.checksums_map.contains_key(&ChecksumKey::of(package)) {
+            bail!(
+                "no checksum for `{} v{}` in Cargo.lock",
+                package.name,
+                package.version
             );
-            generate_third_party_aliases(ctx);
         }
+        vendor_package(package, ctx)?
+    };
 
-        let cwd = std::env::current_dir().expect("Failed to get current directory");
-        let buck_path = Utf8PathBuf::from(cwd.to_str().unwrap()).join("BUCK");
+    let buck_path = package_dir.join("BUCK");
+    let buck_rules = generate_rules(node, package, &buck_path, ctx)?;
+    let buck_content = render_rules(&buck_rules, package, ctx, profile_overrides);
+    std::fs::write(&buck_path, buck_content)
+        .with_context(|| format!("failed to write `{}`", buck_path))?;
+    record_rules(&buck_path, &buck_rules);
+    Ok(buck_path)
+}
+
+/// Render the BUCK files of every package (or only those named in `packages`) in memory,
+/// returning each file path with its freshly generated content. Nothing is vendored or written.
+pub fn render_buck_files(
+    ctx: &BuckalContext,
+    packages: &[String],
+) -> Result<Vec<(Utf8PathBuf, String)>> {
+    let profile_overrides = ProfileOverrides::load(&ctx.workspace_root);
+    let mut rendered = package_buck_paths(ctx, packages)?
+        .into_iter()
+        .map(|(node, package, buck_path)| {
+            let buck_content =
+                render_buck_file(node, package, &buck_path, ctx, &profile_overrides)?;
+            Ok((buck_path, buck_content))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    rendered.sort();
+    Ok(rendered)
+}
+
+/// Generate the rules of every package (or only those named in `packages`) in memory, with
+/// manual edits merged, along with the path of their BUCK file.
+pub(super) fn generate_package_rules(
+    ctx: &BuckalContext,
+    packages: &[String],
+) -> Result<Vec<(Utf8PathBuf, Vec<Rule>)>> {
+    let mut generated = package_buck_paths(ctx, packages)?
+        .into_iter()
+        .map(|(node, package, buck_path)| {
+            let buck_rules = generate_rules(node, package, &buck_path, ctx)?;
+            Ok((buck_path, buck_rules))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    generated.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(generated)
+}
 
-        // Generate BUCK rules
-        let buck_rules = buckify_root_node(root_node, ctx);
+/// The packages (or only those named in `packages`) with the path of their BUCK file.
+fn package_buck_paths<'a>(
+    ctx: &'a BuckalContext,
+    packages: &[String],
+) -> Result<Vec<(&'a Node, &'a Package, Utf8PathBuf)>> {
+    let mut paths = Vec::new();
+    for (id, node) in &ctx.nodes_map {
+        let Some(package) = ctx.packages_map.get(id) else {
+            continue;
+        };
+        // A shared vendored copy has its BUCK file rendered once, from the package owning it
+        if (!packages.is_empty() && !packages.iter().any(|p| p == package.name.as_str()))
+            || ctx.is_linked_vendor(package)
+        {
+            continue;
+        }
 
-        // Generate the BUCK file
-        let mut buck_content = gen_buck_content(&buck_rules);
-        buck_content = windows::patch_root_windows_rustc_flags(buck_content, ctx, root);
-        buck_content = cross::patch_rust_test_target_compatible_with(buck_content);
-        std::fs::write(&buck_path, buck_content).expect("Failed to write BUCK file");
+        let package_dir = if package.source.is_none() {
+            package
+                .manifest_path
+                .parent()
+                .expect("Failed to get manifest directory")
+                .to_owned()
+        } else {
+            ctx.vendor_dir(package)
+                .context("failed to get vendor directory")?
+        };
+        paths.push((node, package, package_dir.join("BUCK")));
     }
+    Ok(paths)
 }
 
-fn generate_third_party_aliases(ctx: &BuckalContext) {
-    let root = get_buck2_root().expect("failed to get buck2 root");
-    let dir = root.join("third-party/rust");
-    std::fs::create_dir_all(&dir).expect("failed to create third-party/rust dir");
+/// Generate the rules of a package, merging manual edits from `buck_path`.
+fn generate_rules(
+    node: &Node,
+    package: &Package,
+    buck_path: &Utf8PathBuf,
+    ctx: &BuckalContext,
+) -> Result<Vec<Rule>> {
+    // Generate BUCK rules
+    let mut buck_rules = if package.source.is_none() {
+        buckify_root_node(node, ctx)?
+    } else {
+        buckify_dep_node(node, ctx)?
+    };
 
-    let buck_file = dir.join("BUCK");
+    // Patch BUCK Rules
+    merge_existing_rules(buck_path, &mut buck_rules, ctx);
+    Ok(buck_rules)
+}
+
+/// Generate the BUCK file content of a package, merging manual edits from `buck_path`.
+fn render_buck_file(
+    node: &Node,
+    package: &Package,
+    buck_path: &Utf8PathBuf,
+    ctx: &BuckalContext,
+    profile_overrides: &ProfileOverrides,
+) -> Result<String> {
+    let buck_rules = generate_rules(node, package, buck_path, ctx)?;
+    Ok(render_rules(&buck_rules, package, ctx, profile_overrides))
+}
+
+/// Generate the BUCK file content of a package from its rules.
+fn render_rules(
+    buck_rules: &[Rule],
+    package: &Package,
+    ctx: &BuckalContext,
+    profile_overrides: &ProfileOverrides,
+) -> String {
+    // Generate the BUCK file
+    let mut buck_content = gen_buck_content(buck_rules);
+    buck_content = profile_overrides.patch_buck_content(buck_content, package, buck_rules);
+    if ctx.workspace_members.contains(&package.id) {
+        buck_content = windows::patch_root_windows_rustc_flags(buck_content, ctx, package);
+    }
+    if let Some(vars) = forwarded_sys_crate_env(&package.name, ctx)
+        && let Some(run) = buck_rules.iter().find_map(|rule| match rule {
+            Rule::BuildscriptRun(run) => Some(run),
+            _ => None,
+        })
+    {
+        buck_content = sys_env::patch_buildscript_run_env(buck_content, vars, &run.env);
+    }
+    // Cfgs declared through `cargo:rustc-check-cfg=cfg(...)`, so that the cfgs set by build
+    // scripts aren't reported by the `unexpected_cfgs` lint
+    if ctx.repo_config.buildscript_check_cfg {
+        buck_content = check_cfg::patch_check_cfg_flags(buck_content, buck_rules);
+    }
+    buck_content = cross::patch_rust_test_target_compatible_with(buck_content);
+    if ctx.repo_config.unittests_test_only {
+        buck_content = unittests::patch_unittest_compatibility(buck_content, package, ctx);
+    }
+    buck_content
+}
+
+/// Whether a BUCK file was written by buckal, from its `@generated` header.
+fn is_generated_buck_file(content: &str) -> bool {
+    content
+        .lines()
+        .next()
+        .is_some_and(|line| line.starts_with("# @generated by") && line.contains("buckal"))
+}
+
+/// Refuse to sync over first-party BUCK files that buckal didn't generate, listing them, since
+/// their hand-written rules would be lost.
+pub fn ensure_generated_buck_files(ctx: &BuckalContext) -> Result<()> {
+    let mut hand_written: Vec<Utf8PathBuf> = ctx
+        .nodes_map
+        .keys()
+        .filter_map(|id| ctx.packages_map.get(id))
+        .filter(|package| package.source.is_none())
+        .filter_map(|package| Some(package.manifest_path.parent()?.join("BUCK")))
+        .filter(|buck_path| {
+            std::fs::read_to_string(buck_path)
+                .is_ok_and(|content| !is_generated_buck_file(&content))
+        })
+        .collect();
+    if hand_written.is_empty() {
+        return Ok(());
+    }
+    hand_written.sort();
+    bail!(
+        "BUCK files not generated by cargo-buckal would be overwritten:\n{}\n\
+         help: move their rules elsewhere, or pass `--force` to overwrite them",
+        hand_written
+            .iter()
+            .map(|path| format!("  {}", path))
+            .collect::<Vec<_>>()
+            .join("\n")
+    )
+}
+
+/// Carry manual edits of the configured `patch_fields` over from an existing BUCK file.
+///
+/// Only rules are carried over: `load` statements are always regenerated from the new rules, so
+/// loads left unused by older versions are pruned. Files buckal didn't generate aren't merged.
+fn merge_existing_rules(buck_path: &Utf8PathBuf, buck_rules: &mut [Rule], ctx: &BuckalContext) {
+    // Skip merging manual changes if `--no-merge` is set
+    if ctx.no_merge || ctx.repo_config.patch_fields.is_empty() || !buck_path.exists() {
+        return;
+    }
+    if !std::fs::read_to_string(buck_path).is_ok_and(|content| is_generated_buck_file(&content)) {
+        return;
+    }
+    match parse_buck_file(buck_path) {
+        Ok(existing_rules) => {
+            patch_buck_rules(&existing_rules, buck_rules, &ctx.repo_config.patch_fields)
+        }
+        Err(e) => buckal_warn!(
+            "Failed to parse existing {}, manual changes are not merged: {}",
+            buck_path,
+            e
+        ),
+    }
+}
+
+/// Extract the name and version of a vendored (non-path) package from its ID.
+///
+/// Returns `None` for path dependencies, which are emitted in place and never vendored.
+pub(super) fn vendored_name_version(id: &PackageId) -> Result<Option<(String, String)>> {
+    static PACKAGE_ID: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"^([^+#]+)\+([^#]+)#([^@]+)@([^+#]+)(?:\+(.+))?$").unwrap());
+    if PackageSource::classify(&id.repr) == PackageSource::Path {
+        return Ok(None);
+    }
+    let caps = PACKAGE_ID
+        .captures(&id.repr)
+        .with_context(|| format!("failed to parse package ID `{}`", id.repr))?;
+    Ok(Some((caps[3].to_string(), caps[4].to_string())))
+}
 
+/// Map each third-party crate used by a first-party package to the label of its latest version.
+///
+/// Only registry/git packages are considered, so a workspace member sharing its name with a
+/// vendored crate keeps its own path-based label.
+fn collect_third_party_aliases(ctx: &BuckalContext) -> BTreeMap<String, String> {
     let mut grouped: BTreeMap<String, Vec<&cargo_metadata::Package>> = BTreeMap::new();
 
     for (pkg_id, pkg) in &ctx.packages_map {
@@ -177,33 +730,1026 @@ fn generate_third_party_aliases(ctx: &BuckalContext) {
         }
     }
 
-    let file = std::fs::File::create(&buck_file).expect("failed to create third-party/rust/BUCK");
-    let mut writer = BufWriter::new(file);
+    grouped
+        .into_iter()
+        .map(|(crate_name, mut versions)| {
+            versions.sort_by(|a, b| a.version.cmp(&b.version));
+            let latest = versions.last().expect("empty version list");
+            (crate_name, third_party_label(latest, ctx))
+        })
+        .collect()
+}
 
-    writeln!(writer, "# @generated by cargo-buckal\n").expect("failed to write header");
+/// Write the third-party alias rules to `third-party/rust/BUCK` and return its path.
+pub fn generate_third_party_aliases(ctx: &BuckalContext) -> Result<Utf8PathBuf> {
+    let root = get_buck2_root().context("failed to get buck2 root")?;
+    write_third_party_aliases(ctx, &root.join("third-party/rust"))
+}
 
-    for (crate_name, mut versions) in grouped {
-        versions.sort_by(|a, b| a.version.cmp(&b.version));
-        let latest = versions.last().expect("empty version list");
+fn write_third_party_aliases(ctx: &BuckalContext, dir: &Utf8PathBuf) -> Result<Utf8PathBuf> {
+    std::fs::create_dir_all(dir).with_context(|| format!("failed to create `{}`", dir))?;
 
-        let actual = format!(
-            "//{RUST_CRATES_ROOT}/{}/{}:{}",
-            crate_name, latest.version, crate_name
-        );
+    let buck_file = dir.join("BUCK");
+    let write_failed = || format!("failed to write `{}`", buck_file);
+
+    let file = std::fs::File::create(&buck_file).with_context(write_failed)?;
+    let mut writer = BufWriter::new(file);
+
+    writeln!(writer, "# @generated by cargo-buckal\n").with_context(write_failed)?;
 
+    for (crate_name, actual) in collect_third_party_aliases(ctx) {
         let rule = Alias {
             name: crate_name.clone(),
             actual,
             visibility: ["PUBLIC"].into_iter().map(String::from).collect(),
         };
-        let rendered = serde_starlark::to_string(&rule).expect("failed to serialize alias");
-        writeln!(writer, "{}", rendered).expect("write failed");
+        let rendered = serde_starlark::to_string(&rule)
+            .with_context(|| format!("failed to serialize the alias of `{}`", crate_name))?;
+        writeln!(writer, "{}", rendered).with_context(write_failed)?;
     }
 
-    writer.flush().expect("failed to flush alias rules");
+    writer.flush().with_context(write_failed)?;
 
     buckal_log!(
         "Generated",
         format!("third-party alias rules at {}", buck_file)
     );
+    Ok(buck_file)
+}
+
+/// Patterns of `third-party/rust/crates/.gitignore`: sources extracted next to the generated
+/// BUCK files are left to `http_archive`, while the BUCK files themselves stay tracked.
+const VENDOR_GITIGNORE: [&str; 2] = ["/*/*/vendor/", "!/*/*/BUCK"];
+
+/// Add the vendor ignore rules to `third-party/rust/crates/.gitignore`, creating it if needed,
+/// and return the file written.
+pub fn generate_vendor_gitignore() -> Utf8PathBuf {
+    let root = get_buck2_root().unwrap_or_exit();
+    write_vendor_gitignore(&root.join(RUST_CRATES_ROOT))
+}
+
+fn write_vendor_gitignore(dir: &Utf8PathBuf) -> Utf8PathBuf {
+    std::fs::create_dir_all(dir).expect("failed to create third-party/rust/crates dir");
+
+    let gitignore = dir.join(".gitignore");
+    let mut content = std::fs::read_to_string(&gitignore).unwrap_or_default();
+    // Patterns already present, e.g. from a previous run, aren't added again
+    let missing: Vec<&str> = VENDOR_GITIGNORE
+        .into_iter()
+        .filter(|pattern| !content.lines().any(|line| line.trim() == *pattern))
+        .collect();
+    if missing.is_empty() {
+        return gitignore;
+    }
+
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+    for pattern in missing {
+        content.push_str(pattern);
+        content.push('\n');
+    }
+    std::fs::write(&gitignore, content)
+        .expect("failed to write third-party/rust/crates/.gitignore");
+    buckal_log!("Generated", format!("vendor ignore rules at {}", gitignore));
+    gitignore
+}
+
+/// Write the sha256 of every vendored crate, as used by its `http_archive`, to
+/// `third-party/rust/checksums.bzl`, and return the file written.
+pub fn generate_checksums_manifest(ctx: &BuckalContext) -> Utf8PathBuf {
+    let root = get_buck2_root().unwrap_or_exit();
+    write_checksums_manifest(ctx, &root.join("third-party/rust"))
+}
+
+fn write_checksums_manifest(ctx: &BuckalContext, dir: &Utf8PathBuf) -> Utf8PathBuf {
+    std::fs::create_dir_all(dir).expect("failed to create third-party/rust dir");
+
+    // Keyed by source, then `name-version`, like `ChecksumKey`, so crates of the same name and
+    // version from different sources don't overwrite each other
+    let mut checksums: BTreeMap<&str, BTreeMap<String, String>> = BTreeMap::new();
+    for package in ctx
+        .nodes_map
+        .keys()
+        .filter_map(|id| ctx.packages_map.get(id))
+    {
+        let Some(source) = &package.source else {
+            continue;
+        };
+        if let Some(checksum) = ctx.checksums_map.get(&ChecksumKey::of(package)) {
+            checksums.entry(source.repr.as_str()).or_default().insert(
+                format!("{}-{}", package.name, package.version),
+                checksum.to_string(),
+            );
+        }
+    }
+
+    let mut content = String::from("# @generated by cargo-buckal\n\nCHECKSUMS = {\n");
+    for (source, crates) in &checksums {
+        content.push_str(&format!("    \"{}\": {{\n", source));
+        for (key, checksum) in crates {
+            content.push_str(&format!("        \"{}\": \"{}\",\n", key, checksum));
+        }
+        content.push_str("    },\n");
+    }
+    content.push_str("}\n");
+
+    let manifest = dir.join("checksums.bzl");
+    std::fs::write(&manifest, content).expect("failed to write third-party/rust/checksums.bzl");
+    buckal_log!("Generated", format!("crate checksums at {}", manifest));
+    manifest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RepoConfig;
+    use crate::testing::{mock_dep, mock_node, mock_package, mock_target};
+    use crate::utils::vendor_package_path;
+    use cargo_metadata::{Node, Package, Target, TargetKind};
+    use std::collections::{BTreeMap, HashMap};
+
+    use crate::testing::REGISTRY;
+
+    /// A library target whose root is `src_path`.
+    fn lib_target(name: &str, src_path: Utf8PathBuf) -> Target {
+        let mut target = mock_target(name, TargetKind::Lib);
+        target.src_path = src_path;
+        target
+    }
+
+    #[test]
+    fn test_third_party_aliases_skip_workspace_member_with_same_name() {
+        // `app` depends on the workspace crate `foo` 0.2.0 and, through a rename, on the
+        // registry crate `foo` 0.1.0.
+        let app = mock_package("app", "0.1.0", "path+file:///ws/app#0.1.0", None);
+        let local_foo = mock_package("foo", "0.2.0", "path+file:///ws/foo#0.2.0", None);
+        let registry_foo = mock_package(
+            "foo",
+            "0.1.0",
+            &format!("{REGISTRY}#foo@0.1.0"),
+            Some(REGISTRY),
+        );
+
+        let nodes_map = HashMap::from([
+            (
+                app.id.clone(),
+                mock_node(
+                    &app.id.repr,
+                    serde_json::json!([
+                        mock_dep("foo", &local_foo.id.repr),
+                        mock_dep("old_foo", &registry_foo.id.repr)
+                    ]),
+                    &[],
+                ),
+            ),
+            (
+                local_foo.id.clone(),
+                mock_node(&local_foo.id.repr, serde_json::json!([]), &[]),
+            ),
+            (
+                registry_foo.id.clone(),
+                mock_node(&registry_foo.id.repr, serde_json::json!([]), &[]),
+            ),
+        ]);
+        let packages_map = [&app, &local_foo, &registry_foo]
+            .into_iter()
+            .map(|p| (p.id.clone(), p.clone()))
+            .collect();
+
+        let ctx = BuckalContext {
+            nodes_map,
+            packages_map,
+            workspace_root: Utf8PathBuf::from("/ws"),
+            workspace_members: vec![app.id.clone(), local_foo.id.clone()],
+            ..Default::default()
+        };
+
+        let aliases = collect_third_party_aliases(&ctx);
+        assert_eq!(aliases.len(), 1);
+        assert_eq!(
+            aliases.get("foo").map(String::as_str),
+            Some("//third-party/rust/crates/foo/0.1.0:foo")
+        );
+    }
+
+    #[test]
+    fn test_merge_prunes_stale_loads() {
+        let dir = crate::testing::temp_dir("stale-loads");
+        std::fs::create_dir_all(&dir).unwrap();
+        let buck_path = dir.join("BUCK");
+
+        let mut foo = mock_package(
+            "foo",
+            "1.0.0",
+            &format!("{REGISTRY}#foo@1.0.0"),
+            Some(REGISTRY),
+        );
+        foo.manifest_path = "/nonexistent/foo-1.0.0/Cargo.toml".into();
+        foo.targets = vec![lib_target(
+            "foo",
+            "/nonexistent/foo-1.0.0/src/lib.rs".into(),
+        )];
+        let ctx = BuckalContext {
+            packages_map: HashMap::from([(foo.id.clone(), foo.clone())]),
+            checksums_map: HashMap::from([(
+                ChecksumKey::of(&foo),
+                "00".repeat(32).parse().unwrap(),
+            )]),
+            workspace_root: Utf8PathBuf::from("/nonexistent"),
+            repo_config: RepoConfig {
+                patch_fields: BTreeSet::from(["env".to_owned()]),
+                ..RepoConfig::default()
+            },
+            ..Default::default()
+        };
+        // Written by an older version, loading `rust_test` without using it
+        std::fs::write(
+            &buck_path,
+            indoc::indoc! {r#"
+                # @generated by `cargo buckal`
+
+                load("@buckal//:cargo_manifest.bzl", "cargo_manifest")
+                load("@buckal//:wrapper.bzl", "rust_library", "rust_test")
+
+                cargo_manifest(
+                    name = "foo-manifest",
+                    vendor = ":foo-vendor",
+                )
+            "#},
+        )
+        .unwrap();
+
+        let content = render_buck_file(
+            &mock_node(&foo.id.repr, serde_json::json!([]), &[]),
+            &foo,
+            &buck_path,
+            &ctx,
+            &ProfileOverrides::default(),
+        )
+        .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(content.contains("rust_library"), "{content}");
+        assert!(!content.contains("rust_test"), "{content}");
+    }
+
+    #[test]
+    fn test_refresh_cargo_manifest_leaves_other_rules() {
+        let mut foo = mock_package(
+            "foo",
+            "1.0.0",
+            &format!("{REGISTRY}#foo@1.0.0"),
+            Some(REGISTRY),
+        );
+        foo.targets = vec![crate::testing::mock_target(
+            "foo",
+            cargo_metadata::TargetKind::Lib,
+        )];
+        foo.manifest_path = "/nonexistent/Cargo.toml".into();
+        let node = mock_node(&foo.id.repr, serde_json::json!([]), &[]);
+        let ctx_with = |foo: &Package| BuckalContext {
+            nodes_map: HashMap::from([(foo.id.clone(), node.clone())]),
+            packages_map: HashMap::from([(foo.id.clone(), foo.clone())]),
+            checksums_map: HashMap::from([(
+                ChecksumKey::of(foo),
+                "00".repeat(32).parse().unwrap(),
+            )]),
+            workspace_root: Utf8PathBuf::from("/ws"),
+            ..Default::default()
+        };
+        let dir = crate::testing::temp_dir("refresh-manifest");
+        std::fs::create_dir_all(&dir).unwrap();
+        let buck_path = dir.join("BUCK");
+        let overrides = ProfileOverrides::default();
+        let render = |foo: &Package| {
+            render_buck_file(&node, foo, &buck_path, &ctx_with(foo), &overrides).unwrap()
+        };
+        let refresh = |content: &str, foo: &Package| {
+            std::fs::write(&buck_path, content).unwrap();
+            let ctx = ctx_with(foo);
+            let buck_rules = generate_rules(&node, foo, &buck_path, &ctx).unwrap();
+            refresh_cargo_manifest_in(content, foo, &buck_rules, &ctx)
+        };
+
+        // A stale manifest is regenerated, and the rest of the file kept byte-identical
+        let synced = render(&foo);
+        let range = windows::rule_call_range(&synced, "cargo_manifest", "foo-manifest").unwrap();
+        let stale = format!(
+            "{}cargo_manifest(\n    name = \"foo-manifest\",\n    vendor = \":stale\",\n){}",
+            &synced[..range.start],
+            &synced[range.end..]
+        );
+        assert_eq!(refresh(&stale, &foo).unwrap(), synced);
+
+        // The env of the Rust rules follows `[package.metadata.buckal]`, as a full sync would
+        let mut with_env = foo.clone();
+        with_env.metadata = serde_json::json!({ "buckal": { "env": { "FOO_DATA": "data" } } });
+        let refreshed = refresh(&stale, &with_env).unwrap();
+        assert!(refreshed.contains("\"FOO_DATA\": \"data\""), "{refreshed}");
+        assert_eq!(refreshed, render(&with_env));
+        // and drops it again once removed
+        assert_eq!(refresh(&refreshed, &foo).unwrap(), synced);
+
+        let mut renamed = ctx_with(&foo);
+        renamed.repo_config = RepoConfig {
+            naming: crate::config::Naming {
+                manifest: "-cargo-manifest".to_owned(),
+                ..Default::default()
+            },
+            ..RepoConfig::default()
+        };
+        let buck_rules = generate_rules(&node, &foo, &buck_path, &renamed).unwrap();
+        let result = refresh_cargo_manifest_in(&stale, &foo, &buck_rules, &renamed);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_hand_written_buck_file_is_not_clobbered() {
+        let dir = crate::testing::temp_dir("hand-written");
+        std::fs::create_dir_all(&dir).unwrap();
+        let buck_path = dir.join("BUCK");
+        let hand_written = "genrule(\n    name = \"manual\",\n    out = \"out.txt\",\n    cmd = \"touch $OUT\",\n)\n";
+        std::fs::write(&buck_path, hand_written).unwrap();
+
+        let mut app = mock_package("app", "0.1.0", "path+file:///ws/app#0.1.0", None);
+        app.manifest_path = dir.join("Cargo.toml");
+        let ctx = BuckalContext {
+            nodes_map: HashMap::from([(
+                app.id.clone(),
+                mock_node(&app.id.repr, serde_json::json!([]), &[]),
+            )]),
+            packages_map: HashMap::from([(app.id.clone(), app.clone())]),
+            workspace_root: dir.clone(),
+            workspace_members: vec![app.id.clone()],
+            ..Default::default()
+        };
+
+        let err = ensure_generated_buck_files(&ctx).unwrap_err().to_string();
+        let kept = std::fs::read_to_string(&buck_path).unwrap();
+
+        // Files buckal generated are fine to overwrite
+        std::fs::write(&buck_path, "# @generated by `cargo buckal`\n").unwrap();
+        let generated = ensure_generated_buck_files(&ctx);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(err.contains(buck_path.as_str()), "{err}");
+        assert!(err.contains("--force"), "{err}");
+        assert_eq!(kept, hand_written);
+        assert!(generated.is_ok());
+    }
+
+    #[test]
+    fn test_vendor_gitignore_is_idempotent() {
+        let dir = crate::testing::temp_dir("gitignore");
+
+        let gitignore = write_vendor_gitignore(&dir);
+        let created = std::fs::read_to_string(&gitignore).unwrap();
+        assert_eq!(created, "/*/*/vendor/\n!/*/*/BUCK\n");
+
+        // Existing rules are kept, and a re-run adds nothing
+        std::fs::write(&gitignore, "*.orig\n/*/*/vendor/").unwrap();
+        write_vendor_gitignore(&dir);
+        write_vendor_gitignore(&dir);
+        let updated = std::fs::read_to_string(&gitignore).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(updated, "*.orig\n/*/*/vendor/\n!/*/*/BUCK\n");
+    }
+
+    #[test]
+    fn test_checksums_manifest() {
+        let dir = crate::testing::temp_dir("checksums");
+
+        let app = mock_package("app", "0.1.0", "path+file:///ws/app#0.1.0", None);
+        let foo = mock_package(
+            "foo",
+            "1.0.0",
+            &format!("{REGISTRY}#foo@1.0.0"),
+            Some(REGISTRY),
+        );
+        // The same name and version from another registry
+        const INTERNAL: &str = "sparse+https://crates.internal.example/index/";
+        let internal_foo = mock_package(
+            "foo",
+            "1.0.0",
+            &format!("{INTERNAL}#foo@1.0.0"),
+            Some(INTERNAL),
+        );
+        let sha256 = "ab".repeat(32);
+        let internal_sha256 = "cd".repeat(32);
+        let ctx = BuckalContext {
+            nodes_map: HashMap::from([
+                (
+                    app.id.clone(),
+                    mock_node(
+                        &app.id.repr,
+                        serde_json::json!([
+                            mock_dep("foo", &foo.id.repr),
+                            mock_dep("foo", &internal_foo.id.repr)
+                        ]),
+                        &[],
+                    ),
+                ),
+                (
+                    foo.id.clone(),
+                    mock_node(&foo.id.repr, serde_json::json!([]), &[]),
+                ),
+                (
+                    internal_foo.id.clone(),
+                    mock_node(&internal_foo.id.repr, serde_json::json!([]), &[]),
+                ),
+            ]),
+            packages_map: HashMap::from([
+                (app.id.clone(), app.clone()),
+                (foo.id.clone(), foo.clone()),
+                (internal_foo.id.clone(), internal_foo.clone()),
+            ]),
+            checksums_map: HashMap::from([
+                (ChecksumKey::of(&foo), sha256.parse().unwrap()),
+                (
+                    ChecksumKey::of(&internal_foo),
+                    internal_sha256.parse().unwrap(),
+                ),
+                // Locked but not in the resolved graph
+                (
+                    ChecksumKey::new("bar", "0.1.0", REGISTRY),
+                    "00".repeat(32).parse().unwrap(),
+                ),
+            ]),
+            workspace_root: Utf8PathBuf::from("/ws"),
+            workspace_members: vec![app.id.clone()],
+            no_merge: true,
+            ..Default::default()
+        };
+
+        let manifest = write_checksums_manifest(&ctx, &dir);
+        let content = std::fs::read_to_string(&manifest).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(manifest, dir.join("checksums.bzl"));
+        assert_eq!(
+            content,
+            format!(
+                "# @generated by cargo-buckal\n\nCHECKSUMS = {{\n    \"{REGISTRY}\": {{\n        \"foo-1.0.0\": \"{sha256}\",\n    }},\n    \"{INTERNAL}\": {{\n        \"foo-1.0.0\": \"{internal_sha256}\",\n    }},\n}}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_alias_file_only_written_with_aliases() {
+        let ws = crate::testing::temp_dir("aliases");
+        std::fs::create_dir_all(ws.join("app")).unwrap();
+
+        let mut app = mock_package("app", "0.1.0", "path+file:///ws/app#0.1.0", None);
+        app.manifest_path = ws.join("app/Cargo.toml");
+        let foo = mock_package(
+            "foo",
+            "0.1.0",
+            &format!("{REGISTRY}#foo@0.1.0"),
+            Some(REGISTRY),
+        );
+        let ctx = BuckalContext {
+            nodes_map: HashMap::from([
+                (
+                    app.id.clone(),
+                    mock_node(
+                        &app.id.repr,
+                        serde_json::json!([mock_dep("foo", &foo.id.repr)]),
+                        &[],
+                    ),
+                ),
+                (
+                    foo.id.clone(),
+                    mock_node(&foo.id.repr, serde_json::json!([]), &[]),
+                ),
+            ]),
+            packages_map: HashMap::from([(app.id.clone(), app.clone()), (foo.id.clone(), foo)]),
+            workspace_root: ws.clone(),
+            workspace_members: vec![app.id.clone()],
+            no_merge: true,
+            repo_config: RepoConfig {
+                inherit_workspace_deps: true,
+                ..RepoConfig::default()
+            },
+            ..Default::default()
+        };
+        let alias_dir = ws.join("third-party/rust");
+        let alias_file = alias_dir.join("BUCK");
+
+        // `--aliases-only` writes the alias rules alone
+        let written = write_third_party_aliases(&ctx, &alias_dir).unwrap();
+        let aliases = std::fs::read_to_string(&alias_file).unwrap();
+        let app_written = ws.join("app/BUCK").exists();
+        std::fs::remove_file(&alias_file).unwrap();
+
+        // `--no-aliases` writes the members alone
+        let (members, failures) = flush_members(&ctx, false);
+        let alias_written = alias_file.exists();
+        std::fs::remove_dir_all(&ws).unwrap();
+
+        assert_eq!(written, alias_file);
+        assert!(aliases.contains("//third-party/rust/crates/foo/0.1.0:foo"));
+        assert!(!app_written);
+        assert_eq!(members, [ws.join("app/BUCK")]);
+        assert!(failures.is_empty());
+        assert!(!alias_written);
+    }
+
+    #[test]
+    fn test_vendored_name_version_skips_path_packages() {
+        let registry = PackageId {
+            repr: format!("{REGISTRY}#foo@0.1.0"),
+        };
+        assert_eq!(
+            vendored_name_version(&registry).unwrap(),
+            Some(("foo".to_string(), "0.1.0".to_string()))
+        );
+
+        // Path packages are never vendored, even when they live outside the workspace root.
+        let local = PackageId {
+            repr: "path+file:///elsewhere/foo#0.1.0".to_string(),
+        };
+        assert_eq!(vendored_name_version(&local).unwrap(), None);
+
+        let legacy = PackageId {
+            repr: format!("foo 0.1.0 ({REGISTRY})"),
+        };
+        let err = vendored_name_version(&legacy).unwrap_err().to_string();
+        assert!(err.contains("failed to parse package ID"), "{err}");
+    }
+
+    #[test]
+    fn test_vendored_name_version_sparse_index() {
+        let sparse = PackageId {
+            repr: "sparse+https://index.crates.io/#foo@0.1.0".to_string(),
+        };
+        assert_eq!(
+            vendored_name_version(&sparse).unwrap(),
+            Some(("foo".to_string(), "0.1.0".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_flush_root_virtual_workspace() {
+        let ws = crate::testing::temp_dir("virtual-ws");
+
+        let members: Vec<Package> = ["alpha", "beta"]
+            .into_iter()
+            .map(|name| {
+                let dir = ws.join(name);
+                std::fs::create_dir_all(dir.join("src")).unwrap();
+                let mut pkg =
+                    mock_package(name, "0.1.0", &format!("path+file://{dir}#0.1.0"), None);
+                pkg.manifest_path = dir.join("Cargo.toml");
+                pkg.targets = vec![lib_target(name, dir.join("src/lib.rs"))];
+                pkg
+            })
+            .collect();
+
+        let ctx = BuckalContext {
+            nodes_map: members
+                .iter()
+                .map(|p| {
+                    (
+                        p.id.clone(),
+                        mock_node(&p.id.repr, serde_json::json!([]), &[]),
+                    )
+                })
+                .collect(),
+            packages_map: members.iter().map(|p| (p.id.clone(), p.clone())).collect(),
+            workspace_root: ws.clone(),
+            workspace_members: members.iter().map(|p| p.id.clone()).collect(),
+            ..Default::default()
+        };
+
+        let (written, failures) = flush_root(&ctx, false);
+        let expected = vec![ws.join("alpha/BUCK"), ws.join("beta/BUCK")];
+        assert_eq!(written, expected);
+        assert!(failures.is_empty());
+        for buck_path in &expected {
+            let content = std::fs::read_to_string(buck_path).unwrap();
+            assert!(content.contains("rust_library("));
+        }
+
+        // Under `--keep-going`, a member failing to be flushed doesn't stop the others
+        let mut ctx = ctx;
+        ctx.nodes_map.remove(&members[1].id);
+        let (written, failures) = flush_root(&ctx, true);
+        assert_eq!(written, [ws.join("alpha/BUCK")]);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].package, "beta v0.1.0");
+
+        std::fs::remove_dir_all(&ws).unwrap();
+    }
+
+    #[test]
+    fn test_separate_skips_first_party_packages() {
+        let ws = crate::testing::temp_dir("separate");
+        let dir = ws.join("local");
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+
+        // A first-party path dependency outside the workspace members
+        let mut local = mock_package("local", "0.1.0", &format!("path+file://{dir}#0.1.0"), None);
+        local.manifest_path = dir.join("Cargo.toml");
+        local.targets = vec![lib_target("local", dir.join("src/lib.rs"))];
+        let serde = mock_package(
+            "serde",
+            "1.0.0",
+            &format!("{REGISTRY}#serde@1.0.0"),
+            Some(REGISTRY),
+        );
+
+        let mut ctx = BuckalContext {
+            nodes_map: [&local, &serde]
+                .iter()
+                .map(|p| {
+                    (
+                        p.id.clone(),
+                        mock_node(&p.id.repr, serde_json::json!([]), &[]),
+                    )
+                })
+                .collect(),
+            packages_map: [&local, &serde]
+                .iter()
+                .map(|p| (p.id.clone(), (*p).clone()))
+                .collect(),
+            workspace_root: ws.clone(),
+            separate: true,
+            ..Default::default()
+        };
+        let changes = BuckalChange {
+            changes: BTreeMap::from([
+                (local.id.clone(), ChangeType::Changed),
+                (serde.id.clone(), ChangeType::Changed),
+            ]),
+        };
+
+        assert_eq!(changes.separated_packages(&ctx), ["local"]);
+        assert!(!changes.is_separated(&serde.id, &ctx));
+        assert!(changes.has_third_party_changes(&ctx));
+
+        // Only the first-party change: nothing is written
+        let first_party_only = BuckalChange {
+            changes: BTreeMap::from([(local.id.clone(), ChangeType::Changed)]),
+        };
+        assert!(!first_party_only.has_third_party_changes(&ctx));
+        assert!(first_party_only.apply(&ctx).is_empty());
+        assert!(!dir.join("BUCK").exists());
+
+        // Without `--separate` the first-party BUCK file is written
+        ctx.separate = false;
+        assert!(changes.separated_packages(&ctx).is_empty());
+        assert_eq!(first_party_only.apply(&ctx), [dir.join("BUCK")]);
+
+        std::fs::remove_dir_all(&ws).unwrap();
+    }
+
+    #[test]
+    fn test_dependents_of_reemits_transitive_dependents_only() {
+        // top -> mid -> leaf, with `other` depending on nothing
+        let package = |name: &str| {
+            mock_package(
+                name,
+                "0.1.0",
+                &format!("path+file:///ws/{name}#0.1.0"),
+                None,
+            )
+        };
+        let (leaf, mid, top, other) = (
+            package("leaf"),
+            package("mid"),
+            package("top"),
+            package("other"),
+        );
+        let nodes = [
+            mock_node(&leaf.id.repr, serde_json::json!([]), &[]),
+            mock_node(
+                &mid.id.repr,
+                serde_json::json!([mock_dep("leaf", &leaf.id.repr)]),
+                &[],
+            ),
+            mock_node(
+                &top.id.repr,
+                serde_json::json!([mock_dep("mid", &mid.id.repr)]),
+                &[],
+            ),
+            mock_node(&other.id.repr, serde_json::json!([]), &[]),
+        ];
+        let ctx = BuckalContext {
+            nodes_map: nodes.into_iter().map(|n| (n.id.clone(), n)).collect(),
+            packages_map: [&leaf, &mid, &top, &other]
+                .iter()
+                .map(|p| (p.id.clone(), (*p).clone()))
+                .collect(),
+            workspace_root: Utf8PathBuf::from("/ws"),
+            no_merge: true,
+            ..Default::default()
+        };
+
+        let mut expected = vec![leaf.id.clone(), mid.id.clone(), top.id.clone()];
+        expected.sort();
+        assert_eq!(dependents_of(&ctx, "leaf").unwrap(), expected);
+
+        let mut expected = vec![mid.id.clone(), top.id.clone()];
+        expected.sort();
+        assert_eq!(dependents_of(&ctx, "mid").unwrap(), expected);
+
+        assert_eq!(dependents_of(&ctx, "top").unwrap(), vec![top.id]);
+        assert!(dependents_of(&ctx, "missing").is_err());
+    }
+
+    #[test]
+    fn test_apply_serial_matches_parallel() {
+        let ws = crate::testing::temp_dir("jobs");
+
+        let packages: Vec<Package> = (0..8)
+            .map(|i| {
+                let name = format!("local{i}");
+                let dir = ws.join(&name);
+                std::fs::create_dir_all(dir.join("src")).unwrap();
+                let mut package =
+                    mock_package(&name, "0.1.0", &format!("path+file://{dir}#0.1.0"), None);
+                package.manifest_path = dir.join("Cargo.toml");
+                package.targets = vec![lib_target(&name, dir.join("src/lib.rs"))];
+                package
+            })
+            .collect();
+        let mut ctx = BuckalContext {
+            nodes_map: packages
+                .iter()
+                .map(|p| {
+                    (
+                        p.id.clone(),
+                        mock_node(&p.id.repr, serde_json::json!([]), &[]),
+                    )
+                })
+                .collect(),
+            packages_map: packages.iter().map(|p| (p.id.clone(), p.clone())).collect(),
+            workspace_root: ws.clone(),
+            no_merge: true,
+            ..Default::default()
+        };
+        let changes = BuckalChange {
+            changes: packages
+                .iter()
+                .map(|p| (p.id.clone(), ChangeType::Added))
+                .collect(),
+        };
+
+        let read_all = |written: &[Utf8PathBuf]| -> Vec<String> {
+            written
+                .iter()
+                .map(|path| std::fs::read_to_string(path).unwrap())
+                .collect()
+        };
+        let serial = changes.apply(&ctx);
+        let serial_contents = read_all(&serial);
+        for path in &serial {
+            std::fs::remove_file(path).unwrap();
+        }
+
+        ctx.jobs = 4;
+        let parallel = changes.apply(&ctx);
+        assert_eq!(parallel, serial);
+        assert_eq!(read_all(&parallel), serial_contents);
+        assert_eq!(serial.len(), packages.len());
+
+        std::fs::remove_dir_all(&ws).unwrap();
+    }
+
+    #[test]
+    fn test_apply_keep_going_skips_failed_packages() {
+        let ws = crate::testing::temp_dir("keep-going");
+
+        let mut packages: Vec<Package> = ["a", "b"]
+            .into_iter()
+            .map(|name| {
+                let dir = ws.join(name);
+                std::fs::create_dir_all(dir.join("src")).unwrap();
+                let mut package =
+                    mock_package(name, "0.1.0", &format!("path+file://{dir}#0.1.0"), None);
+                package.manifest_path = dir.join("Cargo.toml");
+                package.targets = vec![lib_target(name, dir.join("src/lib.rs"))];
+                package
+            })
+            .collect();
+        // Missing from `checksums_map`, so its `http_archive` can't be generated
+        packages.push(mock_package(
+            "foo",
+            "1.0.0",
+            &format!("{REGISTRY}#foo@1.0.0"),
+            Some(REGISTRY),
+        ));
+        // `b` depends on a first-party crate without a library, so its deps can't be resolved
+        let no_lib = mock_package("no-lib", "0.1.0", "path+file:///ws/no-lib#0.1.0", None);
+        let mut nodes_map: HashMap<_, _> = packages
+            .iter()
+            .map(|p| {
+                (
+                    p.id.clone(),
+                    mock_node(&p.id.repr, serde_json::json!([]), &[]),
+                )
+            })
+            .collect();
+        nodes_map.insert(
+            packages[1].id.clone(),
+            mock_node(
+                &packages[1].id.repr,
+                serde_json::json!([mock_dep("no_lib", &no_lib.id.repr)]),
+                &[],
+            ),
+        );
+        nodes_map.insert(
+            no_lib.id.clone(),
+            mock_node(&no_lib.id.repr, serde_json::json!([]), &[]),
+        );
+        let ctx = BuckalContext {
+            nodes_map,
+            packages_map: packages
+                .iter()
+                .chain([&no_lib])
+                .map(|p| (p.id.clone(), p.clone()))
+                .collect(),
+            workspace_root: ws.clone(),
+            no_merge: true,
+            jobs: 2,
+            ..Default::default()
+        };
+        let changes = BuckalChange {
+            changes: packages
+                .iter()
+                .map(|p| (p.id.clone(), ChangeType::Added))
+                .collect(),
+        };
+
+        let (written, failures) = changes.apply_keep_going(&ctx);
+        std::fs::remove_dir_all(&ws).unwrap();
+
+        let mut written = written;
+        written.sort();
+        assert_eq!(written, [ws.join("a/BUCK")]);
+        assert_eq!(failures.len(), 2, "{failures:?}");
+        assert_eq!(failures[0].package, "b v0.1.0");
+        assert!(
+            failures[0].error.contains("failed to set dependencies"),
+            "{failures:?}"
+        );
+        assert_eq!(failures[1].package, "foo v1.0.0");
+        assert!(failures[1].error.contains("no checksum"), "{failures:?}");
+    }
+
+    #[test]
+    fn test_panic_message() {
+        let panic = std::panic::catch_unwind(|| panic!("bad {}", "crate")).unwrap_err();
+        assert_eq!(panic_message(panic.as_ref()), "bad crate");
+    }
+
+    #[test]
+    fn test_parallel_map_keeps_order() {
+        let items: Vec<usize> = (0..100).collect();
+        for jobs in [1, 3, 16, 200] {
+            assert_eq!(
+                parallel_map(&items, jobs, |i| i * 2),
+                items.iter().map(|i| i * 2).collect::<Vec<_>>()
+            );
+        }
+        assert!(parallel_map(&[] as &[usize], 4, |i| *i).is_empty());
+    }
+
+    #[test]
+    fn test_merge_round_trip() {
+        let package = |name: &str, version: &str, targets| -> Package {
+            let mut package = mock_package(
+                name,
+                version,
+                &format!("{REGISTRY}#{name}@{version}"),
+                Some(REGISTRY),
+            );
+            package.manifest_path = format!("/nonexistent/{name}-{version}/Cargo.toml").into();
+            package.targets = targets;
+            package
+        };
+        let target = |name: &str, kind: TargetKind, src_path: &str| {
+            let mut target = mock_target(name, kind);
+            target.src_path = format!("/nonexistent/foo-1.0.0/{src_path}").into();
+            target
+        };
+        let mut foo = package(
+            "foo",
+            "1.0.0",
+            vec![
+                target("foo", TargetKind::Lib, "src/lib.rs"),
+                target("build-script-build", TargetKind::CustomBuild, "build.rs"),
+            ],
+        );
+        foo.features = BTreeMap::from([
+            ("default".to_owned(), vec!["std".to_owned()]),
+            ("std".to_owned(), vec![]),
+        ]);
+        let winapi = package("winapi", "0.3.9", vec![]);
+        let bar = package("bar", "2.0.0", vec![]);
+        let foo_node = |features: &[&str]| -> Node {
+            mock_node(
+                &foo.id.repr,
+                serde_json::json!([
+                    {
+                        "name": "winapi",
+                        "pkg": winapi.id.clone(),
+                        "dep_kinds": [{ "kind": null, "target": "cfg(windows)" }]
+                    },
+                    mock_dep("bar_alias", &bar.id.repr)
+                ]),
+                features,
+            )
+        };
+
+        let ctx = BuckalContext {
+            packages_map: [&foo, &winapi, &bar]
+                .into_iter()
+                .map(|p| (p.id.clone(), p.clone()))
+                .collect(),
+            checksums_map: HashMap::from([(
+                ChecksumKey::of(&foo),
+                "00".repeat(32).parse().unwrap(),
+            )]),
+            workspace_root: Utf8PathBuf::from("/nonexistent"),
+            repo_config: RepoConfig {
+                patch_fields: ["env", "deps", "os_deps", "named_deps"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+                ..RepoConfig::default()
+            },
+            ..Default::default()
+        };
+        let dir = crate::testing::temp_dir("round-trip");
+        std::fs::create_dir_all(&dir).unwrap();
+        let buck_path = dir.join("BUCK");
+        let overrides = ProfileOverrides::default();
+
+        // First sync, then manual additions to the generated library
+        let content =
+            render_buck_file(&foo_node(&["default"]), &foo, &buck_path, &ctx, &overrides).unwrap();
+        std::fs::write(&buck_path, content).unwrap();
+        let mut rules = parse_buck_file(&buck_path).unwrap();
+        for rule in rules.values_mut() {
+            if let Rule::RustLibrary(lib) = rule {
+                lib.env.insert("MANUAL_ENV".to_owned(), "1".to_owned());
+                lib.deps.insert("//manual:dep".to_owned());
+            }
+        }
+        let edited: Vec<Rule> = rules.into_values().collect();
+        std::fs::write(&buck_path, gen_buck_content(&edited)).unwrap();
+
+        // Resync after the crate's features changed
+        let content = render_buck_file(
+            &foo_node(&["default", "std"]),
+            &foo,
+            &buck_path,
+            &ctx,
+            &overrides,
+        )
+        .unwrap();
+        std::fs::write(&buck_path, content).unwrap();
+        let rules = parse_buck_file(&buck_path);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let rules = rules.unwrap();
+        let lib = rules
+            .values()
+            .find_map(|r| match r {
+                Rule::RustLibrary(l) => Some(l),
+                _ => None,
+            })
+            .expect("expected a rust_library rule");
+
+        // Manual fields survive
+        assert_eq!(lib.env.get("MANUAL_ENV").map(String::as_str), Some("1"));
+        assert!(lib.deps.contains("//manual:dep"));
+        // Generated fields are updated or kept
+        assert_eq!(
+            lib.features,
+            BTreeSet::from(["default".to_owned(), "std".to_owned()])
+        );
+        assert!(lib.env.contains_key("OUT_DIR"));
+        assert_eq!(
+            lib.os_deps.get("windows"),
+            Some(&BTreeSet::from([format!(
+                "//{}:winapi",
+                vendor_package_path("winapi", "0.3.9")
+            )]))
+        );
+        assert_eq!(
+            lib.named_deps.get("bar_alias"),
+            Some(&format!("//{}:bar", vendor_package_path("bar", "2.0.0")))
+        );
+        let run = rules
+            .values()
+            .find_map(|r| match r {
+                Rule::BuildscriptRun(run) => Some(run),
+                _ => None,
+            })
+            .expect("expected a buildscript_run rule");
+        assert!(run.features.contains("std"));
+    }
 }
@@ -0,0 +1,386 @@
+use std::collections::{BTreeMap as Map, BTreeSet as Set};
+
+use crate::{
+    buck::{Rule, RuleKey, parse_buck_file},
+    buckal_warn,
+    context::BuckalContext,
+    utils::{UnwrapOrExit, get_buck2_root},
+};
+
+use super::actions::generate_package_rules;
+
+/// Build a buildozer command script (`buildozer -f`) reconciling the on-disk BUCK files with
+/// freshly generated rules, instead of rewriting the files.
+///
+/// Rules are compared attribute by attribute, after merging manual edits of `patch_fields`.
+/// Text-level patches (profile overrides, Windows import libraries, cross-compilation selects)
+/// and `load` statements are not covered, and missing BUCK files are reported but not created.
+pub fn buildozer_script(ctx: &BuckalContext, packages: &[String]) -> String {
+    let buck2_root = get_buck2_root().unwrap_or_exit();
+
+    let mut script = String::new();
+    for (buck_path, buck_rules) in generate_package_rules(ctx, packages).unwrap_or_exit() {
+        if !buck_path.exists() {
+            buckal_warn!(
+                "{} does not exist, run `cargo buckal migrate` to create it",
+                buck_path
+            );
+            continue;
+        }
+        let existing = match parse_buck_file(&buck_path) {
+            Ok(existing) => existing,
+            Err(e) => {
+                buckal_warn!("Failed to parse existing {}, skipping: {}", buck_path, e);
+                continue;
+            }
+        };
+
+        let package_dir = buck_path
+            .parent()
+            .expect("BUCK file has a parent directory");
+        let package = package_dir
+            .strip_prefix(&buck2_root)
+            .unwrap_or(package_dir)
+            .as_str()
+            .replace('\\', "/");
+        for command in buildozer_commands(&existing, &buck_rules, &format!("//{package}")) {
+            script.push_str(&command);
+            script.push('\n');
+        }
+    }
+    script
+}
+
+/// An attribute value, as far as buildozer edits are concerned.
+#[derive(Debug, PartialEq)]
+enum Attr {
+    Str(String),
+    List(Set<String>),
+    Dict(Map<String, String>),
+    /// Any other value, rendered as a Starlark expression
+    Expr(String),
+}
+
+/// Commands turning the `existing` rules of `package` into the `generated` ones.
+fn buildozer_commands(
+    existing: &Map<RuleKey, Rule>,
+    generated: &[Rule],
+    package: &str,
+) -> Vec<String> {
+    let mut commands = Vec::new();
+    let mut generated_keys = Set::new();
+    let package = escape(package);
+
+    for rule in generated {
+        let Some(key) = rule.key() else {
+            continue;
+        };
+        let (kind, name) = &key;
+        let new_attrs = attributes(rule);
+        let old_attrs = match existing.get(&key) {
+            Some(existing_rule) => attributes(existing_rule),
+            None => {
+                commands.push(format!("new {kind} {}|{package}:__pkg__", escape(name)));
+                Map::new()
+            }
+        };
+        let label = format!("{package}:{}", escape(name));
+        diff_attributes(&old_attrs, &new_attrs, &label, &mut commands);
+        generated_keys.insert(key);
+    }
+
+    for key in existing.keys() {
+        if !generated_keys.contains(key) {
+            commands.push(format!("delete|{package}:{}", escape(&key.1)));
+        }
+    }
+    commands
+}
+
+fn diff_attributes(
+    old: &Map<String, Attr>,
+    new: &Map<String, Attr>,
+    label: &str,
+    commands: &mut Vec<String>,
+) {
+    for (attr, value) in new {
+        match (old.get(attr), value) {
+            (Some(old_value), new_value) if old_value == new_value => {}
+            (old_value, Attr::List(items)) => {
+                let empty = Set::new();
+                let old_items = match old_value {
+                    Some(Attr::List(old_items)) => old_items,
+                    None => &empty,
+                    // Not a plain list on disk (e.g. a `select`), replace it wholesale
+                    Some(_) => {
+                        commands.push(set_command(attr, &starlark_list(items), label));
+                        continue;
+                    }
+                };
+                let added: Vec<_> = items
+                    .difference(old_items)
+                    .map(|v| escape(&quote(v)))
+                    .collect();
+                let removed: Vec<_> = old_items
+                    .difference(items)
+                    .map(|v| escape(&quote(v)))
+                    .collect();
+                if !added.is_empty() {
+                    commands.push(format!("add {attr} {}|{label}", added.join(" ")));
+                }
+                if !removed.is_empty() {
+                    commands.push(format!("remove {attr} {}|{label}", removed.join(" ")));
+                }
+            }
+            (old_value, Attr::Dict(entries)) => {
+                let empty = Map::new();
+                let old_entries = match old_value {
+                    Some(Attr::Dict(old_entries)) => old_entries,
+                    _ => {
+                        if old_value.is_some() {
+                            commands.push(format!("remove {attr}|{label}"));
+                        }
+                        &empty
+                    }
+                };
+                for (key, value) in entries {
+                    if old_entries.get(key) != Some(value) {
+                        commands.push(format!(
+                            "dict_set {attr} {}:{}|{label}",
+                            escape(key),
+                            escape(&quote(value))
+                        ));
+                    }
+                }
+                let removed: Vec<_> = old_entries
+                    .keys()
+                    .filter(|key| !entries.contains_key(*key))
+                    .map(|key| escape(key))
+                    .collect();
+                if !removed.is_empty() {
+                    commands.push(format!("dict_remove {attr} {}|{label}", removed.join(" ")));
+                }
+            }
+            (_, Attr::Str(value)) => {
+                commands.push(set_command(attr, &quote(value), label));
+            }
+            (_, Attr::Expr(value)) => {
+                commands.push(set_command(attr, value, label));
+            }
+        }
+    }
+
+    for attr in old.keys() {
+        if !new.contains_key(attr) {
+            commands.push(format!("remove {attr}|{label}"));
+        }
+    }
+}
+
+/// The attributes of a rule other than its `name`, as serialized into BUCK files.
+fn attributes(rule: &Rule) -> Map<String, Attr> {
+    let serde_json::Value::Object(fields) =
+        serde_json::to_value(rule).expect("failed to serialize rule")
+    else {
+        return Map::new();
+    };
+
+    let mut attrs: Map<String, Attr> = fields
+        .into_iter()
+        .filter(|(attr, _)| attr != "name")
+        .map(|(attr, value)| {
+            let value = match value {
+                serde_json::Value::String(s) => Attr::Str(s),
+                serde_json::Value::Array(items) if items.iter().all(|v| v.is_string()) => {
+                    Attr::List(items.into_iter().filter_map(into_string).collect())
+                }
+                serde_json::Value::Object(entries) if entries.values().all(|v| v.is_string()) => {
+                    Attr::Dict(
+                        entries
+                            .into_iter()
+                            .filter_map(|(k, v)| Some((k, into_string(v)?)))
+                            .collect(),
+                    )
+                }
+                other => Attr::Expr(
+                    serde_starlark::to_string(&other).expect("failed to serialize attribute"),
+                ),
+            };
+            (attr, value)
+        })
+        .collect();
+
+    // A glob serializes like a plain list
+    if let Rule::FileGroup(filegroup) = rule {
+        attrs.insert(
+            "srcs".to_owned(),
+            Attr::Expr(
+                serde_starlark::to_string(&filegroup.srcs).expect("failed to serialize glob"),
+            ),
+        );
+    }
+    attrs
+}
+
+fn into_string(value: serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::String(s) => Some(s),
+        _ => None,
+    }
+}
+
+fn starlark_list(items: &Set<String>) -> String {
+    serde_starlark::to_string(items).expect("failed to serialize list")
+}
+
+fn set_command(attr: &str, value: &str, label: &str) -> String {
+    format!("set {attr} {}|{label}", escape(value.trim_end()))
+}
+
+/// A Starlark string literal, so buildozer never reads a value as a label, number or identifier.
+fn quote(value: &str) -> String {
+    format!(
+        "\"{}\"",
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
+
+/// Escape the characters buildozer treats as argument separators.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(' ', "\\ ")
+        .replace('\n', "\\n")
+        .replace('|', "\\|")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buck::RustLibrary;
+
+    const PACKAGE: &str = "//third-party/rust/crates/foo/1.0.0";
+
+    fn library(deps: &[&str]) -> Rule {
+        Rule::RustLibrary(RustLibrary {
+            name: "foo".to_owned(),
+            srcs: Set::from([":foo-vendor".to_owned()]),
+            crate_name: "foo".to_owned(),
+            crate_root: "vendor/src/lib.rs".to_owned(),
+            edition: "2021".to_owned(),
+            visibility: Set::from(["PUBLIC".to_owned()]),
+            deps: deps.iter().map(|d| d.to_string()).collect(),
+            ..Default::default()
+        })
+    }
+
+    fn existing(rules: Vec<Rule>) -> Map<RuleKey, Rule> {
+        rules
+            .into_iter()
+            .map(|rule| (rule.key().unwrap(), rule))
+            .collect()
+    }
+
+    #[test]
+    fn test_buildozer_commands_added_dep() {
+        let existing = existing(vec![library(&["//third-party/rust/crates/bar/1.0.0:bar"])]);
+        let generated = [library(&[
+            "//third-party/rust/crates/bar/1.0.0:bar",
+            "//third-party/rust/crates/baz/0.2.0:baz",
+        ])];
+
+        assert_eq!(
+            buildozer_commands(&existing, &generated, PACKAGE),
+            [
+                "add deps \"//third-party/rust/crates/baz/0.2.0:baz\"|//third-party/rust/crates/foo/1.0.0:foo"
+            ]
+        );
+        // Up-to-date rules need no commands
+        let unchanged = [library(&["//third-party/rust/crates/bar/1.0.0:bar"])];
+        assert!(buildozer_commands(&existing, &unchanged, PACKAGE).is_empty());
+    }
+
+    #[test]
+    fn test_buildozer_commands_new_and_deleted_rules() {
+        let mut stale = RustLibrary {
+            name: "old".to_owned(),
+            ..Default::default()
+        };
+        stale.env.insert("KEY".to_owned(), "value".to_owned());
+        let existing = existing(vec![Rule::RustLibrary(stale)]);
+
+        let commands = buildozer_commands(&existing, &[library(&[])], PACKAGE);
+        assert_eq!(
+            commands,
+            [
+                format!("new rust_library foo|{PACKAGE}:__pkg__"),
+                format!("set crate \"foo\"|{PACKAGE}:foo"),
+                format!("set crate_root \"vendor/src/lib.rs\"|{PACKAGE}:foo"),
+                format!("set edition \"2021\"|{PACKAGE}:foo"),
+                format!("add srcs \":foo-vendor\"|{PACKAGE}:foo"),
+                format!("add visibility \"PUBLIC\"|{PACKAGE}:foo"),
+                format!("delete|{PACKAGE}:old"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_buildozer_commands_dict_changes() {
+        let mut old = RustLibrary {
+            name: "foo".to_owned(),
+            ..Default::default()
+        };
+        old.env.insert("KEEP".to_owned(), "1".to_owned());
+        old.env.insert("DROP".to_owned(), "1".to_owned());
+        let mut new = RustLibrary {
+            name: "foo".to_owned(),
+            ..Default::default()
+        };
+        new.env.insert("KEEP".to_owned(), "1".to_owned());
+        new.env
+            .insert("OUT_DIR".to_owned(), "$(location :foo run)".to_owned());
+
+        let commands = buildozer_commands(
+            &existing(vec![Rule::RustLibrary(old)]),
+            &[Rule::RustLibrary(new)],
+            PACKAGE,
+        );
+        assert_eq!(
+            commands,
+            [
+                format!("dict_set env OUT_DIR:\"$(location\\ :foo\\ run)\"|{PACKAGE}:foo"),
+                format!("dict_remove env DROP|{PACKAGE}:foo"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_buildozer_commands_escape_labels_and_values() {
+        let mut new = RustLibrary {
+            name: "foo bar".to_owned(),
+            crate_name: "foo".to_owned(),
+            crate_root: "vendor/my src/lib.rs".to_owned(),
+            edition: "2021".to_owned(),
+            ..Default::default()
+        };
+        new.env
+            .insert("MSG".to_owned(), "say \"hi\" | \\o/".to_owned());
+        new.deps.insert("//my pkg:dep".to_owned());
+
+        let commands = buildozer_commands(&Map::new(), &[Rule::RustLibrary(new)], "//my pkg");
+        assert_eq!(
+            commands,
+            [
+                "new rust_library foo\\ bar|//my\\ pkg:__pkg__",
+                "set crate \"foo\"|//my\\ pkg:foo\\ bar",
+                "set crate_root \"vendor/my\\ src/lib.rs\"|//my\\ pkg:foo\\ bar",
+                "add deps \"//my\\ pkg:dep\"|//my\\ pkg:foo\\ bar",
+                "set edition \"2021\"|//my\\ pkg:foo\\ bar",
+                "dict_set env MSG:\"say\\ \\\\\"hi\\\\\"\\ \\|\\ \\\\\\\\o/\"|//my\\ pkg:foo\\ bar",
+            ]
+        );
+    }
+}
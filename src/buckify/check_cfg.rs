@@ -0,0 +1,96 @@
+use std::collections::{BTreeMap as Map, BTreeSet as Set};
+
+use crate::buck::Rule;
+
+use super::windows::apply_rustc_flags_patches_to_content;
+
+/// Buckconfig key that enables the `[check_cfg]` flags, e.g. `[buckal] check_cfg = true` once
+/// the buckal bundles in use provide the subtarget.
+const CHECK_CFG_CONFIG: (&str, &str) = ("buckal", "check_cfg");
+
+/// Pass the `cargo:rustc-check-cfg` outputs of build scripts, from the `[check_cfg]` subtarget of
+/// their `buildscript_run`, to the Rust rules built with them.
+///
+/// The flags are only added when the buckconfig enables them, since with bundles that lack the
+/// subtarget every crate with a build script would fail to build.
+pub(super) fn patch_check_cfg_flags(buck_content: String, buck_rules: &[Rule]) -> String {
+    // The Rust rules reading the `[rustc_flags]` of a `buildscript_run`, by its name
+    let mut targets: Map<&str, Set<(&str, &str)>> = Map::new();
+    for rule in buck_rules {
+        let (kind, name, rustc_flags) = match rule {
+            Rule::RustLibrary(rule) => ("rust_library", &rule.name, &rule.rustc_flags),
+            Rule::RustBinary(rule) => ("rust_binary", &rule.name, &rule.rustc_flags),
+            Rule::RustTest(rule) => ("rust_test", &rule.name, &rule.rustc_flags),
+            _ => continue,
+        };
+        for run_name in rustc_flags.iter().filter_map(|flag| {
+            flag.strip_prefix("@$(location :")?
+                .strip_suffix("[rustc_flags])")
+        }) {
+            targets
+                .entry(run_name)
+                .or_default()
+                .insert((kind, name.as_str()));
+        }
+    }
+
+    targets
+        .into_iter()
+        .fold(buck_content, |content, (run_name, targets)| {
+            apply_rustc_flags_patches_to_content(&content, &targets, &gated_check_cfg(run_name))
+        })
+}
+
+/// `(["@$(location :<run>[check_cfg])"] if read_config(...) == "true" else [])`
+fn gated_check_cfg(run_name: &str) -> String {
+    let (section, key) = CHECK_CFG_CONFIG;
+    format!(
+        "([\"@$(location :{run_name}[check_cfg])\"] if read_config(\"{section}\", \"{key}\", \"\") == \"true\" else [])"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::buck::{RustLibrary, RustTest};
+
+    #[test]
+    fn test_patch_check_cfg_flags() {
+        let buck = indoc::indoc! {r#"
+            rust_library(
+                name = "foo",
+                rustc_flags = ["@$(location :foo-build-script-run[rustc_flags])"],
+            )
+
+            rust_test(
+                name = "foo-unittest",
+                rustc_flags = ["--cfg=test"],
+            )
+        "#};
+        let rules = [
+            Rule::RustLibrary(RustLibrary {
+                name: "foo".to_owned(),
+                rustc_flags: Set::from([
+                    "@$(location :foo-build-script-run[rustc_flags])".to_owned()
+                ]),
+                ..Default::default()
+            }),
+            Rule::RustTest(RustTest {
+                name: "foo-unittest".to_owned(),
+                rustc_flags: Set::from(["--cfg=test".to_owned()]),
+                ..Default::default()
+            }),
+        ];
+
+        let patched = patch_check_cfg_flags(buck.to_owned(), &rules);
+        assert_eq!(
+            patched,
+            buck.replacen(
+                "[rustc_flags])\"]",
+                "[rustc_flags])\"] + ([\"@$(location :foo-build-script-run[check_cfg])\"] if read_config(\"buckal\", \"check_cfg\", \"\") == \"true\" else [])",
+                1
+            )
+        );
+    }
+}
@@ -1,20 +1,37 @@
 use std::{
-    collections::{BTreeSet as Set, HashMap},
+    collections::{BTreeMap as Map, BTreeSet as Set, HashMap, HashSet},
     path::PathBuf,
+    sync::OnceLock,
 };
 
 use anyhow::{Context, Result, bail};
-use cargo_metadata::{DependencyKind, Node, NodeDep, Package, PackageId, Target};
+use cargo_metadata::{DepKindInfo, DependencyKind, Node, NodeDep, Package, PackageId, Target};
+use cargo_platform::Cfg;
 
 use crate::{
-    RUST_CRATES_ROOT,
     buck::{CargoTargetKind, RustRule},
     buckal_note, buckal_warn,
-    context::BuckalContext,
+    context::{BuckalContext, ReverseDeps},
     platform::{Os, oses_from_platform, platform_is_target_only},
-    utils::get_buck2_root,
+    utils::{get_buck2_root, get_cfgs, get_target},
 };
 
+use super::drops::{DropReason, DroppedDep, record_drop};
+
+/// The platform proc-macros run on: the triple and cfgs of the host running `cargo buckal`.
+pub(super) struct ExecPlatform {
+    triple: String,
+    cfgs: Vec<Cfg>,
+}
+
+fn host_exec_platform() -> &'static ExecPlatform {
+    static HOST: OnceLock<ExecPlatform> = OnceLock::new();
+    HOST.get_or_init(|| ExecPlatform {
+        triple: get_target(),
+        cfgs: get_cfgs(),
+    })
+}
+
 pub(super) fn dep_kind_matches(target_kind: CargoTargetKind, dep_kind: DependencyKind) -> bool {
     match target_kind {
         CargoTargetKind::CustomBuild => dep_kind == DependencyKind::Build,
@@ -26,7 +43,7 @@ pub(super) fn dep_kind_matches(target_kind: CargoTargetKind, dep_kind: Dependenc
     }
 }
 
-fn get_lib_targets(package: &Package) -> Vec<&Target> {
+pub(super) fn get_lib_targets(package: &Package) -> Vec<&Target> {
     package
         .targets
         .iter()
@@ -41,7 +58,8 @@ fn get_lib_targets(package: &Package) -> Vec<&Target> {
         .collect()
 }
 
-fn resolve_first_party_label(dep_package: &Package) -> Result<String> {
+/// Path of a first-party package relative to the Buck2 root, with forward slashes.
+pub(super) fn first_party_package_path(dep_package: &Package) -> Result<String> {
     let buck2_root = get_buck2_root().context("failed to get buck2 root")?;
     let manifest_path = PathBuf::from(&dep_package.manifest_path);
     let manifest_dir = manifest_path
@@ -59,6 +77,11 @@ fn resolve_first_party_label(dep_package: &Package) -> Result<String> {
         .to_string_lossy()
         // Normalize path separators for Buck2 (always use forward slashes)
         .replace('\\', "/");
+    Ok(relative_path)
+}
+
+fn resolve_first_party_label(dep_package: &Package) -> Result<String> {
+    let relative_path = first_party_package_path(dep_package)?;
 
     let dep_bin_targets: Vec<_> = dep_package
         .targets
@@ -81,6 +104,21 @@ fn resolve_first_party_label(dep_package: &Package) -> Result<String> {
     Ok(format!("//{relative_path}:{buckal_name}"))
 }
 
+/// Label of the library rule of a package, first-party or vendored.
+pub(super) fn library_label(package: &Package, ctx: &BuckalContext) -> Result<String> {
+    if package.source.is_none() {
+        resolve_first_party_label(package)
+    } else {
+        Ok(third_party_label(package, ctx))
+    }
+}
+
+/// Label of the `rust_library` of a vendored crate, which is named after the package even when
+/// its lib target isn't.
+pub(super) fn third_party_label(package: &Package, ctx: &BuckalContext) -> String {
+    format!("//{}:{}", ctx.vendor_path(package), package.name)
+}
+
 fn resolve_buckal_name(dep_bin_targets: &[&Target], dep_lib_targets: &[&Target]) -> String {
     if dep_bin_targets
         .iter()
@@ -92,14 +130,48 @@ fn resolve_buckal_name(dep_bin_targets: &[&Target], dep_lib_targets: &[&Target])
     }
 }
 
+/// Words Starlark reserves, which can't name a `named_deps` entry even where Rust allows them
+/// (e.g. a dependency renamed to `def`).
+const STARLARK_RESERVED: [&str; 33] = [
+    "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del", "elif",
+    "else", "except", "finally", "for", "from", "global", "if", "import", "in", "is", "lambda",
+    "load", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while", "with", "yield",
+];
+
+/// Check that a `named_deps` key, the name a dependency is renamed to, is an identifier Starlark
+/// doesn't reserve.
+fn validate_alias(alias: &str) -> Result<()> {
+    let mut chars = alias.chars();
+    let is_identifier = chars.next().is_some_and(|c| c == '_' || c.is_alphabetic())
+        && chars.all(|c| c == '_' || c.is_alphanumeric());
+    if !is_identifier {
+        bail!("dependency alias '{}' is not a valid identifier", alias);
+    }
+    if STARLARK_RESERVED.contains(&alias) {
+        bail!(
+            "dependency alias '{}' is reserved in Starlark, rename the dependency in Cargo.toml",
+            alias
+        );
+    }
+    Ok(())
+}
+
 fn resolve_dep_label(
     dep: &NodeDep,
     dep_package: &Package,
     use_workspace_alias: bool,
+    ctx: &BuckalContext,
 ) -> Result<(String, Option<String>)> {
-    let dep_package_name = dep_package.name.to_string();
-    let is_renamed = dep.name != dep_package_name.replace("-", "_");
+    // `dep.name` is the name the dependent refers to the crate by, which is the lib target's
+    // crate name unless renamed, and may differ from the package name (e.g. `rust-crypto`
+    // whose lib is `crypto`)
+    let crate_name = get_lib_targets(dep_package)
+        .first()
+        .map_or(dep_package.name.as_str(), |lib| lib.name.as_str())
+        .replace("-", "_");
+    let is_renamed = dep.name != crate_name;
     let alias = if is_renamed {
+        validate_alias(&dep.name)?;
         Some(dep.name.clone())
     } else {
         None
@@ -119,16 +191,182 @@ fn resolve_dep_label(
             if use_workspace_alias {
                 format!("//third-party/rust:{}", dep_package.name)
             } else {
-                format!(
-                    "//{RUST_CRATES_ROOT}/{}/{}:{}",
-                    dep_package.name, dep_package.version, dep_package.name
-                )
+                third_party_label(dep_package, ctx)
             },
             alias,
         ))
     }
 }
 
+/// An artifact dependency (`dep = { ..., artifact = "bin" }`) declared in a manifest.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct ArtifactDep {
+    /// Dependency name as written in the manifest, used in the `CARGO_*_FILE_<DEP>` env vars
+    dep_name: String,
+    /// Requested artifacts, e.g. `bin`, `bin:<name>` or `cdylib`
+    kinds: Vec<String>,
+    /// Whether the library is also depended on, with `lib = true` or a plain dependency on the
+    /// same package in the same table
+    lib: bool,
+}
+
+/// Artifact dependencies keyed by package name and the kind of dependency they're declared as,
+/// as a package can be an artifact dependency of one kind and a plain dependency of another.
+type ArtifactDeps = HashMap<(String, DependencyKind), ArtifactDep>;
+
+/// Collect artifact dependencies from a manifest.
+///
+/// `cargo_metadata` does not expose the artifact fields of dependencies, so they are read from
+/// the dependent's `Cargo.toml` directly.
+fn parse_artifact_deps(manifest: &str) -> ArtifactDeps {
+    let mut artifact_deps = ArtifactDeps::new();
+    let Ok(manifest) = manifest.parse::<toml::Table>() else {
+        return artifact_deps;
+    };
+
+    const DEP_TABLES: [(&str, DependencyKind); 3] = [
+        ("dependencies", DependencyKind::Normal),
+        ("dev-dependencies", DependencyKind::Development),
+        ("build-dependencies", DependencyKind::Build),
+    ];
+    let mut tables: Vec<(DependencyKind, &toml::Table)> = DEP_TABLES
+        .iter()
+        .filter_map(|(key, kind)| Some((*kind, manifest.get(*key)?.as_table()?)))
+        .collect();
+    if let Some(targets) = manifest.get("target").and_then(|v| v.as_table()) {
+        for target in targets.values().filter_map(|v| v.as_table()) {
+            tables.extend(
+                DEP_TABLES
+                    .iter()
+                    .filter_map(|(key, kind)| Some((*kind, target.get(*key)?.as_table()?))),
+            );
+        }
+    }
+
+    let mut plain = HashSet::new();
+    for (dep_kind, table) in tables {
+        for (dep_name, spec) in table {
+            let package = spec
+                .get("package")
+                .and_then(|v| v.as_str())
+                .unwrap_or(dep_name)
+                .to_owned();
+            let kinds: Vec<String> = match spec.get("artifact") {
+                Some(toml::Value::String(kind)) => vec![kind.clone()],
+                Some(toml::Value::Array(kinds)) => kinds
+                    .iter()
+                    .filter_map(|k| k.as_str().map(str::to_owned))
+                    .collect(),
+                _ => {
+                    plain.insert((package, dep_kind));
+                    continue;
+                }
+            };
+            artifact_deps.insert(
+                (package, dep_kind),
+                ArtifactDep {
+                    dep_name: dep_name.clone(),
+                    kinds,
+                    lib: spec.get("lib").and_then(|v| v.as_bool()).unwrap_or(false),
+                },
+            );
+        }
+    }
+    for (key, artifact) in &mut artifact_deps {
+        artifact.lib |= plain.contains(key);
+    }
+    artifact_deps
+}
+
+fn load_artifact_deps(package: &Package) -> ArtifactDeps {
+    match std::fs::read_to_string(&package.manifest_path) {
+        Ok(manifest) if manifest.contains("artifact") => parse_artifact_deps(&manifest),
+        _ => ArtifactDeps::new(),
+    }
+}
+
+/// Env vars Cargo sets for a binary artifact dependency.
+///
+/// `bins` pairs each binary name with its Buck label. The unsuffixed `CARGO_BIN_FILE_<DEP>` is
+/// only set for the binary named after the dependency.
+fn artifact_bin_env(dep_name: &str, bins: &[(String, String)]) -> Map<String, String> {
+    let dep_env_name = dep_name.to_uppercase().replace('-', "_");
+    let mut env = Map::new();
+    for (bin_name, label) in bins {
+        let location = format!("$(location {label})");
+        if bin_name == dep_name {
+            env.insert(format!("CARGO_BIN_FILE_{dep_env_name}"), location.clone());
+        }
+        env.insert(
+            format!("CARGO_BIN_FILE_{dep_env_name}_{bin_name}"),
+            location,
+        );
+    }
+    env
+}
+
+/// Name of the package whose deps are being set, for reporting.
+fn parent_name(node: &Node, packages_map: &HashMap<PackageId, Package>) -> String {
+    packages_map
+        .get(&node.id)
+        .map(|package| package.name.to_string())
+        .unwrap_or_else(|| node.id.repr.clone())
+}
+
+/// Wire the binaries of an artifact dependency into `rust_rule` as `CARGO_BIN_FILE_*` env vars.
+fn insert_artifact_env(
+    rust_rule: &mut dyn RustRule,
+    artifact: &ArtifactDep,
+    dep_package: &Package,
+    node: &Node,
+    packages_map: &HashMap<PackageId, Package>,
+) -> Result<()> {
+    if dep_package.source.is_some() {
+        // Third-party crates are emitted as libraries only
+        buckal_note!(
+            "Artifact dependency '{}' on third-party package '{}' is not supported and will be omitted.",
+            artifact.dep_name,
+            dep_package.name
+        );
+        record_drop(DroppedDep {
+            parent: parent_name(node, packages_map),
+            dep: dep_package.name.to_string(),
+            platform: "-".to_owned(),
+            reason: DropReason::Unmapped,
+        });
+        return Ok(());
+    }
+
+    let relative_path = first_party_package_path(dep_package)?;
+    let mut bins = Vec::new();
+    for kind in &artifact.kinds {
+        let wanted = match kind.as_str() {
+            "bin" => None,
+            _ if kind.starts_with("bin:") => Some(&kind["bin:".len()..]),
+            _ => {
+                buckal_note!(
+                    "Artifact kind '{}' of dependency '{}' is not supported and will be omitted.",
+                    kind,
+                    artifact.dep_name
+                );
+                continue;
+            }
+        };
+        bins.extend(
+            dep_package
+                .targets
+                .iter()
+                .filter(|t| t.kind.contains(&cargo_metadata::TargetKind::Bin))
+                .filter(|t| wanted.is_none_or(|name| t.name == name))
+                .map(|t| (t.name.clone(), format!("//{relative_path}:{}", t.name))),
+        );
+    }
+    rust_rule
+        .env_mut()
+        .extend(artifact_bin_env(&artifact.dep_name, &bins));
+    Ok(())
+}
+
 /// Insert a dependency label into `rust_rule` in the appropriate attribute.
 ///
 /// `target` is the Buck label we want the rule to depend on. If `alias` is `Some`, the
@@ -149,12 +387,24 @@ fn resolve_dep_label(
 ///   once with different targets, we emit a warning and keep the first value.
 /// - For platform-specific named dependencies (`os_named_deps`), an alias may map to only one
 ///   target per OS. Conflicting targets for the same `(alias, os)` are treated as an error.
+///
+/// In both cases, a conflict is settled silently if `alias_resolution` (from `buckal.toml`) maps
+/// the alias to one of the conflicting targets.
 fn insert_dep(
     rust_rule: &mut dyn RustRule,
     target: &str,
     alias: Option<&str>,
     platforms: Option<&Set<Os>>,
+    alias_resolution: &Map<String, String>,
 ) -> Result<()> {
+    // The configured target for `alias`, if it is one of the two conflicting candidates
+    let resolve = |alias: &str, existing: &str| {
+        alias_resolution
+            .get(alias)
+            .filter(|chosen| chosen.as_str() == existing || chosen.as_str() == target)
+            .cloned()
+    };
+
     if let Some(platforms) = platforms {
         for os in platforms {
             let os_key = os.key().to_owned();
@@ -166,6 +416,10 @@ fn insert_dep(
 
                 if let Some(existing) = entries.get(&os_key) {
                     if existing != target {
+                        if let Some(chosen) = resolve(alias, existing) {
+                            entries.insert(os_key.clone(), chosen);
+                            continue;
+                        }
                         bail!(
                             "os_named_deps alias '{}' had conflicting targets for platform '{}': '{}' vs '{}'",
                             alias,
@@ -191,8 +445,12 @@ fn insert_dep(
             std::collections::btree_map::Entry::Vacant(v) => {
                 v.insert(target.to_owned());
             }
-            std::collections::btree_map::Entry::Occupied(o) => {
+            std::collections::btree_map::Entry::Occupied(mut o) => {
                 if o.get() != target {
+                    if let Some(chosen) = resolve(alias, o.get()) {
+                        o.insert(chosen);
+                        return Ok(());
+                    }
                     buckal_warn!(
                         "named_deps alias '{}' had conflicting targets: '{}' vs '{}'",
                         alias,
@@ -208,6 +466,127 @@ fn insert_dep(
     Ok(())
 }
 
+/// The platforms under which a dependency edge applies.
+#[derive(Default)]
+struct DepPlatforms {
+    /// The edge applies on every platform
+    unconditional: bool,
+    /// OSes the edge is gated to, if not unconditional
+    platforms: Set<Os>,
+    /// Some dep kinds only target platforms buckal does not support
+    has_unsupported_platform: bool,
+}
+
+fn classify_dep_platforms<'a>(dep_kinds: impl Iterator<Item = &'a DepKindInfo>) -> DepPlatforms {
+    let mut result = DepPlatforms::default();
+    for dk in dep_kinds {
+        match &dk.target {
+            None => result.unconditional = true,
+            Some(platform) => {
+                let oses = oses_from_platform(platform);
+                if oses.is_empty() {
+                    if platform_is_target_only(platform) {
+                        result.has_unsupported_platform = true;
+                        continue;
+                    }
+                    result.unconditional = true;
+                    continue;
+                }
+                result.platforms.extend(oses);
+            }
+        }
+    }
+    result
+}
+
+/// Like [`classify_dep_platforms`], but for a proc-macro: the edge applies if it does on the exec
+/// platform, and is dropped otherwise.
+fn classify_exec_dep_platforms<'a>(
+    mut dep_kinds: impl Iterator<Item = &'a DepKindInfo>,
+    exec: &ExecPlatform,
+) -> DepPlatforms {
+    DepPlatforms {
+        unconditional: dep_kinds.any(|dk| {
+            dk.target
+                .as_ref()
+                .is_none_or(|platform| platform.matches(&exec.triple, &exec.cfgs))
+        }),
+        ..Default::default()
+    }
+}
+
+/// Infer the OS a package is restricted to from the way its dependents use it.
+///
+/// Returns `Some` only when [`dependent_oses`] narrows the package down to a single OS.
+pub(super) fn infer_dependent_os(node_id: &PackageId, ctx: &BuckalContext) -> Option<Os> {
+    match dependent_oses(node_id, ctx) {
+        Some(oses) if oses.len() == 1 => oses.first().copied(),
+        _ => None,
+    }
+}
+
+/// The OSes a package is used on, following its dependents up to the workspace members, or
+/// `None` when it is used on every OS.
+///
+/// An edge gated to some OSes narrows the OSes its dependent is used on to those, so a crate only
+/// pulled in by a Windows-only dependency is itself Windows-only.
+pub(super) fn dependent_oses(node_id: &PackageId, ctx: &BuckalContext) -> Option<Set<Os>> {
+    dependent_oses_in(node_id, ctx.reverse_deps(), &ctx.workspace_members)
+}
+
+/// [`dependent_oses`] over any graph, given its reverse edges.
+pub fn dependent_oses_in(
+    node_id: &PackageId,
+    reverse_deps: &ReverseDeps,
+    workspace_members: &[PackageId],
+) -> Option<Set<Os>> {
+    let oses = used_oses(
+        node_id,
+        reverse_deps,
+        workspace_members,
+        &mut HashMap::new(),
+    );
+    (!oses.is_empty() && oses.len() < Os::mapped().len()).then_some(oses)
+}
+
+fn used_oses<'a>(
+    node_id: &'a PackageId,
+    reverse_deps: &'a ReverseDeps,
+    workspace_members: &[PackageId],
+    visited: &mut HashMap<&'a PackageId, Option<Set<Os>>>,
+) -> Set<Os> {
+    let all = Os::mapped;
+    // Dev-dependencies can form cycles: assume every OS for packages still being visited
+    if let Some(oses) = visited.get(node_id) {
+        return oses.clone().unwrap_or_else(all);
+    }
+    let edges = match reverse_deps.get(node_id) {
+        Some(edges) if !workspace_members.contains(node_id) => edges,
+        _ => return all(),
+    };
+    visited.insert(node_id, None);
+
+    let mut oses = Set::new();
+    for (dependent, dep) in edges {
+        let dep_platforms = classify_dep_platforms(dep.dep_kinds.iter());
+        let edge_oses = if dep_platforms.unconditional {
+            all()
+        } else {
+            dep_platforms.platforms
+        };
+        if edge_oses.is_empty() {
+            continue;
+        }
+        let dependent_oses = used_oses(dependent, reverse_deps, workspace_members, visited);
+        oses.extend(edge_oses.intersection(&dependent_oses));
+        if oses.len() == Os::mapped().len() {
+            break;
+        }
+    }
+    visited.insert(node_id, Some(oses.clone()));
+    oses
+}
+
 pub(super) fn set_deps(
     rust_rule: &mut dyn RustRule,
     node: &Node,
@@ -215,39 +594,57 @@ pub(super) fn set_deps(
     kind: CargoTargetKind,
     ctx: &BuckalContext,
 ) -> Result<()> {
-    let use_workspace_alias =
-        ctx.repo_config.inherit_workspace_deps && ctx.workspace_members.contains(&node.id);
+    // Proc-macros are built for and run on the exec platform, so their platform-gated deps are
+    // settled against it rather than bucketed by target OS
+    let is_proc_macro = kind == CargoTargetKind::Lib
+        && packages_map.get(&node.id).is_some_and(|package| {
+            get_lib_targets(package)
+                .iter()
+                .any(|t| t.kind.contains(&cargo_metadata::TargetKind::ProcMacro))
+        });
+    let exec = is_proc_macro.then(host_exec_platform);
+    set_deps_on(rust_rule, node, packages_map, kind, ctx, exec)
+}
+
+/// [`set_deps`], evaluating platform-gated deps against `exec` when given.
+fn set_deps_on(
+    rust_rule: &mut dyn RustRule,
+    node: &Node,
+    packages_map: &HashMap<PackageId, Package>,
+    kind: CargoTargetKind,
+    ctx: &BuckalContext,
+    exec: Option<&ExecPlatform>,
+) -> Result<()> {
+    // Every first-party crate's deps are covered by the aliases, but only workspace members use
+    // them unless `alias_path_deps` is set
+    let is_first_party = packages_map
+        .get(&node.id)
+        .is_some_and(|package| package.source.is_none());
+    let use_workspace_alias = ctx.repo_config.inherit_workspace_deps
+        && (ctx.workspace_members.contains(&node.id)
+            || ctx.repo_config.alias_path_deps && is_first_party);
+    let artifact_deps = packages_map
+        .get(&node.id)
+        .map(load_artifact_deps)
+        .unwrap_or_default();
 
     for dep in &node.deps {
         let Some(dep_package) = packages_map.get(&dep.pkg) else {
             continue;
         };
 
-        let mut unconditional = false;
-        let mut platforms = Set::<Os>::new();
-        let mut has_unsupported_platform = false;
-
-        for dk in dep
+        let dep_kinds = dep
             .dep_kinds
             .iter()
-            .filter(|dk| dep_kind_matches(kind, dk.kind))
-        {
-            match &dk.target {
-                None => unconditional = true,
-                Some(platform) => {
-                    let oses = oses_from_platform(platform);
-                    if oses.is_empty() {
-                        if platform_is_target_only(platform) {
-                            has_unsupported_platform = true;
-                            continue;
-                        }
-                        unconditional = true;
-                        continue;
-                    }
-                    platforms.extend(oses);
-                }
-            }
-        }
+            .filter(|dk| dep_kind_matches(kind, dk.kind));
+        let DepPlatforms {
+            unconditional,
+            platforms,
+            has_unsupported_platform,
+        } = match exec {
+            Some(exec) => classify_exec_dep_platforms(dep_kinds, exec),
+            None => classify_dep_platforms(dep_kinds),
+        };
 
         if !unconditional && platforms.is_empty() {
             if has_unsupported_platform {
@@ -256,22 +653,76 @@ pub(super) fn set_deps(
                     dep.name,
                     dep_package.name
                 );
+                record_drop(DroppedDep {
+                    parent: parent_name(node, packages_map),
+                    dep: dep_package.name.to_string(),
+                    platform: dep
+                        .dep_kinds
+                        .iter()
+                        .filter(|dk| dep_kind_matches(kind, dk.kind))
+                        .filter_map(|dk| dk.target.as_ref().map(ToString::to_string))
+                        .collect::<Set<_>>()
+                        .into_iter()
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    reason: DropReason::Unsupported,
+                });
             }
             continue;
         }
 
-        let (target_label, alias) = resolve_dep_label(dep, dep_package, use_workspace_alias)
+        // Only the binaries of an artifact dependency are wired in, unless it also asks for
+        // `lib = true` or the package is a plain dependency of another matching kind
+        let mut links_lib = false;
+        for dep_kind in dep
+            .dep_kinds
+            .iter()
+            .filter(|dk| dep_kind_matches(kind, dk.kind))
+            .map(|dk| dk.kind)
+            .collect::<HashSet<_>>()
+        {
+            let Some(artifact) = artifact_deps.get(&(dep_package.name.to_string(), dep_kind))
+            else {
+                links_lib = true;
+                continue;
+            };
+            insert_artifact_env(rust_rule, artifact, dep_package, node, packages_map)
+                .with_context(|| {
+                    format!(
+                        "failed to resolve artifact dependency '{}' (package '{}')",
+                        artifact.dep_name, dep_package.name
+                    )
+                })?;
+            links_lib |= artifact.lib;
+        }
+        if !links_lib {
+            continue;
+        }
+
+        let (target_label, alias) = resolve_dep_label(dep, dep_package, use_workspace_alias, ctx)
             .with_context(|| {
-                format!(
-                    "failed to resolve dependency label for '{}' (package '{}')",
-                    dep.name, dep_package.name
-                )
-            })?;
+            format!(
+                "failed to resolve dependency label for '{}' (package '{}')",
+                dep.name, dep_package.name
+            )
+        })?;
 
         if unconditional {
-            insert_dep(rust_rule, &target_label, alias.as_deref(), None)?;
+            insert_dep(
+                rust_rule,
+                &target_label,
+                alias.as_deref(),
+                None,
+                &ctx.repo_config.alias_resolution,
+            )?;
         } else {
-            insert_dep(rust_rule, &target_label, alias.as_deref(), Some(&platforms))?;
+            insert_dep(
+                rust_rule,
+                &target_label,
+                alias.as_deref(),
+                Some(&platforms),
+                &ctx.repo_config.alias_resolution,
+            )?;
         }
     }
     Ok(())
@@ -280,24 +731,12 @@ pub(super) fn set_deps(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::RUST_CRATES_ROOT;
+    use crate::buck::{RustBinary, RustLibrary};
+    use crate::context::ChecksumKey;
+    use crate::testing::{REGISTRY, mock_dep, mock_node, mock_target};
     use cargo_metadata::TargetKind;
 
-    fn mock_target(name: &str, kind: TargetKind) -> Target {
-        // Target struct construction is verbose, using a helper or json deserialization might be easier
-        // but let's try strict construction if possible, or use serde_json
-        serde_json::from_value(serde_json::json!({
-            "name": name,
-            "kind": [kind],
-            "crate_types": [],
-            "required_features": [],
-            "src_path": "/tmp/dummy.rs",
-            "edition": "2021",
-            "doctest": true,
-            "test": true
-        }))
-        .unwrap()
-    }
-
     #[test]
     fn test_resolve_buckal_name_with_collision() {
         let lib = mock_target("foo", TargetKind::Lib);
@@ -321,4 +760,752 @@ mod tests {
         let name = resolve_buckal_name(&bin_targets, &lib_targets);
         assert_eq!(name, "foo");
     }
+
+    fn mock_ctx(nodes: Vec<Node>) -> BuckalContext {
+        BuckalContext {
+            nodes_map: nodes.into_iter().map(|n| (n.id.clone(), n)).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_infer_dependent_os_windows_only() {
+        let ctx = mock_ctx(vec![
+            mock_node(
+                "app",
+                serde_json::json!([{
+                    "name": "winapi",
+                    "pkg": "winapi",
+                    "dep_kinds": [{ "kind": null, "target": "cfg(windows)" }]
+                }]),
+                &[],
+            ),
+            mock_node(
+                "tool",
+                serde_json::json!([{
+                    "name": "winapi",
+                    "pkg": "winapi",
+                    "dep_kinds": [{ "kind": "build", "target": "cfg(target_os = \"windows\")" }]
+                }]),
+                &[],
+            ),
+            mock_node("winapi", serde_json::json!([]), &[]),
+        ]);
+
+        let id = PackageId {
+            repr: "winapi".to_string(),
+        };
+        assert_eq!(infer_dependent_os(&id, &ctx), Some(Os::Windows));
+    }
+
+    #[test]
+    fn test_dependent_oses_narrowed_through_dependents() {
+        let ctx = mock_ctx(vec![
+            mock_node(
+                "app",
+                serde_json::json!([{
+                    "name": "winapi_util",
+                    "pkg": "winapi-util",
+                    "dep_kinds": [{ "kind": null, "target": "cfg(windows)" }]
+                }]),
+                &[],
+            ),
+            mock_node(
+                "winapi-util",
+                serde_json::json!([{
+                    "name": "winapi",
+                    "pkg": "winapi",
+                    "dep_kinds": [{ "kind": null, "target": null }]
+                }]),
+                &[],
+            ),
+            mock_node("winapi", serde_json::json!([]), &[]),
+        ]);
+
+        // `winapi` is an unconditional dependency of a crate only used on Windows
+        let winapi = PackageId {
+            repr: "winapi".to_string(),
+        };
+        assert_eq!(
+            dependent_oses(&winapi, &ctx),
+            Some(Set::from([Os::Windows]))
+        );
+    }
+
+    #[test]
+    fn test_infer_dependent_os_unconditional_or_mixed() {
+        let ctx = mock_ctx(vec![
+            mock_node(
+                "app",
+                serde_json::json!([
+                    {
+                        "name": "libc",
+                        "pkg": "libc",
+                        "dep_kinds": [{ "kind": null, "target": "cfg(unix)" }]
+                    },
+                    {
+                        "name": "log",
+                        "pkg": "log",
+                        "dep_kinds": [{ "kind": null, "target": "cfg(windows)" }]
+                    }
+                ]),
+                &[],
+            ),
+            mock_node(
+                "tool",
+                serde_json::json!([{
+                    "name": "log",
+                    "pkg": "log",
+                    "dep_kinds": [{ "kind": null, "target": null }]
+                }]),
+                &[],
+            ),
+        ]);
+
+        // `libc` is used on both linux and macos
+        let libc = PackageId {
+            repr: "libc".to_string(),
+        };
+        assert_eq!(infer_dependent_os(&libc, &ctx), None);
+
+        // `log` is also depended on unconditionally
+        let log = PackageId {
+            repr: "log".to_string(),
+        };
+        assert_eq!(infer_dependent_os(&log, &ctx), None);
+    }
+
+    #[test]
+    fn test_ios_gated_dep_lands_in_ios_bucket() {
+        let mut rule = crate::buck::RustLibrary::default();
+        insert_dep(
+            &mut rule,
+            "//third-party/rust/crates/objc2/0.5.0:objc2",
+            None,
+            Some(&Set::from([Os::Ios])),
+            &Map::new(),
+        )
+        .unwrap();
+        assert!(rule.deps.is_empty());
+        assert_eq!(
+            rule.os_deps.keys().map(String::as_str).collect::<Vec<_>>(),
+            ["ios"]
+        );
+    }
+
+    #[test]
+    fn test_insert_dep_alias_resolution() {
+        let mut rule = crate::buck::RustLibrary::default();
+        let resolution = Map::from([("foo".to_owned(), "//b:foo".to_owned())]);
+
+        insert_dep(&mut rule, "//a:foo", Some("foo"), None, &resolution).unwrap();
+        insert_dep(&mut rule, "//b:foo", Some("foo"), None, &resolution).unwrap();
+        assert_eq!(
+            rule.named_deps.get("foo").map(String::as_str),
+            Some("//b:foo")
+        );
+
+        // Without a configured resolution the first target is kept
+        let mut rule = crate::buck::RustLibrary::default();
+        insert_dep(&mut rule, "//a:foo", Some("foo"), None, &Map::new()).unwrap();
+        insert_dep(&mut rule, "//b:foo", Some("foo"), None, &Map::new()).unwrap();
+        assert_eq!(
+            rule.named_deps.get("foo").map(String::as_str),
+            Some("//a:foo")
+        );
+    }
+
+    #[test]
+    fn test_insert_dep_os_alias_resolution() {
+        let platforms = Set::from([Os::Linux]);
+        let resolution = Map::from([("foo".to_owned(), "//a:foo".to_owned())]);
+
+        let mut rule = crate::buck::RustLibrary::default();
+        insert_dep(
+            &mut rule,
+            "//a:foo",
+            Some("foo"),
+            Some(&platforms),
+            &resolution,
+        )
+        .unwrap();
+        insert_dep(
+            &mut rule,
+            "//b:foo",
+            Some("foo"),
+            Some(&platforms),
+            &resolution,
+        )
+        .unwrap();
+        assert_eq!(
+            rule.os_named_deps["foo"]
+                .get(Os::Linux.key())
+                .map(String::as_str),
+            Some("//a:foo")
+        );
+
+        // Unresolved conflicts are still an error
+        let mut rule = crate::buck::RustLibrary::default();
+        insert_dep(
+            &mut rule,
+            "//a:foo",
+            Some("foo"),
+            Some(&platforms),
+            &Map::new(),
+        )
+        .unwrap();
+        assert!(
+            insert_dep(
+                &mut rule,
+                "//b:foo",
+                Some("foo"),
+                Some(&platforms),
+                &Map::new()
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_validate_alias() {
+        validate_alias("serde_json").unwrap();
+        validate_alias("_private").unwrap();
+        // Non-ASCII identifiers are valid crate names
+        validate_alias("fancy\u{e9}").unwrap();
+        validate_alias("\u{30af}\u{30ec}\u{30fc}\u{30c8}").unwrap();
+
+        for invalid in ["my\"dep", "back\\slash", "1st", ""] {
+            let err = validate_alias(invalid).unwrap_err().to_string();
+            assert!(err.contains("is not a valid identifier"), "{err}");
+        }
+        for reserved in ["def", "load", "lambda"] {
+            let err = validate_alias(reserved).unwrap_err().to_string();
+            assert!(err.contains("is reserved in Starlark"), "{err}");
+        }
+
+        // Resolving a dependency renamed to a reserved word fails rather than emitting it
+        let dep: NodeDep = serde_json::from_value(serde_json::json!({
+            "name": "def",
+            "pkg": "registry+https://github.com/rust-lang/crates.io-index#geom@1.0.0",
+            "dep_kinds": [{ "kind": null, "target": null }]
+        }))
+        .unwrap();
+        let package = mock_registry_package("geom", "1.0.0");
+        assert!(resolve_dep_label(&dep, &package, false, &BuckalContext::default()).is_err());
+    }
+
+    #[test]
+    fn test_lib_name_differs_from_package_name() {
+        let mut package = mock_registry_package("rust-crypto", "0.2.36");
+        let mut lib = mock_target("crypto", TargetKind::Lib);
+        lib.src_path = "/nonexistent/rust-crypto-0.2.36/src/lib.rs".into();
+        package.targets = vec![lib];
+        let dep = |name: &str| -> NodeDep {
+            serde_json::from_value(serde_json::json!({
+                "name": name,
+                "pkg": package.id.clone(),
+                "dep_kinds": [{ "kind": null, "target": null }]
+            }))
+            .unwrap()
+        };
+
+        // Referred to by its lib name, the crate needs no alias
+        let (label, alias) =
+            resolve_dep_label(&dep("crypto"), &package, false, &BuckalContext::default()).unwrap();
+        assert_eq!(
+            label,
+            format!("//{RUST_CRATES_ROOT}/rust-crypto/0.2.36:rust-crypto")
+        );
+        assert_eq!(alias, None);
+        let (_, alias) =
+            resolve_dep_label(&dep("rc"), &package, false, &BuckalContext::default()).unwrap();
+        assert_eq!(alias.as_deref(), Some("rc"));
+
+        // The label points at the emitted library
+        let node = mock_node(&package.id.repr, serde_json::json!([]), &[]);
+        let mut ctx = mock_ctx(vec![]);
+        ctx.packages_map.insert(package.id.clone(), package.clone());
+        ctx.checksums_map
+            .insert(ChecksumKey::of(&package), "00".repeat(32).parse().unwrap());
+        let library = super::super::buckify_dep_node(&node, &ctx)
+            .unwrap()
+            .into_iter()
+            .find_map(|r| match r {
+                crate::buck::Rule::RustLibrary(l) => Some(l),
+                _ => None,
+            })
+            .expect("expected a rust_library rule");
+        assert!(label.ends_with(&format!(":{}", library.name)));
+        assert_eq!(library.crate_name, "crypto");
+    }
+
+    #[test]
+    fn test_build_metadata_version_label() {
+        let package = mock_registry_package("foo", "1.0.0-alpha.1+build");
+        let dep: NodeDep =
+            serde_json::from_value(serde_json::json!(mock_dep("foo", &package.id.repr))).unwrap();
+        let (label, _) =
+            resolve_dep_label(&dep, &package, false, &BuckalContext::default()).unwrap();
+        assert_eq!(
+            label,
+            format!("//{RUST_CRATES_ROOT}/foo/1.0.0-alpha.1_build:foo")
+        );
+        assert_eq!(
+            crate::utils::version_from_segment("1.0.0-alpha.1_build"),
+            "1.0.0-alpha.1+build"
+        );
+
+        // The archive is still downloaded under the original version
+        let mut ctx = mock_ctx(vec![]);
+        ctx.checksums_map
+            .insert(ChecksumKey::of(&package), "00".repeat(32).parse().unwrap());
+        let archive = super::super::emit::emit_http_archive(&package, &ctx);
+        assert_eq!(
+            archive.urls,
+            Set::from([
+                "https://static.crates.io/crates/foo/foo-1.0.0-alpha.1+build.crate".to_owned()
+            ])
+        );
+        assert_eq!(archive.strip_prefix, "foo-1.0.0-alpha.1+build");
+    }
+
+    #[test]
+    fn test_checksums_keyed_by_source() {
+        const INTERNAL: &str = "registry+https://registry.example.com/index";
+        let public = mock_registry_package("foo", "1.0.0");
+        let mut internal = mock_registry_package("foo", "1.0.0");
+        internal.source = serde_json::from_value(serde_json::json!(INTERNAL)).unwrap();
+
+        let mut ctx = mock_ctx(vec![]);
+        ctx.checksums_map
+            .insert(ChecksumKey::of(&public), "aa".repeat(32).parse().unwrap());
+        ctx.checksums_map
+            .insert(ChecksumKey::of(&internal), "bb".repeat(32).parse().unwrap());
+
+        let emit_http_archive = super::super::emit::emit_http_archive;
+        assert_eq!(emit_http_archive(&public, &ctx).sha256, "aa".repeat(32));
+        assert_eq!(emit_http_archive(&internal, &ctx).sha256, "bb".repeat(32));
+
+        // Sources are spelled the same way in `Cargo.lock` and `cargo metadata`
+        let locked = cargo_lock::SourceId::from_url(INTERNAL).unwrap();
+        assert_eq!(locked.to_string(), INTERNAL);
+    }
+
+    #[test]
+    fn test_versioned_vendor_names() {
+        let mut ctx = mock_ctx(vec![]);
+        ctx.repo_config.versioned_vendor_names = true;
+        let mut rules = Vec::new();
+        // Build metadata is spelled as in the vendor directory
+        for version in ["1.0.0", "2.0.0+build.1"] {
+            let mut package = mock_registry_package("foo", version);
+            let mut lib = mock_target("foo", TargetKind::Lib);
+            lib.src_path = format!("/nonexistent/foo-{version}/src/lib.rs").into();
+            package.targets = vec![lib];
+            let node = mock_node(&package.id.repr, serde_json::json!([]), &[]);
+            ctx.checksums_map
+                .insert(ChecksumKey::of(&package), "00".repeat(32).parse().unwrap());
+            ctx.packages_map.insert(package.id.clone(), package);
+            rules.extend(super::super::buckify_dep_node(&node, &ctx).unwrap());
+        }
+
+        let archives: Vec<_> = rules
+            .iter()
+            .filter_map(|r| match r {
+                crate::buck::Rule::HttpArchive(a) => Some(a.name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(archives, ["foo-1.0.0-vendor", "foo-2.0.0_build.1-vendor"]);
+        let srcs: Vec<_> = rules
+            .iter()
+            .filter_map(|r| match r {
+                crate::buck::Rule::RustLibrary(l) => Some(l.srcs.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            srcs,
+            [
+                Set::from([":foo-1.0.0-vendor".to_owned()]),
+                Set::from([":foo-2.0.0_build.1-vendor".to_owned()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_artifact_deps() {
+        let manifest = indoc::indoc! {r#"
+            [package]
+            name = "app"
+
+            [dependencies]
+            serde = "1"
+            tool = { path = "../tool", artifact = "bin" }
+            helper = "1"
+
+            [build-dependencies]
+            gen = { path = "../gen", package = "codegen", artifact = ["bin:gen", "cdylib"], lib = true }
+            helper = { version = "1", artifact = "bin" }
+
+            [target.'cfg(unix)'.dev-dependencies]
+            tool = { path = "../tool", artifact = "bin" }
+            tool-lib = { path = "../tool", package = "tool" }
+        "#};
+
+        let deps = parse_artifact_deps(manifest);
+        let dep = |package: &str, kind| &deps[&(package.to_owned(), kind)];
+        assert_eq!(deps.len(), 4);
+        assert_eq!(
+            *dep("tool", DependencyKind::Normal),
+            ArtifactDep {
+                dep_name: "tool".to_owned(),
+                kinds: vec!["bin".to_owned()],
+                lib: false,
+            }
+        );
+        assert_eq!(dep("codegen", DependencyKind::Build).dep_name, "gen");
+        assert_eq!(
+            dep("codegen", DependencyKind::Build).kinds,
+            vec!["bin:gen", "cdylib"]
+        );
+        assert!(dep("codegen", DependencyKind::Build).lib);
+        // A plain dependency only links the library for its own kind
+        assert!(!dep("helper", DependencyKind::Build).lib);
+        assert!(dep("tool", DependencyKind::Development).lib);
+    }
+
+    #[test]
+    fn test_set_deps_artifact_deps() {
+        let dir = crate::testing::temp_dir("artifact-deps");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("Cargo.toml"),
+            indoc::indoc! {r#"
+                [package]
+                name = "app"
+
+                [dependencies]
+                tool = { version = "0.1", artifact = "bin" }
+                gen = { version = "0.2", artifact = "bin", lib = true }
+                helper = "1"
+
+                [build-dependencies]
+                helper = { version = "1", artifact = "bin" }
+            "#},
+        )
+        .unwrap();
+        let mut app = mock_registry_package("app", "0.1.0");
+        app.manifest_path = dir.join("Cargo.toml");
+        let tool = mock_registry_package("tool", "0.1.0");
+        let codegen = mock_registry_package("gen", "0.2.0");
+        let helper = mock_registry_package("helper", "1.0.0");
+        let node = mock_node(
+            &app.id.repr,
+            serde_json::json!([
+                mock_dep("tool", &tool.id.repr),
+                mock_dep("gen", &codegen.id.repr),
+                {
+                    "name": "helper",
+                    "pkg": helper.id,
+                    "dep_kinds": [
+                        { "kind": null, "target": null },
+                        { "kind": "build", "target": null }
+                    ]
+                }
+            ]),
+            &[],
+        );
+        let packages_map: HashMap<_, _> = [app, tool, codegen, helper]
+            .into_iter()
+            .map(|p| (p.id.clone(), p))
+            .collect();
+        let ctx = mock_ctx(vec![node.clone()]);
+
+        let mut library = RustLibrary::default();
+        set_deps(
+            &mut library,
+            &node,
+            &packages_map,
+            CargoTargetKind::Lib,
+            &ctx,
+        )
+        .unwrap();
+        let mut build_script = RustBinary::default();
+        set_deps(
+            &mut build_script,
+            &node,
+            &packages_map,
+            CargoTargetKind::CustomBuild,
+            &ctx,
+        )
+        .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // A bin-only artifact dependency adds nothing to `deps`; with `lib = true` the library
+        // is linked as usual, and so is a plain dependency that is an artifact dependency of
+        // another kind
+        assert_eq!(
+            library.deps,
+            Set::from([
+                "//third-party/rust/crates/gen/0.2.0:gen".to_owned(),
+                "//third-party/rust/crates/helper/1.0.0:helper".to_owned(),
+            ])
+        );
+        assert!(build_script.deps.is_empty());
+    }
+
+    #[test]
+    fn test_artifact_bin_env() {
+        let bins = vec![
+            ("my-tool".to_owned(), "//tools/my-tool:my-tool".to_owned()),
+            ("helper".to_owned(), "//tools/my-tool:helper".to_owned()),
+        ];
+        let env = artifact_bin_env("my-tool", &bins);
+        assert_eq!(
+            env,
+            Map::from([
+                (
+                    "CARGO_BIN_FILE_MY_TOOL".to_owned(),
+                    "$(location //tools/my-tool:my-tool)".to_owned()
+                ),
+                (
+                    "CARGO_BIN_FILE_MY_TOOL_helper".to_owned(),
+                    "$(location //tools/my-tool:helper)".to_owned()
+                ),
+                (
+                    "CARGO_BIN_FILE_MY_TOOL_my-tool".to_owned(),
+                    "$(location //tools/my-tool:my-tool)".to_owned()
+                ),
+            ])
+        );
+    }
+
+    fn mock_registry_package(name: &str, version: &str) -> Package {
+        let id = format!("{REGISTRY}#{name}@{version}");
+        let mut package = crate::testing::mock_package(name, version, &id, Some(REGISTRY));
+        package.manifest_path = format!("/nonexistent/{name}-{version}/Cargo.toml").into();
+        package
+    }
+
+    #[test]
+    fn test_set_deps_platform_gated_build_dependency() {
+        let foo = mock_registry_package("foo", "0.1.0");
+        let cc = mock_registry_package("cc", "1.0.0");
+        let log = mock_registry_package("log", "0.4.0");
+        let node = mock_node(
+            &foo.id.repr,
+            serde_json::json!([
+                {
+                    "name": "cc",
+                    "pkg": cc.id,
+                    "dep_kinds": [{ "kind": "build", "target": "cfg(windows)" }]
+                },
+                mock_dep("log", &log.id.repr)
+            ]),
+            &[],
+        );
+        let packages_map: HashMap<_, _> = [foo, cc, log]
+            .into_iter()
+            .map(|p| (p.id.clone(), p))
+            .collect();
+        let ctx = mock_ctx(vec![node.clone()]);
+
+        let mut build_script = RustBinary::default();
+        set_deps(
+            &mut build_script,
+            &node,
+            &packages_map,
+            CargoTargetKind::CustomBuild,
+            &ctx,
+        )
+        .unwrap();
+
+        // The windows-only build-dependency is gated, and normal deps stay off the build script
+        assert!(build_script.deps.is_empty());
+        assert_eq!(
+            build_script.os_deps,
+            Map::from([(
+                "windows".to_owned(),
+                Set::from(["//third-party/rust/crates/cc/1.0.0:cc".to_owned()])
+            )])
+        );
+
+        let mut library = RustLibrary::default();
+        set_deps(
+            &mut library,
+            &node,
+            &packages_map,
+            CargoTargetKind::Lib,
+            &ctx,
+        )
+        .unwrap();
+        assert!(library.os_deps.is_empty());
+        assert_eq!(
+            library.deps,
+            Set::from(["//third-party/rust/crates/log/0.4.0:log".to_owned()])
+        );
+    }
+
+    #[test]
+    fn test_workspace_alias_for_first_party_crates() {
+        let first_party = |name: &str| {
+            let mut package = mock_registry_package(name, "0.1.0");
+            package.id = PackageId {
+                repr: format!("path+file:///ws/{name}#0.1.0"),
+            };
+            package.source = None;
+            package
+        };
+        let log = mock_registry_package("log", "0.4.0");
+        let log_dep = serde_json::json!([mock_dep("log", &log.id.repr)]);
+        // `core` is a workspace member other than the root `app`, `util` a path dependency
+        // outside the workspace
+        let (app, core, util) = (first_party("app"), first_party("core"), first_party("util"));
+        let core_node = mock_node(&core.id.repr, log_dep.clone(), &[]);
+        let util_node = mock_node(&util.id.repr, log_dep, &[]);
+        let packages_map: HashMap<_, _> = [log, core.clone(), util]
+            .into_iter()
+            .map(|p| (p.id.clone(), p))
+            .collect();
+        let mut ctx = mock_ctx(vec![core_node.clone(), util_node.clone()]);
+        ctx.workspace_members = vec![app.id.clone(), core.id.clone()];
+        ctx.repo_config.inherit_workspace_deps = true;
+
+        let lib_deps = |node: &Node, ctx: &BuckalContext| {
+            let mut library = RustLibrary::default();
+            set_deps(&mut library, node, &packages_map, CargoTargetKind::Lib, ctx).unwrap();
+            library.deps
+        };
+        let alias = Set::from(["//third-party/rust:log".to_owned()]);
+        let vendored = Set::from(["//third-party/rust/crates/log/0.4.0:log".to_owned()]);
+
+        assert_eq!(lib_deps(&core_node, &ctx), alias);
+        assert_eq!(lib_deps(&util_node, &ctx), vendored);
+        ctx.repo_config.alias_path_deps = true;
+        assert_eq!(lib_deps(&util_node, &ctx), alias);
+
+        // The aliases only exist with `inherit_workspace_deps`
+        ctx.repo_config.inherit_workspace_deps = false;
+        assert_eq!(lib_deps(&core_node, &ctx), vendored);
+    }
+
+    #[test]
+    fn test_unsupported_platform_dep_is_explained() {
+        let app = mock_registry_package("explain_drop_app", "0.1.0");
+        let wasm = mock_registry_package("wasm-bindgen", "0.2.0");
+        let node = mock_node(
+            &app.id.repr,
+            serde_json::json!([{
+                "name": "wasm_bindgen",
+                "pkg": wasm.id,
+                "dep_kinds": [{ "kind": null, "target": "cfg(target_arch = \"wasm32\")" }]
+            }]),
+            &[],
+        );
+        let packages_map: HashMap<_, _> =
+            [app, wasm].into_iter().map(|p| (p.id.clone(), p)).collect();
+        let ctx = mock_ctx(vec![node.clone()]);
+
+        let mut library = RustLibrary::default();
+        set_deps(
+            &mut library,
+            &node,
+            &packages_map,
+            CargoTargetKind::Lib,
+            &ctx,
+        )
+        .unwrap();
+        assert!(library.deps.is_empty() && library.os_deps.is_empty());
+
+        let dropped: Vec<_> = crate::buckify::drops::dropped_deps()
+            .into_iter()
+            .filter(|d| d.parent == "explain_drop_app")
+            .collect();
+        assert_eq!(
+            dropped,
+            [DroppedDep {
+                parent: "explain_drop_app".to_owned(),
+                dep: "wasm-bindgen".to_owned(),
+                platform: "cfg(target_arch = \"wasm32\")".to_owned(),
+                reason: DropReason::Unsupported,
+            }]
+        );
+        let table = crate::buckify::drops::explain_drops(&dropped);
+        assert!(
+            table.lines().nth(1).unwrap().ends_with("unsupported"),
+            "{table}"
+        );
+    }
+
+    #[test]
+    fn test_proc_macro_deps_follow_exec_platform() {
+        let mut derive = mock_registry_package("derive_foo", "0.1.0");
+        derive.targets = vec![mock_target("derive_foo", TargetKind::ProcMacro)];
+        let winapi = mock_registry_package("winapi", "0.3.0");
+        let node = mock_node(
+            &derive.id.repr,
+            serde_json::json!([{
+                "name": "winapi",
+                "pkg": winapi.id,
+                "dep_kinds": [{ "kind": null, "target": "cfg(windows)" }]
+            }]),
+            &[],
+        );
+        let packages_map: HashMap<_, _> = [derive, winapi]
+            .into_iter()
+            .map(|p| (p.id.clone(), p))
+            .collect();
+        let ctx = mock_ctx(vec![node.clone()]);
+        let exec = |triple: &str, cfgs: &[&str]| ExecPlatform {
+            triple: triple.to_owned(),
+            cfgs: cfgs.iter().map(|cfg| cfg.parse().unwrap()).collect(),
+        };
+
+        let windows = exec(
+            "x86_64-pc-windows-msvc",
+            &["windows", "target_os=\"windows\""],
+        );
+        let mut library = RustLibrary::default();
+        set_deps_on(
+            &mut library,
+            &node,
+            &packages_map,
+            CargoTargetKind::Lib,
+            &ctx,
+            Some(&windows),
+        )
+        .unwrap();
+        assert!(library.os_deps.is_empty());
+        assert_eq!(
+            library.deps,
+            Set::from(["//third-party/rust/crates/winapi/0.3.0:winapi".to_owned()])
+        );
+
+        let linux = exec("x86_64-unknown-linux-gnu", &["unix", "target_os=\"linux\""]);
+        let mut library = RustLibrary::default();
+        set_deps_on(
+            &mut library,
+            &node,
+            &packages_map,
+            CargoTargetKind::Lib,
+            &ctx,
+            Some(&linux),
+        )
+        .unwrap();
+        assert!(library.deps.is_empty());
+        assert!(library.os_deps.is_empty());
+
+        // Whatever the host, the dep is never bucketed by target OS
+        let mut library = RustLibrary::default();
+        set_deps(
+            &mut library,
+            &node,
+            &packages_map,
+            CargoTargetKind::Lib,
+            &ctx,
+        )
+        .unwrap();
+        assert!(library.os_deps.is_empty());
+    }
 }
@@ -0,0 +1,136 @@
+use std::{
+    collections::BTreeSet as Set,
+    fmt::{self, Write},
+    sync::Mutex,
+};
+
+use crate::buckal_note;
+
+/// Dependencies left out of the generated rules during this run, for `--explain-drop`.
+///
+/// Rules are generated by several threads, and dependencies resolved once per target of a
+/// crate, so drops are collected here as a set rather than threaded through every emitter.
+static DROPPED: Mutex<Set<DroppedDep>> = Mutex::new(Set::new());
+
+/// Why a dependency was left out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DropReason {
+    /// Only targets platforms buckal doesn't support
+    Unsupported,
+    /// Has no Buck target to map to, e.g. an artifact dependency on a third-party crate
+    Unmapped,
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DropReason::Unsupported => "unsupported",
+            DropReason::Unmapped => "unmapped",
+        })
+    }
+}
+
+/// A dependency edge left out of the generated rules.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DroppedDep {
+    /// Package depending on it
+    pub parent: String,
+    /// Name of the dependency
+    pub dep: String,
+    /// Platform expressions gating the edge, `-` if unconditional
+    pub platform: String,
+    pub reason: DropReason,
+}
+
+pub(super) fn record_drop(dropped: DroppedDep) {
+    DROPPED
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(dropped);
+}
+
+/// Every dependency dropped so far, ordered by parent and dependency.
+pub fn dropped_deps() -> Vec<DroppedDep> {
+    DROPPED
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .iter()
+        .cloned()
+        .collect()
+}
+
+/// Render the dropped dependencies as an aligned table.
+pub fn explain_drops(dropped: &[DroppedDep]) -> String {
+    const HEADER: [&str; 4] = ["CRATE", "DEPENDENCY", "PLATFORM", "REASON"];
+    let rows: Vec<[String; 4]> = dropped
+        .iter()
+        .map(|d| {
+            [
+                d.parent.clone(),
+                d.dep.clone(),
+                d.platform.clone(),
+                d.reason.to_string(),
+            ]
+        })
+        .collect();
+    let mut widths = HEADER.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut table = String::new();
+    for row in std::iter::once(HEADER.map(str::to_owned)).chain(rows) {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        let _ = writeln!(table, "{}", line.trim_end());
+    }
+    table
+}
+
+/// Print every dependency dropped during this run, for `--explain-drop`.
+pub fn print_dropped_deps() {
+    let dropped = dropped_deps();
+    if dropped.is_empty() {
+        buckal_note!("No dependencies were dropped");
+        return;
+    }
+    buckal_note!("Dropped {} dependency(ies):", dropped.len());
+    print!("{}", explain_drops(&dropped));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_explain_drops_table() {
+        let dropped = [
+            DroppedDep {
+                parent: "app".to_owned(),
+                dep: "wasm-bindgen".to_owned(),
+                platform: "cfg(target_arch = \"wasm32\")".to_owned(),
+                reason: DropReason::Unsupported,
+            },
+            DroppedDep {
+                parent: "app".to_owned(),
+                dep: "tool".to_owned(),
+                platform: "-".to_owned(),
+                reason: DropReason::Unmapped,
+            },
+        ];
+        assert_eq!(
+            explain_drops(&dropped),
+            indoc::indoc! {r#"
+                CRATE  DEPENDENCY    PLATFORM                     REASON
+                app    wasm-bindgen  cfg(target_arch = "wasm32")  unsupported
+                app    tool          -                            unmapped
+            "#}
+        );
+    }
+}
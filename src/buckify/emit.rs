@@ -1,22 +1,29 @@
 use std::{
-    borrow::Cow,
-    collections::{BTreeSet as Set, HashMap},
+    collections::{BTreeMap as Map, BTreeSet as Set, HashMap},
+    sync::OnceLock,
 };
 
-use cargo_metadata::{Node, Package, PackageId, Target, camino::Utf8PathBuf};
+use anyhow::{Context, Result};
+use cargo_metadata::{Node, Package, PackageId, Target, TargetKind, camino::Utf8PathBuf};
+use serde::Deserialize;
+use walkdir::WalkDir;
 
 use crate::{
-    RUST_CRATES_ROOT,
     buck::{
         BuildscriptRun, CargoManifest, CargoTargetKind, FileGroup, Glob, HttpArchive, RustBinary,
         RustLibrary, RustRule, RustTest,
     },
-    context::BuckalContext,
-    platform::{buck_labels, lookup_platforms},
-    utils::{UnwrapOrExit, get_cfgs, get_target},
+    buckal_warn,
+    config::BuildscriptVisibility,
+    context::{BuckalContext, ChecksumKey},
+    platform::{buck_labels, host_constraints, lookup_platforms},
+    utils::{PackageSource, get_cfgs, get_target, platform_exists, version_segment},
 };
 
-use super::deps::{dep_kind_matches, set_deps};
+use super::deps::{
+    dep_kind_matches, dependent_oses, first_party_package_path, infer_dependent_os, set_deps,
+};
+use super::windows::is_windows_import_lib;
 
 /// Emit `rust_library` rule for the given lib target
 pub(super) fn emit_rust_library(
@@ -27,17 +34,14 @@ pub(super) fn emit_rust_library(
     manifest_dir: &Utf8PathBuf,
     buckal_name: &str,
     ctx: &BuckalContext,
-) -> RustLibrary {
+) -> Result<RustLibrary> {
     let mut rust_library = RustLibrary {
         name: buckal_name.to_owned(),
-        srcs: Set::from([get_vendor_target(package)]),
+        srcs: Set::from([get_vendor_target(package, ctx)]),
         crate_name: lib_target.name.to_owned().replace("-", "_"),
         edition: package.edition.to_string(),
-        features: Set::from_iter(node.features.iter().map(|f| f.to_string())),
-        rustc_flags: Set::from([format!(
-            "@$(location :{}-manifest[env_flags])",
-            package.name
-        )]),
+        features: node_features(node),
+        rustc_flags: manifest_env_flags(package, manifest_dir, ctx),
         visibility: Set::from(["PUBLIC".to_owned()]),
         ..Default::default()
     };
@@ -49,6 +53,14 @@ pub(super) fn emit_rust_library(
         rust_library.proc_macro = Some(true);
     }
 
+    // Sources the automatic layout misses, from `buckal.toml`
+    if let Some(mapped_srcs) = ctx.repo_config.mapped_srcs.get(package.name.as_str()) {
+        rust_library.mapped_srcs = mapped_srcs.clone();
+    }
+
+    // Map native library crate types onto Buck2 linkage
+    rust_library.preferred_linkage = preferred_linkage(lib_target).map(str::to_owned);
+
     // Set the crate root path
     rust_library.crate_root = format!(
         "vendor/{}",
@@ -62,11 +74,62 @@ pub(super) fn emit_rust_library(
         )
     );
 
-    // look up platform compatibility
-    if let Some(platforms) = lookup_platforms(&package.name) {
-        rust_library.compatible_with = buck_labels(&platforms);
+    // look up platform compatibility, narrowed to where dependents actually use the crate
+    let used_oses = if package.source.is_some() {
+        dependent_oses(&node.id, ctx)
+    } else {
+        None
+    };
+    if let Some(mut platforms) = lookup_platforms(&package.name) {
+        if let Some(used_oses) = &used_oses
+            && !platforms.oses.is_disjoint(used_oses)
+        {
+            platforms.oses.retain(|os| used_oses.contains(os));
+        }
+        platforms.compatibility().apply(
+            &mut rust_library.compatible_with,
+            &mut rust_library.target_compatible_with,
+        );
+    } else if let Some(used_oses) = used_oses {
+        // Third-party crates only ever pulled in for some OSes are not buildable elsewhere
+        match infer_dependent_os(&node.id, ctx) {
+            Some(os) => {
+                rust_library.target_compatible_with = Set::from([os.buck_label().to_owned()])
+            }
+            None => rust_library.compatible_with = buck_labels(&used_oses),
+        }
+    }
+
+    // Doctests of first-party libraries, built docs.rs-style
+    if package.source.is_none()
+        && let Some(doc_cfg) = &ctx.repo_config.doc_cfg
+    {
+        warn_unknown_features(
+            package,
+            &doc_cfg.features,
+            "`doc_cfg.features` in buckal.toml",
+        );
+        rust_library.doctests = Some(true);
+        rust_library.rustdoc_flags = Set::from_iter(
+            std::iter::once("--cfg=docsrs".to_owned()).chain(
+                doc_cfg
+                    .features
+                    .iter()
+                    .map(|f| format!("--cfg=feature=\"{f}\"")),
+            ),
+        );
+    }
+
+    if let Some(rustdoc_flags) = ctx.repo_config.rustdoc_flags.get(package.name.as_str()) {
+        rust_library
+            .rustdoc_flags
+            .extend(rustdoc_flags.iter().cloned());
     }
 
+    warn_near_duplicate_features(package, &rust_library.features);
+    warn_unknown_features(package, &rust_library.features, "the resolved features");
+    apply_package_metadata(&mut rust_library, package);
+
     // Set dependencies
     set_deps(
         &mut rust_library,
@@ -75,9 +138,68 @@ pub(super) fn emit_rust_library(
         CargoTargetKind::Lib,
         ctx,
     )
-    .unwrap_or_exit_ctx(format!("failed to set dependencies for '{}'", buckal_name));
+    .with_context(|| format!("failed to set dependencies for '{}'", buckal_name))?;
+
+    if rust_library.doctests == Some(true) {
+        set_doc_deps(&mut rust_library, node, packages_map, ctx)
+            .with_context(|| format!("failed to set doctest dependencies for '{}'", buckal_name))?;
+    }
+
+    Ok(rust_library)
+}
 
-    rust_library
+/// Doctests may use dev-dependencies: resolve the deps the way `rust_test` does and keep those the
+/// library itself doesn't have.
+fn set_doc_deps(
+    rust_library: &mut RustLibrary,
+    node: &Node,
+    packages_map: &HashMap<PackageId, Package>,
+    ctx: &BuckalContext,
+) -> anyhow::Result<()> {
+    let mut test_deps = RustTest::default();
+    set_deps(
+        &mut test_deps,
+        node,
+        packages_map,
+        CargoTargetKind::Test,
+        ctx,
+    )?;
+
+    rust_library.doc_deps = test_deps
+        .deps
+        .difference(&rust_library.deps)
+        .cloned()
+        .collect();
+    rust_library.doc_named_deps = test_deps
+        .named_deps
+        .into_iter()
+        .filter(|(alias, _)| !rust_library.named_deps.contains_key(alias))
+        .collect();
+    Ok(())
+}
+
+/// Buck2 `preferred_linkage` for library targets that only build native artifacts.
+///
+/// Targets that also produce an rlib keep the default linkage so Rust dependents are unaffected.
+fn preferred_linkage(lib_target: &Target) -> Option<&'static str> {
+    if lib_target
+        .kind
+        .iter()
+        .any(|k| matches!(k, TargetKind::Lib | TargetKind::RLib))
+    {
+        return None;
+    }
+    if lib_target
+        .kind
+        .iter()
+        .any(|k| matches!(k, TargetKind::CDyLib | TargetKind::DyLib))
+    {
+        Some("shared")
+    } else if lib_target.kind.contains(&TargetKind::StaticLib) {
+        Some("static")
+    } else {
+        None
+    }
 }
 
 /// Emit `rust_binary` rule for the given bin target
@@ -89,17 +211,14 @@ pub(super) fn emit_rust_binary(
     manifest_dir: &Utf8PathBuf,
     buckal_name: &str,
     ctx: &BuckalContext,
-) -> RustBinary {
+) -> Result<RustBinary> {
     let mut rust_binary = RustBinary {
         name: buckal_name.to_owned(),
-        srcs: Set::from([get_vendor_target(package)]),
+        srcs: Set::from([get_vendor_target(package, ctx)]),
         crate_name: bin_target.name.to_owned().replace("-", "_"),
         edition: package.edition.to_string(),
-        features: Set::from_iter(node.features.iter().map(|f| f.to_string())),
-        rustc_flags: Set::from([format!(
-            "@$(location :{}-manifest[env_flags])",
-            package.name
-        )]),
+        features: node_features(node),
+        rustc_flags: manifest_env_flags(package, manifest_dir, ctx),
         visibility: Set::from(["PUBLIC".to_owned()]),
         ..Default::default()
     };
@@ -116,6 +235,7 @@ pub(super) fn emit_rust_binary(
                 .as_str()
         )
     );
+    apply_package_metadata(&mut rust_binary, package);
 
     // Set dependencies
     set_deps(
@@ -125,13 +245,54 @@ pub(super) fn emit_rust_binary(
         CargoTargetKind::Bin,
         ctx,
     )
-    .unwrap_or_exit_ctx(format!("failed to set dependencies for '{}'", buckal_name));
+    .with_context(|| format!("failed to set dependencies for '{}'", buckal_name))?;
 
     if let Some(platforms) = lookup_platforms(&package.name) {
-        rust_binary.compatible_with = buck_labels(&platforms);
+        platforms.compatibility().apply(
+            &mut rust_binary.compatible_with,
+            &mut rust_binary.target_compatible_with,
+        );
     }
 
-    rust_binary
+    // Host tooling such as `xtask` only makes sense on the machine running the build
+    if ctx
+        .repo_config
+        .host_only_packages
+        .contains(package.name.as_str())
+    {
+        rust_binary.target_compatible_with = host_constraints(&get_cfgs());
+    }
+
+    if ctx.repo_config.default_target_platform {
+        set_default_target_platform(&mut rust_binary, package, host_platform());
+    }
+
+    Ok(rust_binary)
+}
+
+/// The host's `//platforms:<triple>`, if the repository defines it.
+fn host_platform() -> Option<&'static str> {
+    static HOST_PLATFORM: OnceLock<Option<String>> = OnceLock::new();
+    HOST_PLATFORM
+        .get_or_init(|| triple_platform(&get_target(), platform_exists))
+        .as_deref()
+}
+
+/// `//platforms:<triple>`, if `exists` finds it defined.
+pub(super) fn triple_platform(triple: &str, exists: impl Fn(&str) -> bool) -> Option<String> {
+    let platform = format!("//platforms:{triple}");
+    exists(&platform).then_some(platform)
+}
+
+/// Default a first-party binary to `host_platform`, when available.
+pub(super) fn set_default_target_platform(
+    rust_binary: &mut RustBinary,
+    package: &Package,
+    host_platform: Option<&str>,
+) {
+    if package.source.is_none() {
+        rust_binary.default_target_platform = host_platform.map(str::to_owned);
+    }
 }
 
 /// Emit `rust_test` rule for the given bin target
@@ -143,17 +304,16 @@ pub(super) fn emit_rust_test(
     manifest_dir: &Utf8PathBuf,
     buckal_name: &str,
     ctx: &BuckalContext,
-) -> RustTest {
+) -> Result<RustTest> {
+    // The whole vendored package, so that helper modules shared by integration tests
+    // (e.g. `tests/common/mod.rs`) resolve from the crate root
     let mut rust_test = RustTest {
         name: buckal_name.to_owned(),
-        srcs: Set::from([get_vendor_target(package)]),
+        srcs: Set::from([get_vendor_target(package, ctx)]),
         crate_name: test_target.name.to_owned().replace("-", "_"),
         edition: package.edition.to_string(),
-        features: Set::from_iter(node.features.iter().map(|f| f.to_string())),
-        rustc_flags: Set::from([format!(
-            "@$(location :{}-manifest[env_flags])",
-            package.name
-        )]),
+        features: node_features(node),
+        rustc_flags: manifest_env_flags(package, manifest_dir, ctx),
         visibility: Set::from(["PUBLIC".to_owned()]),
         ..Default::default()
     };
@@ -171,6 +331,12 @@ pub(super) fn emit_rust_test(
         )
     );
 
+    // Integration tests often read fixtures next to them at runtime (e.g. `tests/data`)
+    if test_target.kind.contains(&TargetKind::Test) {
+        rust_test.resources = test_resources(test_target, manifest_dir);
+    }
+    apply_package_metadata(&mut rust_test, package);
+
     // Set dependencies
     set_deps(
         &mut rust_test,
@@ -179,13 +345,34 @@ pub(super) fn emit_rust_test(
         CargoTargetKind::Test,
         ctx,
     )
-    .unwrap_or_exit_ctx(format!("failed to set dependencies for '{}'", buckal_name));
+    .with_context(|| format!("failed to set dependencies for '{}'", buckal_name))?;
 
     if let Some(platforms) = lookup_platforms(&package.name) {
-        rust_test.compatible_with = buck_labels(&platforms);
+        platforms.compatibility().apply(
+            &mut rust_test.compatible_with,
+            &mut rust_test.target_compatible_with,
+        );
     }
 
-    rust_test
+    // Runtime env and runner arguments from `buckal.toml`, e.g. to pin `--test-threads=1`
+    let test_run = ctx.repo_config.test_run(&package.name);
+    rust_test.env.extend(test_run.env);
+    rust_test.args = test_run.args;
+
+    if ctx.repo_config.test_labels {
+        let kind = if test_target.kind.contains(&TargetKind::Test) {
+            "integration"
+        } else {
+            "unit"
+        };
+        rust_test.labels.extend([
+            format!("crate:{}", package.name),
+            format!("version:{}", package.version),
+            format!("test:{kind}"),
+        ]);
+    }
+
+    Ok(rust_test)
 }
 
 /// Emit `buildscript_build` rule for the given build target
@@ -196,18 +383,15 @@ pub(super) fn emit_buildscript_build(
     packages_map: &HashMap<PackageId, Package>,
     manifest_dir: &Utf8PathBuf,
     ctx: &BuckalContext,
-) -> RustBinary {
+) -> Result<RustBinary> {
     // create the build script rule
     let mut buildscript_build = RustBinary {
-        name: format!("{}-{}", package.name, build_target.name),
-        srcs: Set::from([get_vendor_target(package)]),
+        name: buildscript_build_name(&package.name, build_target),
+        srcs: Set::from([get_vendor_target(package, ctx)]),
         crate_name: build_target.name.to_owned().replace("-", "_"),
         edition: package.edition.to_string(),
-        features: Set::from_iter(node.features.iter().map(|f| f.to_string())),
-        rustc_flags: Set::from([format!(
-            "@$(location :{}-manifest[env_flags])",
-            package.name
-        )]),
+        features: node_features(node),
+        rustc_flags: manifest_env_flags(package, manifest_dir, ctx),
         ..Default::default()
     };
 
@@ -232,12 +416,32 @@ pub(super) fn emit_buildscript_build(
         CargoTargetKind::CustomBuild,
         ctx,
     )
-    .unwrap_or_exit_ctx(format!(
-        "failed to set dependencies for '{}'",
-        &buildscript_build.name
-    ));
+    .with_context(|| {
+        format!(
+            "failed to set dependencies for '{}'",
+            &buildscript_build.name
+        )
+    })?;
+
+    // Build-dependencies are built for the exec platform, like the build script itself.
+    //
+    // Only the unconditional, unrenamed ones can move: `exec_deps` is a plain list, with no
+    // per-OS or named counterpart to `os_deps`, `named_deps` and `os_named_deps`. Those stay on
+    // the target side, selected against the target platform, so a platform-gated or renamed
+    // build-dependency is still built for the target even with `buildscript_exec_deps`.
+    if ctx.repo_config.buildscript_exec_deps {
+        buildscript_build.exec_deps = std::mem::take(&mut buildscript_build.deps);
+    }
+
+    // Build scripts always run on the exec machine, even when cross-compiling
+    buildscript_build.exec_compatible_with = host_constraints(&get_cfgs());
+
+    // Only the crate's own `buildscript_run` needs it, unless configured otherwise
+    if ctx.repo_config.buildscript_visibility == BuildscriptVisibility::Public {
+        buildscript_build.visibility = Set::from(["PUBLIC".to_owned()]);
+    }
 
-    buildscript_build
+    Ok(buildscript_build)
 }
 
 /// Emit `buildscript_run` rule for the given build target
@@ -246,18 +450,21 @@ pub(super) fn emit_buildscript_run(
     node: &Node,
     packages_map: &HashMap<PackageId, Package>,
     build_target: &Target,
+    ctx: &BuckalContext,
 ) -> BuildscriptRun {
     // create the build script run rule
-    let build_name = get_build_name(&build_target.name);
     let mut buildscript_run = BuildscriptRun {
-        name: format!("{}-{}-run", package.name, build_name),
+        name: buildscript_run_name(&package.name, build_target, ctx),
         package_name: package.name.to_string(),
-        buildscript_rule: format!(":{}-{}", package.name, build_target.name),
-        env_srcs: Set::from([format!(":{}-manifest[env_dict]", package.name)]),
-        features: Set::from_iter(node.features.iter().map(|f| f.to_string())),
+        buildscript_rule: format!(":{}", buildscript_build_name(&package.name, build_target)),
+        env_srcs: Set::from([format!(
+            ":{}[env_dict]",
+            ctx.repo_config.naming.manifest_name(&package.name)
+        )]),
+        features: node_features(node),
         version: package.version.to_string(),
-        manifest_dir: format!(":{}-vendor", package.name),
-        visibility: Set::from(["PUBLIC".to_owned()]),
+        manifest_dir: get_vendor_target(package, ctx),
+        visibility: buildscript_run_visibility(package, ctx),
         ..Default::default()
     };
 
@@ -279,15 +486,11 @@ pub(super) fn emit_buildscript_run(
             })
         {
             // Only normal dependencies with The links Manifest Key for current arch are considered
-            let custom_build_target_dep = dep_package
-                .targets
-                .iter()
-                .find(|t| t.kind.contains(&cargo_metadata::TargetKind::CustomBuild));
-            if let Some(build_target_dep) = custom_build_target_dep {
-                let build_name_dep = get_build_name(&build_target_dep.name);
+            if let Some(build_target_dep) = custom_build_target(dep_package) {
                 buildscript_run.env_srcs.insert(format!(
-                    "//{RUST_CRATES_ROOT}/{}/{}:{}-{build_name_dep}-run[metadata]",
-                    dep_package.name, dep_package.version, dep_package.name
+                    "//{}:{}[metadata]",
+                    ctx.vendor_path(dep_package),
+                    buildscript_run_name(&dep_package.name, build_target_dep, ctx)
                 ));
             } else {
                 panic!(
@@ -298,41 +501,123 @@ pub(super) fn emit_buildscript_run(
         }
     }
 
+    if let Some(configured) = ctx.repo_config.sys_crate_env.get(package.name.as_str()) {
+        buildscript_run.env = configured.clone();
+    }
+
     buildscript_run
 }
 
+/// Env read by the build scripts of common crates wrapping native libraries.
+static SYS_CRATE_ENV: phf::Map<&'static str, &'static [&'static str]> = phf::phf_map! {
+    "openssl-sys" => &[
+        "OPENSSL_DIR",
+        "OPENSSL_LIB_DIR",
+        "OPENSSL_INCLUDE_DIR",
+        "OPENSSL_STATIC",
+        "OPENSSL_NO_VENDOR",
+        "PKG_CONFIG_PATH",
+    ],
+    "libz-sys" => &["LIBZ_SYS_STATIC", "PKG_CONFIG_PATH"],
+    "libgit2-sys" => &["LIBGIT2_NO_VENDOR", "PKG_CONFIG_PATH"],
+    "libssh2-sys" => &["LIBSSH2_SYS_USE_PKG_CONFIG", "PKG_CONFIG_PATH"],
+    "curl-sys" => &["PKG_CONFIG_PATH"],
+    "libsqlite3-sys" => &["SQLITE3_LIB_DIR", "SQLITE3_INCLUDE_DIR", "SQLITE3_STATIC"],
+    "zstd-sys" => &["ZSTD_SYS_USE_PKG_CONFIG", "PKG_CONFIG_PATH"],
+    "pq-sys" => &["PQ_LIB_DIR", "PQ_LIB_STATIC", "PKG_CONFIG_PATH"],
+};
+
+/// The known vars of a `*-sys` crate to forward to its build script, with
+/// `forward_sys_crate_env`.
+pub(super) fn forwarded_sys_crate_env(
+    package_name: &str,
+    ctx: &BuckalContext,
+) -> Option<&'static [&'static str]> {
+    if !ctx.repo_config.forward_sys_crate_env {
+        return None;
+    }
+    SYS_CRATE_ENV.get(package_name).copied()
+}
+
+/// Visibility of a `buildscript_run` target.
+///
+/// Besides the crate itself, the build scripts of dependents read its `[metadata]` when it has a
+/// `links` key, and root packages read the `[rustc_flags]` of Windows import library crates.
+fn buildscript_run_visibility(package: &Package, ctx: &BuckalContext) -> Set<String> {
+    if ctx.repo_config.buildscript_visibility == BuildscriptVisibility::Public
+        || is_windows_import_lib(&package.name)
+    {
+        return Set::from(["PUBLIC".to_owned()]);
+    }
+    if package.links.is_none() {
+        return Set::new();
+    }
+
+    let mut visibility = Set::new();
+    let dependents = ctx.reverse_deps().get(&package.id).into_iter().flatten();
+    for (dependent_id, _) in dependents {
+        let Some(dependent) = ctx.packages_map.get(dependent_id) else {
+            continue;
+        };
+        if dependent.source.is_some() {
+            visibility.insert(format!("//{}:", ctx.vendor_path(dependent)));
+        } else {
+            match first_party_package_path(dependent) {
+                Ok(path) => {
+                    visibility.insert(format!("//{path}:"));
+                }
+                Err(e) => buckal_warn!(
+                    "Failed to grant {} visibility on the build script of {}: {}",
+                    dependent.name,
+                    package.name,
+                    e
+                ),
+            }
+        }
+    }
+    visibility
+}
+
 /// Patch the given `rust_library` or `rust_binary` rule to support build scripts
 pub(super) fn patch_with_buildscript(
     rust_rule: &mut dyn RustRule,
     build_target: &Target,
     package: &Package,
+    ctx: &BuckalContext,
 ) {
-    let build_name = get_build_name(&build_target.name);
+    let run_name = buildscript_run_name(&package.name, build_target, ctx);
     rust_rule.env_mut().insert(
         "OUT_DIR".to_owned(),
-        format!("$(location :{}-{build_name}-run[out_dir])", package.name).to_owned(),
-    );
-    rust_rule.rustc_flags_mut().insert(
-        format!(
-            "@$(location :{}-{build_name}-run[rustc_flags])",
-            package.name
-        )
-        .to_owned(),
+        format!("$(location :{run_name}[out_dir])"),
     );
+    rust_rule
+        .rustc_flags_mut()
+        .insert(format!("@$(location :{run_name}[rustc_flags])"));
+    // Env set through `cargo:rustc-env=KEY=VALUE`, in the same flag format as the manifest's
+    // `env_flags`
+    if ctx.repo_config.buildscript_rustc_env {
+        rust_rule
+            .rustc_flags_mut()
+            .insert(format!("@$(location :{run_name}[rustc_env])"));
+    }
 }
 
 /// Emit `http_archive` rule for the given package
 pub(super) fn emit_http_archive(package: &Package, ctx: &BuckalContext) -> HttpArchive {
-    let vendor_name = format!("{}-vendor", package.name);
+    if PackageSource::of(package) != PackageSource::CratesIo {
+        buckal_warn!(
+            "{} v{} is not from crates.io, its archive is still fetched from static.crates.io",
+            package.name,
+            package.version
+        );
+    }
+    let vendor_name = vendor_rule_name(package, ctx);
     let url = format!(
         "https://static.crates.io/crates/{}/{}-{}.crate",
         package.name, package.name, package.version
     );
     let buckal_name = format!("{}-{}", package.name, package.version);
-    let checksum = ctx
-        .checksums_map
-        .get(&format!("{}-{}", package.name, package.version))
-        .unwrap();
+    let checksum = ctx.checksums_map.get(&ChecksumKey::of(package)).unwrap();
 
     HttpArchive {
         name: vendor_name,
@@ -344,42 +629,222 @@ pub(super) fn emit_http_archive(package: &Package, ctx: &BuckalContext) -> HttpA
     }
 }
 
+/// Build outputs and VCS metadata found in first-party package directories, never sources.
+const FILEGROUP_EXCLUDES: [&str; 3] = ["target/**", ".git/**", "buck-out/**"];
+
 /// Emit `filegroup` rule for the given package
-pub(super) fn emit_filegroup(package: &Package) -> FileGroup {
-    let vendor_name = format!("{}-vendor", package.name);
+pub(super) fn emit_filegroup(package: &Package, ctx: &BuckalContext) -> FileGroup {
+    let vendor_name = vendor_rule_name(package, ctx);
+    let mut exclude: Set<String> = FILEGROUP_EXCLUDES.map(str::to_owned).into();
+    exclude.extend(ctx.repo_config.filegroup_excludes.iter().cloned());
     FileGroup {
         name: vendor_name,
         srcs: Glob {
             include: Set::from(["**/**".to_owned()]),
-            ..Default::default()
+            exclude,
         },
         out: Some("vendor".to_owned()),
     }
 }
 
 /// Emit `cargo_manifest` rule for the given package
-pub(super) fn emit_cargo_manifest(package: &Package) -> CargoManifest {
+pub(super) fn emit_cargo_manifest(package: &Package, ctx: &BuckalContext) -> CargoManifest {
     CargoManifest {
-        name: format!("{}-manifest", package.name),
-        vendor: get_vendor_target(package),
+        name: ctx.repo_config.naming.manifest_name(&package.name),
+        vendor: get_vendor_target(package, ctx),
+    }
+}
+
+/// The manifest's `env_flags` (the `CARGO_PKG_*` and `CARGO_MANIFEST_*` env of the crate) for
+/// the package's Rust rules.
+///
+/// Sources of third-party crates are only fetched at build time, so they always get the flags.
+/// First-party crates get them only if their sources mention a `CARGO_` variable.
+fn manifest_env_flags(
+    package: &Package,
+    manifest_dir: &Utf8PathBuf,
+    ctx: &BuckalContext,
+) -> Set<String> {
+    let env_flags = format!(
+        "@$(location :{}[env_flags])",
+        ctx.repo_config.naming.manifest_name(&package.name)
+    );
+    if package.source.is_some() {
+        return Set::from([env_flags]);
     }
+
+    let uses_manifest_env = WalkDir::new(manifest_dir)
+        .into_iter()
+        .filter_entry(|entry| entry.depth() == 0 || entry.file_name() != "target")
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            entry.file_type().is_file() && entry.path().extension().is_some_and(|ext| ext == "rs")
+        })
+        .any(|entry| std::fs::read_to_string(entry.path()).is_ok_and(|src| src.contains("CARGO_")));
+    if uses_manifest_env {
+        Set::from([env_flags])
+    } else {
+        Set::new()
+    }
+}
+
+/// Buck-specific needs a crate declares in its own manifest, under `[package.metadata.buckal]`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct PackageMetadata {
+    env: Map<String, String>,
+    rustc_flags: Vec<String>,
+}
+
+/// Apply the `env` and `rustc_flags` of the package's `[package.metadata.buckal]` table.
+fn apply_package_metadata(rust_rule: &mut dyn RustRule, package: &Package) {
+    let Some(table) = package.metadata.get("buckal") else {
+        return;
+    };
+    let metadata = match PackageMetadata::deserialize(table) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            buckal_warn!(
+                "Ignoring invalid [package.metadata.buckal] of {} v{}: {}",
+                package.name,
+                package.version,
+                e
+            );
+            return;
+        }
+    };
+    rust_rule.env_mut().extend(metadata.env);
+    rust_rule.rustc_flags_mut().extend(metadata.rustc_flags);
+}
+
+/// The features enabled on a node, trimmed and deduplicated in a stable order.
+fn node_features(node: &Node) -> Set<String> {
+    node.features
+        .iter()
+        .map(|f| f.trim())
+        .filter(|f| !f.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Warn about features that only differ by case or `-`/`_`.
+///
+/// Cargo treats them as distinct features, so they are kept as is rather than merged.
+fn warn_near_duplicate_features(package: &Package, features: &Set<String>) {
+    let mut normalized: HashMap<String, &str> = HashMap::new();
+    for feature in features {
+        if let Some(other) = normalized.insert(feature.to_lowercase().replace('-', "_"), feature) {
+            buckal_warn!(
+                "{} v{} enables near-duplicate features `{}` and `{}`",
+                package.name,
+                package.version,
+                other,
+                feature
+            );
+        }
+    }
+}
+
+/// Features not declared by the package, including the implicit features of optional
+/// dependencies, which Cargo reports along the declared ones.
+pub(super) fn unknown_features<'a>(package: &Package, features: &'a Set<String>) -> Vec<&'a str> {
+    features
+        .iter()
+        .filter(|feature| !package.features.contains_key(feature.as_str()))
+        .map(String::as_str)
+        .collect()
 }
 
-fn get_build_name(s: &str) -> Cow<'_, str> {
-    if let Some(stripped) = s.strip_suffix("-build") {
-        Cow::Owned(stripped.to_string())
+/// Warn about features that the package doesn't declare, e.g. typos in configured features.
+fn warn_unknown_features(package: &Package, features: &Set<String>, origin: &str) {
+    for feature in unknown_features(package, features) {
+        buckal_warn!(
+            "{} v{} has no feature `{}`, enabled by {}",
+            package.name,
+            package.version,
+            feature,
+            origin
+        );
+    }
+}
+
+/// Name of the `buildscript_build` rule of a package's build target.
+pub(super) fn buildscript_build_name(package_name: &str, build_target: &Target) -> String {
+    format!("{}-{}", package_name, build_target.name)
+}
+
+/// Name of the `buildscript_run` rule of a package's build target: `<package>-build-script-run`
+/// for the usual `build-script-build`, and `<package>-<target>-run` for any other name, `-run`
+/// being the `naming.run` suffix.
+///
+/// Every reference to the rule, from the crate itself or from other packages, goes through
+/// this function so they can't disagree.
+pub(super) fn buildscript_run_name(
+    package_name: &str,
+    build_target: &Target,
+    ctx: &BuckalContext,
+) -> String {
+    let build_name = build_target
+        .name
+        .strip_suffix("-build")
+        .filter(|name| !name.is_empty())
+        .unwrap_or(&build_target.name);
+    ctx.repo_config.naming.run_name(package_name, build_name)
+}
+
+/// The build script target of a package, if it has one.
+pub(super) fn custom_build_target(package: &Package) -> Option<&Target> {
+    package
+        .targets
+        .iter()
+        .find(|t| t.kind.contains(&TargetKind::CustomBuild))
+}
+
+/// Name of the rule providing a package's sources, qualified by its version with
+/// `versioned_vendor_names`. The version is spelled as in the vendor directory, since Buck
+/// rejects the `+` of build metadata in target names.
+fn vendor_rule_name(package: &Package, ctx: &BuckalContext) -> String {
+    let naming = &ctx.repo_config.naming;
+    if ctx.repo_config.versioned_vendor_names {
+        let version = version_segment(&package.version.to_string());
+        naming.vendor_name(&format!("{}-{}", package.name, version))
     } else {
-        Cow::Borrowed(s)
+        naming.vendor_name(&package.name)
     }
 }
 
-fn get_vendor_target(package: &Package) -> String {
-    format!(":{}-vendor", package.name)
+fn get_vendor_target(package: &Package, ctx: &BuckalContext) -> String {
+    format!(":{}", vendor_rule_name(package, ctx))
+}
+
+/// Collect the non-Rust files under an integration test's directory, relative to the package
+/// root, so they can be attached to the test as runtime resources.
+fn test_resources(test_target: &Target, manifest_dir: &Utf8PathBuf) -> Set<String> {
+    let Some(test_dir) = test_target.src_path.parent() else {
+        return Set::new();
+    };
+    // A test at the package root would otherwise pull in the whole package
+    if test_dir == manifest_dir {
+        return Set::new();
+    }
+
+    WalkDir::new(test_dir)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| Utf8PathBuf::from_path_buf(entry.into_path()).ok())
+        .filter(|path| path.extension() != Some("rs"))
+        .filter_map(|path| {
+            path.strip_prefix(manifest_dir)
+                .ok()
+                .map(|rel| normalize_path_for_buck(rel.as_str()))
+        })
+        .collect()
 }
 
 /// Normalize a path for Buck by converting backslashes to forward slashes.
 /// This normalization is critical on Windows, where paths use backslashes,
 /// as Buck2 requires forward slashes in all generated BUCK files regardless of the host platform.
-fn normalize_path_for_buck(path: &str) -> String {
+pub(super) fn normalize_path_for_buck(path: &str) -> String {
     path.replace('\\', "/")
 }
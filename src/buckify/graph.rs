@@ -0,0 +1,286 @@
+use std::collections::{BTreeMap as Map, BTreeSet as Set};
+use std::fmt::{self, Write};
+
+use anyhow::{Result, bail};
+use cargo_metadata::{DependencyKind, PackageId};
+
+use crate::context::BuckalContext;
+
+/// Fill color of first-party packages in the exported graph.
+const FIRST_PARTY_COLOR: &str = "lightblue";
+/// Fill color of third-party packages in the exported graph.
+const THIRD_PARTY_COLOR: &str = "lightgray";
+
+/// Render the resolved dependency graph as Graphviz DOT, with a node per package labeled
+/// `name@version` and an edge per dependency, whatever its kind or platform.
+pub fn dependency_graph_dot(ctx: &BuckalContext) -> String {
+    // Packages ordered by name and version, so that the output is stable
+    let mut packages: Vec<_> = ctx
+        .nodes_map
+        .keys()
+        .filter_map(|id| ctx.packages_map.get(id))
+        .collect();
+    packages.sort_by(|a, b| (&a.name, &a.version, &a.id).cmp(&(&b.name, &b.version, &b.id)));
+    let index: Map<&PackageId, usize> = packages
+        .iter()
+        .enumerate()
+        .map(|(i, package)| (&package.id, i))
+        .collect();
+
+    let mut dot = String::from("digraph dependencies {\n    node [shape=box, style=filled];\n");
+    for (i, package) in packages.iter().enumerate() {
+        let color = if package.source.is_none() {
+            FIRST_PARTY_COLOR
+        } else {
+            THIRD_PARTY_COLOR
+        };
+        let _ = writeln!(
+            dot,
+            "    n{i} [label=\"{}@{}\", fillcolor={color}];",
+            package.name, package.version
+        );
+    }
+
+    for (i, package) in packages.iter().enumerate() {
+        let deps: Set<usize> = ctx.nodes_map[&package.id]
+            .deps
+            .iter()
+            .filter_map(|dep| index.get(&dep.pkg).copied())
+            .collect();
+        for dep in deps {
+            let _ = writeln!(dot, "    n{i} -> n{dep};");
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// A cycle among first-party packages.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DependencyCycle {
+    /// Names of the packages along the cycle, starting from the smallest and ending with it again
+    pub path: Vec<String>,
+    /// Whether an edge of the cycle is only a dev-dependency
+    pub through_dev: bool,
+}
+
+impl fmt::Display for DependencyCycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.path.join(" → "))?;
+        if self.through_dev {
+            f.write_str(" (through dev-dependencies)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Find the cycles among first-party packages, whatever the kind of their edges.
+///
+/// Every cycle closed by a back edge of a depth-first search is reported once, so each strongly
+/// connected component is covered by at least one cycle.
+pub fn first_party_cycles(ctx: &BuckalContext) -> Vec<DependencyCycle> {
+    let mut packages: Vec<_> = ctx
+        .nodes_map
+        .keys()
+        .filter_map(|id| ctx.packages_map.get(id))
+        .filter(|package| package.source.is_none())
+        .collect();
+    packages.sort_by(|a, b| (&a.name, &a.version, &a.id).cmp(&(&b.name, &b.version, &b.id)));
+    let index: Map<&PackageId, usize> = packages
+        .iter()
+        .enumerate()
+        .map(|(i, package)| (&package.id, i))
+        .collect();
+    // Edges to first-party packages, flagged when only a dev-dependency
+    let edges: Vec<Map<usize, bool>> = packages
+        .iter()
+        .map(|package| {
+            let mut edges = Map::new();
+            for dep in &ctx.nodes_map[&package.id].deps {
+                let Some(&to) = index.get(&dep.pkg) else {
+                    continue;
+                };
+                let dev = dep
+                    .dep_kinds
+                    .iter()
+                    .all(|dk| dk.kind == DependencyKind::Development);
+                let only_dev = edges.entry(to).or_insert(dev);
+                *only_dev &= dev;
+            }
+            edges
+        })
+        .collect();
+
+    let mut cycles = Set::new();
+    let mut visited = vec![false; packages.len()];
+    for start in 0..packages.len() {
+        if visited[start] {
+            continue;
+        }
+        // Iterative DFS, keeping the current path and the edges left to explore along it
+        let mut path = vec![start];
+        let mut pending = vec![edges[start].iter()];
+        visited[start] = true;
+        while let Some(next) = pending.last_mut() {
+            let Some((&to, _)) = next.next() else {
+                pending.pop();
+                path.pop();
+                continue;
+            };
+            if let Some(pos) = path.iter().position(|&i| i == to) {
+                let mut cycle: Vec<usize> = path[pos..].to_vec();
+                // Start from the smallest package, so a cycle reads the same wherever it's found
+                let min = (0..cycle.len()).min_by_key(|&i| cycle[i]).unwrap_or(0);
+                cycle.rotate_left(min);
+                let through_dev =
+                    (0..cycle.len()).any(|i| edges[cycle[i]][&cycle[(i + 1) % cycle.len()]]);
+                cycle.push(cycle[0]);
+                cycles.insert(DependencyCycle {
+                    path: cycle
+                        .iter()
+                        .map(|&i| packages[i].name.to_string())
+                        .collect(),
+                    through_dev,
+                });
+            } else if !visited[to] {
+                visited[to] = true;
+                path.push(to);
+                pending.push(edges[to].iter());
+            }
+        }
+    }
+    cycles.into_iter().collect()
+}
+
+/// Fail with every first-party dependency cycle, which Buck2 can't build, for `--check-cycles`.
+pub fn check_cycles(ctx: &BuckalContext) -> Result<()> {
+    let cycles = first_party_cycles(ctx);
+    if cycles.is_empty() {
+        return Ok(());
+    }
+    let mut message = format!("found {} first-party dependency cycle(s):", cycles.len());
+    for cycle in &cycles {
+        let _ = write!(message, "\n  {cycle}");
+    }
+    bail!(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cargo_metadata::{Node, Package, camino::Utf8PathBuf};
+    use std::collections::HashMap;
+
+    use crate::testing::REGISTRY;
+
+    fn mock_package(name: &str, id: &str, source: Option<&str>) -> Package {
+        crate::testing::mock_package(name, "0.1.0", id, source)
+    }
+
+    fn mock_node(id: &PackageId, deps: &[&PackageId]) -> Node {
+        // A dependency of two kinds is a single edge
+        let deps: Vec<_> = deps
+            .iter()
+            .map(|pkg| {
+                serde_json::json!({
+                    "name": "dep",
+                    "pkg": pkg,
+                    "dep_kinds": [
+                        { "kind": null, "target": null },
+                        { "kind": "build", "target": null }
+                    ]
+                })
+            })
+            .collect();
+        crate::testing::mock_node(&id.repr, serde_json::json!(deps), &[])
+    }
+
+    #[test]
+    fn test_first_party_cycles() {
+        let app = mock_package("app", "path+file:///ws/app#0.1.0", None);
+        let core = mock_package("core", "path+file:///ws/core#0.1.0", None);
+        let util = mock_package("util", "path+file:///ws/util#0.1.0", None);
+        let serde = mock_package("serde", &format!("{REGISTRY}#serde@0.1.0"), Some(REGISTRY));
+        let mut ctx = BuckalContext {
+            nodes_map: HashMap::from([
+                (app.id.clone(), mock_node(&app.id, &[&core.id, &serde.id])),
+                (core.id.clone(), mock_node(&core.id, &[&util.id])),
+                (util.id.clone(), mock_node(&util.id, &[&serde.id])),
+                (serde.id.clone(), mock_node(&serde.id, &[])),
+            ]),
+            packages_map: [&app, &core, &util, &serde]
+                .into_iter()
+                .map(|p| (p.id.clone(), p.clone()))
+                .collect(),
+            workspace_root: Utf8PathBuf::from("/ws"),
+            workspace_members: vec![app.id.clone(), core.id.clone(), util.id.clone()],
+            ..Default::default()
+        };
+        assert!(first_party_cycles(&ctx).is_empty());
+        assert!(check_cycles(&ctx).is_ok());
+
+        // `util` depends back on `core`
+        ctx.nodes_map
+            .insert(util.id.clone(), mock_node(&util.id, &[&serde.id, &core.id]));
+        let cycles = first_party_cycles(&ctx);
+        assert_eq!(
+            cycles,
+            [DependencyCycle {
+                path: vec!["core".to_owned(), "util".to_owned(), "core".to_owned()],
+                through_dev: false,
+            }]
+        );
+        let error = check_cycles(&ctx).unwrap_err().to_string();
+        assert!(error.contains("core → util → core"), "{error}");
+
+        // A dev-dependency closes a cycle as well
+        let mut util_node = mock_node(&util.id, &[]);
+        util_node.deps = serde_json::from_value(serde_json::json!([{
+            "name": "core",
+            "pkg": core.id,
+            "dep_kinds": [{ "kind": "dev", "target": null }]
+        }]))
+        .unwrap();
+        ctx.nodes_map.insert(util.id.clone(), util_node);
+        assert_eq!(
+            first_party_cycles(&ctx)[0].to_string(),
+            "core → util → core (through dev-dependencies)"
+        );
+    }
+
+    #[test]
+    fn test_dependency_graph_dot() {
+        let app = mock_package("app", "path+file:///ws/app#0.1.0", None);
+        let serde = mock_package("serde", &format!("{REGISTRY}#serde@0.1.0"), Some(REGISTRY));
+        let itoa = mock_package("itoa", &format!("{REGISTRY}#itoa@0.1.0"), Some(REGISTRY));
+        let ctx = BuckalContext {
+            nodes_map: HashMap::from([
+                (app.id.clone(), mock_node(&app.id, &[&serde.id, &itoa.id])),
+                (serde.id.clone(), mock_node(&serde.id, &[&itoa.id])),
+                (itoa.id.clone(), mock_node(&itoa.id, &[])),
+            ]),
+            packages_map: [&app, &serde, &itoa]
+                .into_iter()
+                .map(|p| (p.id.clone(), p.clone()))
+                .collect(),
+            workspace_root: Utf8PathBuf::from("/ws"),
+            workspace_members: vec![app.id.clone()],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            dependency_graph_dot(&ctx),
+            indoc::indoc! {r#"
+                digraph dependencies {
+                    node [shape=box, style=filled];
+                    n0 [label="app@0.1.0", fillcolor=lightblue];
+                    n1 [label="itoa@0.1.0", fillcolor=lightgray];
+                    n2 [label="serde@0.1.0", fillcolor=lightgray];
+                    n0 -> n1;
+                    n0 -> n2;
+                    n2 -> n1;
+                }
+            "#}
+        );
+    }
+}
@@ -0,0 +1,325 @@
+use std::collections::{BTreeMap as Map, BTreeSet as Set};
+
+use cargo_metadata::{Package, camino::Utf8Path};
+
+use crate::buck::Rule;
+
+use super::emit::{buildscript_build_name, custom_build_target};
+use super::windows::{
+    apply_rustc_flags_patches_to_content, build_empty_list, build_select, build_string_list,
+    pretty_print_expr,
+};
+
+/// Mode constraints set by the `debug`/`release` modifiers, keyed by the Cargo profile they mirror.
+const PROFILE_MODES: [(&str, &str); 2] = [
+    ("dev", "buckal//config/mode:debug"),
+    ("release", "buckal//config/mode:release"),
+];
+
+/// Flag of the `panic = "abort"` profile setting.
+const PANIC_ABORT_FLAG: &str = "-Cpanic=abort";
+
+/// Mode constraint -> rustc flags
+type ModeFlags = Map<&'static str, Vec<String>>;
+
+/// Per-package profile overrides (`[profile.<name>.package.<spec>]`) of the workspace manifest,
+/// translated to rustc flags, along with the profile-wide panic strategy.
+#[derive(Debug, Default, PartialEq)]
+pub(super) struct ProfileOverrides {
+    /// Mode constraint -> package spec (`name`, `name@version` or `*`) -> rustc flags
+    by_mode: Map<&'static str, Map<String, Vec<String>>>,
+    /// Mode constraints whose profile sets `panic = "abort"`
+    panic_abort: Set<&'static str>,
+}
+
+impl ProfileOverrides {
+    /// Read the overrides from the workspace `Cargo.toml`; a missing or invalid manifest yields
+    /// no overrides.
+    pub(super) fn load(workspace_root: &Utf8Path) -> Self {
+        std::fs::read_to_string(workspace_root.join("Cargo.toml"))
+            .map(|manifest| Self::parse(&manifest))
+            .unwrap_or_default()
+    }
+
+    fn parse(manifest: &str) -> Self {
+        let mut overrides = Self::default();
+        let Ok(manifest) = manifest.parse::<toml::Table>() else {
+            return overrides;
+        };
+        let Some(profiles) = manifest.get("profile").and_then(|v| v.as_table()) else {
+            return overrides;
+        };
+
+        for (profile, mode) in PROFILE_MODES {
+            if profiles
+                .get(profile)
+                .and_then(|p| p.get("panic"))
+                .and_then(|p| p.as_str())
+                == Some("abort")
+            {
+                overrides.panic_abort.insert(mode);
+            }
+            let Some(packages) = profiles
+                .get(profile)
+                .and_then(|p| p.get("package"))
+                .and_then(|p| p.as_table())
+            else {
+                continue;
+            };
+            for (spec, settings) in packages {
+                let flags = settings.as_table().map(rustc_flags).unwrap_or_default();
+                if !flags.is_empty() {
+                    overrides
+                        .by_mode
+                        .entry(mode)
+                        .or_default()
+                        .insert(spec.clone(), flags);
+                }
+            }
+        }
+        overrides
+    }
+
+    /// Flags overriding each mode for `package`. A spec naming the package wins over `*`, which
+    /// only applies to non-workspace packages, as in Cargo.
+    fn flags_for(&self, package: &Package) -> Map<&'static str, &[String]> {
+        let versioned = format!("{}@{}", package.name, package.version);
+        self.by_mode
+            .iter()
+            .filter_map(|(mode, specs)| {
+                let flags = specs
+                    .get(&versioned)
+                    .or_else(|| specs.get(package.name.as_str()))
+                    .or_else(|| specs.get("*").filter(|_| package.source.is_some()))?;
+                Some((*mode, flags.as_slice()))
+            })
+            .collect()
+    }
+
+    /// Append a `select` on the mode constraint to the `rustc_flags` of every Rust rule of
+    /// `package` in the generated BUCK content.
+    ///
+    /// `panic = "abort"` only reaches the final binaries of workspace members: libraries are
+    /// linked into binaries of either strategy, which rustc rejects once the standard library
+    /// and the dependencies disagree, build scripts run on the host, and Cargo ignores the
+    /// setting for tests.
+    pub(super) fn patch_buck_content(
+        &self,
+        mut buck_content: String,
+        package: &Package,
+        buck_rules: &[Rule],
+    ) -> String {
+        let flags = self.flags_for(package);
+        let first_party = package.source.is_none();
+        if flags.is_empty() && (self.panic_abort.is_empty() || !first_party) {
+            return buck_content;
+        }
+
+        // Rules sharing the same flags are patched with the same select
+        let mut groups: Map<ModeFlags, Set<(&str, &str)>> = Map::new();
+        for rule in buck_rules {
+            let (kind, name, aborts) = match rule {
+                Rule::RustLibrary(r) => ("rust_library", &r.name, false),
+                Rule::RustBinary(r) => (
+                    "rust_binary",
+                    &r.name,
+                    first_party && !is_build_script(package, &r.name),
+                ),
+                Rule::RustTest(r) => ("rust_test", &r.name, false),
+                _ => continue,
+            };
+            let mut rule_flags: ModeFlags = flags
+                .iter()
+                .map(|(mode, flags)| (*mode, flags.to_vec()))
+                .collect();
+            if aborts {
+                for mode in &self.panic_abort {
+                    rule_flags
+                        .entry(*mode)
+                        .or_default()
+                        .push(PANIC_ABORT_FLAG.to_owned());
+                }
+            }
+            if !rule_flags.is_empty() {
+                groups.entry(rule_flags).or_default().insert((kind, name));
+            }
+        }
+
+        for (flags, targets) in groups {
+            let mut entries: Vec<_> = flags
+                .iter()
+                .map(|(mode, flags)| (*mode, build_string_list(flags)))
+                .collect();
+            entries.push(("DEFAULT", build_empty_list()));
+            let mut select_expr = String::new();
+            pretty_print_expr(&build_select(&entries), &mut select_expr, 4);
+            buck_content =
+                apply_rustc_flags_patches_to_content(&buck_content, &targets, &select_expr);
+        }
+        buck_content
+    }
+}
+
+/// Whether `rule_name` is the `buildscript_build` rule of `package`.
+fn is_build_script(package: &Package, rule_name: &str) -> bool {
+    custom_build_target(package)
+        .is_some_and(|target| buildscript_build_name(&package.name, target) == rule_name)
+}
+
+/// Translate the settings of a profile override table to rustc codegen flags.
+fn rustc_flags(settings: &toml::Table) -> Vec<String> {
+    let switch = |on: bool| if on { "yes" } else { "no" };
+    settings
+        .iter()
+        .filter_map(|(key, value)| match (key.as_str(), value) {
+            ("opt-level", toml::Value::Integer(level)) => Some(format!("-Copt-level={level}")),
+            ("opt-level", toml::Value::String(level)) => Some(format!("-Copt-level={level}")),
+            ("debug", toml::Value::Boolean(debug)) => {
+                Some(format!("-Cdebuginfo={}", if *debug { 2 } else { 0 }))
+            }
+            ("debug", toml::Value::Integer(level)) => Some(format!("-Cdebuginfo={level}")),
+            ("debug", toml::Value::String(level)) => Some(format!("-Cdebuginfo={level}")),
+            ("debug-assertions", toml::Value::Boolean(on)) => {
+                Some(format!("-Cdebug-assertions={}", switch(*on)))
+            }
+            ("overflow-checks", toml::Value::Boolean(on)) => {
+                Some(format!("-Coverflow-checks={}", switch(*on)))
+            }
+            ("codegen-units", toml::Value::Integer(units)) => {
+                Some(format!("-Ccodegen-units={units}"))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buck::{RustBinary, RustLibrary};
+    use indoc::indoc;
+
+    fn mock_package(name: &str, source: Option<&str>) -> Package {
+        let mut package =
+            crate::testing::mock_package(name, "1.0.0", &format!("{name} 1.0.0"), source);
+        package.manifest_path = format!("/tmp/{name}/Cargo.toml").into();
+        package
+    }
+
+    const MANIFEST: &str = indoc! {r#"
+        [workspace]
+        members = ["app"]
+
+        [profile.release.package.foo]
+        opt-level = 0
+        debug = true
+
+        [profile.release.package."*"]
+        opt-level = "s"
+
+        [profile.dev.package."bar@1.0.0"]
+        overflow-checks = false
+    "#};
+
+    #[test]
+    fn test_parse_profile_overrides() {
+        let overrides = ProfileOverrides::parse(MANIFEST);
+        let release = &overrides.by_mode["buckal//config/mode:release"];
+        assert_eq!(release["foo"], ["-Cdebuginfo=2", "-Copt-level=0"]);
+        assert_eq!(release["*"], ["-Copt-level=s"]);
+        let debug = &overrides.by_mode["buckal//config/mode:debug"];
+        assert_eq!(debug["bar@1.0.0"], ["-Coverflow-checks=no"]);
+
+        let registry = Some("registry+https://github.com/rust-lang/crates.io-index");
+        let bar = overrides.flags_for(&mock_package("bar", registry));
+        assert_eq!(bar["buckal//config/mode:release"], ["-Copt-level=s"]);
+        assert_eq!(bar["buckal//config/mode:debug"], ["-Coverflow-checks=no"]);
+        // `*` doesn't cover workspace members
+        assert!(overrides.flags_for(&mock_package("app", None)).is_empty());
+    }
+
+    #[test]
+    fn test_patch_release_opt_level_override() {
+        let overrides = ProfileOverrides::parse(MANIFEST);
+        let foo = mock_package("foo", None);
+        let rules = vec![Rule::RustLibrary(RustLibrary {
+            name: "foo".to_owned(),
+            ..Default::default()
+        })];
+        let content = indoc! {r#"
+            rust_library(
+                name = "foo",
+                rustc_flags = ["@$(location :foo-manifest[env_flags])"],
+            )
+        "#};
+
+        let patched = overrides.patch_buck_content(content.to_owned(), &foo, &rules);
+        assert_eq!(
+            patched,
+            indoc! {r#"
+                rust_library(
+                    name = "foo",
+                    rustc_flags = ["@$(location :foo-manifest[env_flags])"] + select({
+                        "buckal//config/mode:release": [
+                            "-Cdebuginfo=2",
+                            "-Copt-level=0",
+                        ],
+                        "DEFAULT": [],
+                    }),
+                )
+            "#}
+        );
+
+        // Packages without overrides are left untouched
+        let app = mock_package("app", None);
+        assert_eq!(
+            overrides.patch_buck_content(content.to_owned(), &app, &rules),
+            content
+        );
+    }
+
+    #[test]
+    fn test_panic_abort_only_reaches_first_party_binaries() {
+        let overrides = ProfileOverrides::parse(indoc! {r#"
+            [profile.release]
+            panic = "abort"
+        "#});
+        let app = mock_package("app", None);
+        let rules = vec![Rule::RustBinary(RustBinary {
+            name: "app".to_owned(),
+            ..Default::default()
+        })];
+        let content = "rust_binary(\n    name = \"app\",\n    rustc_flags = [],\n)\n";
+        let patched = overrides.patch_buck_content(content.to_owned(), &app, &rules);
+        assert!(
+            patched.contains("\"buckal//config/mode:release\": ["),
+            "{patched}"
+        );
+        assert!(patched.contains("\"-Cpanic=abort\""), "{patched}");
+
+        // Third-party binaries are built with the toolchain's strategy
+        let registry = Some("registry+https://github.com/rust-lang/crates.io-index");
+        let tool = mock_package("tool", registry);
+        let rules = vec![Rule::RustBinary(RustBinary {
+            name: "tool".to_owned(),
+            ..Default::default()
+        })];
+        let content = "rust_binary(\n    name = \"tool\",\n    rustc_flags = [],\n)\n";
+        assert_eq!(
+            overrides.patch_buck_content(content.to_owned(), &tool, &rules),
+            content
+        );
+
+        // Libraries too, first-party or not
+        let lib = mock_package("lib", None);
+        let rules = vec![Rule::RustLibrary(RustLibrary {
+            name: "lib".to_owned(),
+            ..Default::default()
+        })];
+        let content = "rust_library(\n    name = \"lib\",\n    rustc_flags = [],\n)\n";
+        assert_eq!(
+            overrides.patch_buck_content(content.to_owned(), &lib, &rules),
+            content
+        );
+    }
+}
@@ -0,0 +1,404 @@
+use std::collections::{BTreeMap as Map, HashMap};
+
+use anyhow::{Context, Result, bail};
+use cargo_metadata::{
+    DependencyKind, PackageId, Target, TargetKind,
+    camino::{Utf8Path, Utf8PathBuf},
+};
+use cargo_platform::Cfg;
+use serde_json::{Value, json};
+
+use crate::{
+    buck2::Buck2Command,
+    buckal_warn,
+    context::BuckalContext,
+    utils::{get_cfgs, rustc_command},
+};
+
+use super::deps::library_label;
+
+/// Kinds of targets other crates can depend on.
+const LIB_KINDS: [TargetKind; 6] = [
+    TargetKind::Lib,
+    TargetKind::RLib,
+    TargetKind::DyLib,
+    TargetKind::CDyLib,
+    TargetKind::StaticLib,
+    TargetKind::ProcMacro,
+];
+
+/// What `rust-project.json` needs from the toolchain and the build, beyond the dependency graph.
+#[derive(Debug, Default)]
+pub struct ProjectToolchain {
+    /// Sysroot of the toolchain, for rust-analyzer to find the standard library
+    pub sysroot: Option<Utf8PathBuf>,
+    /// Cfgs of the host target, e.g. `unix` or `target_os="linux"`
+    pub cfgs: Vec<String>,
+    /// Built proc-macro dylibs, by package
+    pub proc_macro_dylibs: HashMap<PackageId, Utf8PathBuf>,
+}
+
+impl ProjectToolchain {
+    /// Query rustc for the sysroot and cfgs, and build the proc-macros of the graph with buck2
+    /// so that rust-analyzer can expand them.
+    pub fn detect(ctx: &BuckalContext) -> Result<Self> {
+        let output = rustc_command()
+            .args(["--print", "sysroot"])
+            .output()
+            .context("failed to run rustc")?;
+        if !output.status.success() {
+            bail!(
+                "`rustc --print sysroot` failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let sysroot = Utf8PathBuf::from(String::from_utf8(output.stdout)?.trim());
+        if !sysroot_src(&sysroot).exists() {
+            buckal_warn!(
+                "No standard library sources in `{}`, install them with `rustup component add rust-src`",
+                sysroot
+            );
+        }
+
+        Ok(Self {
+            sysroot: Some(sysroot),
+            cfgs: get_cfgs().iter().map(cfg_to_string).collect(),
+            proc_macro_dylibs: build_proc_macros(ctx),
+        })
+    }
+}
+
+/// Where rust-analyzer expects the standard library sources in a sysroot.
+fn sysroot_src(sysroot: &Utf8Path) -> Utf8PathBuf {
+    sysroot.join("lib/rustlib/src/rust/library")
+}
+
+/// A cfg the way `rust-project.json` lists them: `name` or `key="value"`.
+fn cfg_to_string(cfg: &Cfg) -> String {
+    match cfg {
+        Cfg::Name(name) => name.to_string(),
+        Cfg::KeyPair(key, value) => format!("{key}=\"{value}\""),
+    }
+}
+
+/// Build every proc-macro library in one buck2 invocation and map them to their dylibs.
+///
+/// A failed build is reported and leaves the proc-macros unexpanded, rather than failing the
+/// sync.
+fn build_proc_macros(ctx: &BuckalContext) -> HashMap<PackageId, Utf8PathBuf> {
+    let mut labels: HashMap<String, PackageId> = HashMap::new();
+    for package in ctx
+        .nodes_map
+        .keys()
+        .filter_map(|id| ctx.packages_map.get(id))
+        .filter(|package| {
+            package
+                .targets
+                .iter()
+                .any(|t| t.kind.contains(&TargetKind::ProcMacro))
+        })
+    {
+        match library_label(package, ctx) {
+            Ok(label) => {
+                labels.insert(label, package.id.clone());
+            }
+            Err(e) => buckal_warn!("No proc-macro dylib for `{}`: {:#}", package.name, e),
+        }
+    }
+    if labels.is_empty() {
+        return HashMap::new();
+    }
+
+    let mut command = Buck2Command::new()
+        .subcommand("build")
+        .arg("--show-full-output");
+    for label in labels.keys() {
+        command = command.arg(label);
+    }
+    let output = match command.output() {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            buckal_warn!(
+                "Failed to build proc-macros for rust-project.json: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            return HashMap::new();
+        }
+        Err(e) => {
+            buckal_warn!("Failed to build proc-macros for rust-project.json: {}", e);
+            return HashMap::new();
+        }
+    };
+    parse_full_output(&String::from_utf8_lossy(&output.stdout), &labels)
+}
+
+/// Map the `<label> <path>` lines of `buck2 build --show-full-output` back to packages. buck2
+/// prints labels with their cell (`root//...`), which `labels` don't have.
+fn parse_full_output(
+    stdout: &str,
+    labels: &HashMap<String, PackageId>,
+) -> HashMap<PackageId, Utf8PathBuf> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let (label, path) = line.split_once(' ')?;
+            let label = label.find("//").map_or(label, |i| &label[i..]);
+            Some((labels.get(label)?.clone(), Utf8PathBuf::from(path.trim())))
+        })
+        .collect()
+}
+
+/// Describe the resolved graph in rust-analyzer's `rust-project.json` format, so that an IDE
+/// understands the project without invoking cargo.
+///
+/// Every library is a crate, as is every binary of a workspace member. Crates are ordered by
+/// package name and version, and depend on the libraries of their normal and dev dependencies
+/// under the name they're imported with, as the same crate covers the tests. Each crate gets the
+/// target cfgs of `toolchain` and a `feature="..."` cfg per enabled feature.
+pub fn rust_project_json(ctx: &BuckalContext, toolchain: &ProjectToolchain) -> Value {
+    let mut packages: Vec<_> = ctx
+        .nodes_map
+        .keys()
+        .filter_map(|id| ctx.packages_map.get(id))
+        .collect();
+    packages.sort_by(|a, b| (&a.name, &a.version, &a.id).cmp(&(&b.name, &b.version, &b.id)));
+
+    // Libraries first, so binaries can refer to them by index
+    let mut crates: Vec<(&PackageId, &Target)> = Vec::new();
+    for package in &packages {
+        if let Some(lib) = package
+            .targets
+            .iter()
+            .find(|t| t.kind.iter().any(|kind| LIB_KINDS.contains(kind)))
+        {
+            crates.push((&package.id, lib));
+        }
+    }
+    let libs: Map<&PackageId, (usize, String)> = crates
+        .iter()
+        .enumerate()
+        .map(|(i, (id, lib))| (*id, (i, lib.name.replace('-', "_"))))
+        .collect();
+    for package in &packages {
+        if ctx.workspace_members.contains(&package.id) {
+            crates.extend(
+                package
+                    .targets
+                    .iter()
+                    .filter(|t| t.kind.contains(&TargetKind::Bin))
+                    .map(|bin| (&package.id, bin)),
+            );
+        }
+    }
+
+    let crates: Vec<Value> = crates
+        .iter()
+        .map(|(id, target)| {
+            let package = &ctx.packages_map[*id];
+            let node = &ctx.nodes_map[*id];
+
+            let mut deps: Vec<Value> = node
+                .deps
+                .iter()
+                .filter(|dep| {
+                    dep.dep_kinds.iter().any(|dk| {
+                        matches!(
+                            dk.kind,
+                            DependencyKind::Normal | DependencyKind::Development
+                        )
+                    })
+                })
+                .filter_map(|dep| {
+                    let (index, _) = libs.get(&dep.pkg)?;
+                    Some(json!({ "crate": index, "name": dep.name }))
+                })
+                .collect();
+            // A binary uses the library of its own package
+            if !target.kind.iter().any(|kind| LIB_KINDS.contains(kind))
+                && let Some((index, name)) = libs.get(*id)
+            {
+                deps.push(json!({ "crate": index, "name": name }));
+            }
+
+            let is_proc_macro = target.kind.contains(&TargetKind::ProcMacro);
+            let mut krate = json!({
+                "display_name": target.name.replace('-', "_"),
+                "root_module": target.src_path,
+                "edition": target.edition.to_string(),
+                "deps": deps,
+                "cfg": toolchain
+                    .cfgs
+                    .iter()
+                    .cloned()
+                    .chain(
+                        node.features
+                            .iter()
+                            .map(|feature| format!("feature=\"{feature}\"")),
+                    )
+                    .collect::<Vec<_>>(),
+                "is_workspace_member": ctx.workspace_members.contains(&package.id),
+                "is_proc_macro": is_proc_macro,
+            });
+            if is_proc_macro && let Some(dylib) = toolchain.proc_macro_dylibs.get(*id) {
+                krate["proc_macro_dylib_path"] = json!(dylib);
+            }
+            krate
+        })
+        .collect();
+
+    let mut project = json!({ "crates": crates });
+    if let Some(sysroot) = &toolchain.sysroot {
+        project["sysroot"] = json!(sysroot);
+        project["sysroot_src"] = json!(sysroot_src(sysroot));
+    }
+    project
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{REGISTRY, mock_node, mock_package, mock_target};
+    use cargo_metadata::Package;
+
+    fn package(name: &str, id: &str, source: Option<&str>, kinds: &[TargetKind]) -> Package {
+        let mut package = mock_package(name, "0.1.0", id, source);
+        package.targets = kinds
+            .iter()
+            .map(|kind| {
+                let mut target = mock_target(name, kind.clone());
+                let src = if *kind == TargetKind::Bin {
+                    "main"
+                } else {
+                    "lib"
+                };
+                target.src_path = format!("/ws/{name}/src/{src}.rs").into();
+                target
+            })
+            .collect();
+        package
+    }
+
+    #[test]
+    fn test_rust_project_json() {
+        let app = package(
+            "app",
+            "path+file:///ws/app#0.1.0",
+            None,
+            &[TargetKind::Lib, TargetKind::Bin],
+        );
+        let serde = package(
+            "serde",
+            &format!("{REGISTRY}#serde@0.1.0"),
+            Some(REGISTRY),
+            &[TargetKind::Lib],
+        );
+        let cc = package(
+            "cc",
+            &format!("{REGISTRY}#cc@0.1.0"),
+            Some(REGISTRY),
+            &[TargetKind::Lib],
+        );
+        let derive = package(
+            "derive",
+            &format!("{REGISTRY}#derive@0.1.0"),
+            Some(REGISTRY),
+            &[TargetKind::ProcMacro],
+        );
+        let dep = |name: &str, pkg: &PackageId, kind: Option<&str>| json!({ "name": name, "pkg": pkg, "dep_kinds": [{ "kind": kind, "target": null }] });
+        let app_node = mock_node(
+            &app.id.repr,
+            json!([
+                dep("serde", &serde.id, None),
+                dep("cc", &cc.id, Some("build")),
+                dep("derive", &derive.id, Some("dev")),
+            ]),
+            &[],
+        );
+        let mut serde_node = mock_node(&serde.id.repr, json!([]), &[]);
+        serde_node.features = serde_json::from_value(json!(["std"])).unwrap();
+        let ctx = BuckalContext {
+            nodes_map: [
+                app_node,
+                serde_node,
+                mock_node(&cc.id.repr, json!([]), &[]),
+                mock_node(&derive.id.repr, json!([]), &[]),
+            ]
+            .into_iter()
+            .map(|node| (node.id.clone(), node))
+            .collect(),
+            packages_map: [&app, &serde, &cc, &derive]
+                .into_iter()
+                .map(|p| (p.id.clone(), p.clone()))
+                .collect(),
+            workspace_root: Utf8PathBuf::from("/ws"),
+            workspace_members: vec![app.id.clone()],
+            ..Default::default()
+        };
+        let toolchain = ProjectToolchain {
+            sysroot: Some(Utf8PathBuf::from("/rust")),
+            cfgs: vec!["unix".to_owned(), "target_os=\"linux\"".to_owned()],
+            proc_macro_dylibs: HashMap::from([(
+                derive.id.clone(),
+                Utf8PathBuf::from("/out/libderive.so"),
+            )]),
+        };
+
+        let project = rust_project_json(&ctx, &toolchain);
+        assert_eq!(project["sysroot"], json!("/rust"));
+        assert_eq!(
+            project["sysroot_src"],
+            json!("/rust/lib/rustlib/src/rust/library")
+        );
+        let crates = project["crates"].as_array().unwrap();
+        let names: Vec<_> = crates
+            .iter()
+            .map(|c| c["display_name"].as_str().unwrap())
+            .collect();
+        // A library per package, then the binaries of workspace members
+        assert_eq!(names, ["app", "cc", "derive", "serde", "app"]);
+
+        // Dev-dependencies are used by the tests of the library, build-dependencies aren't
+        assert_eq!(
+            crates[0]["deps"],
+            json!([{ "crate": 3, "name": "serde" }, { "crate": 2, "name": "derive" }])
+        );
+        assert_eq!(crates[0]["is_workspace_member"], json!(true));
+        assert_eq!(
+            crates[3]["cfg"],
+            json!(["unix", "target_os=\"linux\"", "feature=\"std\""])
+        );
+        assert_eq!(crates[3]["root_module"], json!("/ws/serde/src/lib.rs"));
+        assert_eq!(crates[2]["is_proc_macro"], json!(true));
+        assert_eq!(
+            crates[2]["proc_macro_dylib_path"],
+            json!("/out/libderive.so")
+        );
+        assert!(crates[3].get("proc_macro_dylib_path").is_none());
+        assert_eq!(
+            crates[4]["deps"],
+            json!([
+                { "crate": 3, "name": "serde" },
+                { "crate": 2, "name": "derive" },
+                { "crate": 0, "name": "app" }
+            ])
+        );
+        assert_eq!(crates[4]["root_module"], json!("/ws/app/src/main.rs"));
+    }
+
+    #[test]
+    fn test_parse_full_output() {
+        let derive = PackageId {
+            repr: format!("{REGISTRY}#derive@0.1.0"),
+        };
+        let labels = HashMap::from([(
+            "//third-party/rust/crates/derive/0.1.0:derive".to_owned(),
+            derive.clone(),
+        )]);
+        let stdout = "root//third-party/rust/crates/derive/0.1.0:derive /repo/buck-out/libderive.so\n\
+                      root//other:thing /repo/buck-out/other\n";
+        assert_eq!(
+            parse_full_output(stdout, &labels),
+            HashMap::from([(derive, Utf8PathBuf::from("/repo/buck-out/libderive.so"))])
+        );
+    }
+}
@@ -0,0 +1,129 @@
+use cargo_metadata::camino::{Utf8Path, Utf8PathBuf};
+use serde::Serialize;
+
+use crate::{
+    buckal_warn,
+    cache::{BuckalChange, ChangeType},
+    context::BuckalContext,
+};
+
+use super::actions::vendored_name_version;
+
+/// Summary of a sync, written by `--report` for reviewing dependency updates.
+#[derive(Serialize, Debug, Default)]
+pub struct BuckalReport {
+    pub added: Vec<CrateEntry>,
+    pub changed: Vec<CrateEntry>,
+    pub removed: Vec<CrateEntry>,
+    pub buck_files: Vec<Utf8PathBuf>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct CrateEntry {
+    pub name: String,
+    pub version: String,
+}
+
+impl BuckalReport {
+    pub fn new(changes: &BuckalChange, ctx: &BuckalContext, buck_files: &[Utf8PathBuf]) -> Self {
+        let mut report = BuckalReport::default();
+        for (id, change_type) in &changes.changes {
+            let entry = match ctx.packages_map.get(id) {
+                Some(package) => CrateEntry {
+                    name: package.name.to_string(),
+                    version: package.version.to_string(),
+                },
+                // Removed packages are no longer in the metadata; first-party ones were never
+                // vendored and are left out
+                None => match vendored_name_version(id) {
+                    Ok(Some((name, version))) => CrateEntry { name, version },
+                    Ok(None) => continue,
+                    Err(e) => {
+                        buckal_warn!("Leaving a removed package out of the report: {:#}", e);
+                        continue;
+                    }
+                },
+            };
+            match change_type {
+                ChangeType::Added => report.added.push(entry),
+                ChangeType::Changed => report.changed.push(entry),
+                ChangeType::Removed => report.removed.push(entry),
+            }
+        }
+
+        let workspace_root = &ctx.workspace_root;
+        report.buck_files = buck_files
+            .iter()
+            .map(|f| {
+                f.strip_prefix(workspace_root)
+                    .map(Utf8Path::to_path_buf)
+                    .unwrap_or_else(|_| f.clone())
+            })
+            .collect();
+        report.buck_files.sort();
+        report.buck_files.dedup();
+        report
+    }
+
+    pub fn write(&self, path: &Utf8Path) -> std::io::Result<()> {
+        let content = serde_json::to_string_pretty(self).map_err(std::io::Error::other)?;
+        std::fs::write(path, format!("{content}\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cargo_metadata::{Package, PackageId};
+    use std::collections::{BTreeMap, HashMap};
+
+    use crate::testing::REGISTRY;
+
+    fn mock_package(name: &str, version: &str) -> Package {
+        let id = format!("{REGISTRY}#{name}@{version}");
+        let mut package = crate::testing::mock_package(name, version, &id, Some(REGISTRY));
+        package.manifest_path = format!("/registry/{name}-{version}/Cargo.toml").into();
+        package
+    }
+
+    #[test]
+    fn test_report_lists_added_crate() {
+        let serde = mock_package("serde", "1.0.200");
+        let ctx = BuckalContext {
+            packages_map: HashMap::from([(serde.id.clone(), serde.clone())]),
+            workspace_root: Utf8PathBuf::from("/ws"),
+            ..Default::default()
+        };
+        let removed = PackageId {
+            repr: format!("{REGISTRY}#log@0.4.20"),
+        };
+        let changes = BuckalChange {
+            changes: BTreeMap::from([
+                (serde.id.clone(), ChangeType::Added),
+                (removed, ChangeType::Removed),
+            ]),
+        };
+        let buck_file = Utf8PathBuf::from("/ws/third-party/rust/crates/serde/1.0.200/BUCK");
+
+        let report = BuckalReport::new(&changes, &ctx, std::slice::from_ref(&buck_file));
+        assert_eq!(
+            report.added,
+            vec![CrateEntry {
+                name: "serde".to_owned(),
+                version: "1.0.200".to_owned(),
+            }]
+        );
+        assert!(report.changed.is_empty());
+        assert_eq!(report.removed[0].name, "log");
+        assert_eq!(
+            report.buck_files,
+            vec![Utf8PathBuf::from(
+                "third-party/rust/crates/serde/1.0.200/BUCK"
+            )]
+        );
+
+        let json: serde_json::Value = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["added"][0]["name"], "serde");
+        assert_eq!(json["added"][0]["version"], "1.0.200");
+    }
+}
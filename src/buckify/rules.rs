@@ -1,20 +1,24 @@
 use std::{collections::BTreeSet as Set, vec};
 
+use anyhow::{Context, Result, bail};
 use cargo_metadata::{Node, Package, camino::Utf8PathBuf};
 use itertools::Itertools;
 
 use crate::{
-    buck::{Load, Rule, RustRule},
+    buck::{Load, Rule, RustLibrary, RustRule},
+    buckal_note,
     context::BuckalContext,
-    utils::{UnwrapOrExit, get_vendor_dir},
+    utils::{PackageSource, explicit_max_download_size},
 };
 
+use super::deps::get_lib_targets;
 use super::emit::{
-    emit_buildscript_build, emit_buildscript_run, emit_cargo_manifest, emit_filegroup,
-    emit_http_archive, emit_rust_binary, emit_rust_library, emit_rust_test, patch_with_buildscript,
+    custom_build_target, emit_buildscript_build, emit_buildscript_run, emit_cargo_manifest,
+    emit_filegroup, emit_http_archive, emit_rust_binary, emit_rust_library, emit_rust_test,
+    normalize_path_for_buck, patch_with_buildscript,
 };
 
-pub fn buckify_dep_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
+pub fn buckify_dep_node(node: &Node, ctx: &BuckalContext) -> Result<Vec<Rule>> {
     let package = ctx.packages_map.get(&node.id).unwrap().to_owned();
 
     // emit buck rules for lib target
@@ -37,9 +41,10 @@ pub fn buckify_dep_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
     let http_archive = emit_http_archive(&package, ctx);
     buck_rules.push(Rule::HttpArchive(http_archive));
 
-    let cargo_manifest = emit_cargo_manifest(&package);
+    let cargo_manifest = emit_cargo_manifest(&package, ctx);
     buck_rules.push(Rule::CargoManifest(cargo_manifest));
 
+    // Named after the package, which dependents' labels rely on (see `third_party_label`)
     let rust_library = emit_rust_library(
         &package,
         node,
@@ -48,21 +53,16 @@ pub fn buckify_dep_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
         &manifest_dir,
         &package.name,
         ctx,
-    );
+    )?;
 
     buck_rules.push(Rule::RustLibrary(rust_library));
 
     // Check if the package has a build script
-    let custom_build_target = package
-        .targets
-        .iter()
-        .find(|t| t.kind.contains(&cargo_metadata::TargetKind::CustomBuild));
-
-    if let Some(build_target) = custom_build_target {
+    if let Some(build_target) = custom_build_target(&package) {
         // Patch the rust_library rule to support build scripts
         for rule in &mut buck_rules {
             if let Some(rust_rule) = rule.as_rust_rule_mut() {
-                patch_with_buildscript(rust_rule, build_target, &package);
+                patch_with_buildscript(rust_rule, build_target, &package, ctx);
             }
         }
 
@@ -74,20 +74,23 @@ pub fn buckify_dep_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
             &ctx.packages_map,
             &manifest_dir,
             ctx,
-        );
+        )?;
         buck_rules.push(Rule::RustBinary(buildscript_build));
 
         // create the build script run rule
-        let buildscript_run = emit_buildscript_run(&package, node, &ctx.packages_map, build_target);
+        let buildscript_run =
+            emit_buildscript_run(&package, node, &ctx.packages_map, build_target, ctx);
         buck_rules.push(Rule::BuildscriptRun(buildscript_run));
     }
 
-    buck_rules
+    Ok(buck_rules)
 }
 
-pub fn buckify_root_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
+pub fn buckify_root_node(node: &Node, ctx: &BuckalContext) -> Result<Vec<Rule>> {
     let package = ctx.packages_map.get(&node.id).unwrap().to_owned();
 
+    // Targets are taken from cargo metadata as is, never discovered from the source tree, so
+    // `autobins`, `autotests` and friends are honored exactly as cargo resolves them
     let bin_targets = package
         .targets
         .iter()
@@ -118,10 +121,10 @@ pub fn buckify_root_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
     let manifest_dir = package.manifest_path.parent().unwrap().to_owned();
 
     // emit filegroup rule for vendor
-    let filegroup = emit_filegroup(&package);
+    let filegroup = emit_filegroup(&package, ctx);
     buck_rules.push(Rule::FileGroup(filegroup));
 
-    let cargo_manifest = emit_cargo_manifest(&package);
+    let cargo_manifest = emit_cargo_manifest(&package, ctx);
     buck_rules.push(Rule::CargoManifest(cargo_manifest));
 
     // emit buck rules for bin targets
@@ -136,7 +139,7 @@ pub fn buckify_root_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
             &manifest_dir,
             &buckal_name,
             ctx,
-        );
+        )?;
 
         if lib_targets.iter().any(|l| l.name == bin_target.name) {
             // Cargo allows `main.rs` to use items from `lib.rs` via the crate's own name by default.
@@ -164,15 +167,15 @@ pub fn buckify_root_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
             &manifest_dir,
             &buckal_name,
             ctx,
-        );
+        )?;
 
         buck_rules.push(Rule::RustLibrary(rust_library));
 
         if !ctx.repo_config.ignore_tests && lib_target.test {
             // If the library target has inline tests, emit a rust_test rule for it
-            let buckal_name = format!("{}-unittest", lib_target.name);
+            let buckal_name = ctx.repo_config.naming.unittest_name(&lib_target.name);
 
-            let rust_test = emit_rust_test(
+            let mut rust_test = emit_rust_test(
                 &package,
                 node,
                 &ctx.packages_map,
@@ -180,7 +183,10 @@ pub fn buckify_root_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
                 &manifest_dir,
                 &buckal_name,
                 ctx,
-            );
+            )?;
+            if let Some(label) = &ctx.repo_config.unittest_label {
+                rust_test.labels.insert(label.clone());
+            }
 
             buck_rules.push(Rule::RustTest(rust_test));
         }
@@ -199,17 +205,17 @@ pub fn buckify_root_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
                 &manifest_dir,
                 &buckal_name,
                 ctx,
-            );
+            )?;
 
-            let package_name = package.name.replace("-", "_");
-            let mut lib_alias = false;
-            if bin_targets.iter().any(|b| b.name == package_name) {
-                lib_alias = true;
+            // Like Cargo, expose every bin of the package to integration tests
+            for bin_target in &bin_targets {
                 rust_test.env_mut().insert(
-                    format!("CARGO_BIN_EXE_{}", package_name),
-                    format!("$(location :{})", package_name),
+                    format!("CARGO_BIN_EXE_{}", bin_target.name),
+                    format!("$(location :{})", bin_target.name),
                 );
             }
+            let package_name = package.name.replace("-", "_");
+            let lib_alias = bin_targets.iter().any(|b| b.name == package_name);
             if lib_targets.iter().any(|l| l.name == package_name) {
                 if lib_alias {
                     rust_test
@@ -225,16 +231,11 @@ pub fn buckify_root_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
     }
 
     // Check if the package has a build script
-    let custom_build_target = package
-        .targets
-        .iter()
-        .find(|t| t.kind.contains(&cargo_metadata::TargetKind::CustomBuild));
-
-    if let Some(build_target) = custom_build_target {
+    if let Some(build_target) = custom_build_target(&package) {
         // Patch the rust_library and rust_binary rules to support build scripts
         for rule in &mut buck_rules {
             if let Some(rust_rule) = rule.as_rust_rule_mut() {
-                patch_with_buildscript(rust_rule, build_target, &package);
+                patch_with_buildscript(rust_rule, build_target, &package, ctx);
             }
         }
 
@@ -246,26 +247,203 @@ pub fn buckify_root_node(node: &Node, ctx: &BuckalContext) -> Vec<Rule> {
             &ctx.packages_map,
             &manifest_dir,
             ctx,
-        );
+        )?;
         buck_rules.push(Rule::RustBinary(buildscript_build));
 
         // create the build script run rule
-        let buildscript_run = emit_buildscript_run(&package, node, &ctx.packages_map, build_target);
+        let buildscript_run =
+            emit_buildscript_run(&package, node, &ctx.packages_map, build_target, ctx);
         buck_rules.push(Rule::BuildscriptRun(buildscript_run));
     }
 
-    buck_rules
+    Ok(buck_rules)
 }
 
-pub fn vendor_package(package: &Package) -> Utf8PathBuf {
+/// Create the directory of a third-party crate, returning it.
+pub fn vendor_package(package: &Package, ctx: &BuckalContext) -> Result<Utf8PathBuf> {
+    if let Some(limit) = explicit_max_download_size() {
+        check_archive_size(package, limit)?;
+    }
+
     // Vendor the package sources to `third-party/rust/crates/<package_name>/<version>`
-    let vendor_dir = get_vendor_dir(&package.name, &package.version.to_string())
-        .unwrap_or_exit_ctx("failed to get vendor directory");
+    let vendor_dir = ctx
+        .vendor_dir(package)
+        .context("failed to get vendor directory")?;
     if !vendor_dir.exists() {
-        std::fs::create_dir_all(&vendor_dir).expect("Failed to create target directory");
+        std::fs::create_dir_all(&vendor_dir)
+            .with_context(|| format!("failed to create `{}`", vendor_dir))?;
     }
 
-    vendor_dir
+    Ok(vendor_dir)
+}
+
+/// Fail if the archive the `http_archive` of a package downloads is larger than `limit` bytes,
+/// as buck2 fetches it without any cap.
+///
+/// Only registry packages are fetched as archives. Their size is that of the `.crate` cargo
+/// downloaded to its registry cache when resolving the metadata, which the `Cargo.lock`
+/// checksum pins to the same bytes.
+fn check_archive_size(package: &Package, limit: u64) -> Result<()> {
+    if !matches!(
+        PackageSource::classify(&package.id.repr),
+        PackageSource::CratesIo | PackageSource::Registry
+    ) {
+        return Ok(());
+    }
+    let Some(size) = cached_crate_archive(package)
+        .and_then(|path| path.metadata().ok())
+        .map(|metadata| metadata.len())
+    else {
+        buckal_note!(
+            "{} v{} is not in cargo's registry cache, its archive is not checked against `--max-download-size`",
+            package.name,
+            package.version
+        );
+        return Ok(());
+    };
+    if size > limit {
+        bail!(
+            "archive of `{} v{}` is {} bytes, over the maximum download size of {} bytes (see `--max-download-size`)",
+            package.name,
+            package.version,
+            size,
+            limit
+        );
+    }
+    Ok(())
+}
+
+/// The `.crate` archive cargo keeps next to the extracted sources of a registry package, i.e.
+/// `$CARGO_HOME/registry/cache/<index>/<name>-<version>.crate` for
+/// `$CARGO_HOME/registry/src/<index>/<name>-<version>`.
+fn cached_crate_archive(package: &Package) -> Option<Utf8PathBuf> {
+    let package_dir = package.manifest_path.parent()?;
+    let index_dir = package_dir.parent()?;
+    let src_dir = index_dir.parent()?;
+    let registry_dir = src_dir.parent()?;
+    if src_dir.file_name()? != "src" || registry_dir.file_name()? != "registry" {
+        return None;
+    }
+    let archive = registry_dir
+        .join("cache")
+        .join(index_dir.file_name()?)
+        .join(format!("{}-{}.crate", package.name, package.version));
+    archive.is_file().then_some(archive)
+}
+
+/// Check the `crate_root` of a library against the package it was generated from: it must be
+/// the `src_path` of the package's library target, and that file must be part of what the
+/// `vendor` rule provides, i.e. matched by the `filegroup` globs of a first-party crate, or under
+/// the `strip_prefix` of the archive of a third-party one.
+pub fn check_crate_root(
+    rust_library: &RustLibrary,
+    package: &Package,
+    vendor: &Rule,
+) -> Result<()> {
+    let lib_target = get_lib_targets(package)
+        .into_iter()
+        .next()
+        .with_context(|| format!("`{}` has no library target", package.name))?;
+    let manifest_dir = package.manifest_path.parent().unwrap();
+    let relative = lib_target
+        .src_path
+        .strip_prefix(manifest_dir)
+        .map(|path| normalize_path_for_buck(path.as_str()))
+        .with_context(|| {
+            format!(
+                "source `{}` of `{}` is outside of its package",
+                lib_target.src_path, package.name
+            )
+        })?;
+
+    if rust_library.crate_root != format!("vendor/{relative}") {
+        bail!(
+            "crate_root `{}` of `{}` does not match its library target: expected `vendor/{}`",
+            rust_library.crate_root,
+            rust_library.name,
+            relative
+        );
+    }
+    if !lib_target.src_path.is_file() {
+        bail!(
+            "crate_root `{}` of `{}` does not exist: expected `{}`",
+            rust_library.crate_root,
+            rust_library.name,
+            lib_target.src_path
+        );
+    }
+
+    match vendor {
+        Rule::FileGroup(filegroup) => {
+            let included = filegroup
+                .srcs
+                .include
+                .iter()
+                .any(|glob| glob_matches(glob, &relative));
+            if let Some(glob) = filegroup
+                .srcs
+                .exclude
+                .iter()
+                .find(|glob| glob_matches(glob, &relative))
+            {
+                bail!(
+                    "crate_root `{}` of `{}` is excluded from `{}` by `{}`",
+                    rust_library.crate_root,
+                    rust_library.name,
+                    filegroup.name,
+                    glob
+                );
+            }
+            if !included {
+                bail!(
+                    "crate_root `{}` of `{}` is not included in `{}`",
+                    rust_library.crate_root,
+                    rust_library.name,
+                    filegroup.name
+                );
+            }
+        }
+        Rule::HttpArchive(http_archive) => {
+            // Cargo extracts the archive into a directory named after its top-level one
+            let top_dir = manifest_dir.file_name().unwrap_or_default();
+            if http_archive.strip_prefix != top_dir {
+                bail!(
+                    "crate_root `{}` of `{}` is not in `{}`: strip_prefix `{}` does not match the archive's `{}`",
+                    rust_library.crate_root,
+                    rust_library.name,
+                    http_archive.name,
+                    http_archive.strip_prefix,
+                    top_dir
+                );
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Match a path against a Buck2 glob, where `**` spans directories and `*`/`?` don't.
+fn glob_matches(glob: &str, path: &str) -> bool {
+    let mut pattern = String::from("^");
+    let mut rest = glob;
+    while let Some(c) = rest.chars().next() {
+        if let Some(tail) = rest.strip_prefix("**/") {
+            pattern.push_str("(?:.*/)?");
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix("**") {
+            pattern.push_str(".*");
+            rest = tail;
+        } else {
+            match c {
+                '*' => pattern.push_str("[^/]*"),
+                '?' => pattern.push_str("[^/]"),
+                c => pattern.push_str(&regex::escape(&c.to_string())),
+            }
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    pattern.push('$');
+    regex::Regex::new(&pattern).is_ok_and(|re| re.is_match(path))
 }
 
 pub fn gen_buck_content(rules: &[Rule]) -> String {
@@ -341,52 +519,47 @@ pub fn gen_buck_content(rules: &[Rule]) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::RepoConfig;
+    use crate::buck::{FileGroup, Glob, HttpArchive, RustBinary};
+    use crate::buckify::emit::{set_default_target_platform, triple_platform, unknown_features};
+    use crate::config::{BuildscriptVisibility, DocCfg, Naming, RepoConfig, TestRun};
+    use crate::context::ChecksumKey;
+    use crate::testing::{mock_dep, mock_node, mock_target};
     use cargo_metadata::{TargetKind, camino::Utf8PathBuf};
-    use std::collections::HashMap;
-
-    fn mock_target(name: &str, kind: TargetKind) -> cargo_metadata::Target {
-        serde_json::from_value(serde_json::json!({
-            "name": name,
-            "kind": [kind],
-            "crate_types": [],
-            "required_features": [],
-            "src_path": "/tmp/dummy.rs",
-            "edition": "2021",
-            "doctest": true,
-            "test": true
-        }))
-        .unwrap()
-    }
+    use std::collections::{BTreeMap as Map, HashMap};
 
     fn mock_package(name: &str, targets: Vec<cargo_metadata::Target>) -> Package {
-        serde_json::from_value(serde_json::json!({
-            "name": name,
-            "version": "0.1.0",
-            "id": format!("{} 0.1.0 (registry+...)", name),
-            "license": null,
-            "license_file": null,
-            "description": null,
-            "source": null,
-            "dependencies": [],
-            "targets": targets,
-            "features": {},
-            "manifest_path": "/tmp/Cargo.toml",
-            "metadata": null,
-            "publish": null,
-            "authors": [],
-            "categories": [],
-            "keywords": [],
-            "readme": null,
-            "repository": null,
-            "homepage": null,
-            "documentation": null,
-            "edition": "2021",
-            "links": null,
-            "default_run": null,
-            "rust_version": null
-        }))
-        .unwrap()
+        let id = format!("path+file:///ws/{name}#0.1.0");
+        let mut package = crate::testing::mock_package(name, "0.1.0", &id, None);
+        package.targets = targets;
+        // Next to the sources of `mock_target`
+        package.manifest_path = "/nonexistent/Cargo.toml".into();
+        package
+    }
+
+    #[test]
+    fn test_archive_size_checked_against_cached_crate() {
+        let cargo_home = crate::testing::temp_dir("archive-size");
+        let registry = cargo_home.join("registry");
+        let index = "index.crates.io-1949cf8c6b5b557f";
+        let mut package = mock_package("foo", vec![]);
+        package.id.repr = format!("{}#foo@0.1.0", crate::testing::REGISTRY);
+        package.manifest_path = registry.join(format!("src/{index}/foo-0.1.0/Cargo.toml"));
+        let cache_dir = registry.join("cache").join(index);
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        std::fs::write(cache_dir.join("foo-0.1.0.crate"), [0u8; 64]).unwrap();
+
+        assert_eq!(
+            cached_crate_archive(&package),
+            Some(cache_dir.join("foo-0.1.0.crate"))
+        );
+        check_archive_size(&package, 64).unwrap();
+        let err = check_archive_size(&package, 63).unwrap_err().to_string();
+        assert!(err.contains("`foo v0.1.0` is 64 bytes"), "{err}");
+
+        // Git packages aren't fetched as archives
+        package.id.repr = "git+https://github.com/foo/foo?rev=abc#foo@0.1.0".to_owned();
+        check_archive_size(&package, 63).unwrap();
+        std::fs::remove_dir_all(&cargo_home).unwrap();
     }
 
     #[test]
@@ -398,29 +571,19 @@ mod tests {
         let mut packages_map = HashMap::new();
         packages_map.insert(pkg.id.clone(), pkg.clone());
 
-        let node: Node = serde_json::from_value(serde_json::json!({
-            "id": pkg.id.clone(),
-            "deps": [],
-            "dependencies": [],
-            "features": []
-        }))
-        .unwrap();
+        let node = mock_node(&pkg.id.repr, serde_json::json!([]), &[]);
 
         let ctx = BuckalContext {
             packages_map,
-            nodes_map: HashMap::new(),
-            root: Some(pkg.clone()),
             repo_config: RepoConfig {
                 ignore_tests: false,
                 ..RepoConfig::default()
             },
-            checksums_map: HashMap::new(),
-            workspace_root: Utf8PathBuf::from("/tmp"),
-            no_merge: false,
             workspace_members: vec![pkg.id.clone()],
+            ..Default::default()
         };
 
-        let rules = buckify_root_node(&node, &ctx);
+        let rules = buckify_root_node(&node, &ctx).unwrap();
 
         let lib_rule = rules.iter().find_map(|r| {
             if let Rule::RustLibrary(l) = r {
@@ -434,6 +597,256 @@ mod tests {
         assert_eq!(lib_rule.unwrap().name, "foo-lib");
     }
 
+    #[test]
+    fn test_integration_test_sees_every_bin() {
+        let pkg = mock_package(
+            "foo",
+            vec![
+                mock_target("foo", TargetKind::Bin),
+                mock_target("foo-cli", TargetKind::Bin),
+                mock_target("it", TargetKind::Test),
+            ],
+        );
+        let node = mock_node(&pkg.id.repr, serde_json::json!([]), &[]);
+
+        let ctx = BuckalContext {
+            packages_map: HashMap::from([(pkg.id.clone(), pkg.clone())]),
+            repo_config: RepoConfig {
+                ignore_tests: false,
+                ..RepoConfig::default()
+            },
+            workspace_members: vec![pkg.id.clone()],
+            ..Default::default()
+        };
+
+        let rules = buckify_root_node(&node, &ctx).unwrap();
+        let test_rule = rules
+            .iter()
+            .find_map(|r| match r {
+                Rule::RustTest(t) if t.name == "it" => Some(t),
+                _ => None,
+            })
+            .expect("expected an integration test rule");
+        assert_eq!(test_rule.env["CARGO_BIN_EXE_foo"], "$(location :foo)");
+        assert_eq!(
+            test_rule.env["CARGO_BIN_EXE_foo-cli"],
+            "$(location :foo-cli)"
+        );
+    }
+
+    #[test]
+    fn test_autobins_disabled_ignores_stray_bin() {
+        let dir = crate::testing::temp_dir("autobins");
+        std::fs::create_dir_all(dir.join("src/bin")).unwrap();
+        std::fs::write(
+            dir.join("Cargo.toml"),
+            "[package]\nname = \"foo\"\nautobins = false\n\n[[bin]]\nname = \"foo\"\npath = \"src/main.rs\"\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("src/main.rs"), "fn main() {}\n").unwrap();
+        // Not a target: cargo skips it with `autobins = false`
+        std::fs::write(dir.join("src/bin/extra.rs"), "fn main() {}\n").unwrap();
+
+        // The targets cargo metadata reports for that manifest
+        let mut bin = mock_target("foo", TargetKind::Bin);
+        bin.src_path = dir.join("src/main.rs");
+        let mut pkg = mock_package("foo", vec![bin]);
+        pkg.manifest_path = dir.join("Cargo.toml");
+        let node = mock_node(&pkg.id.repr, serde_json::json!([]), &[]);
+
+        let ctx = BuckalContext {
+            packages_map: HashMap::from([(pkg.id.clone(), pkg.clone())]),
+            repo_config: RepoConfig {
+                ignore_tests: false,
+                ..RepoConfig::default()
+            },
+            workspace_root: dir.clone(),
+            workspace_members: vec![pkg.id.clone()],
+            ..Default::default()
+        };
+
+        let rules = buckify_root_node(&node, &ctx).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let binaries: Vec<_> = rules
+            .iter()
+            .filter_map(|r| match r {
+                Rule::RustBinary(b) => Some((b.name.as_str(), b.crate_root.as_str())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(binaries, [("foo", "vendor/src/main.rs")]);
+        let content = gen_buck_content(&rules);
+        assert!(!content.contains("extra"), "{content}");
+    }
+
+    #[test]
+    fn test_default_target_platform_of_first_party_binary() {
+        let pkg = mock_package("foo", vec![mock_target("foo", TargetKind::Bin)]);
+        let triple = "x86_64-unknown-linux-gnu";
+        assert_eq!(triple_platform(triple, |_| false), None);
+        let host = triple_platform(triple, |platform| {
+            platform == "//platforms:x86_64-unknown-linux-gnu"
+        });
+        let host = host.as_deref();
+
+        let mut rust_binary = RustBinary::default();
+        set_default_target_platform(&mut rust_binary, &pkg, host);
+        assert_eq!(
+            rust_binary.default_target_platform.as_deref(),
+            Some("//platforms:x86_64-unknown-linux-gnu")
+        );
+        let rendered = serde_starlark::to_string(&rust_binary).unwrap();
+        assert!(
+            rendered.contains("default_target_platform = \"//platforms:x86_64-unknown-linux-gnu\""),
+            "{rendered}"
+        );
+
+        // Nothing without the host platform, or for third-party binaries
+        let mut rust_binary = RustBinary::default();
+        set_default_target_platform(&mut rust_binary, &pkg, None);
+        assert_eq!(rust_binary.default_target_platform, None);
+
+        let mut third_party = pkg.clone();
+        third_party.source = Some(cargo_metadata::Source {
+            repr: "registry+https://github.com/rust-lang/crates.io-index".to_owned(),
+        });
+        set_default_target_platform(&mut rust_binary, &third_party, host);
+        assert_eq!(rust_binary.default_target_platform, None);
+    }
+
+    #[test]
+    fn test_custom_unittest_suffix() {
+        let pkg = mock_package("foo", vec![mock_target("foo", TargetKind::Lib)]);
+        let node = mock_node(&pkg.id.repr, serde_json::json!([]), &[]);
+
+        let ctx = BuckalContext {
+            packages_map: HashMap::from([(pkg.id.clone(), pkg.clone())]),
+            repo_config: RepoConfig {
+                ignore_tests: false,
+                naming: Naming {
+                    unittest: "-tests".to_owned(),
+                    ..Naming::default()
+                },
+                ..RepoConfig::default()
+            },
+            workspace_members: vec![pkg.id.clone()],
+            ..Default::default()
+        };
+
+        let rules = buckify_root_node(&node, &ctx).unwrap();
+        let test_names: Vec<_> = rules
+            .iter()
+            .filter_map(|r| match r {
+                Rule::RustTest(t) => Some(t.name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(test_names, ["foo-tests"]);
+    }
+
+    #[test]
+    fn test_unittest_label() {
+        let pkg = mock_package(
+            "foo",
+            vec![
+                mock_target("foo", TargetKind::Lib),
+                mock_target("it", TargetKind::Test),
+            ],
+        );
+        let node = mock_node(&pkg.id.repr, serde_json::json!([]), &[]);
+
+        let ctx = BuckalContext {
+            packages_map: HashMap::from([(pkg.id.clone(), pkg.clone())]),
+            repo_config: RepoConfig {
+                ignore_tests: false,
+                unittest_label: Some("unittest".to_owned()),
+                ..RepoConfig::default()
+            },
+            workspace_members: vec![pkg.id.clone()],
+            ..Default::default()
+        };
+
+        let rules = buckify_root_node(&node, &ctx).unwrap();
+        let labels: Map<&str, &Set<String>> = rules
+            .iter()
+            .filter_map(|r| match r {
+                Rule::RustTest(t) => Some((t.name.as_str(), &t.labels)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(labels["foo-unittest"], &Set::from(["unittest".to_owned()]));
+        // Integration tests aren't inline unit tests
+        assert!(labels["it"].is_empty());
+    }
+
+    #[test]
+    fn test_first_party_filegroup_excludes() {
+        let pkg = mock_package("foo", vec![mock_target("foo", TargetKind::Lib)]);
+        let mut ctx = BuckalContext {
+            packages_map: HashMap::from([(pkg.id.clone(), pkg.clone())]),
+            workspace_members: vec![pkg.id.clone()],
+            ..Default::default()
+        };
+
+        let filegroup = emit_filegroup(&pkg, &ctx);
+        assert!(filegroup.srcs.include.contains("**/**"));
+        assert!(filegroup.srcs.exclude.contains("target/**"));
+        assert!(filegroup.srcs.exclude.contains(".git/**"));
+
+        ctx.repo_config.filegroup_excludes = Set::from(["docs/**".to_owned()]);
+        assert_eq!(
+            emit_filegroup(&pkg, &ctx).srcs.exclude,
+            Set::from([
+                ".git/**".to_owned(),
+                "buck-out/**".to_owned(),
+                "docs/**".to_owned(),
+                "target/**".to_owned(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_test_labels() {
+        let pkg = mock_package(
+            "foo",
+            vec![
+                mock_target("foo", TargetKind::Lib),
+                mock_target("it", TargetKind::Test),
+            ],
+        );
+        let node = mock_node(&pkg.id.repr, serde_json::json!([]), &[]);
+
+        let ctx = BuckalContext {
+            packages_map: HashMap::from([(pkg.id.clone(), pkg.clone())]),
+            repo_config: RepoConfig {
+                ignore_tests: false,
+                test_labels: true,
+                ..RepoConfig::default()
+            },
+            workspace_members: vec![pkg.id.clone()],
+            ..Default::default()
+        };
+
+        let rules = buckify_root_node(&node, &ctx).unwrap();
+        let labels: Map<&str, &Set<String>> = rules
+            .iter()
+            .filter_map(|r| match r {
+                Rule::RustTest(t) => Some((t.name.as_str(), &t.labels)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            labels["it"],
+            &Set::from([
+                "crate:foo".to_owned(),
+                "test:integration".to_owned(),
+                "version:0.1.0".to_owned(),
+            ])
+        );
+        assert!(labels["foo-unittest"].contains("test:unit"));
+    }
+
     #[test]
     fn test_buckify_root_node_test_deps_lib_alias() {
         let lib = mock_target("foo", TargetKind::Lib);
@@ -445,29 +858,19 @@ mod tests {
         let mut packages_map = HashMap::new();
         packages_map.insert(pkg.id.clone(), pkg.clone());
 
-        let node: Node = serde_json::from_value(serde_json::json!({
-            "id": pkg.id.clone(),
-            "deps": [],
-            "dependencies": [],
-            "features": []
-        }))
-        .unwrap();
+        let node = mock_node(&pkg.id.repr, serde_json::json!([]), &[]);
 
         let ctx = BuckalContext {
             packages_map,
-            nodes_map: HashMap::new(),
-            root: Some(pkg.clone()),
             repo_config: RepoConfig {
                 ignore_tests: false,
                 ..RepoConfig::default()
             },
-            checksums_map: HashMap::new(),
-            workspace_root: Utf8PathBuf::from("/tmp"),
-            no_merge: false,
             workspace_members: vec![pkg.id.clone()],
+            ..Default::default()
         };
 
-        let rules = buckify_root_node(&node, &ctx);
+        let rules = buckify_root_node(&node, &ctx).unwrap();
 
         let test_rule = rules.iter().find_map(|r| {
             if let Rule::RustTest(t) = r {
@@ -485,4 +888,1151 @@ mod tests {
         let test_rule = test_rule.unwrap();
         assert!(test_rule.deps.contains(":foo-lib"));
     }
+
+    fn emit_library_linkage(kinds: &[TargetKind]) -> Option<String> {
+        let mut lib = mock_target("ffi", kinds[0].clone());
+        lib.kind = kinds.to_vec();
+        let pkg = mock_package("ffi", vec![lib]);
+
+        let node = mock_node(&pkg.id.repr, serde_json::json!([]), &[]);
+
+        let ctx = BuckalContext {
+            packages_map: HashMap::from([(pkg.id.clone(), pkg.clone())]),
+            workspace_members: vec![pkg.id.clone()],
+            ..Default::default()
+        };
+
+        buckify_root_node(&node, &ctx)
+            .unwrap()
+            .into_iter()
+            .find_map(|r| match r {
+                Rule::RustLibrary(l) => Some(l),
+                _ => None,
+            })
+            .expect("expected a rust_library rule")
+            .preferred_linkage
+    }
+
+    #[test]
+    fn test_buckify_root_node_native_crate_types() {
+        assert_eq!(
+            emit_library_linkage(&[TargetKind::CDyLib]).as_deref(),
+            Some("shared")
+        );
+        assert_eq!(
+            emit_library_linkage(&[TargetKind::StaticLib]).as_deref(),
+            Some("static")
+        );
+        // Crates that also build an rlib keep the default linkage for Rust dependents
+        assert_eq!(
+            emit_library_linkage(&[TargetKind::CDyLib, TargetKind::RLib]),
+            None
+        );
+        assert_eq!(emit_library_linkage(&[TargetKind::Lib]), None);
+    }
+
+    #[test]
+    fn test_buckify_root_node_buildscript_exec_compatible_with() {
+        let lib = mock_target("foo", TargetKind::Lib);
+        let build = mock_target("build-script-build", TargetKind::CustomBuild);
+        let pkg = mock_package("foo", vec![lib, build]);
+
+        let node = mock_node(&pkg.id.repr, serde_json::json!([]), &[]);
+
+        let ctx = BuckalContext {
+            packages_map: HashMap::from([(pkg.id.clone(), pkg.clone())]),
+            workspace_members: vec![pkg.id.clone()],
+            ..Default::default()
+        };
+
+        let rules = buckify_root_node(&node, &ctx).unwrap();
+        let build_rule = rules
+            .iter()
+            .find_map(|r| match r {
+                Rule::RustBinary(b) if b.name == "foo-build-script-build" => Some(b),
+                _ => None,
+            })
+            .expect("expected a build script rule");
+
+        // Pinned to the host, which runs the build script even when cross-compiling
+        assert_eq!(
+            build_rule.exec_compatible_with,
+            crate::platform::host_constraints(&crate::utils::get_cfgs())
+        );
+        assert!(
+            build_rule
+                .exec_compatible_with
+                .iter()
+                .any(|c| c.starts_with("prelude//os/constraints:"))
+        );
+    }
+
+    #[test]
+    fn test_buckify_root_node_test_resources() {
+        let root = crate::testing::temp_dir("resources");
+        std::fs::create_dir_all(root.join("tests/data")).unwrap();
+        std::fs::write(root.join("tests/it.rs"), "").unwrap();
+        std::fs::write(root.join("tests/data/fixture.json"), "{}").unwrap();
+
+        let mut lib = mock_target("foo", TargetKind::Lib);
+        lib.src_path = root.join("src/lib.rs");
+        let mut test = mock_target("it", TargetKind::Test);
+        test.src_path = root.join("tests/it.rs");
+        let mut pkg = mock_package("foo", vec![lib, test]);
+        pkg.manifest_path = root.join("Cargo.toml");
+
+        let node = mock_node(&pkg.id.repr, serde_json::json!([]), &[]);
+
+        let ctx = BuckalContext {
+            packages_map: HashMap::from([(pkg.id.clone(), pkg.clone())]),
+            repo_config: RepoConfig {
+                ignore_tests: false,
+                ..RepoConfig::default()
+            },
+            workspace_root: root.clone(),
+            workspace_members: vec![pkg.id.clone()],
+            ..Default::default()
+        };
+
+        let rules = buckify_root_node(&node, &ctx).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let test_rule = rules
+            .iter()
+            .find_map(|r| match r {
+                Rule::RustTest(t) if t.name == "it" => Some(t),
+                _ => None,
+            })
+            .expect("expected an integration test rule");
+        assert_eq!(
+            test_rule.resources,
+            Set::from(["tests/data/fixture.json".to_owned()])
+        );
+    }
+
+    #[test]
+    fn test_integration_test_sees_helper_modules() {
+        let root = crate::testing::temp_dir("test-helpers");
+        std::fs::create_dir_all(root.join("tests/common")).unwrap();
+        std::fs::write(root.join("tests/it.rs"), "mod common;\n").unwrap();
+        std::fs::write(root.join("tests/common/mod.rs"), "pub fn setup() {}\n").unwrap();
+
+        let mut test = mock_target("it", TargetKind::Test);
+        test.src_path = root.join("tests/it.rs");
+        let mut pkg = mock_package("foo", vec![test]);
+        pkg.manifest_path = root.join("Cargo.toml");
+
+        let node = mock_node(&pkg.id.repr, serde_json::json!([]), &[]);
+
+        let ctx = BuckalContext {
+            packages_map: HashMap::from([(pkg.id.clone(), pkg.clone())]),
+            repo_config: RepoConfig {
+                ignore_tests: false,
+                ..RepoConfig::default()
+            },
+            workspace_root: root.clone(),
+            workspace_members: vec![pkg.id.clone()],
+            ..Default::default()
+        };
+
+        let rules = buckify_root_node(&node, &ctx).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let test_rule = rules
+            .iter()
+            .find_map(|r| match r {
+                Rule::RustTest(t) if t.name == "it" => Some(t),
+                _ => None,
+            })
+            .expect("expected an integration test rule");
+        assert_eq!(test_rule.crate_root, "vendor/tests/it.rs");
+        // `mod common;` resolves next to the crate root, inside the vendored package
+        assert_eq!(test_rule.srcs, Set::from([":foo-vendor".to_owned()]));
+        let vendor = rules
+            .iter()
+            .find_map(|r| match r {
+                Rule::FileGroup(f) if f.name == "foo-vendor" => Some(f),
+                _ => None,
+            })
+            .expect("expected the vendor filegroup");
+        assert_eq!(vendor.out.as_deref(), Some("vendor"));
+        assert!(vendor.srcs.include.contains("**/**"));
+        assert!(
+            !vendor
+                .srcs
+                .exclude
+                .iter()
+                .any(|pattern| pattern.starts_with("tests"))
+        );
+        // Helper modules are compiled, not read at runtime
+        assert!(test_rule.resources.is_empty());
+    }
+
+    #[test]
+    fn test_buckify_root_node_test_env_and_args() {
+        let lib = mock_target("foo", TargetKind::Lib);
+        let pkg = mock_package("foo", vec![lib]);
+
+        let node = mock_node(&pkg.id.repr, serde_json::json!([]), &[]);
+
+        let ctx = BuckalContext {
+            packages_map: HashMap::from([(pkg.id.clone(), pkg.clone())]),
+            repo_config: RepoConfig {
+                ignore_tests: false,
+                test_env: Map::from([("RUST_BACKTRACE".to_owned(), "1".to_owned())]),
+                test_args: vec!["--test-threads=1".to_owned()],
+                test_overrides: Map::from([(
+                    "foo".to_owned(),
+                    TestRun {
+                        env: Map::from([("FOO_FIXTURES".to_owned(), "fixtures".to_owned())]),
+                        args: vec!["--nocapture".to_owned()],
+                    },
+                )]),
+                ..RepoConfig::default()
+            },
+            workspace_root: Utf8PathBuf::from("/nonexistent"),
+            workspace_members: vec![pkg.id.clone()],
+            ..Default::default()
+        };
+
+        let rules = buckify_root_node(&node, &ctx).unwrap();
+        let test_rule = rules
+            .iter()
+            .find_map(|r| match r {
+                Rule::RustTest(t) => Some(t),
+                _ => None,
+            })
+            .expect("expected a unit test rule");
+        assert_eq!(
+            test_rule.env.get("RUST_BACKTRACE").map(String::as_str),
+            Some("1")
+        );
+        assert_eq!(
+            test_rule.env.get("FOO_FIXTURES").map(String::as_str),
+            Some("fixtures")
+        );
+        assert_eq!(test_rule.args, ["--test-threads=1", "--nocapture"]);
+    }
+
+    fn emit_library_with_buildscript(repo_config: RepoConfig) -> crate::buck::RustLibrary {
+        let lib = mock_target("foo", TargetKind::Lib);
+        let build = mock_target("build-script-build", TargetKind::CustomBuild);
+        let pkg = mock_package("foo", vec![lib, build]);
+
+        let node = mock_node(&pkg.id.repr, serde_json::json!([]), &[]);
+
+        let ctx = BuckalContext {
+            packages_map: HashMap::from([(pkg.id.clone(), pkg.clone())]),
+            repo_config,
+            workspace_members: vec![pkg.id.clone()],
+            ..Default::default()
+        };
+
+        buckify_root_node(&node, &ctx)
+            .unwrap()
+            .into_iter()
+            .find_map(|r| match r {
+                Rule::RustLibrary(l) => Some(l),
+                _ => None,
+            })
+            .expect("expected a rust_library rule")
+    }
+
+    #[test]
+    fn test_buckify_root_node_buildscript_rustc_env() {
+        // A build script printing `cargo:rustc-env=FOO=bar` exposes it via the `[rustc_env]`
+        // subtarget, which the library must consume to see `env!("FOO")`
+        let rustc_env = "@$(location :foo-build-script-run[rustc_env])";
+
+        let lib = emit_library_with_buildscript(RepoConfig {
+            buildscript_rustc_env: true,
+            ..RepoConfig::default()
+        });
+        assert!(lib.rustc_flags.contains(rustc_env));
+        assert!(
+            lib.rustc_flags
+                .contains("@$(location :foo-build-script-run[rustc_flags])")
+        );
+        assert_eq!(
+            lib.env.get("OUT_DIR").map(String::as_str),
+            Some("$(location :foo-build-script-run[out_dir])")
+        );
+
+        let lib = emit_library_with_buildscript(RepoConfig::default());
+        assert!(!lib.rustc_flags.contains(rustc_env));
+    }
+
+    #[test]
+    fn test_buckify_root_node_doc_cfg() {
+        let lib = emit_library_with_buildscript(RepoConfig {
+            doc_cfg: Some(DocCfg {
+                features: Set::from(["nightly".to_owned()]),
+            }),
+            ..RepoConfig::default()
+        });
+        assert_eq!(lib.doctests, Some(true));
+        assert_eq!(
+            lib.rustdoc_flags,
+            Set::from([
+                "--cfg=docsrs".to_owned(),
+                "--cfg=feature=\"nightly\"".to_owned()
+            ])
+        );
+        assert!(!lib.rustc_flags.contains("--cfg=docsrs"));
+
+        let lib = emit_library_with_buildscript(RepoConfig::default());
+        assert_eq!(lib.doctests, None);
+        assert!(lib.rustdoc_flags.is_empty());
+    }
+
+    #[test]
+    fn test_configured_rustdoc_flags() {
+        let lib = emit_library_with_buildscript(RepoConfig {
+            rustdoc_flags: Map::from([(
+                "foo".to_owned(),
+                vec![
+                    "--cfg=docsrs".to_owned(),
+                    "--html-in-header=katex.html".to_owned(),
+                ],
+            )]),
+            ..RepoConfig::default()
+        });
+        assert_eq!(
+            lib.rustdoc_flags,
+            Set::from([
+                "--cfg=docsrs".to_owned(),
+                "--html-in-header=katex.html".to_owned()
+            ])
+        );
+        assert!(!lib.rustc_flags.iter().any(|f| f.contains("docsrs")));
+        assert_eq!(lib.doctests, None);
+    }
+
+    #[test]
+    fn test_host_only_package_binary() {
+        let pkg = mock_package("xtask", vec![mock_target("xtask", TargetKind::Bin)]);
+        let node = mock_node(&pkg.id.repr, serde_json::json!([]), &[]);
+        let emit_bin = |repo_config: RepoConfig| {
+            let ctx = BuckalContext {
+                packages_map: HashMap::from([(pkg.id.clone(), pkg.clone())]),
+                repo_config,
+                workspace_root: Utf8PathBuf::from("/nonexistent"),
+                workspace_members: vec![pkg.id.clone()],
+                ..Default::default()
+            };
+            buckify_root_node(&node, &ctx)
+                .unwrap()
+                .into_iter()
+                .find_map(|r| match r {
+                    Rule::RustBinary(b) => Some(b),
+                    _ => None,
+                })
+                .expect("expected a rust_binary rule")
+        };
+
+        let host_only = emit_bin(RepoConfig {
+            host_only_packages: Set::from(["xtask".to_owned()]),
+            ..RepoConfig::default()
+        });
+        assert_eq!(
+            host_only.target_compatible_with,
+            crate::platform::host_constraints(&crate::utils::get_cfgs())
+        );
+        assert!(
+            host_only
+                .target_compatible_with
+                .iter()
+                .any(|c| c.starts_with("prelude//os/constraints:"))
+        );
+
+        let bin = emit_bin(RepoConfig::default());
+        assert!(bin.target_compatible_with.is_empty());
+    }
+
+    #[test]
+    fn test_package_metadata_env_and_rustc_flags() {
+        let mut pkg = mock_package("foo", vec![mock_target("foo", TargetKind::Lib)]);
+        pkg.metadata = serde_json::json!({
+            "buckal": {
+                "env": { "FOO_DATA_DIR": "data" },
+                "rustc_flags": ["--cfg=foo_buck"]
+            },
+            "docs": { "rs": { "all-features": true } }
+        });
+        let node = mock_node(&pkg.id.repr, serde_json::json!([]), &[]);
+        let ctx = BuckalContext {
+            packages_map: HashMap::from([(pkg.id.clone(), pkg.clone())]),
+            workspace_root: Utf8PathBuf::from("/nonexistent"),
+            workspace_members: vec![pkg.id.clone()],
+            ..Default::default()
+        };
+
+        let lib = buckify_root_node(&node, &ctx)
+            .unwrap()
+            .into_iter()
+            .find_map(|r| match r {
+                Rule::RustLibrary(l) => Some(l),
+                _ => None,
+            })
+            .expect("expected a rust_library rule");
+        assert_eq!(
+            lib.env.get("FOO_DATA_DIR").map(String::as_str),
+            Some("data")
+        );
+        assert!(lib.rustc_flags.contains("--cfg=foo_buck"));
+    }
+
+    #[test]
+    fn test_unknown_configured_feature() {
+        let mut pkg = mock_package("foo", vec![mock_target("foo", TargetKind::Lib)]);
+        pkg.features = Map::from([
+            ("nightly".to_owned(), vec![]),
+            ("serde".to_owned(), vec!["dep:serde".to_owned()]),
+        ]);
+
+        let configured = Set::from(["nightyl".to_owned(), "serde".to_owned()]);
+        assert_eq!(unknown_features(&pkg, &configured), ["nightyl"]);
+        let configured = Set::from(["nightly".to_owned()]);
+        assert!(unknown_features(&pkg, &configured).is_empty());
+    }
+
+    #[test]
+    fn test_buckify_root_node_doctest_dev_deps() {
+        let registry: cargo_metadata::Source = serde_json::from_value(serde_json::json!(
+            "registry+https://github.com/rust-lang/crates.io-index"
+        ))
+        .unwrap();
+        let mut serde = mock_package("serde", vec![]);
+        serde.source = Some(registry.clone());
+        let mut pretty = mock_package("pretty_assertions", vec![]);
+        pretty.source = Some(registry);
+        let pkg = mock_package("foo", vec![mock_target("foo", TargetKind::Lib)]);
+
+        let node = mock_node(
+            &pkg.id.repr,
+            serde_json::json!([
+                mock_dep("serde", &serde.id.repr),
+                {
+                    "name": "pretty_assertions",
+                    "pkg": pretty.id.clone(),
+                    "dep_kinds": [{ "kind": "dev", "target": null }]
+                }
+            ]),
+            &[],
+        );
+
+        let ctx = BuckalContext {
+            packages_map: HashMap::from([
+                (pkg.id.clone(), pkg.clone()),
+                (serde.id.clone(), serde),
+                (pretty.id.clone(), pretty),
+            ]),
+            repo_config: RepoConfig {
+                doc_cfg: Some(DocCfg::default()),
+                ..RepoConfig::default()
+            },
+            workspace_root: Utf8PathBuf::from("/nonexistent"),
+            workspace_members: vec![pkg.id.clone()],
+            ..Default::default()
+        };
+
+        let lib = buckify_root_node(&node, &ctx)
+            .unwrap()
+            .into_iter()
+            .find_map(|r| match r {
+                Rule::RustLibrary(l) => Some(l),
+                _ => None,
+            })
+            .expect("expected a rust_library rule");
+        let serde_label = format!("//{}/serde/0.1.0:serde", crate::RUST_CRATES_ROOT);
+        let pretty_label = format!(
+            "//{}/pretty_assertions/0.1.0:pretty_assertions",
+            crate::RUST_CRATES_ROOT
+        );
+        assert_eq!(lib.deps, Set::from([serde_label]));
+        assert_eq!(lib.doc_deps, Set::from([pretty_label]));
+    }
+
+    fn buildscript_rules(
+        buildscript_visibility: BuildscriptVisibility,
+    ) -> (crate::buck::RustBinary, crate::buck::BuildscriptRun) {
+        let registry: cargo_metadata::Source = serde_json::from_value(serde_json::json!(
+            "registry+https://github.com/rust-lang/crates.io-index"
+        ))
+        .unwrap();
+        let mut sys = mock_package(
+            "foo-sys",
+            vec![
+                mock_target("foo_sys", TargetKind::Lib),
+                mock_target("build-script-build", TargetKind::CustomBuild),
+            ],
+        );
+        sys.source = Some(registry.clone());
+        sys.links = Some("foo".to_owned());
+        let mut dependent = mock_package("bar", vec![mock_target("bar", TargetKind::Lib)]);
+        dependent.source = Some(registry);
+
+        let sys_node = mock_node(&sys.id.repr, serde_json::json!([]), &[]);
+        let dependent_node = mock_node(
+            &dependent.id.repr,
+            serde_json::json!([mock_dep("foo_sys", &sys.id.repr)]),
+            &[],
+        );
+
+        let ctx = BuckalContext {
+            nodes_map: HashMap::from([
+                (sys.id.clone(), sys_node.clone()),
+                (dependent.id.clone(), dependent_node),
+            ]),
+            packages_map: HashMap::from([
+                (sys.id.clone(), sys.clone()),
+                (dependent.id.clone(), dependent),
+            ]),
+            repo_config: RepoConfig {
+                buildscript_visibility,
+                ..RepoConfig::default()
+            },
+            checksums_map: HashMap::from([(
+                ChecksumKey::of(&sys),
+                "0".repeat(64).parse().unwrap(),
+            )]),
+            workspace_root: Utf8PathBuf::from("/nonexistent"),
+            ..Default::default()
+        };
+
+        let mut build = None;
+        let mut run = None;
+        for rule in buckify_dep_node(&sys_node, &ctx).unwrap() {
+            match rule {
+                Rule::RustBinary(b) => build = Some(b),
+                Rule::BuildscriptRun(r) => run = Some(r),
+                _ => {}
+            }
+        }
+        (build.unwrap(), run.unwrap())
+    }
+
+    #[test]
+    fn test_buckify_dep_node_buildscript_visibility() {
+        // The build script is private to the crate, and its `[metadata]` only visible to the
+        // dependents reading it through the `links` key
+        let (build, run) = buildscript_rules(BuildscriptVisibility::Package);
+        assert!(build.visibility.is_empty());
+        assert_eq!(
+            run.visibility,
+            Set::from([format!("//{}/bar/0.1.0:", crate::RUST_CRATES_ROOT)])
+        );
+
+        let public = Set::from(["PUBLIC".to_owned()]);
+        let (build, run) = buildscript_rules(BuildscriptVisibility::Public);
+        assert_eq!(build.visibility, public);
+        assert_eq!(run.visibility, public);
+    }
+
+    #[test]
+    fn test_buildscript_build_dependencies_as_exec_deps() {
+        let registry: cargo_metadata::Source = serde_json::from_value(serde_json::json!(
+            "registry+https://github.com/rust-lang/crates.io-index"
+        ))
+        .unwrap();
+        let mut sys = mock_package(
+            "foo-sys",
+            vec![
+                mock_target("foo_sys", TargetKind::Lib),
+                mock_target("build-script-build", TargetKind::CustomBuild),
+            ],
+        );
+        sys.source = Some(registry.clone());
+        let mut cc = mock_package("cc", vec![mock_target("cc", TargetKind::Lib)]);
+        cc.source = Some(registry.clone());
+        let mut winres = mock_package("winres", vec![mock_target("winres", TargetKind::Lib)]);
+        winres.source = Some(registry);
+
+        let sys_node = mock_node(
+            &sys.id.repr,
+            serde_json::json!([
+                {
+                    "name": "cc",
+                    "pkg": cc.id.clone(),
+                    "dep_kinds": [{ "kind": "build", "target": null }]
+                },
+                {
+                    "name": "winres",
+                    "pkg": winres.id.clone(),
+                    "dep_kinds": [{ "kind": "build", "target": "cfg(windows)" }]
+                }
+            ]),
+            &[],
+        );
+
+        let build_script = |buildscript_exec_deps: bool| {
+            let ctx = BuckalContext {
+                nodes_map: HashMap::from([(sys.id.clone(), sys_node.clone())]),
+                packages_map: HashMap::from([
+                    (sys.id.clone(), sys.clone()),
+                    (cc.id.clone(), cc.clone()),
+                    (winres.id.clone(), winres.clone()),
+                ]),
+                checksums_map: HashMap::from([(
+                    ChecksumKey::of(&sys),
+                    "0".repeat(64).parse().unwrap(),
+                )]),
+                workspace_root: Utf8PathBuf::from("/nonexistent"),
+                repo_config: RepoConfig {
+                    buildscript_exec_deps,
+                    ..RepoConfig::default()
+                },
+                ..Default::default()
+            };
+            buckify_dep_node(&sys_node, &ctx)
+                .unwrap()
+                .into_iter()
+                .find_map(|rule| match rule {
+                    Rule::RustBinary(b) => Some(b),
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        let cc_label = format!("//{}/cc/0.1.0:cc", crate::RUST_CRATES_ROOT);
+        let winres_label = format!("//{}/winres/0.1.0:winres", crate::RUST_CRATES_ROOT);
+        let build = build_script(true);
+        assert_eq!(build.exec_deps, Set::from([cc_label.clone()]));
+        assert!(build.deps.is_empty());
+        // Platform-gated build-dependencies have no exec counterpart and stay in `os_deps`
+        assert_eq!(build.os_deps["windows"], Set::from([winres_label]));
+
+        let build = build_script(false);
+        assert_eq!(build.deps, Set::from([cc_label]));
+        assert!(build.exec_deps.is_empty());
+    }
+
+    #[test]
+    fn test_buckify_dep_node_mapped_srcs_from_config() {
+        let mut pkg = mock_package("foo", vec![mock_target("foo", TargetKind::Lib)]);
+        pkg.source = Some(
+            serde_json::from_value(serde_json::json!(
+                "registry+https://github.com/rust-lang/crates.io-index"
+            ))
+            .unwrap(),
+        );
+        let node = mock_node(&pkg.id.repr, serde_json::json!([]), &[]);
+        let ctx = BuckalContext {
+            nodes_map: HashMap::from([(pkg.id.clone(), node.clone())]),
+            packages_map: HashMap::from([(pkg.id.clone(), pkg.clone())]),
+            checksums_map: HashMap::from([(
+                ChecksumKey::of(&pkg),
+                "0".repeat(64).parse().unwrap(),
+            )]),
+            workspace_root: Utf8PathBuf::from("/nonexistent"),
+            repo_config: RepoConfig {
+                mapped_srcs: Map::from([(
+                    "foo".to_owned(),
+                    Map::from([(
+                        "//codegen:foo_tables".to_owned(),
+                        "vendor/src/generated/tables.rs".to_owned(),
+                    )]),
+                )]),
+                ..RepoConfig::default()
+            },
+            ..Default::default()
+        };
+
+        let rules = buckify_dep_node(&node, &ctx).unwrap();
+        let rust_library = rules
+            .iter()
+            .find_map(|rule| match rule {
+                Rule::RustLibrary(lib) => Some(lib),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(
+            rust_library.mapped_srcs,
+            Map::from([(
+                "//codegen:foo_tables".to_owned(),
+                "vendor/src/generated/tables.rs".to_owned()
+            )])
+        );
+        assert!(gen_buck_content(&rules).contains("mapped_srcs"));
+    }
+
+    #[test]
+    fn test_buckify_dep_node_with_hundreds_of_features() {
+        // Like the `windows` crate, with a feature per API namespace
+        let features: Vec<String> = (0..320)
+            .map(|i| format!("Win32_System_Namespace{i:03}"))
+            .collect();
+        let mut pkg = mock_package("windows", vec![mock_target("windows", TargetKind::Lib)]);
+        pkg.source = Some(
+            serde_json::from_value(serde_json::json!(
+                "registry+https://github.com/rust-lang/crates.io-index"
+            ))
+            .unwrap(),
+        );
+        pkg.features = features.iter().map(|f| (f.clone(), vec![])).collect();
+        let node = mock_node(
+            &pkg.id.repr,
+            serde_json::json!([]),
+            &features.iter().map(String::as_str).collect::<Vec<_>>(),
+        );
+        let ctx = BuckalContext {
+            nodes_map: HashMap::from([(pkg.id.clone(), node.clone())]),
+            packages_map: HashMap::from([(pkg.id.clone(), pkg.clone())]),
+            checksums_map: HashMap::from([(
+                ChecksumKey::of(&pkg),
+                "0".repeat(64).parse().unwrap(),
+            )]),
+            workspace_root: Utf8PathBuf::from("/nonexistent"),
+            ..Default::default()
+        };
+
+        let content = gen_buck_content(&buckify_dep_node(&node, &ctx).unwrap());
+        // One feature per line, however many there are
+        let longest = content.lines().map(str::len).max().unwrap_or_default();
+        assert!(longest < 120, "longest line is {longest} bytes");
+
+        let dir = crate::testing::temp_dir("features");
+        std::fs::create_dir_all(&dir).unwrap();
+        let buck_path = dir.join("BUCK");
+        std::fs::write(&buck_path, &content).unwrap();
+        let parsed = crate::buck::parse_buck_file(&buck_path);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let parsed = parsed.unwrap();
+        let rust_library = parsed
+            .values()
+            .find_map(|rule| match rule {
+                Rule::RustLibrary(lib) => Some(lib),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(rust_library.features, Set::from_iter(features));
+    }
+
+    #[test]
+    fn test_sys_crate_env_on_build_script_run() {
+        let mut sys = mock_package(
+            "openssl-sys",
+            vec![
+                mock_target("openssl_sys", TargetKind::Lib),
+                mock_target("build-script-main", TargetKind::CustomBuild),
+            ],
+        );
+        sys.source = Some(
+            serde_json::from_value(serde_json::json!(
+                "registry+https://github.com/rust-lang/crates.io-index"
+            ))
+            .unwrap(),
+        );
+        let node = mock_node(&sys.id.repr, serde_json::json!([]), &[]);
+        let mut ctx = BuckalContext {
+            nodes_map: HashMap::from([(sys.id.clone(), node.clone())]),
+            packages_map: HashMap::from([(sys.id.clone(), sys.clone())]),
+            checksums_map: HashMap::from([(
+                ChecksumKey::of(&sys),
+                "0".repeat(64).parse().unwrap(),
+            )]),
+            workspace_root: Utf8PathBuf::from("/nonexistent"),
+            ..Default::default()
+        };
+        let run_env = |ctx: &BuckalContext| {
+            buckify_dep_node(&node, ctx)
+                .unwrap()
+                .into_iter()
+                .find_map(|rule| match rule {
+                    Rule::BuildscriptRun(run) => Some(run.env),
+                    _ => None,
+                })
+                .expect("expected a buildscript_run rule")
+        };
+
+        // Nothing unless configured
+        assert!(run_env(&ctx).is_empty());
+
+        ctx.repo_config.sys_crate_env = Map::from([(
+            "openssl-sys".to_owned(),
+            Map::from([("OPENSSL_NO_VENDOR".to_owned(), "1".to_owned())]),
+        )]);
+        assert_eq!(
+            run_env(&ctx),
+            Map::from([("OPENSSL_NO_VENDOR".to_owned(), "1".to_owned())])
+        );
+
+        // Forwarded vars are looked up from the buckconfig when the BUCK file is loaded, so the
+        // rule only ever holds the configured values
+        ctx.repo_config.forward_sys_crate_env = true;
+        assert_eq!(
+            run_env(&ctx),
+            Map::from([("OPENSSL_NO_VENDOR".to_owned(), "1".to_owned())])
+        );
+        let vars = crate::buckify::emit::forwarded_sys_crate_env("openssl-sys", &ctx).unwrap();
+        assert!(vars.contains(&"OPENSSL_DIR"));
+    }
+
+    #[test]
+    fn test_unusual_build_script_name_is_referenced_consistently() {
+        let registry: cargo_metadata::Source = serde_json::from_value(serde_json::json!(
+            "registry+https://github.com/rust-lang/crates.io-index"
+        ))
+        .unwrap();
+        // `build = "generate.rs"` outside the usual `build-script-build` naming
+        let mut sys = mock_package(
+            "foo-sys",
+            vec![
+                mock_target("foo_sys", TargetKind::Lib),
+                mock_target("generate", TargetKind::CustomBuild),
+            ],
+        );
+        sys.source = Some(registry.clone());
+        sys.links = Some("foo".to_owned());
+        let mut dependent = mock_package(
+            "bar",
+            vec![
+                mock_target("bar", TargetKind::Lib),
+                mock_target("build-script-build", TargetKind::CustomBuild),
+            ],
+        );
+        dependent.source = Some(registry);
+
+        let sys_node = mock_node(&sys.id.repr, serde_json::json!([]), &[]);
+        let dependent_node = mock_node(
+            &dependent.id.repr,
+            serde_json::json!([mock_dep("foo_sys", &sys.id.repr)]),
+            &[],
+        );
+        let ctx = BuckalContext {
+            nodes_map: HashMap::from([
+                (sys.id.clone(), sys_node.clone()),
+                (dependent.id.clone(), dependent_node.clone()),
+            ]),
+            packages_map: HashMap::from([
+                (sys.id.clone(), sys.clone()),
+                (dependent.id.clone(), dependent.clone()),
+            ]),
+            checksums_map: HashMap::from([
+                (ChecksumKey::of(&sys), "0".repeat(64).parse().unwrap()),
+                (ChecksumKey::of(&dependent), "0".repeat(64).parse().unwrap()),
+            ]),
+            workspace_root: Utf8PathBuf::from("/nonexistent"),
+            ..Default::default()
+        };
+
+        let rules = buckify_dep_node(&sys_node, &ctx).unwrap();
+        let mut library = None;
+        let mut build = None;
+        let mut run = None;
+        for rule in &rules {
+            match rule {
+                Rule::RustLibrary(r) => library = Some(r),
+                Rule::RustBinary(r) => build = Some(r),
+                Rule::BuildscriptRun(r) => run = Some(r),
+                _ => {}
+            }
+        }
+        let (library, build, run) = (library.unwrap(), build.unwrap(), run.unwrap());
+        assert_eq!(build.name, "foo-sys-generate");
+        assert_eq!(run.buildscript_rule, ":foo-sys-generate");
+        assert_eq!(run.name, "foo-sys-generate-run");
+        assert_eq!(
+            library.env.get("OUT_DIR").map(String::as_str),
+            Some("$(location :foo-sys-generate-run[out_dir])")
+        );
+        assert!(
+            library
+                .rustc_flags
+                .contains("@$(location :foo-sys-generate-run[rustc_flags])")
+        );
+
+        // The dependent reads the `[metadata]` of the very same rule
+        let dependent_run = buckify_dep_node(&dependent_node, &ctx)
+            .unwrap()
+            .into_iter()
+            .find_map(|rule| match rule {
+                Rule::BuildscriptRun(r) => Some(r),
+                _ => None,
+            })
+            .unwrap();
+        assert!(dependent_run.env_srcs.contains(&format!(
+            "//{}/foo-sys/0.1.0:foo-sys-generate-run[metadata]",
+            crate::RUST_CRATES_ROOT
+        )));
+        assert_eq!(dependent_run.name, "bar-build-script-run");
+    }
+
+    /// `$(location ...)` macros must reference labels, so generated rules keep working with a
+    /// relocated `buck-out` directory.
+    fn assert_label_relative(buck_content: &str) {
+        let location = regex::Regex::new(r"\$\(location ([^)]*)\)").unwrap();
+        let mut found = false;
+        for captures in location.captures_iter(buck_content) {
+            found = true;
+            let target = &captures[1];
+            assert!(
+                target.starts_with(':') || target.starts_with("//"),
+                "`$(location {target})` is not a label"
+            );
+        }
+        assert!(
+            found,
+            "expected `$(location ...)` macros in:\n{buck_content}"
+        );
+        // Only the filegroup's exclude may name it
+        assert!(
+            !buck_content
+                .replace("\"buck-out/**\"", "")
+                .contains("buck-out")
+        );
+        assert!(!buck_content.contains("/nonexistent"));
+    }
+
+    #[test]
+    fn test_generated_locations_are_label_relative() {
+        let pkg = mock_package(
+            "foo",
+            vec![
+                mock_target("foo", TargetKind::Lib),
+                mock_target("foo", TargetKind::Bin),
+                mock_target("build-script-build", TargetKind::CustomBuild),
+                mock_target("it", TargetKind::Test),
+            ],
+        );
+        let node = mock_node(&pkg.id.repr, serde_json::json!([]), &[]);
+        let ctx = BuckalContext {
+            packages_map: HashMap::from([(pkg.id.clone(), pkg.clone())]),
+            repo_config: RepoConfig {
+                ignore_tests: false,
+                buildscript_rustc_env: true,
+                ..RepoConfig::default()
+            },
+            workspace_root: Utf8PathBuf::from("/nonexistent"),
+            workspace_members: vec![pkg.id.clone()],
+            ..Default::default()
+        };
+        assert_label_relative(&gen_buck_content(&buckify_root_node(&node, &ctx).unwrap()));
+
+        let (build, run) = buildscript_rules(BuildscriptVisibility::Package);
+        assert_label_relative(&gen_buck_content(&[
+            Rule::RustBinary(build),
+            Rule::BuildscriptRun(run),
+        ]));
+    }
+
+    #[test]
+    fn test_buckify_dep_node_compatibility_narrowed_from_usage() {
+        let mut libc = mock_package("libc", vec![mock_target("libc", TargetKind::Lib)]);
+        libc.source = serde_json::from_value(serde_json::json!(
+            "registry+https://github.com/rust-lang/crates.io-index"
+        ))
+        .unwrap();
+        let app = mock_package("app", vec![mock_target("app", TargetKind::Bin)]);
+
+        let libc_node = mock_node(&libc.id.repr, serde_json::json!([]), &[]);
+        let app_node = mock_node(
+            &app.id.repr,
+            serde_json::json!([{
+                "name": "libc",
+                "pkg": libc.id.clone(),
+                "dep_kinds": [{ "kind": null, "target": "cfg(unix)" }]
+            }]),
+            &[],
+        );
+
+        let ctx = BuckalContext {
+            nodes_map: HashMap::from([
+                (libc.id.clone(), libc_node.clone()),
+                (app.id.clone(), app_node),
+            ]),
+            packages_map: HashMap::from([
+                (libc.id.clone(), libc.clone()),
+                (app.id.clone(), app.clone()),
+            ]),
+            checksums_map: HashMap::from([(
+                ChecksumKey::of(&libc),
+                "0".repeat(64).parse().unwrap(),
+            )]),
+            workspace_root: Utf8PathBuf::from("/nonexistent"),
+            workspace_members: vec![app.id.clone()],
+            ..Default::default()
+        };
+
+        let lib = buckify_dep_node(&libc_node, &ctx)
+            .unwrap()
+            .into_iter()
+            .find_map(|r| match r {
+                Rule::RustLibrary(l) => Some(l),
+                _ => None,
+            })
+            .expect("expected a rust_library rule");
+        // Only used on unix: compatible with either linux or macos
+        assert_eq!(
+            lib.compatible_with,
+            Set::from([
+                "prelude//os/constraints:linux".to_owned(),
+                "prelude//os/constraints:macos".to_owned()
+            ])
+        );
+        assert!(lib.target_compatible_with.is_empty());
+    }
+
+    #[test]
+    fn test_buckify_root_node_manifest_env_flags() {
+        let root = crate::testing::temp_dir("env-flags");
+        std::fs::create_dir_all(root.join("src")).unwrap();
+
+        let mut lib = mock_target("foo", TargetKind::Lib);
+        lib.src_path = root.join("src/lib.rs");
+        let mut pkg = mock_package("foo", vec![lib]);
+        pkg.manifest_path = root.join("Cargo.toml");
+
+        let node = mock_node(&pkg.id.repr, serde_json::json!([]), &[]);
+
+        let ctx = BuckalContext {
+            packages_map: HashMap::from([(pkg.id.clone(), pkg.clone())]),
+            workspace_root: root.clone(),
+            workspace_members: vec![pkg.id.clone()],
+            ..Default::default()
+        };
+        let emit_lib = || {
+            buckify_root_node(&node, &ctx)
+                .unwrap()
+                .into_iter()
+                .find_map(|r| match r {
+                    Rule::RustLibrary(l) => Some(l),
+                    _ => None,
+                })
+                .expect("expected a rust_library rule")
+        };
+
+        std::fs::write(root.join("src/lib.rs"), "pub fn answer() -> u32 { 42 }\n").unwrap();
+        let flagless = emit_lib();
+        std::fs::write(
+            root.join("src/lib.rs"),
+            "pub const VERSION: &str = env!(\"CARGO_PKG_VERSION\");\n",
+        )
+        .unwrap();
+        let with_env = emit_lib();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let env_flags = "@$(location :foo-manifest[env_flags])";
+        assert!(!flagless.rustc_flags.contains(env_flags));
+        assert!(flagless.rustc_flags.is_empty());
+        assert!(with_env.rustc_flags.contains(env_flags));
+    }
+
+    #[test]
+    fn test_buckify_root_node_features_are_stable() {
+        let pkg = mock_package("foo", vec![mock_target("foo", TargetKind::Lib)]);
+        let ctx = BuckalContext {
+            packages_map: HashMap::from([(pkg.id.clone(), pkg.clone())]),
+            workspace_root: Utf8PathBuf::from("/nonexistent"),
+            workspace_members: vec![pkg.id.clone()],
+            ..Default::default()
+        };
+        let buckify = |features: &[&str]| {
+            let node = mock_node(&pkg.id.repr, serde_json::json!([]), features);
+            buckify_root_node(&node, &ctx).unwrap()
+        };
+
+        // Cargo may report the same features in any order, and with duplicates
+        let first = buckify(&["std", "default", "alloc", "std"]);
+        let second = buckify(&["alloc", "std", "default"]);
+        assert_eq!(gen_buck_content(&first), gen_buck_content(&second));
+
+        let Some(Rule::RustLibrary(lib)) = first.iter().find(|r| matches!(r, Rule::RustLibrary(_)))
+        else {
+            panic!("expected a rust_library rule");
+        };
+        assert_eq!(
+            lib.features,
+            Set::from(["alloc".to_owned(), "default".to_owned(), "std".to_owned()])
+        );
+    }
+
+    #[test]
+    fn test_check_crate_root() {
+        let dir = crate::testing::temp_dir("crate-root").join("foo-1.0.0");
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("src/lib.rs"), "").unwrap();
+
+        let mut package = crate::testing::mock_package(
+            "foo",
+            "1.0.0",
+            "foo 1.0.0",
+            Some(crate::testing::REGISTRY),
+        );
+        package.manifest_path = dir.join("Cargo.toml");
+        let mut lib_target = mock_target("foo", TargetKind::Lib);
+        lib_target.src_path = dir.join("src/lib.rs");
+        package.targets = vec![lib_target];
+
+        let rust_library = RustLibrary {
+            name: "foo".to_owned(),
+            crate_root: "vendor/src/lib.rs".to_owned(),
+            ..Default::default()
+        };
+        let http_archive = |strip_prefix: &str| {
+            Rule::HttpArchive(HttpArchive {
+                name: "foo-vendor".to_owned(),
+                strip_prefix: strip_prefix.to_owned(),
+                ..Default::default()
+            })
+        };
+        let filegroup = |exclude: &[&str]| {
+            Rule::FileGroup(FileGroup {
+                name: "foo-vendor".to_owned(),
+                srcs: Glob {
+                    include: Set::from(["**/**".to_owned()]),
+                    exclude: exclude.iter().map(|glob| glob.to_string()).collect(),
+                },
+                out: Some("vendor".to_owned()),
+            })
+        };
+
+        assert!(check_crate_root(&rust_library, &package, &http_archive("foo-1.0.0")).is_ok());
+        assert!(check_crate_root(&rust_library, &package, &filegroup(&["target/**"])).is_ok());
+
+        // A stale BUCK file whose root isn't the library target any more
+        let stale = RustLibrary {
+            name: "foo".to_owned(),
+            crate_root: "vendor/lib.rs".to_owned(),
+            ..Default::default()
+        };
+        let err = check_crate_root(&stale, &package, &http_archive("foo-1.0.0"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("expected `vendor/src/lib.rs`"), "{err}");
+
+        // The archive is unpacked one level off
+        let err = check_crate_root(&rust_library, &package, &http_archive("foo"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("strip_prefix `foo`"), "{err}");
+
+        // The root is filtered out of the filegroup
+        let err = check_crate_root(&rust_library, &package, &filegroup(&["src/**"]))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("excluded from `foo-vendor` by `src/**`"),
+            "{err}"
+        );
+
+        std::fs::remove_file(dir.join("src/lib.rs")).unwrap();
+        let err = check_crate_root(&rust_library, &package, &http_archive("foo-1.0.0"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("does not exist"), "{err}");
+
+        std::fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("**/**", "src/lib.rs"));
+        assert!(glob_matches("**/*.rs", "lib.rs"));
+        assert!(glob_matches("target/**", "target/debug/foo"));
+        assert!(!glob_matches("target/**", "src/target.rs"));
+        assert!(!glob_matches("*.rs", "src/lib.rs"));
+        assert!(glob_matches("src/li?.rs", "src/lib.rs"));
+    }
 }
@@ -0,0 +1,195 @@
+use std::{
+    collections::{BTreeMap as Map, BTreeSet as Set},
+    sync::Mutex,
+};
+
+use cargo_metadata::camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{buck::Rule, buckal_note};
+
+/// Totals of the BUCK files written during this run, by file, for `--stats`.
+///
+/// BUCK files are written by several threads, so their totals are collected here as they are
+/// written rather than returned through every flush. Keyed by file, so a file written twice
+/// (e.g. by `flush_root` and `apply`) is counted once, with its last rules.
+static WRITTEN: Mutex<Map<Utf8PathBuf, BuckifyStats>> = Mutex::new(Map::new());
+
+/// Rule kinds always listed by `--stats`, even when none were emitted.
+const REPORTED_KINDS: [&str; 5] = [
+    "rust_library",
+    "rust_binary",
+    "rust_test",
+    "buildscript_run",
+    "http_archive",
+];
+
+/// Totals of a sync, printed by `--stats`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BuckifyStats {
+    /// BUCK files holding a crate's rules
+    pub crates: usize,
+    /// Crates running a build script
+    pub build_scripts: usize,
+    /// Rules emitted, by kind
+    pub rules: Map<String, usize>,
+    /// Entries of `os_deps` and `os_named_deps`
+    pub platform_deps: usize,
+}
+
+/// Record the rules written to `buck_path`.
+pub(super) fn record_rules(buck_path: &Utf8Path, rules: &[Rule]) {
+    WRITTEN
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(buck_path.to_owned(), BuckifyStats::of_crate(rules));
+}
+
+impl BuckifyStats {
+    /// The totals of the given BUCK files, from the rules recorded when they were written.
+    pub fn of_written(buck_files: &[Utf8PathBuf]) -> Self {
+        let written = WRITTEN
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let buck_files: Set<&Utf8PathBuf> = buck_files.iter().collect();
+        let mut stats = BuckifyStats::default();
+        for crate_stats in buck_files.into_iter().filter_map(|f| written.get(f)) {
+            stats.crates += crate_stats.crates;
+            stats.build_scripts += crate_stats.build_scripts;
+            stats.platform_deps += crate_stats.platform_deps;
+            for (kind, count) in &crate_stats.rules {
+                *stats.rules.entry(kind.clone()).or_default() += count;
+            }
+        }
+        stats
+    }
+
+    fn of_crate(rules: &[Rule]) -> Self {
+        let mut stats = BuckifyStats::default();
+        for rule in rules {
+            let Some((kind, _)) = rule.key() else {
+                continue;
+            };
+            if let Rule::BuildscriptRun(_) = rule {
+                stats.build_scripts += 1;
+            }
+            stats.platform_deps += match rule {
+                Rule::RustLibrary(r) => platform_deps(&r.os_deps, &r.os_named_deps),
+                Rule::RustBinary(r) => platform_deps(&r.os_deps, &r.os_named_deps),
+                Rule::RustTest(r) => platform_deps(&r.os_deps, &r.os_named_deps),
+                _ => 0,
+            };
+            *stats.rules.entry(kind).or_default() += 1;
+        }
+        if !stats.rules.is_empty() {
+            stats.crates = 1;
+        }
+        stats
+    }
+
+    pub fn print(&self) {
+        buckal_note!(
+            "Buckified {} crate(s), {} with build scripts",
+            self.crates,
+            self.build_scripts
+        );
+        let rules: Vec<_> = REPORTED_KINDS
+            .iter()
+            .map(|kind| format!("{} {}", kind, self.rules.get(*kind).unwrap_or(&0)))
+            .collect();
+        buckal_note!("Rules emitted: {}", rules.join(", "));
+        buckal_note!("Platform-conditional deps: {}", self.platform_deps);
+    }
+}
+
+fn platform_deps(
+    os_deps: &Map<String, Set<String>>,
+    os_named_deps: &Map<String, Map<String, String>>,
+) -> usize {
+    os_deps.values().map(Set::len).sum::<usize>()
+        + os_named_deps.values().map(Map::len).sum::<usize>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = r#"# @generated by `cargo buckal`
+
+load("@buckal//:wrapper.bzl", "buildscript_run", "rust_library", "rust_test")
+
+http_archive(
+    name = "foo-vendor",
+    urls = ["https://static.crates.io/crates/foo/foo-1.0.0.crate"],
+    sha256 = "abc",
+    type = "tar.gz",
+    strip_prefix = "foo-1.0.0",
+    out = "vendor",
+)
+
+rust_library(
+    name = "foo",
+    srcs = [":foo-vendor"],
+    crate = "foo",
+    crate_root = "vendor/src/lib.rs",
+    edition = "2021",
+    os_named_deps = {"baz": {"windows": "//third-party/rust/crates/baz/0.1.0:baz"}},
+    os_deps = {
+        "linux": ["//third-party/rust/crates/libc/0.2.0:libc"],
+        "macos": ["//third-party/rust/crates/libc/0.2.0:libc"],
+    },
+    visibility = ["PUBLIC"],
+)
+
+rust_test(
+    name = "foo-unittest",
+    srcs = [":foo-vendor"],
+    crate = "foo",
+    crate_root = "vendor/src/lib.rs",
+    edition = "2021",
+    visibility = ["PUBLIC"],
+)
+
+buildscript_run(
+    name = "foo-build-script-run",
+    package_name = "foo",
+    buildscript_rule = ":foo-build-script-build",
+    version = "1.0.0",
+)
+"#;
+
+    #[test]
+    fn test_stats_of_written_rules() {
+        let dir = crate::testing::temp_dir("stats");
+        std::fs::create_dir_all(&dir).unwrap();
+        let buck_file = dir.join("BUCK");
+        std::fs::write(&buck_file, FIXTURE).unwrap();
+        let rules: Vec<Rule> = crate::buck::parse_buck_file(&buck_file)
+            .unwrap()
+            .into_values()
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // A file written twice is counted once, with its last rules
+        record_rules(&buck_file, &rules[..1]);
+        record_rules(&buck_file, &rules);
+        let aliases = dir.join("aliases.BUCK");
+        record_rules(&aliases, &[]);
+        let stats = BuckifyStats::of_written(&[buck_file.clone(), aliases, buck_file]);
+
+        assert_eq!(
+            stats,
+            BuckifyStats {
+                crates: 1,
+                build_scripts: 1,
+                rules: Map::from([
+                    ("buildscript_run".to_owned(), 1),
+                    ("http_archive".to_owned(), 1),
+                    ("rust_library".to_owned(), 1),
+                    ("rust_test".to_owned(), 1),
+                ]),
+                platform_deps: 3,
+            }
+        );
+        assert_eq!(BuckifyStats::of_written(&[]), BuckifyStats::default());
+    }
+}
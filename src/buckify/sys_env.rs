@@ -0,0 +1,156 @@
+use std::collections::BTreeMap as Map;
+
+use starlark_syntax::syntax::ast::{ArgumentP, AstStmt, ExprP, Stmt};
+use starlark_syntax::syntax::module::AstModuleFields;
+use starlark_syntax::syntax::{AstModule, Dialect};
+
+use super::cross::{insert_pos_before_closing_paren, needs_leading_comma};
+
+/// Buckconfig section the forwarded env of `*-sys` crates is read from, e.g. in
+/// `.buckconfig.local`.
+const SYS_CRATE_ENV_SECTION: &str = "sys_crate_env";
+
+/// Make the `buildscript_run` of a BUCK file read `vars` from the buckconfig, on top of its
+/// literal `env`.
+///
+/// The values are machine-local, so they're looked up when buck2 loads the file rather than
+/// written into it. Vars the buckconfig doesn't set are left out, as build scripts tell an
+/// empty var from an unset one (e.g. `OPENSSL_STATIC`).
+pub(super) fn patch_buildscript_run_env(
+    buck_content: String,
+    vars: &[&str],
+    env: &Map<String, String>,
+) -> String {
+    let ast = match AstModule::parse("BUCK", buck_content.clone(), &Dialect::Extended) {
+        Ok(ast) => ast,
+        Err(_) => return buck_content,
+    };
+    let Some((start, end, existing)) = find_env_arg(ast.statement()) else {
+        return buck_content;
+    };
+
+    let expr = forwarded_env_expr(vars, env);
+    let mut out = buck_content;
+    if existing {
+        out.replace_range(start..end, &expr);
+    } else {
+        let mut insert = String::new();
+        if needs_leading_comma(&out, start) {
+            insert.push_str(",\n");
+        } else if !out[..start].ends_with('\n') {
+            insert.push('\n');
+        }
+        insert.push_str(&format!("    env = {expr},\n"));
+        out.insert_str(start, &insert);
+    }
+    out
+}
+
+/// The span of the `env` value of the `buildscript_run` call, and whether it exists, or else the
+/// position to insert it at.
+fn find_env_arg(stmt: &AstStmt) -> Option<(usize, usize, bool)> {
+    match &stmt.node {
+        Stmt::Statements(stmts) => stmts.iter().find_map(find_env_arg),
+        Stmt::Expression(expr) => {
+            let ExprP::Call(callee, args) = &expr.node else {
+                return None;
+            };
+            if !matches!(&callee.node, ExprP::Identifier(ident) if ident.node.ident == "buildscript_run")
+            {
+                return None;
+            }
+            let env = args.args.iter().find_map(|arg| match &arg.node {
+                ArgumentP::Named(name, value) if name.node == "env" => Some(value.span),
+                _ => None,
+            });
+            match env {
+                Some(span) => Some((span.begin().get() as usize, span.end().get() as usize, true)),
+                None => {
+                    let pos = insert_pos_before_closing_paren(expr.span)?;
+                    Some((pos, pos, false))
+                }
+            }
+        }
+        _ => None,
+    }
+}
+
+/// `{k: v for k, v in {...}.items() if v}`, with a `read_config` for each of `vars` not set in
+/// `env`.
+fn forwarded_env_expr(vars: &[&str], env: &Map<String, String>) -> String {
+    let quote = |s: &str| {
+        format!(
+            "\"{}\"",
+            s.replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+        )
+    };
+    let entries = vars
+        .iter()
+        .filter(|var| !env.contains_key(**var))
+        .map(|var| {
+            format!(
+                "{}: read_config({}, {}, \"\")",
+                quote(var),
+                quote(SYS_CRATE_ENV_SECTION),
+                quote(var)
+            )
+        })
+        .chain(
+            env.iter()
+                .map(|(key, value)| format!("{}: {}", quote(key), quote(value))),
+        )
+        .collect::<Vec<_>>();
+    format!(
+        "{{k: v for k, v in {{{}}}.items() if v}}",
+        entries.join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patch_buildscript_run_env() {
+        let buck = indoc::indoc! {r#"
+            buildscript_run(
+                name = "openssl-sys-build-script-run",
+                env = {
+                    "OPENSSL_NO_VENDOR": "1",
+                },
+                version = "0.9.0",
+            )
+        "#};
+        let env = Map::from([("OPENSSL_NO_VENDOR".to_owned(), "1".to_owned())]);
+        let patched =
+            patch_buildscript_run_env(buck.to_owned(), &["OPENSSL_DIR", "OPENSSL_NO_VENDOR"], &env);
+        assert_eq!(
+            patched,
+            indoc::indoc! {r#"
+                buildscript_run(
+                    name = "openssl-sys-build-script-run",
+                    env = {k: v for k, v in {"OPENSSL_DIR": read_config("sys_crate_env", "OPENSSL_DIR", ""), "OPENSSL_NO_VENDOR": "1"}.items() if v},
+                    version = "0.9.0",
+                )
+            "#}
+        );
+
+        // Without a literal `env`, one is added
+        let buck = indoc::indoc! {r#"
+            buildscript_run(
+                name = "zstd-sys-build-script-run",
+                version = "2.0.0",
+            )
+        "#};
+        let patched =
+            patch_buildscript_run_env(buck.to_owned(), &["ZSTD_SYS_USE_PKG_CONFIG"], &Map::new());
+        assert!(
+            patched.ends_with(
+                "    version = \"2.0.0\",\n    env = {k: v for k, v in {\"ZSTD_SYS_USE_PKG_CONFIG\": read_config(\"sys_crate_env\", \"ZSTD_SYS_USE_PKG_CONFIG\", \"\")}.items() if v},\n)\n"
+            ),
+            "{patched}"
+        );
+    }
+}
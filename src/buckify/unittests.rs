@@ -0,0 +1,105 @@
+use cargo_metadata::Package;
+
+use crate::context::BuckalContext;
+
+use super::{deps::get_lib_targets, windows::append_to_rule_kwarg};
+
+/// Buckconfig key under which the inline unit tests are compatible, set by `cargo buckal test`.
+pub const UNITTESTS_CONFIG: (&str, &str) = ("buckal", "unittests");
+
+/// The `target_compatible_with` attribute of `rust_test`, with the attributes emitted before it.
+const TARGET_COMPATIBLE_WITH_KWARG: (&str, &[&str]) = (
+    "target_compatible_with",
+    &["name", "srcs", "crate", "crate_root", "edition"],
+);
+
+/// Make the `rust_test` rules of the package's inline unit tests incompatible unless the
+/// buckconfig enables them, so `buck2 build //...` skips them while `cargo buckal test` (or
+/// `buck2 test -c buckal.unittests=true`) still builds and runs them.
+///
+/// The condition is wrapped in a `select`, whose branches aren't merged back from the BUCK file,
+/// so the incompatibility never becomes part of the rule itself.
+pub(super) fn patch_unittest_compatibility(
+    buck_content: String,
+    package: &Package,
+    ctx: &BuckalContext,
+) -> String {
+    let (section, key) = UNITTESTS_CONFIG;
+    let gate = format!(
+        "select({{\"DEFAULT\": [] if read_config(\"{section}\", \"{key}\", \"\") == \"true\" else [\"config//:none\"]}})"
+    );
+    get_lib_targets(package)
+        .into_iter()
+        .filter(|lib_target| lib_target.test)
+        .map(|lib_target| ctx.repo_config.naming.unittest_name(&lib_target.name))
+        .fold(buck_content, |content, name| {
+            append_to_rule_kwarg(
+                &content,
+                "rust_test",
+                &name,
+                TARGET_COMPATIBLE_WITH_KWARG,
+                &gate,
+            )
+            .unwrap_or(content)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::buck::{Rule, parse_buck_file};
+    use crate::testing::{mock_package, mock_target};
+    use cargo_metadata::TargetKind;
+
+    #[test]
+    fn test_patch_unittest_compatibility() {
+        let mut package = mock_package("foo", "0.1.0", "path+file:///ws/foo#0.1.0", None);
+        package.targets = vec![
+            mock_target("foo", TargetKind::Lib),
+            mock_target("it", TargetKind::Test),
+        ];
+        let buck = indoc::indoc! {r#"
+            rust_test(
+                name = "foo-unittest",
+                srcs = [":foo-vendor"],
+                crate = "foo",
+                target_compatible_with = select({"//platforms:cross": ["config//:none"], "DEFAULT": []}),
+            )
+
+            rust_test(
+                name = "it",
+                srcs = [":foo-vendor"],
+                crate = "it",
+            )
+        "#};
+
+        let patched =
+            patch_unittest_compatibility(buck.to_owned(), &package, &BuckalContext::default());
+        let gate = "select({\"DEFAULT\": [] if read_config(\"buckal\", \"unittests\", \"\") == \"true\" else [\"config//:none\"]})";
+        assert!(
+            patched.contains(&format!(
+                "target_compatible_with = select({{\"//platforms:cross\": [\"config//:none\"], \"DEFAULT\": []}}) + {gate},"
+            )),
+            "{patched}"
+        );
+        // Integration tests are built by `buck2 build //...` as before
+        assert_eq!(patched.matches("read_config").count(), 1);
+
+        // The gate is dropped when the BUCK file is merged back into the rules
+        let dir = crate::testing::temp_dir("unittests");
+        std::fs::create_dir_all(&dir).unwrap();
+        let buck_file = dir.join("BUCK");
+        std::fs::write(&buck_file, &patched).unwrap();
+        let rules = parse_buck_file(&buck_file).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let unittest = rules
+            .values()
+            .find_map(|rule| match rule {
+                Rule::RustTest(test) if test.name == "foo-unittest" => Some(test),
+                _ => None,
+            })
+            .expect("expected rust_test");
+        assert!(!unittest.target_compatible_with.contains("config//:none"));
+    }
+}
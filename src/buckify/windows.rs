@@ -3,12 +3,27 @@ use std::{collections::BTreeSet as Set, vec};
 use cargo_metadata::Package;
 use starlark_syntax::codemap::{Pos, Span, Spanned};
 use starlark_syntax::syntax::ast::{
-    ArgumentP, AstExpr, AstLiteral, AstNoPayload, AstStmt, CallArgsP, ExprP, IdentP, Stmt,
+    ArgumentP, AstExpr, AstLiteral, AstNoPayload, AstStmt, BinOp, CallArgsP, ExprP, IdentP, Stmt,
 };
 use starlark_syntax::syntax::module::AstModuleFields;
 use starlark_syntax::syntax::{AstModule, Dialect};
 
-use crate::{RUST_CRATES_ROOT, context::BuckalContext};
+use crate::context::BuckalContext;
+
+use super::cross::{insert_pos_before_closing_paren, needs_leading_comma};
+use super::emit::{buildscript_run_name, custom_build_target};
+
+/// Crates whose build scripts provide the Windows import libraries linked by root packages.
+const WINDOWS_GNU_IMPORT_LIBS: [&str; 2] = ["windows_x86_64_gnu", "winapi-x86_64-pc-windows-gnu"];
+const WINDOWS_MSVC_IMPORT_LIBS: [&str; 1] = ["windows_x86_64_msvc"];
+
+/// Whether root packages reference the `buildscript_run` target of this crate.
+pub(super) fn is_windows_import_lib(package_name: &str) -> bool {
+    WINDOWS_GNU_IMPORT_LIBS
+        .iter()
+        .chain(&WINDOWS_MSVC_IMPORT_LIBS)
+        .any(|name| *name == package_name)
+}
 
 #[derive(Default)]
 struct WindowsImportLibFlags {
@@ -17,7 +32,7 @@ struct WindowsImportLibFlags {
 }
 
 pub(super) fn patch_root_windows_rustc_flags(
-    mut buck_content: String,
+    buck_content: String,
     ctx: &BuckalContext,
     root: &Package,
 ) -> String {
@@ -50,7 +65,7 @@ pub(super) fn patch_root_windows_rustc_flags(
 
     for lib_target in lib_targets {
         if lib_target.test {
-            rust_test_names.insert(format!("{}-unittest", lib_target.name));
+            rust_test_names.insert(ctx.repo_config.naming.unittest_name(&lib_target.name));
         }
     }
 
@@ -64,25 +79,16 @@ pub(super) fn patch_root_windows_rustc_flags(
         return buck_content;
     }
 
-    for bin_name in bin_names {
-        buck_content = apply_rustc_flags_patch_to_content(
-            &buck_content,
-            "rust_binary",
-            &bin_name,
-            &select_expr,
-        );
-    }
-
-    for test_name in rust_test_names {
-        buck_content = apply_rustc_flags_patch_to_content(
-            &buck_content,
-            "rust_test",
-            &test_name,
-            &select_expr,
-        );
-    }
-
-    buck_content
+    let targets: Set<(&str, &str)> = bin_names
+        .iter()
+        .map(|name| ("rust_binary", name.as_str()))
+        .chain(
+            rust_test_names
+                .iter()
+                .map(|name| ("rust_test", name.as_str())),
+        )
+        .collect();
+    apply_rustc_flags_patches_to_content(&buck_content, &targets, &select_expr)
 }
 
 fn windows_import_lib_flags(ctx: &BuckalContext) -> WindowsImportLibFlags {
@@ -92,24 +98,31 @@ fn windows_import_lib_flags(ctx: &BuckalContext) -> WindowsImportLibFlags {
         let mut matches: Vec<_> = ctx
             .packages_map
             .values()
-            .filter(|p| p.name.to_string() == package_name)
+            .filter(|p| p.source.is_some() && p.name.to_string() == package_name)
             .collect();
         matches.sort_by(|a, b| a.version.cmp(&b.version));
         for package in matches {
-            let pkg_name = package.name.to_string();
+            // Crates without a build script have no flags to pass on
+            let Some(build_target) = custom_build_target(package) else {
+                continue;
+            };
             out.push(format!(
-                "@$(location //{}/{}/{}:{}-build-script-run[rustc_flags])",
-                RUST_CRATES_ROOT, pkg_name, package.version, pkg_name
+                "@$(location //{}:{}[rustc_flags])",
+                ctx.vendor_path(package),
+                buildscript_run_name(&package.name, build_target, ctx)
             ));
         }
     };
 
     // GNU targets.
-    push_build_script_rustc_flags("windows_x86_64_gnu", &mut flags.gnu);
-    push_build_script_rustc_flags("winapi-x86_64-pc-windows-gnu", &mut flags.gnu);
+    for package_name in WINDOWS_GNU_IMPORT_LIBS {
+        push_build_script_rustc_flags(package_name, &mut flags.gnu);
+    }
 
     // MSVC targets.
-    push_build_script_rustc_flags("windows_x86_64_msvc", &mut flags.msvc_x86_64);
+    for package_name in WINDOWS_MSVC_IMPORT_LIBS {
+        push_build_script_rustc_flags(package_name, &mut flags.msvc_x86_64);
+    }
 
     flags
 }
@@ -158,18 +171,18 @@ fn build_string_literal(s: &str) -> AstExpr {
 }
 
 /// Build a list of string literals
-fn build_string_list(items: &[String]) -> AstExpr {
+pub(super) fn build_string_list(items: &[String]) -> AstExpr {
     let list_items: Vec<AstExpr> = items.iter().map(|s| build_string_literal(s)).collect();
     spanned(ExprP::List(list_items))
 }
 
 /// Build an empty list
-fn build_empty_list() -> AstExpr {
+pub(super) fn build_empty_list() -> AstExpr {
     spanned(ExprP::List(vec![]))
 }
 
 /// Build a select() call with a dictionary argument
-fn build_select(entries: &[(&str, AstExpr)]) -> AstExpr {
+pub(super) fn build_select(entries: &[(&str, AstExpr)]) -> AstExpr {
     let dict_entries: Vec<(AstExpr, AstExpr)> = entries
         .iter()
         .map(|(k, v)| (build_string_literal(k), v.clone()))
@@ -190,7 +203,7 @@ fn build_select(entries: &[(&str, AstExpr)]) -> AstExpr {
 }
 
 /// Pretty-print an AST expression with proper indentation
-fn pretty_print_expr(expr: &AstExpr, out: &mut String, indent: usize) {
+pub(super) fn pretty_print_expr(expr: &AstExpr, out: &mut String, indent: usize) {
     match &expr.node {
         ExprP::Literal(AstLiteral::String(s)) => {
             write_string_literal(out, &s.node);
@@ -254,7 +267,7 @@ fn write_indent(out: &mut String, spaces: usize) {
     }
 }
 
-fn write_string_literal(out: &mut String, s: &str) {
+pub(super) fn write_string_literal(out: &mut String, s: &str) {
     out.push('"');
     for c in s.chars() {
         match c {
@@ -269,72 +282,247 @@ fn write_string_literal(out: &mut String, s: &str) {
     out.push('"');
 }
 
-fn apply_rustc_flags_patch_to_content(
+/// Append `select_expr` to the `rustc_flags` of every `(rule kind, name)` in `targets`.
+///
+/// The content is parsed once and all insertion points are applied in a single pass, so root
+/// packages with many binaries and tests don't re-parse the file for each of them.
+pub(super) fn apply_rustc_flags_patches_to_content(
     buck_content: &str,
-    rule_name: &str,
-    bin_name: &str,
+    targets: &Set<(&str, &str)>,
     select_expr: &str,
 ) -> String {
+    if targets.is_empty() {
+        return buck_content.to_owned();
+    }
+
     // Parse the Starlark content into an AST
     let ast = match AstModule::parse("BUCK", buck_content.to_owned(), &Dialect::Extended) {
         Ok(ast) => ast,
         Err(_) => return buck_content.to_owned(),
     };
 
-    // Find the insertion point by walking the AST
-    let insert_pos = match find_rustc_flags_end_in_rule(ast.statement(), rule_name, bin_name) {
-        Some(pos) => pos,
-        None => return buck_content.to_owned(),
-    };
+    // Find the insertion points by walking the AST
+    let mut insertions: Vec<(usize, &str)> = Vec::new();
+    collect_rustc_flags_positions(ast.statement(), targets, &mut insertions);
+    if insertions.is_empty() {
+        return buck_content.to_owned();
+    }
+    insertions.sort_by_key(|(pos, _)| *pos);
 
-    // Insert the select expression at the found position
-    let mut out = String::with_capacity(buck_content.len() + select_expr.len() + 4);
-    out.push_str(&buck_content[..insert_pos]);
-    out.push_str(" + ");
-    out.push_str(select_expr);
-    out.push_str(&buck_content[insert_pos..]);
+    // Insert the select expression at the found positions, front to back
+    let extra: usize = insertions
+        .iter()
+        .map(|(_, prefix)| prefix.len() + select_expr.len())
+        .sum();
+    let mut out = String::with_capacity(buck_content.len() + extra);
+    let mut last = 0;
+    for (pos, prefix) in insertions {
+        out.push_str(&buck_content[last..pos]);
+        out.push_str(prefix);
+        out.push_str(select_expr);
+        last = pos;
+    }
+    out.push_str(&buck_content[last..]);
     out
 }
 
-/// Walk the AST to find a rust rule call with the given name and return the
-/// byte position just after the closing `]` of its `rustc_flags` list.
-fn find_rustc_flags_end_in_rule(
+/// Byte range of the `kind(name = "<name>", ...)` rule call in `buck_content`, if any.
+pub(super) fn rule_call_range(
+    buck_content: &str,
+    kind: &str,
+    name: &str,
+) -> Option<std::ops::Range<usize>> {
+    let ast = AstModule::parse("BUCK", buck_content.to_owned(), &Dialect::Extended).ok()?;
+    let (span, _) = find_rule_call(ast.statement(), kind, name)?;
+    Some(span.begin().get() as usize..span.end().get() as usize)
+}
+
+/// Set the `kwarg` of the `kind(name = "<name>", ...)` rule call in `buck_content` to the
+/// expression `value`, or remove it if `value` is `None`. Returns `None` if there is no such
+/// rule.
+///
+/// A missing `kwarg` is added after the last of the `after` attributes in the call, to keep the
+/// order the rules are emitted in.
+pub(super) fn set_rule_kwarg(
+    buck_content: &str,
+    kind: &str,
+    name: &str,
+    (kwarg, after): (&str, &[&str]),
+    value: Option<&str>,
+) -> Option<String> {
+    let ast = AstModule::parse("BUCK", buck_content.to_owned(), &Dialect::Extended).ok()?;
+    let (span, args) = find_rule_call(ast.statement(), kind, name)?;
+    let existing = args.iter().find_map(|arg| match &arg.node {
+        ArgumentP::Named(arg_name, arg_value) if arg_name.node == kwarg => {
+            Some((arg.span, arg_value.span))
+        }
+        _ => None,
+    });
+
+    let mut out = buck_content.to_owned();
+    match (existing, value) {
+        (Some((_, value_span)), Some(value)) => {
+            let range = value_span.begin().get() as usize..value_span.end().get() as usize;
+            out.replace_range(range, value);
+        }
+        (Some((arg_span, _)), None) => {
+            // Remove the whole `kwarg = ...,` line
+            let start = arg_span.begin().get() as usize;
+            let start = out[..start].trim_end_matches([' ', '\t']).len();
+            let mut end = arg_span.end().get() as usize;
+            let rest = &out[end..];
+            if let Some(after_comma) = rest.trim_start_matches([' ', '\t']).strip_prefix(',') {
+                end = out.len() - after_comma.len();
+            }
+            if out[end..].starts_with('\n') && out[..start].ends_with('\n') {
+                end += 1;
+            }
+            out.replace_range(start..end, "");
+        }
+        (None, Some(value)) => {
+            let preceding = args
+                .iter()
+                .filter(|arg| {
+                    matches!(&arg.node, ArgumentP::Named(arg_name, _) if after.contains(&arg_name.node.as_str()))
+                })
+                .map(|arg| arg.span.end().get() as usize)
+                .max();
+            let pos = match preceding {
+                Some(end) => {
+                    let rest = out[end..].trim_start_matches([' ', '\t']);
+                    let rest = rest.strip_prefix(',').unwrap_or(rest);
+                    let rest = rest.strip_prefix('\n').unwrap_or(rest);
+                    out.len() - rest.len()
+                }
+                None => insert_pos_before_closing_paren(span)?,
+            };
+            let mut insert = String::new();
+            if needs_leading_comma(&out, pos) {
+                insert.push_str(",\n");
+            } else if !out[..pos].ends_with('\n') {
+                insert.push('\n');
+            }
+            insert.push_str(&format!("    {kwarg} = {value},\n"));
+            out.insert_str(pos, &insert);
+        }
+        (None, None) => {}
+    }
+    Some(out)
+}
+
+/// Append ` + expr` to the `kwarg` of the `kind(name = "<name>", ...)` rule call in
+/// `buck_content`, or set it to `expr` if missing. Returns `None` if there is no such rule.
+pub(super) fn append_to_rule_kwarg(
+    buck_content: &str,
+    kind: &str,
+    name: &str,
+    (kwarg, after): (&str, &[&str]),
+    expr: &str,
+) -> Option<String> {
+    let ast = AstModule::parse("BUCK", buck_content.to_owned(), &Dialect::Extended).ok()?;
+    let (_, args) = find_rule_call(ast.statement(), kind, name)?;
+    let existing_end = args.iter().find_map(|arg| match &arg.node {
+        ArgumentP::Named(arg_name, arg_value) if arg_name.node == kwarg => {
+            Some(arg_value.span.end().get() as usize)
+        }
+        _ => None,
+    });
+    match existing_end {
+        Some(end) => {
+            let mut out = buck_content.to_owned();
+            out.insert_str(end, &format!(" + {expr}"));
+            Some(out)
+        }
+        None => set_rule_kwarg(buck_content, kind, name, (kwarg, after), Some(expr)),
+    }
+}
+
+/// The span and arguments of the top-level `kind(name = "<name>", ...)` call.
+fn find_rule_call<'a>(
+    stmt: &'a AstStmt,
+    kind: &str,
+    name: &str,
+) -> Option<(Span, &'a [Spanned<ArgumentP<AstNoPayload>>])> {
+    let Stmt::Statements(stmts) = &stmt.node else {
+        return None;
+    };
+    stmts.iter().find_map(|stmt| match &stmt.node {
+        Stmt::Expression(expr) => match &expr.node {
+            ExprP::Call(callee, args)
+                if matches!(&callee.node, ExprP::Identifier(ident) if ident.node.ident == kind)
+                    && call_target_name(&args.args) == Some(name) =>
+            {
+                Some((expr.span, args.args.as_slice()))
+            }
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+/// Where to patch the `rustc_flags` of a rule.
+enum RustcFlagsPos {
+    /// Byte position just after the end of the `rustc_flags` value
+    End(usize),
+    /// Byte position just after the `name` value, for rules without `rustc_flags`
+    Missing(usize),
+}
+
+/// Walk the AST for rust rule calls in `targets`, collecting where to insert into their
+/// `rustc_flags` along with the text preceding the select expression.
+fn collect_rustc_flags_positions(
     stmt: &AstStmt,
-    rule_name: &str,
-    target_name: &str,
-) -> Option<usize> {
+    targets: &Set<(&str, &str)>,
+    insertions: &mut Vec<(usize, &'static str)>,
+) {
     match &stmt.node {
         Stmt::Statements(stmts) => {
             for s in stmts {
-                if let Some(pos) = find_rustc_flags_end_in_rule(s, rule_name, target_name) {
-                    return Some(pos);
+                collect_rustc_flags_positions(s, targets, insertions);
+            }
+        }
+        Stmt::Expression(expr) => {
+            if let ExprP::Call(callee, args) = &expr.node
+                && let ExprP::Identifier(ident) = &callee.node
+            {
+                let rule_name = ident.node.ident.as_str();
+                let Some(target_name) = call_target_name(&args.args) else {
+                    return;
+                };
+                if !targets.contains(&(rule_name, target_name)) {
+                    return;
+                }
+                match find_rustc_flags_in_call(&args.args, target_name) {
+                    Some(RustcFlagsPos::End(pos)) => insertions.push((pos, " + ")),
+                    // Rules without `rustc_flags` get the select as a new argument after `name`
+                    Some(RustcFlagsPos::Missing(pos)) => {
+                        insertions.push((pos, ",\n    rustc_flags = "))
+                    }
+                    None => {}
                 }
             }
-            None
         }
-        Stmt::Expression(expr) => find_in_expr(expr, rule_name, target_name),
-        _ => None,
+        _ => {}
     }
 }
 
-fn find_in_expr(expr: &AstExpr, rule_name: &str, target_name: &str) -> Option<usize> {
-    if let ExprP::Call(callee, args) = &expr.node {
-        // Check if this is a call to a target rule
-        if let ExprP::Identifier(ident) = &callee.node
-            && ident.node.ident == rule_name
-        {
-            return find_rustc_flags_in_call(&args.args, target_name);
-        }
-    }
-    None
+/// The `name` of a rule call, if it is a string literal.
+fn call_target_name(args: &[Spanned<ArgumentP<AstNoPayload>>]) -> Option<&str> {
+    args.iter().find_map(|arg| match &arg.node {
+        ArgumentP::Named(name, value) if name.node == "name" => match &value.node {
+            ExprP::Literal(AstLiteral::String(s)) => Some(s.node.as_str()),
+            _ => None,
+        },
+        _ => None,
+    })
 }
 
 fn find_rustc_flags_in_call(
     args: &[Spanned<ArgumentP<AstNoPayload>>],
     target_name: &str,
-) -> Option<usize> {
+) -> Option<RustcFlagsPos> {
     // First, check if the `name` argument matches
-    let mut name_matches = false;
+    let mut name_end: Option<usize> = None;
     let mut rustc_flags_end: Option<usize> = None;
 
     for arg in args {
@@ -345,26 +533,72 @@ fn find_rustc_flags_in_call(
                 if let ExprP::Literal(AstLiteral::String(s)) = &value.node
                     && s.node == target_name
                 {
-                    name_matches = true;
+                    name_end = Some(value.span.end().get() as usize);
                 }
             } else if arg_name == "rustc_flags" {
-                // Get the end position of the rustc_flags value (should be a list)
-                if let ExprP::List(_) = &value.node {
+                // Get the end position of the rustc_flags value (a list, possibly already
+                // concatenated with other patches)
+                if let ExprP::List(_) | ExprP::Op(_, BinOp::Add, _) = &value.node {
                     rustc_flags_end = Some(value.span.end().get() as usize);
                 }
             }
         }
     }
 
-    if name_matches { rustc_flags_end } else { None }
+    let name_end = name_end?;
+    Some(match rustc_flags_end {
+        Some(pos) => RustcFlagsPos::End(pos),
+        None => RustcFlagsPos::Missing(name_end),
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::RUST_CRATES_ROOT;
+    use crate::testing::{REGISTRY, mock_package, mock_target};
+    use cargo_metadata::TargetKind;
 
     use indoc::indoc;
 
+    /// Patch the `rustc_flags` of a single rule.
+    fn apply_rustc_flags_patch_to_content(
+        buck_content: &str,
+        rule_name: &str,
+        bin_name: &str,
+        select_expr: &str,
+    ) -> String {
+        apply_rustc_flags_patches_to_content(
+            buck_content,
+            &Set::from([(rule_name, bin_name)]),
+            select_expr,
+        )
+    }
+
+    #[test]
+    fn windows_import_lib_flags_follow_build_target_name() {
+        let mut package = mock_package(
+            "windows_x86_64_msvc",
+            "0.52.6",
+            &format!("{REGISTRY}#windows_x86_64_msvc@0.52.6"),
+            Some(REGISTRY),
+        );
+        package.targets = vec![mock_target("build-script-main", TargetKind::CustomBuild)];
+        let ctx = BuckalContext {
+            packages_map: [(package.id.clone(), package)].into(),
+            ..Default::default()
+        };
+
+        let flags = windows_import_lib_flags(&ctx);
+        assert_eq!(
+            flags.msvc_x86_64,
+            [format!(
+                "@$(location //{RUST_CRATES_ROOT}/windows_x86_64_msvc/0.52.6:windows_x86_64_msvc-build-script-main-run[rustc_flags])"
+            )]
+        );
+        assert!(flags.gnu.is_empty());
+    }
+
     #[test]
     fn render_windows_rustc_flags_select_empty() {
         let flags = WindowsImportLibFlags::default();
@@ -525,4 +759,67 @@ mod tests {
         );
         assert_eq!(patched, expected);
     }
+
+    #[test]
+    fn apply_rustc_flags_patch_to_content_adds_missing_rustc_flags() {
+        let input = indoc! {r#"
+            rust_binary(
+                name = "bin",
+                crate = "bin",
+            )
+            "#};
+
+        let expected = indoc! {r#"
+            rust_binary(
+                name = "bin",
+                rustc_flags = select({"DEFAULT": []}),
+                crate = "bin",
+            )
+            "#};
+
+        let patched = apply_rustc_flags_patch_to_content(
+            input,
+            "rust_binary",
+            "bin",
+            "select({\"DEFAULT\": []})",
+        );
+        assert_eq!(patched, expected);
+    }
+
+    #[test]
+    fn apply_rustc_flags_patches_to_content_many_binaries() {
+        let bin_count = 64;
+        let mut input = String::new();
+        for i in 0..bin_count {
+            input.push_str(&format!(
+                "rust_binary(\n    name = \"bin{i}\",\n    rustc_flags = [\n        \"flag{i}\",\n    ],\n)\n\n"
+            ));
+        }
+        input.push_str("rust_binary(\n    name = \"untouched\",\n    rustc_flags = [],\n)\n");
+
+        let names: Vec<String> = (0..bin_count).map(|i| format!("bin{i}")).collect();
+        let targets: Set<(&str, &str)> = names
+            .iter()
+            .map(|name| ("rust_binary", name.as_str()))
+            .collect();
+        let patched =
+            apply_rustc_flags_patches_to_content(&input, &targets, "select({\"DEFAULT\": []})");
+
+        // Same result as patching one binary at a time, with offsets kept in sync
+        let mut expected = input.clone();
+        for name in &names {
+            expected = apply_rustc_flags_patch_to_content(
+                &expected,
+                "rust_binary",
+                name,
+                "select({\"DEFAULT\": []})",
+            );
+        }
+        assert_eq!(patched, expected);
+        assert_eq!(
+            patched.matches("] + select({\"DEFAULT\": []})").count(),
+            bin_count
+        );
+        assert!(patched.ends_with("rustc_flags = [],\n)\n"));
+    }
 }
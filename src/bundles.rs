@@ -3,11 +3,14 @@ use std::io::Write;
 
 use anyhow::Result;
 use ini::Ini;
-use reqwest::blocking::Client;
+use reqwest::blocking::{Client, Response};
 use reqwest::header::USER_AGENT;
-use serde::Deserialize;
+use serde::{Deserialize, de::DeserializeOwned};
 
-use crate::{buckal_log, buckal_warn, user_agent};
+use crate::{
+    buckal_log, buckal_warn, user_agent,
+    utils::{max_download_size, read_limited},
+};
 
 type Section = String;
 type Lines = Vec<String>;
@@ -39,6 +42,23 @@ impl BuckConfig {
         Ok(Self::parse(contents))
     }
 
+    /// Load a config file that may not exist yet, like `.buckconfig.local`.
+    pub fn load_or_default(path: &std::path::Path) -> std::io::Result<Self> {
+        if path.exists() {
+            Self::load(path)
+        } else {
+            Ok(Self::default())
+        }
+    }
+
+    pub fn get(&self, section: &str, key: &str) -> Option<&str> {
+        self.ini.get_from(Some(section), key)
+    }
+
+    pub fn has_section(&self, section: &str) -> bool {
+        self.section_order.iter().any(|s| s == section)
+    }
+
     pub fn save(&self, path: &std::path::Path) -> std::io::Result<()> {
         std::fs::write(path, self.serialize())?;
         Ok(())
@@ -232,51 +252,173 @@ pub fn init_modifier(dest: &std::path::Path) -> Result<()> {
     Ok(())
 }
 
-pub fn init_buckal_cell(dest: &std::path::Path) -> Result<()> {
+/// Config file for machine-specific overrides, read by buck2 on top of `.buckconfig`.
+const LOCAL_BUCKCONFIG: &str = ".buckconfig.local";
+
+/// Register the buckal cell in `.buckconfig`, fetching the bundles from their latest commit.
+/// With `local`, the cell and its bundle source (git origin and commit) go to
+/// `.buckconfig.local` instead, keeping the committed config machine-agnostic.
+pub fn init_buckal_cell(dest: &std::path::Path, local: bool) -> Result<()> {
+    register_buckal_cell(dest, local)?;
+    save_bundle_source(dest, local, &latest_bundle_hash())
+}
+
+/// Register the buckal cell, leaving its bundle source to [`save_bundle_source`].
+fn register_buckal_cell(dest: &std::path::Path, local: bool) -> Result<()> {
     let mut buckconfig = BuckConfig::load(&dest.join(".buckconfig"))?;
-    buckconfig.upsert_kv("cells", "buckal", "buckal");
-    buckconfig.append_kv("external_cells", "buckal", "git");
-    buckconfig.insert_comment_before_key(
+    let mut local_buckconfig = if local {
+        Some(BuckConfig::load_or_default(&dest.join(LOCAL_BUCKCONFIG))?)
+    } else {
+        None
+    };
+
+    let cell_config = local_buckconfig.as_mut().unwrap_or(&mut buckconfig);
+    cell_config.upsert_kv("cells", "buckal", "buckal");
+    cell_config.append_kv("external_cells", "buckal", "git");
+    cell_config.insert_comment_before_key(
         "external_cells",
         "buckal",
         "Added by cargo-buckal. See [external_cell_buckal] for git configuration.",
     );
-    buckconfig.ensure_section_after("external_cells", "external_cell_buckal");
-    buckconfig.clear_section("external_cell_buckal");
-    buckconfig.upsert_kv(
-        "external_cell_buckal",
-        "git_origin",
-        &format!("https://github.com/{}", crate::BUCKAL_BUNDLES_REPO),
-    );
-    let commit_hash = match fetch() {
-        Ok(hash) => hash,
-        Err(e) => {
-            buckal_warn!(
-                "Failed to fetch latest bundle hash ({}), using default hash instead.",
-                e
-            );
-            crate::DEFAULT_BUNDLE_HASH.to_string()
-        }
-    };
-    buckconfig.upsert_kv("external_cell_buckal", "commit_hash", &commit_hash);
+    cell_config.ensure_section_after("external_cells", "external_cell_buckal");
+
     buckconfig.ensure_section("project");
     buckconfig.clear_section("project");
     buckconfig.upsert_kv("project", "ignore", ".git .buckal buck-out target");
     buckconfig.save(&dest.join(".buckconfig"))?;
-
+    if let Some(local_buckconfig) = local_buckconfig {
+        local_buckconfig.save(&dest.join(LOCAL_BUCKCONFIG))?;
+    }
     Ok(())
 }
 
+/// Update the buckal bundles to their latest commit, in whichever config file defines them.
 pub fn fetch_buckal_cell(dest: &std::path::Path) -> Result<()> {
-    let mut buckconfig = BuckConfig::load(&dest.join(".buckconfig"))?;
-    buckconfig.ensure_section("external_cell_buckal");
+    save_bundle_source(dest, bundle_source_is_local(dest)?, &latest_bundle_hash())
+}
+
+/// Whether `.buckconfig.local` defines the bundle source, overriding `.buckconfig`.
+fn bundle_source_is_local(dest: &std::path::Path) -> Result<bool> {
+    Ok(BuckConfig::load_or_default(&dest.join(LOCAL_BUCKCONFIG))?
+        .has_section("external_cell_buckal"))
+}
+
+/// Where the buckal bundles are fetched from.
+pub trait BundleRemote {
+    /// Hash of the latest commit.
+    fn latest(&self) -> Result<String>;
+    /// Whether `head` is an ancestor of `base`, i.e. moving from `base` to `head` goes back.
+    fn is_behind(&self, base: &str, head: &str) -> Result<bool>;
+}
+
+/// The buckal bundles repository on GitHub.
+pub struct GithubBundles;
+
+impl BundleRemote for GithubBundles {
+    fn latest(&self) -> Result<String> {
+        fetch()
+    }
+
+    fn is_behind(&self, base: &str, head: &str) -> Result<bool> {
+        let url = format!(
+            "https://api.github.com/repos/{}/compare/{}...{}",
+            crate::BUCKAL_BUNDLES_REPO,
+            base,
+            head
+        );
+        let response: GithubComparison = read_json(
+            Client::new()
+                .get(&url)
+                .header(USER_AGENT, user_agent())
+                .send()?
+                .error_for_status()?,
+        )?;
+        Ok(response.status == "behind")
+    }
+}
+
+/// The bundle commit of a project before and after `update_bundle`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BundleUpdate {
+    pub old: Option<String>,
+    pub new: String,
+}
+
+/// Point the project at `dest` to the bundle commit `to`, or to the latest one from `remote`.
+///
+/// The hash is written to whichever config file defines the bundle source.
+pub fn update_bundle(
+    dest: &std::path::Path,
+    to: Option<&str>,
+    remote: &impl BundleRemote,
+) -> Result<BundleUpdate> {
+    let new = match to {
+        Some(hash) => validate_commit_hash(hash)?,
+        None => validate_commit_hash(&remote.latest()?)?,
+    };
+
+    let local = bundle_source_is_local(dest)?;
+    let path = dest.join(if local {
+        LOCAL_BUCKCONFIG
+    } else {
+        ".buckconfig"
+    });
+    let old = BuckConfig::load_or_default(&path)?
+        .get("external_cell_buckal", "commit_hash")
+        .map(str::to_owned);
+
+    // Force-pushes to the bundles repository can make the latest commit an older one
+    if to.is_none()
+        && let Some(old) = &old
+        && *old != new
+    {
+        match remote.is_behind(old, &new) {
+            Ok(true) => buckal_warn!(
+                "The latest bundle commit {} is older than the current one {}",
+                new,
+                old
+            ),
+            Ok(false) => {}
+            Err(e) => buckal_warn!("Failed to compare bundle commits: {}", e),
+        }
+    }
+
+    save_bundle_source(dest, local, &new)?;
+    Ok(BundleUpdate { old, new })
+}
+
+/// A full, 40 hex digit commit hash, as Buck2 requires for git external cells.
+fn validate_commit_hash(hash: &str) -> Result<String> {
+    let hash = hash.trim();
+    if hash.len() != 40 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        anyhow::bail!("`{}` is not a full 40 character commit hash", hash);
+    }
+    Ok(hash.to_ascii_lowercase())
+}
+
+/// Write the `[external_cell_buckal]` section to `.buckconfig`, or `.buckconfig.local`.
+fn save_bundle_source(dest: &std::path::Path, local: bool, commit_hash: &str) -> Result<()> {
+    let path = dest.join(if local {
+        LOCAL_BUCKCONFIG
+    } else {
+        ".buckconfig"
+    });
+    let mut buckconfig = BuckConfig::load_or_default(&path)?;
+    buckconfig.ensure_section_after("external_cells", "external_cell_buckal");
     buckconfig.clear_section("external_cell_buckal");
     buckconfig.upsert_kv(
         "external_cell_buckal",
         "git_origin",
         &format!("https://github.com/{}", crate::BUCKAL_BUNDLES_REPO),
     );
-    let commit_hash = match fetch() {
+    buckconfig.upsert_kv("external_cell_buckal", "commit_hash", commit_hash);
+    buckconfig.save(&path)?;
+
+    Ok(())
+}
+
+fn latest_bundle_hash() -> String {
+    match fetch() {
         Ok(hash) => hash,
         Err(e) => {
             buckal_warn!(
@@ -285,11 +427,7 @@ pub fn fetch_buckal_cell(dest: &std::path::Path) -> Result<()> {
             );
             crate::DEFAULT_BUNDLE_HASH.to_string()
         }
-    };
-    buckconfig.upsert_kv("external_cell_buckal", "commit_hash", &commit_hash);
-    buckconfig.save(&dest.join(".buckconfig"))?;
-
-    Ok(())
+    }
 }
 
 #[derive(Deserialize)]
@@ -297,6 +435,11 @@ struct GithubCommit {
     sha: String,
 }
 
+#[derive(Deserialize)]
+struct GithubComparison {
+    status: String,
+}
+
 pub fn fetch() -> Result<String> {
     let url = format!(
         "https://api.github.com/repos/{}/commits",
@@ -307,18 +450,29 @@ pub fn fetch() -> Result<String> {
         format!("https://github.com/{}", crate::BUCKAL_BUNDLES_REPO)
     );
     let client = Client::new();
-    let response: Vec<GithubCommit> = client
-        .get(&url)
-        .header(USER_AGENT, user_agent())
-        .query(&[("per_page", "1")])
-        .send()?
-        .json()?;
+    let response: Vec<GithubCommit> = read_json(
+        client
+            .get(&url)
+            .header(USER_AGENT, user_agent())
+            .query(&[("per_page", "1")])
+            .send()?,
+    )?;
     Ok(response[0].sha.clone())
 }
 
+/// Decode a JSON response, within the `--max-download-size` limit.
+fn read_json<T: DeserializeOwned>(response: Response) -> Result<T> {
+    let content_length = response.content_length();
+    let body = read_limited(response, content_length, max_download_size())?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::BuckConfig;
+    use super::{
+        BuckConfig, BundleRemote, BundleUpdate, LOCAL_BUCKCONFIG, register_buckal_cell,
+        save_bundle_source, update_bundle,
+    };
     use indoc::indoc;
 
     #[test]
@@ -458,4 +612,116 @@ mod tests {
         "#};
         assert_eq!(output, expected.trim_end());
     }
+
+    #[test]
+    fn save_bundle_source_to_local_buckconfig() {
+        let dest = crate::testing::temp_dir("buckconfig-local").into_std_path_buf();
+        std::fs::create_dir_all(&dest).unwrap();
+        let shared = indoc! {r#"
+            [cells]
+              root = .
+              buckal = buckal
+
+            [external_cells]
+              buckal = git
+        "#};
+        std::fs::write(dest.join(".buckconfig"), shared).unwrap();
+
+        save_bundle_source(&dest, true, "deadbeef").unwrap();
+        let local = std::fs::read_to_string(dest.join(LOCAL_BUCKCONFIG)).unwrap();
+        let shared_after = std::fs::read_to_string(dest.join(".buckconfig")).unwrap();
+        std::fs::remove_dir_all(&dest).unwrap();
+
+        assert_eq!(shared_after, shared);
+        assert_eq!(
+            local,
+            format!(
+                "[external_cell_buckal]\n  commit_hash = deadbeef\n  git_origin = https://github.com/{}",
+                crate::BUCKAL_BUNDLES_REPO
+            )
+        );
+    }
+
+    #[test]
+    fn register_buckal_cell_in_local_buckconfig() {
+        let dest = crate::testing::temp_dir("register-local").into_std_path_buf();
+        std::fs::create_dir_all(&dest).unwrap();
+        std::fs::write(dest.join(".buckconfig"), "[cells]\n  root = .\n").unwrap();
+
+        register_buckal_cell(&dest, true).unwrap();
+        save_bundle_source(&dest, true, "deadbeef").unwrap();
+        let shared = std::fs::read_to_string(dest.join(".buckconfig")).unwrap();
+        let local = std::fs::read_to_string(dest.join(LOCAL_BUCKCONFIG)).unwrap();
+        std::fs::remove_dir_all(&dest).unwrap();
+
+        // Nothing about the buckal cell is committed
+        assert!(!shared.contains("buckal = "), "{shared}");
+        assert!(
+            shared.contains("ignore = .git .buckal buck-out target"),
+            "{shared}"
+        );
+        assert_eq!(
+            local,
+            format!(
+                "[cells]\n  buckal = buckal\n\n[external_cells]\n  \
+                 # Added by cargo-buckal. See [external_cell_buckal] for git configuration.\n  \
+                 buckal = git\n\n[external_cell_buckal]\n  commit_hash = deadbeef\n  \
+                 git_origin = https://github.com/{}",
+                crate::BUCKAL_BUNDLES_REPO
+            )
+        );
+    }
+
+    struct MockRemote {
+        latest: &'static str,
+    }
+
+    impl BundleRemote for MockRemote {
+        fn latest(&self) -> anyhow::Result<String> {
+            Ok(self.latest.to_owned())
+        }
+
+        fn is_behind(&self, _base: &str, _head: &str) -> anyhow::Result<bool> {
+            Ok(false)
+        }
+    }
+
+    #[test]
+    fn update_bundle_to_latest_and_pinned_hash() {
+        let dest = crate::testing::temp_dir("bundle-update").into_std_path_buf();
+        std::fs::create_dir_all(&dest).unwrap();
+        let old = "1".repeat(40);
+        std::fs::write(
+            dest.join(".buckconfig"),
+            format!("[external_cell_buckal]\n  commit_hash = {old}\n"),
+        )
+        .unwrap();
+
+        let latest = MockRemote {
+            latest: "ABCDEF0123456789ABCDEF0123456789ABCDEF01",
+        };
+        let update = update_bundle(&dest, None, &latest).unwrap();
+        assert_eq!(
+            update,
+            BundleUpdate {
+                old: Some(old),
+                new: "abcdef0123456789abcdef0123456789abcdef01".to_owned(),
+            }
+        );
+
+        let pinned = "2".repeat(40);
+        let update = update_bundle(&dest, Some(&pinned), &latest).unwrap();
+        assert_eq!(
+            update.old.as_deref(),
+            Some("abcdef0123456789abcdef0123456789abcdef01")
+        );
+        assert_eq!(update.new, pinned);
+
+        let invalid = update_bundle(&dest, Some("abc123"), &latest);
+        let buckconfig = std::fs::read_to_string(dest.join(".buckconfig")).unwrap();
+        std::fs::remove_dir_all(&dest).unwrap();
+
+        assert!(invalid.is_err());
+        assert!(buckconfig.contains(&format!("commit_hash = {pinned}")));
+    }
 }
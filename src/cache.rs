@@ -1,20 +1,32 @@
 use std::collections::{BTreeMap, HashMap};
+use std::process::Command;
 
-use anyhow::{Error, Result, anyhow};
-use cargo_metadata::{Node, PackageId, camino::Utf8PathBuf};
+use anyhow::{Context, Error, Result, anyhow, bail};
+use cargo_metadata::{
+    MetadataCommand, Node, NodeDep, PackageId,
+    camino::{Utf8Path, Utf8PathBuf},
+};
 use serde::{Deserialize, Serialize};
 
-use crate::utils::{UnwrapOrExit, get_cache_path};
+use crate::{
+    buckify::dependent_oses_in,
+    context::{BuckalContext, reverse_deps},
+    platform::Os,
+    utils::{UnwrapOrExit, get_cache_path},
+};
 
 // type Fingerprint = [u8; 32];
 
 /// CACHE_VERSION is incremented whenever the cache format or logic changes in a way that is not backward-compatible.
 ///
 /// Version 2: Added multi-platform support to the cache format.
+/// Version 3: Fingerprints cover the edges from each package's dependents.
+/// Version 4: Fingerprints cover the OSes each package is used on.
+/// Version 5: Added the fingerprint of the repository configuration.
 ///
 /// Migration strategy: There is no automatic migration; if a cache version mismatch is detected, the old cache is ignored and a new cache is created.
 /// This ensures correctness at the cost of recomputation.
-const CACHE_VERSION: u32 = 2;
+const CACHE_VERSION: u32 = 5;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Fingerprint([u8; 32]);
@@ -44,16 +56,18 @@ impl<'de> Deserialize<'de> for Fingerprint {
     }
 }
 
-pub trait BuckalHash {
-    fn fingerprint(&self) -> Fingerprint;
-}
-
-impl BuckalHash for Node {
-    fn fingerprint(&self) -> Fingerprint {
-        let encoded = bincode::serde::encode_to_vec(self, bincode::config::standard())
+/// Fingerprint of a package: its node, along with what its rules derive from the rest of the
+/// graph: the edges from its dependents (e.g. for the visibility of its build script) and the
+/// OSes it is used on (for its compatibility).
+fn package_fingerprint(
+    node: &Node,
+    dependents: &[(PackageId, NodeDep)],
+    used_oses: &[&str],
+) -> Fingerprint {
+    let encoded =
+        bincode::serde::encode_to_vec((node, dependents, used_oses), bincode::config::standard())
             .expect("Serialization failed");
-        Fingerprint(blake3::hash(&encoded).into())
-    }
+    Fingerprint(blake3::hash(&encoded).into())
 }
 
 pub trait PackageIdExt {
@@ -98,17 +112,35 @@ impl PackageIdExt for PackageId {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BuckalCache {
     fingerprints: BTreeMap<PackageId, Fingerprint>,
+    /// Fingerprint of the `buckal.toml` config the BUCK files were generated with, which every
+    /// rule depends on (e.g. through `naming`)
+    config: Option<Fingerprint>,
     version: u32,
 }
 
 impl BuckalCache {
-    pub fn new(resolve: &HashMap<PackageId, Node>, workspace_root: &Utf8PathBuf) -> Self {
+    /// Snapshot of the graph `resolve`, with the workspace of `ctx`.
+    pub fn new(resolve: &HashMap<PackageId, Node>, ctx: &BuckalContext) -> Self {
+        let dependents = reverse_deps(resolve);
         let fingerprints = resolve
             .iter()
-            .map(|(id, node)| (id.canonicalize(workspace_root), node.fingerprint()))
+            .map(|(id, node)| {
+                let edges = dependents.get(id).map_or(&[][..], Vec::as_slice);
+                let used_oses: Vec<_> = dependent_oses_in(id, &dependents, &ctx.workspace_members)
+                    .into_iter()
+                    .flatten()
+                    .map(Os::key)
+                    .collect();
+                (
+                    id.canonicalize(&ctx.workspace_root),
+                    package_fingerprint(node, edges, &used_oses),
+                )
+            })
             .collect();
+        let config = serde_json::to_vec(&ctx.repo_config).expect("Serialization failed");
         Self {
             fingerprints,
+            config: Some(Fingerprint(blake3::hash(&config).into())),
             version: CACHE_VERSION,
         }
     }
@@ -116,10 +148,83 @@ impl BuckalCache {
     pub fn new_empty() -> Self {
         Self {
             fingerprints: BTreeMap::new(),
+            config: None,
             version: CACHE_VERSION,
         }
     }
 
+    /// Snapshot of the dependency graph at the git revision `rev`, for re-emitting only the
+    /// packages whose dependency edges changed since then.
+    ///
+    /// Without changes to any `Cargo.toml` or `Cargo.lock` since `rev`, this is the current graph.
+    /// Otherwise `cargo metadata` runs in a temporary worktree checked out at `rev`.
+    pub fn at_git_ref(rev: &str, ctx: &BuckalContext) -> Result<Self> {
+        let workspace_root = &ctx.workspace_root;
+        let status = Command::new("git")
+            .arg("-C")
+            .arg(workspace_root)
+            .args(["diff", "--quiet", rev, "--"])
+            .args([":(glob)**/Cargo.toml", ":(glob)**/Cargo.lock"])
+            .status()
+            .context("failed to run `git diff`")?;
+        match status.code() {
+            Some(0) => return Ok(Self::new(&ctx.nodes_map, ctx)),
+            Some(1) => {}
+            _ => bail!("failed to diff the manifests against `{}`", rev),
+        }
+
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(workspace_root)
+            .args(["rev-parse", "--show-toplevel"])
+            .output()
+            .context("failed to run `git rev-parse`")?;
+        let toplevel = Utf8PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+        let canonical_root = Utf8PathBuf::from_path_buf(workspace_root.canonicalize()?)
+            .map_err(|path| anyhow!("non UTF-8 workspace root `{}`", path.display()))?;
+        let relative = canonical_root
+            .strip_prefix(&toplevel)
+            .with_context(|| format!("`{}` is not in a git repository", workspace_root))?;
+
+        let worktree = Utf8PathBuf::from_path_buf(std::env::temp_dir())
+            .map_err(|path| anyhow!("non UTF-8 temporary directory `{}`", path.display()))?
+            .join(format!("cargo-buckal-since-{}", std::process::id()));
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(&toplevel)
+            .args(["worktree", "add", "--detach", "--quiet"])
+            .arg(&worktree)
+            .arg(rev)
+            .output()
+            .context("failed to run `git worktree add`")?;
+        if !output.status.success() {
+            bail!(
+                "failed to check out `{}`: {}",
+                rev,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        let metadata = MetadataCommand::new()
+            .current_dir(worktree.join(relative))
+            .exec();
+        let _ = Command::new("git")
+            .arg("-C")
+            .arg(&toplevel)
+            .args(["worktree", "remove", "--force"])
+            .arg(&worktree)
+            .output();
+
+        let metadata =
+            metadata.with_context(|| format!("failed to get cargo metadata at `{}`", rev))?;
+        let nodes = metadata
+            .resolve
+            .ok_or_else(|| anyhow!("cargo metadata at `{}` has no dependency graph", rev))?
+            .nodes;
+        let nodes = rebase_nodes(nodes, &metadata.workspace_root, workspace_root);
+        Ok(Self::new(&nodes, ctx))
+    }
+
     pub fn load() -> Result<Self, Error> {
         let cache_path = get_cache_path().unwrap_or_exit_ctx("failed to get cache path");
         if !cache_path.exists() {
@@ -145,11 +250,13 @@ impl BuckalCache {
         std::fs::write(cache_path, format!("{}\n{}", comment, content)).unwrap_or_exit();
     }
 
+    /// The changes from `other` to `self`. A change of config changes every package.
     pub fn diff(&self, other: &BuckalCache, workspace_root: &Utf8PathBuf) -> BuckalChange {
         let mut _diff = BuckalChange::default();
+        let config_changed = self.config != other.config;
         for (id, fp) in &self.fingerprints {
             if let Some(other_fp) = other.fingerprints.get(id) {
-                if fp != other_fp {
+                if config_changed || fp != other_fp {
                     _diff
                         .changes
                         .insert(id.resolve(workspace_root), ChangeType::Changed);
@@ -173,6 +280,42 @@ impl BuckalCache {
     }
 }
 
+/// Move the path packages of `nodes` from the workspace at `from` to the one at `to`, so that
+/// graphs resolved from two checkouts of the same workspace fingerprint alike.
+pub fn rebase_nodes(nodes: Vec<Node>, from: &Utf8Path, to: &Utf8Path) -> HashMap<PackageId, Node> {
+    nodes
+        .into_iter()
+        .map(|mut node| {
+            node.id = rebase_id(&node.id, from, to);
+            for dep in &mut node.deps {
+                dep.pkg = rebase_id(&dep.pkg, from, to);
+            }
+            for id in &mut node.dependencies {
+                *id = rebase_id(id, from, to);
+            }
+            (node.id.clone(), node)
+        })
+        .collect()
+}
+
+/// Rebase the ID of a path package under `from`, e.g. `path+file:///from/app#0.1.0`, leaving any
+/// other ID as is.
+fn rebase_id(id: &PackageId, from: &Utf8Path, to: &Utf8Path) -> PackageId {
+    let Some(rest) = id.repr.strip_prefix("path+file://") else {
+        return id.clone();
+    };
+    let (path, fragment) = rest.split_at(rest.find('#').unwrap_or(rest.len()));
+    match Utf8Path::new(path).strip_prefix(from) {
+        Ok(relative) if relative.as_str().is_empty() => PackageId {
+            repr: format!("path+file://{to}{fragment}"),
+        },
+        Ok(relative) => PackageId {
+            repr: format!("path+file://{}{fragment}", to.join(relative)),
+        },
+        Err(_) => id.clone(),
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct BuckalChange {
     pub changes: BTreeMap<PackageId, ChangeType>,
@@ -184,3 +327,46 @@ pub enum ChangeType {
     Removed,
     Changed,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{REGISTRY, mock_dep, mock_node};
+
+    #[test]
+    fn test_rebase_nodes() {
+        let serde = format!("{REGISTRY}#serde@1.0.0");
+        let nodes = vec![
+            mock_node(
+                "path+file:///tmp/worktree#app@0.1.0",
+                serde_json::json!([
+                    mock_dep("core", "path+file:///tmp/worktree/core#0.1.0"),
+                    mock_dep("serde", &serde)
+                ]),
+                &[],
+            ),
+            // Outside the workspace, though its path starts with the same characters
+            mock_node(
+                "path+file:///tmp/worktree2/core#0.1.0",
+                serde_json::json!([]),
+                &[],
+            ),
+        ];
+
+        let nodes = rebase_nodes(nodes, "/tmp/worktree".into(), "/ws".into());
+        let mut ids: Vec<_> = nodes.keys().map(|id| id.repr.as_str()).collect();
+        ids.sort();
+        assert_eq!(
+            ids,
+            [
+                "path+file:///tmp/worktree2/core#0.1.0",
+                "path+file:///ws#app@0.1.0"
+            ]
+        );
+        let app = &nodes[&PackageId {
+            repr: "path+file:///ws#app@0.1.0".to_owned(),
+        }];
+        let deps: Vec<_> = app.deps.iter().map(|dep| dep.pkg.repr.as_str()).collect();
+        assert_eq!(deps, ["path+file:///ws/core#0.1.0", serde.as_str()]);
+    }
+}
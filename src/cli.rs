@@ -1,6 +1,15 @@
+use anyhow::bail;
+use cargo_metadata::camino::Utf8PathBuf;
 use clap::Parser;
 
-use crate::{build_version, commands};
+use crate::{
+    build_version, commands,
+    config::{parse_config_override, set_config_overrides},
+    utils::{
+        ColorChoice, UnwrapOrExit, parse_size, set_color_choice, set_log_file,
+        set_max_download_size, set_metadata_files, set_toolchain,
+    },
+};
 
 #[derive(Parser, Debug)]
 #[command(bin_name = "cargo")]
@@ -25,6 +34,80 @@ pub struct BuckalArgs {
     pub subcommands: Option<BuckalSubCommands>,
     #[arg(long, short = 'V', help = "Print version")]
     pub version: bool,
+    #[arg(
+        long,
+        value_enum,
+        value_name = "WHEN",
+        default_value_t = ColorChoice::Auto,
+        global = true,
+        help = "Coloring: auto, always, never"
+    )]
+    pub color: ColorChoice,
+    #[arg(
+        long,
+        value_name = "NAME",
+        global = true,
+        help = "Rust toolchain used for rustc queries (defaults to RUSTUP_TOOLCHAIN)"
+    )]
+    pub toolchain: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        global = true,
+        help = "Also append buckal's output, with timestamps and levels, to this file"
+    )]
+    pub log_file: Option<Utf8PathBuf>,
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        global = true,
+        help = "Abort downloads larger than this size (default 256MiB), and check crate archives against it (e.g. 64MiB)"
+    )]
+    pub max_download_size: Option<u64>,
+    #[arg(
+        long = "config",
+        value_name = "KEY=VALUE",
+        value_parser = parse_config_override,
+        global = true,
+        help = "Override a `buckal.toml` field for this run (e.g. ignore_tests=true)"
+    )]
+    pub config: Vec<toml::Table>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        global = true,
+        conflicts_with = "metadata",
+        help = "Write the resolved `cargo metadata` to this file, for later runs to reuse"
+    )]
+    pub dump_metadata: Option<Utf8PathBuf>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        global = true,
+        help = "Read `cargo metadata` from a file written by `--dump-metadata` instead of running cargo (not with add/remove/update)"
+    )]
+    pub metadata: Option<Utf8PathBuf>,
+}
+
+impl BuckalArgs {
+    /// Reject `--metadata` for the commands that edit manifests, as they re-resolve the
+    /// workspace after the edit and a metadata file can only describe it from before.
+    fn check_metadata_file(&self) -> anyhow::Result<()> {
+        if self.metadata.is_some()
+            && let Some(
+                BuckalSubCommands::Add(_)
+                | BuckalSubCommands::Remove(_)
+                | BuckalSubCommands::Update(_),
+            ) = &self.subcommands
+        {
+            bail!(
+                "`--metadata` can't be used with `add`, `remove` or `update`, which change the \
+                 manifests it was resolved from"
+            );
+        }
+        Ok(())
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -38,18 +121,31 @@ pub enum BuckalSubCommands {
     /// Compile the current package
     Build(crate::commands::build::BuildArgs),
 
+    /// Update the buckal bundles to the latest commit, or the one given with `--to`
+    BundleUpdate(crate::commands::bundle_update::BundleUpdateArgs),
+
     /// Remove generated artifacts
     Clean(crate::commands::clean::CleanArgs),
 
+    /// Print the effective `buckal.toml` configuration, with defaults and `--config` overrides
+    Config(crate::commands::config::ConfigArgs),
+
+    /// Show how regenerating BUCK files would change them, without writing anything
+    Diff(crate::commands::diff::DiffArgs),
+
     /// Create a new package in an existing directory
     Init(crate::commands::init::InitArgs),
 
     /// Migrate existing Cargo packages to Buck2
+    #[command(visible_alias = "sync")]
     Migrate(crate::commands::migrate::MigrateArgs),
 
     /// Create a new package
     New(crate::commands::new::NewArgs),
 
+    /// Query the Buck2 target graph, excluding third-party targets by default
+    Query(crate::commands::query::QueryArgs),
+
     /// Remove dependencies from a manifest file
     Remove(crate::commands::remove::RemoveArgs),
 
@@ -58,12 +154,30 @@ pub enum BuckalSubCommands {
 
     /// Update dependencies in a manifest file
     Update(crate::commands::update::UpdateArgs),
+
+    /// Inspect and prune vendored third-party crates
+    Vendor(crate::commands::vendor::VendorArgs),
+
+    /// Build every vendored third-party crate to check its generated rules
+    Verify(crate::commands::verify::VerifyArgs),
 }
 
 impl Cli {
     pub fn run(&self) {
         match &self.command {
             Commands::Buckal(args) => {
+                set_color_choice(args.color);
+                set_toolchain(args.toolchain.clone());
+                if let Some(size) = args.max_download_size {
+                    set_max_download_size(size);
+                }
+                set_config_overrides(args.config.clone());
+                args.check_metadata_file().unwrap_or_exit();
+                set_metadata_files(args.dump_metadata.clone(), args.metadata.clone());
+                if let Some(log_file) = &args.log_file {
+                    set_log_file(Some(log_file))
+                        .unwrap_or_exit_ctx(format!("failed to open log file `{}`", log_file));
+                }
                 if args.version {
                     println!("buckal {}", build_version());
                     return;
@@ -73,13 +187,21 @@ impl Cli {
                         BuckalSubCommands::Add(args) => commands::add::execute(args),
                         BuckalSubCommands::Autoremove(args) => commands::autoremove::execute(args),
                         BuckalSubCommands::Build(args) => commands::build::execute(args),
+                        BuckalSubCommands::BundleUpdate(args) => {
+                            commands::bundle_update::execute(args)
+                        }
                         BuckalSubCommands::Clean(args) => commands::clean::execute(args),
+                        BuckalSubCommands::Config(args) => commands::config::execute(args),
+                        BuckalSubCommands::Diff(args) => commands::diff::execute(args),
                         BuckalSubCommands::Init(args) => commands::init::execute(args),
                         BuckalSubCommands::Migrate(args) => commands::migrate::execute(args),
                         BuckalSubCommands::New(args) => commands::new::execute(args),
+                        BuckalSubCommands::Query(args) => commands::query::execute(args),
                         BuckalSubCommands::Remove(args) => commands::remove::execute(args),
                         BuckalSubCommands::Test(args) => commands::test::execute(args),
                         BuckalSubCommands::Update(args) => commands::update::execute(args),
+                        BuckalSubCommands::Vendor(args) => commands::vendor::execute(args),
+                        BuckalSubCommands::Verify(args) => commands::verify::execute(args),
                     },
                     None => {
                         // If no subcommand is provided, print help information
@@ -97,6 +219,81 @@ mod tests {
     use super::*;
     use crate::utils::validate_target_triple;
 
+    #[test]
+    fn test_cli_color_is_global() {
+        let cli = Cli::try_parse_from(["cargo", "buckal", "build", "--color", "never"])
+            .expect("failed to parse build args with --color");
+
+        match cli.command {
+            Commands::Buckal(args) => assert_eq!(args.color, ColorChoice::Never),
+        }
+    }
+
+    #[test]
+    fn test_cli_metadata_file_flags() {
+        let cli = Cli::try_parse_from(["cargo", "buckal", "sync", "--dump-metadata", "meta.json"])
+            .expect("failed to parse sync args with --dump-metadata");
+        match cli.command {
+            Commands::Buckal(args) => {
+                assert_eq!(args.dump_metadata.as_deref(), Some("meta.json".into()))
+            }
+        }
+
+        assert!(
+            Cli::try_parse_from([
+                "cargo",
+                "buckal",
+                "build",
+                "--metadata",
+                "meta.json",
+                "--dump-metadata",
+                "meta.json",
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_cli_metadata_file_rejected_for_manifest_edits() {
+        let args = |argv: &[&str]| match Cli::try_parse_from(argv).unwrap().command {
+            Commands::Buckal(args) => args,
+        };
+        for command in ["add", "remove", "update"] {
+            let args = args(&[
+                "cargo",
+                "buckal",
+                command,
+                "serde",
+                "--metadata",
+                "meta.json",
+            ]);
+            assert!(args.check_metadata_file().is_err(), "{command}");
+        }
+        assert!(
+            args(&["cargo", "buckal", "sync", "--metadata", "meta.json"])
+                .check_metadata_file()
+                .is_ok()
+        );
+        assert!(
+            args(&["cargo", "buckal", "add", "serde"])
+                .check_metadata_file()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_cli_sync_is_alias_for_migrate() {
+        let cli = Cli::try_parse_from(["cargo", "buckal", "sync", "--force"])
+            .expect("failed to parse sync args with --force");
+
+        match cli.command {
+            Commands::Buckal(args) => match args.subcommands {
+                Some(BuckalSubCommands::Migrate(migrate_args)) => assert!(migrate_args.no_cache),
+                other => panic!("expected migrate subcommand, got {other:?}"),
+            },
+        }
+    }
+
     #[test]
     fn test_cli_build_accepts_target() {
         let cli = Cli::try_parse_from([
@@ -60,9 +60,9 @@ pub fn execute(args: &AddArgs) {
     let _ = MetadataCommand::new().exec();
 
     let ctx = BuckalContext::new();
-    flush_root(&ctx);
+    flush_root(&ctx, false);
 
-    let new_cache = BuckalCache::new(&ctx.nodes_map, &ctx.workspace_root);
+    let new_cache = BuckalCache::new(&ctx.nodes_map, &ctx);
     let changes = new_cache.diff(&last_cache, &ctx.workspace_root);
 
     changes.apply(&ctx);
@@ -1,12 +1,13 @@
 use clap::Parser;
 use serde::Deserialize;
 
+use super::test::resolve_exclude_patterns;
 use crate::{
     buck2::Buck2Command,
-    buckal_error, buckal_log,
+    buckal_error, buckal_log, buckal_warn,
     utils::{
-        UnwrapOrExit, check_buck2_package, ensure_prerequisites, get_buck2_root, get_target,
-        platform_exists, validate_target_triple,
+        UnwrapOrExit, cargo_metadata, check_buck2_package, ensure_prerequisites, get_buck2_root,
+        get_target, platform_exists, validate_target_platform, validate_target_triple,
     },
 };
 
@@ -51,6 +52,10 @@ pub struct BuildArgs {
     /// Build for the target platform (passed to buck2 `--target-platforms`)
     #[arg(long, value_name = "PLATFORM", conflicts_with = "target")]
     pub target_platforms: Option<String>,
+
+    /// Exclude packages from the build
+    #[arg(long, value_name = "SPEC")]
+    pub exclude: Vec<String>,
 }
 
 impl BuildArgs {
@@ -91,7 +96,7 @@ pub fn execute(args: &BuildArgs) {
     args.validate_target_selection().unwrap_or_exit();
 
     // Get the root directory of the Buck2 project
-    let buck2_root = get_buck2_root().unwrap_or_exit_ctx("failed to get Buck2 project root");
+    let buck2_root = get_buck2_root().unwrap_or_exit();
     let cwd = std::env::current_dir().unwrap_or_exit_ctx("failed to get current directory");
     let relative = cwd.strip_prefix(&buck2_root).ok();
 
@@ -124,6 +129,15 @@ pub fn execute(args: &BuildArgs) {
         get_available_targets(&relative_path)
     };
 
+    let targets = if args.exclude.is_empty() {
+        targets
+    } else {
+        let metadata = cargo_metadata().unwrap_or_exit_ctx("failed to fetch cargo metadata");
+        let exclude_patterns = resolve_exclude_patterns(&args.exclude, &metadata, &buck2_root)
+            .unwrap_or_exit_ctx("failed to resolve excluded packages");
+        exclude_targets(targets, &exclude_patterns)
+    };
+
     if targets.is_empty() {
         buckal_error!("no targets found matching the specified criteria");
         std::process::exit(1);
@@ -139,7 +153,13 @@ pub fn execute(args: &BuildArgs) {
             }
         }
     } else if let Some(platform) = &args.target_platforms {
-        Some(platform.clone())
+        match validate_target_platform(platform) {
+            Ok(platform) => Some(platform),
+            Err(e) => {
+                buckal_error!(e);
+                std::process::exit(1);
+            }
+        }
     } else {
         let platform = format!("//platforms:{}", get_target());
         if platform_exists(&platform) {
@@ -179,6 +199,38 @@ pub fn execute(args: &BuildArgs) {
     }
 }
 
+/// Drop targets living under any of the excluded `//path/...` patterns.
+///
+/// Recursive patterns among `targets` (the fallback when listing targets fails) can't be
+/// narrowed this way and are kept, with a warning.
+fn exclude_targets(targets: Vec<String>, exclude_patterns: &[String]) -> Vec<String> {
+    targets
+        .into_iter()
+        .filter(|target| {
+            if target.ends_with("...") {
+                buckal_warn!(
+                    "cannot apply --exclude to the recursive pattern `{}`",
+                    target
+                );
+                return true;
+            }
+            !exclude_patterns
+                .iter()
+                .any(|pattern| pattern_contains_target(pattern, target))
+        })
+        .collect()
+}
+
+/// Whether a recursive `//path/...` pattern covers the `//package:name` target.
+fn pattern_contains_target(pattern: &str, target: &str) -> bool {
+    let Some(prefix) = pattern.strip_suffix("...") else {
+        return false;
+    };
+    let package = target.split(':').next().unwrap_or(target);
+    // `//...` covers everything, `//foo/...` covers `//foo` and everything below it
+    prefix == "//" || package == prefix.trim_end_matches('/') || package.starts_with(prefix)
+}
+
 /// Build specific targets based on target selection arguments
 fn build_specific_targets(args: &BuildArgs, relative_path: &str) -> Vec<String> {
     let mut targets = Vec::new();
@@ -279,12 +331,18 @@ fn get_available_targets_by_kind(relative_path: &str, include_tests: bool) -> Ve
     }
 }
 
-fn filter_root_third_party(mut targets: Vec<String>, relative_path: &str) -> Vec<String> {
+/// Leave out third-party, toolchain and platform targets, unless run from a subdirectory.
+/// Labels may carry the `root` cell prefix, e.g. `root//third-party/...`.
+pub(super) fn filter_root_third_party(
+    mut targets: Vec<String>,
+    relative_path: &str,
+) -> Vec<String> {
     if !relative_path.is_empty() {
         return targets;
     }
 
     targets.retain(|target| {
+        let target = target.strip_prefix("root").unwrap_or(target);
         !target.starts_with("//third-party/")
             && !target.starts_with("//toolchains/")
             && !target.starts_with("//platforms/")
@@ -497,6 +555,7 @@ mod tests {
             all_targets: false,
             target: None,
             target_platforms: None,
+            exclude: vec![],
         };
         assert!(args.validate_target_selection().is_ok());
 
@@ -511,6 +570,7 @@ mod tests {
             all_targets: false,
             target: None,
             target_platforms: None,
+            exclude: vec![],
         };
         assert!(args.validate_target_selection().is_ok());
 
@@ -526,6 +586,7 @@ mod tests {
             all_targets: true,
             target: None,
             target_platforms: None,
+            exclude: vec![],
         };
         assert!(args.validate_target_selection().is_ok());
 
@@ -541,6 +602,7 @@ mod tests {
             all_targets: true,
             target: None,
             target_platforms: None,
+            exclude: vec![],
         };
         assert!(args.validate_target_selection().is_err());
     }
@@ -558,6 +620,7 @@ mod tests {
             all_targets: false,
             target: None,
             target_platforms: None,
+            exclude: vec![],
         };
         assert!(!args.has_target_selection());
 
@@ -572,6 +635,7 @@ mod tests {
             all_targets: false,
             target: None,
             target_platforms: None,
+            exclude: vec![],
         };
         assert!(args.has_target_selection());
 
@@ -586,6 +650,7 @@ mod tests {
             all_targets: false,
             target: None,
             target_platforms: None,
+            exclude: vec![],
         };
         assert!(args.has_target_selection());
 
@@ -600,6 +665,7 @@ mod tests {
             all_targets: true,
             target: None,
             target_platforms: None,
+            exclude: vec![],
         };
         assert!(args.has_target_selection());
     }
@@ -617,6 +683,7 @@ mod tests {
             all_targets: false,
             target: None,
             target_platforms: None,
+            exclude: vec![],
         };
         assert!(!args.has_other_target_selection());
 
@@ -631,6 +698,7 @@ mod tests {
             all_targets: false,
             target: None,
             target_platforms: None,
+            exclude: vec![],
         };
         assert!(args.has_other_target_selection());
 
@@ -645,6 +713,7 @@ mod tests {
             all_targets: false,
             target: None,
             target_platforms: None,
+            exclude: vec![],
         };
         assert!(args.has_other_target_selection());
 
@@ -659,6 +728,7 @@ mod tests {
             all_targets: true,
             target: None,
             target_platforms: None,
+            exclude: vec![],
         };
         assert!(!args.has_other_target_selection());
     }
@@ -874,6 +944,7 @@ mod tests {
             all_targets: false,
             target: None,
             target_platforms: None,
+            exclude: vec![],
         };
 
         assert!(args.has_target_selection());
@@ -890,4 +961,32 @@ mod tests {
         let extracted = extract_target_name(target, "");
         assert_eq!(extracted, "myapp");
     }
+
+    #[test]
+    fn test_exclude_targets() {
+        let targets = vec![
+            "//:root_bin".to_string(),
+            "//crates/foo:foo".to_string(),
+            "//crates/foo/sub:helper".to_string(),
+            "//crates/foobar:foobar".to_string(),
+            "//crates/bar:bar".to_string(),
+        ];
+
+        let kept = exclude_targets(targets.clone(), &["//crates/foo/...".to_string()]);
+        assert_eq!(
+            kept,
+            vec!["//:root_bin", "//crates/foobar:foobar", "//crates/bar:bar"]
+        );
+
+        // Excluding the root package excludes everything
+        assert!(exclude_targets(targets.clone(), &["//...".to_string()]).is_empty());
+        assert_eq!(exclude_targets(targets.clone(), &[]), targets);
+
+        // Recursive fallback patterns can't be narrowed
+        let fallback = vec!["//crates/foo/...".to_string()];
+        assert_eq!(
+            exclude_targets(fallback.clone(), &["//crates/foo/...".to_string()]),
+            fallback
+        );
+    }
 }
@@ -0,0 +1,28 @@
+use clap::Parser;
+
+use crate::{
+    buckal_log, buckal_note,
+    bundles::{BundleUpdate, GithubBundles, update_bundle},
+    utils::{UnwrapOrExit, get_buck2_root},
+};
+
+#[derive(Parser, Debug)]
+pub struct BundleUpdateArgs {
+    /// Pin the bundles to this commit instead of the latest one
+    #[arg(long, value_name = "HASH")]
+    pub to: Option<String>,
+}
+
+pub fn execute(args: &BundleUpdateArgs) {
+    let buck2_root = get_buck2_root().unwrap_or_exit();
+
+    let BundleUpdate { old, new } =
+        update_bundle(buck2_root.as_std_path(), args.to.as_deref(), &GithubBundles)
+            .unwrap_or_exit_ctx("failed to update buckal bundles");
+
+    match old {
+        Some(old) if old == new => buckal_note!("buckal bundles are already at {}", new),
+        Some(old) => buckal_log!("Updated", format!("buckal bundles {} -> {}", old, new)),
+        None => buckal_log!("Updated", format!("buckal bundles to {}", new)),
+    }
+}
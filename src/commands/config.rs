@@ -0,0 +1,36 @@
+use clap::Parser;
+
+use crate::{
+    RUST_CRATES_ROOT,
+    config::RepoConfig,
+    utils::{UnwrapOrExit, ensure_prerequisites, get_buck2_root, get_cache_path},
+};
+
+#[derive(Parser, Debug)]
+pub struct ConfigArgs {}
+
+pub fn execute(_args: &ConfigArgs) {
+    // Ensure all prerequisites are installed before proceeding
+    ensure_prerequisites().unwrap_or_exit();
+
+    let buck2_root = get_buck2_root().unwrap_or_exit_ctx("failed to get buck2 root");
+    let repo_config = RepoConfig::load();
+
+    // Paths are printed as comments, so the output stays a valid `buckal.toml`
+    println!("# buck2 root: {}", buck2_root);
+    println!(
+        "# repo config: {}",
+        RepoConfig::repo_config_path().display()
+    );
+    println!("# vendor root: {}", buck2_root.join(RUST_CRATES_ROOT));
+    println!(
+        "# cache: {}",
+        get_cache_path().unwrap_or_exit_ctx("failed to get cache path")
+    );
+    print!(
+        "{}",
+        repo_config
+            .to_toml()
+            .unwrap_or_exit_ctx("failed to serialize the repo config")
+    );
+}
@@ -0,0 +1,104 @@
+use cargo_metadata::camino::Utf8Path;
+use clap::Parser;
+use colored::Colorize;
+use similar::TextDiff;
+
+use crate::{
+    buckal_note,
+    buckify::render_buck_files,
+    context::BuckalContext,
+    utils::{UnwrapOrExit, ensure_prerequisites, get_buck2_root},
+};
+
+#[derive(Parser, Debug)]
+pub struct DiffArgs {
+    /// Only compare the BUCK files of these packages
+    #[arg(short, long, value_name = "NAME")]
+    pub package: Vec<String>,
+}
+
+pub fn execute(args: &DiffArgs) {
+    // Ensure all prerequisites are installed before proceeding
+    ensure_prerequisites().unwrap_or_exit();
+
+    let buck2_root = get_buck2_root().unwrap_or_exit();
+
+    // Merge manual edits of `patch_fields` so they aren't reported as changes
+    let mut ctx = BuckalContext::new();
+    ctx.no_merge = false;
+
+    let mut changed = 0;
+    for (buck_path, generated) in render_buck_files(&ctx, &args.package).unwrap_or_exit() {
+        let existing = std::fs::read_to_string(&buck_path).unwrap_or_default();
+        let display_path = buck_path.strip_prefix(&buck2_root).unwrap_or(&buck_path);
+        if let Some(diff) = render_diff(display_path, &existing, &generated) {
+            print!("{diff}");
+            changed += 1;
+        }
+    }
+
+    if changed == 0 {
+        buckal_note!("BUCK files are up to date");
+    } else {
+        buckal_note!("{} BUCK file(s) would change", changed);
+    }
+}
+
+/// Render a colored unified diff from the on-disk BUCK file to the generated one, or `None` if
+/// they are identical.
+fn render_diff(path: &Utf8Path, existing: &str, generated: &str) -> Option<String> {
+    if existing == generated {
+        return None;
+    }
+
+    let diff = TextDiff::from_lines(existing, generated);
+    let unified = diff
+        .unified_diff()
+        .context_radius(3)
+        .header(&format!("a/{path}"), &format!("b/{path}"))
+        .to_string();
+
+    let mut out = String::with_capacity(unified.len());
+    for line in unified.split_inclusive('\n') {
+        let colored = if line.starts_with("+++") || line.starts_with("---") {
+            line.bold()
+        } else if line.starts_with('+') {
+            line.green()
+        } else if line.starts_with('-') {
+            line.red()
+        } else if line.starts_with("@@") {
+            line.cyan()
+        } else {
+            line.normal()
+        };
+        out.push_str(&colored.to_string());
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_diff() {
+        colored::control::set_override(false);
+        let path = Utf8Path::new("third-party/rust/crates/foo/1.0.0/BUCK");
+        let existing = "rust_library(\n    name = \"foo\",\n    edition = \"2018\",\n)\n";
+        let generated = "rust_library(\n    name = \"foo\",\n    edition = \"2021\",\n)\n";
+
+        assert!(render_diff(path, existing, existing).is_none());
+        let diff = render_diff(path, existing, generated).unwrap();
+        assert_eq!(
+            diff,
+            "--- a/third-party/rust/crates/foo/1.0.0/BUCK\n\
+             +++ b/third-party/rust/crates/foo/1.0.0/BUCK\n\
+             @@ -1,4 +1,4 @@\n \
+             rust_library(\n     \
+             name = \"foo\",\n\
+             -    edition = \"2018\",\n\
+             +    edition = \"2021\",\n \
+             )\n"
+        );
+    }
+}
@@ -35,6 +35,9 @@ pub struct InitArgs {
     /// Set up a Buck2 project with a simple package
     #[arg(long, default_value = "false", conflicts_with = "repo")]
     pub lite: bool,
+    /// Register the buckal cell and its bundle source in `.buckconfig.local` instead of `.buckconfig`
+    #[arg(long, default_value = "false")]
+    pub local: bool,
 }
 
 pub fn execute(args: &InitArgs) {
@@ -92,10 +95,13 @@ pub fn execute(args: &InitArgs) {
             .open(".gitignore")
             .unwrap_or_exit();
         writeln!(git_ignore, "/buck-out").unwrap_or_exit();
+        if args.local {
+            writeln!(git_ignore, "/.buckconfig.local").unwrap_or_exit();
+        }
 
         // Configure the buckal cell in .buckconfig
         let cwd = std::env::current_dir().unwrap_or_exit();
-        init_buckal_cell(&cwd).unwrap_or_exit();
+        init_buckal_cell(&cwd, args.local).unwrap_or_exit();
 
         extract_buck2_assets(&cwd).unwrap_or_exit_ctx("failed to extract buck2 assets");
 
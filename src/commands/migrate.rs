@@ -1,24 +1,39 @@
 use std::{fs::OpenOptions, io::Write, path::PathBuf};
 
+use cargo_metadata::camino::Utf8PathBuf;
+
 use clap::Parser;
 
 use crate::{
     RUST_CRATES_ROOT,
     assets::extract_buck2_assets,
     buck2::Buck2Command,
-    buckal_error,
-    buckify::flush_root,
+    buckal_error, buckal_note,
+    buckify::{
+        BuckalReport, BuckifyStats, ProjectToolchain, buildozer_script, check_cycles,
+        dependency_graph_dot, dependents_of, ensure_generated_buck_files, flush_members,
+        flush_packages, flush_root, generate_checksums_manifest, generate_third_party_aliases,
+        generate_vendor_gitignore, print_dropped_deps, refresh_cargo_manifests, rust_project_json,
+    },
     bundles::{fetch_buckal_cell, init_buckal_cell, init_modifier},
     cache::BuckalCache,
     context::BuckalContext,
-    utils::{UnwrapOrExit, ensure_prerequisites, get_buck2_root},
+    platform::check_host_triple,
+    utils::{
+        UnwrapOrExit, ensure_buck_files_clean, ensure_prerequisites, get_buck2_root, get_target,
+        warning_count,
+    },
 };
 
 #[derive(Parser, Debug)]
 pub struct MigrateArgs {
-    /// Do not use cached data from previous runs
-    #[clap(long, name = "no-cache")]
+    /// Ignore the existing snapshot and regenerate BUCK files for every package, overwriting
+    /// first-party BUCK files that cargo-buckal didn't generate
+    #[clap(long, name = "no-cache", visible_alias = "force")]
     pub no_cache: bool,
+    /// Only regenerate packages changed since the last snapshot (default)
+    #[clap(long, conflicts_with = "no-cache")]
+    pub keep_cache: bool,
     /// Merge manual edits with generated content
     #[clap(long)]
     pub merge: bool,
@@ -28,11 +43,96 @@ pub struct MigrateArgs {
     /// Fetch latest bundles from remote repository
     #[clap(long)]
     pub fetch: bool,
+    /// Write a JSON summary of added, changed and removed crates to this path
+    #[clap(long, value_name = "PATH")]
+    pub report: Option<Utf8PathBuf>,
+    /// Only write third-party crates, leaving the BUCK files of first-party packages untouched
+    #[clap(long)]
+    pub separate: bool,
+    /// How to apply the generated rules
+    #[clap(long, value_enum, default_value_t = OutputFormat::Files)]
+    pub format: OutputFormat,
+    /// Print totals of the crates and rules generated
+    #[clap(long)]
+    pub stats: bool,
+    /// Only regenerate the given crate and every package depending on it, transitively
+    #[clap(long, value_name = "CRATE", conflicts_with_all = ["no-cache", "separate", "report"])]
+    pub dependents_of: Option<String>,
+    /// Only regenerate packages whose dependencies changed since this git revision, instead of
+    /// since the last snapshot
+    #[clap(long, value_name = "REF", conflicts_with_all = ["no-cache", "dependents_of"])]
+    pub since: Option<String>,
+    /// Number of packages to buckify in parallel (defaults to the number of CPUs)
+    #[clap(long, short = 'j', value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub jobs: Option<usize>,
+    /// Sync even if generated BUCK files have uncommitted changes
+    #[clap(long)]
+    pub allow_dirty: bool,
+    /// Leave `third-party/rust/BUCK` untouched, even with `inherit_workspace_deps`
+    #[clap(long, conflicts_with = "aliases_only")]
+    pub no_aliases: bool,
+    /// Only regenerate the third-party alias rules in `third-party/rust/BUCK`
+    #[clap(long, conflicts_with_all = ["no-cache", "separate", "report", "dependents_of", "since"])]
+    pub aliases_only: bool,
+    /// Only regenerate the `cargo_manifest` rule of the given crate and the `env` of its Rust
+    /// rules (e.g. after editing `[package.metadata.buckal]`), leaving the rest of its BUCK file
+    /// untouched
+    #[clap(long, value_name = "CRATE", conflicts_with_all = ["no-cache", "separate", "report", "dependents_of", "since", "aliases_only"])]
+    pub manifest_only: Option<String>,
+    /// Ignore vendored crate sources in `third-party/rust/crates/.gitignore`, keeping the
+    /// generated BUCK files tracked
+    #[clap(long)]
+    pub write_gitignore: bool,
+    /// Print a table of every dependency left out of the generated rules, and why
+    #[clap(long)]
+    pub explain_drop: bool,
+    /// Write the resolved dependency graph to this path, in Graphviz DOT format
+    #[clap(long, value_name = "PATH")]
+    pub print_graph: Option<Utf8PathBuf>,
+    /// Write a `rust-project.json` describing every crate to this path (`rust-project.json` by
+    /// default), so that rust-analyzer understands the project without cargo
+    #[clap(long, value_name = "PATH", default_missing_value = "rust-project.json", num_args = 0..=1)]
+    pub emit_compile_commands: Option<Utf8PathBuf>,
+    /// Exit with an error if any warning was printed during the sync
+    #[clap(long)]
+    pub fail_on_warn: bool,
+    /// Carry on past packages that fail to be buckified, and report them at the end
+    #[clap(long)]
+    pub keep_going: bool,
+    /// Fail before generating anything if first-party packages depend on each other in a
+    /// cycle, dev-dependencies included
+    #[clap(long)]
+    pub check_cycles: bool,
+}
+
+/// How `migrate` applies the generated rules
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Rewrite the BUCK files
+    #[default]
+    Files,
+    /// Print buildozer commands reconciling the existing BUCK files, without writing anything
+    Buildozer,
 }
 
 pub fn execute(args: &MigrateArgs) {
+    migrate(args);
+    check_warnings(args.fail_on_warn).unwrap_or_exit();
+}
+
+/// With `--fail-on-warn`, turn the warnings printed so far into an error.
+fn check_warnings(fail_on_warn: bool) -> anyhow::Result<()> {
+    let count = warning_count();
+    if fail_on_warn && count > 0 {
+        anyhow::bail!("{} warning(s) emitted with `--fail-on-warn`", count);
+    }
+    Ok(())
+}
+
+fn migrate(args: &MigrateArgs) {
     // Ensure all prerequisites are installed before proceeding
     ensure_prerequisites().unwrap_or_exit();
+    check_host_triple(&get_target());
 
     // Initialize Buck2 project if requested
     // Compared to `cargo buckal init`, here we only setup Buck2 related files
@@ -73,9 +173,7 @@ pub fn execute(args: &MigrateArgs) {
             cwd.display()
         ));
 
-        let buck2_root = existing_root.unwrap_or_else(|| {
-            get_buck2_root().unwrap_or_exit_ctx("failed to get Buck2 project root")
-        });
+        let buck2_root = existing_root.unwrap_or_else(|| get_buck2_root().unwrap_or_exit());
 
         let crates_dir = buck2_root.join(RUST_CRATES_ROOT);
         std::fs::create_dir_all(&crates_dir).unwrap_or_exit_ctx(format!(
@@ -90,7 +188,7 @@ pub fn execute(args: &MigrateArgs) {
         writeln!(git_ignore, "/buck-out").unwrap_or_exit();
 
         // Configure the buckal cell in .buckconfig
-        init_buckal_cell(buck2_root.as_std_path()).unwrap_or_exit();
+        init_buckal_cell(buck2_root.as_std_path(), false).unwrap_or_exit();
 
         extract_buck2_assets(buck2_root.as_std_path())
             .unwrap_or_exit_ctx("failed to extract buck2 assets");
@@ -105,24 +203,407 @@ pub fn execute(args: &MigrateArgs) {
         fetch_buckal_cell(&cwd).unwrap_or_exit();
     }
 
+    if args.write_gitignore {
+        generate_vendor_gitignore();
+    }
+
     // get cargo metadata and generate context
     let mut ctx = BuckalContext::new();
     ctx.no_merge = !args.merge;
+    ctx.separate = args.separate;
+    if let Some(jobs) = args.jobs {
+        ctx.jobs = jobs;
+    }
+
+    if args.check_cycles {
+        check_cycles(&ctx).unwrap_or_exit();
+    }
+
+    if let Some(graph_path) = &args.print_graph {
+        std::fs::write(graph_path, dependency_graph_dot(&ctx)).unwrap_or_exit_ctx(format!(
+            "failed to write the dependency graph to `{}`",
+            graph_path
+        ));
+    }
+
+    if args.format == OutputFormat::Buildozer {
+        print!("{}", buildozer_script(&ctx, &[]));
+        return;
+    }
+
+    // Manual edits outside `patch_fields` would be lost
+    if !args.allow_dirty {
+        ensure_buck_files_clean(&get_buck2_root().unwrap_or_exit()).unwrap_or_exit();
+    }
+    // Hand-written BUCK files would be lost altogether
+    if !args.no_cache && !ctx.separate {
+        ensure_generated_buck_files(&ctx).unwrap_or_exit();
+    }
+
+    if args.aliases_only {
+        let buck_file = generate_third_party_aliases(&ctx).unwrap_or_exit();
+        if args.stats {
+            BuckifyStats::of_written(&[buck_file]).print();
+        }
+        return;
+    }
+
+    if let Some(name) = &args.manifest_only {
+        let buck_files = refresh_cargo_manifests(&ctx, name).unwrap_or_exit();
+        if args.stats {
+            BuckifyStats::of_written(&buck_files).print();
+        }
+        return;
+    }
+
+    if let Some(name) = &args.dependents_of {
+        let affected = dependents_of(&ctx, name).unwrap_or_exit();
+        let buck_files = flush_packages(&ctx, &affected);
+        buckal_note!(
+            "Regenerated `{}` and {} dependent package(s)",
+            name,
+            affected.len().saturating_sub(1)
+        );
+        if args.stats {
+            BuckifyStats::of_written(&buck_files).print();
+        }
+        if args.explain_drop {
+            print_dropped_deps();
+        }
+        // Packages outside the affected set may have pending changes, so the snapshot is
+        // kept for the next full sync.
+        return;
+    }
 
     // Process the root node
-    flush_root(&ctx);
+    let (mut buck_files, mut failures) = if ctx.separate {
+        (Vec::new(), Vec::new())
+    } else if args.no_aliases {
+        flush_members(&ctx, args.keep_going)
+    } else {
+        flush_root(&ctx, args.keep_going)
+    };
     // Process dep nodes
-    let last_cache = if args.no_cache || BuckalCache::load().is_err() {
+    let last_cache = if args.no_cache {
         BuckalCache::new_empty()
+    } else if let Some(rev) = &args.since {
+        BuckalCache::at_git_ref(rev, &ctx)
+            .unwrap_or_exit_ctx(format!("failed to resolve dependencies at `{}`", rev))
     } else {
-        BuckalCache::load().unwrap_or_exit_ctx("failed to load existing cache")
+        BuckalCache::load().unwrap_or_else(|_| BuckalCache::new_empty())
     };
-    let new_cache = BuckalCache::new(&ctx.nodes_map, &ctx.workspace_root);
+    let new_cache = BuckalCache::new(&ctx.nodes_map, &ctx);
     let changes = new_cache.diff(&last_cache, &ctx.workspace_root);
 
+    if ctx.separate {
+        let mut skipped: Vec<String> = ctx
+            .workspace_members
+            .iter()
+            .filter_map(|id| ctx.packages_map.get(id))
+            .map(|package| package.name.to_string())
+            .collect();
+        skipped.extend(changes.separated_packages(&ctx));
+        buckal_note!(
+            "--separate: skipping first-party packages: {}",
+            skipped.join(", ")
+        );
+        if !changes.has_third_party_changes(&ctx) {
+            buckal_note!("No third-party crates changed, nothing to do");
+            return;
+        }
+    }
+
     // Apply changes to BUCK files
-    changes.apply(&ctx);
+    if args.keep_going {
+        let (written, apply_failures) = changes.apply_keep_going(&ctx);
+        buck_files.extend(written);
+        failures.extend(apply_failures);
+    } else {
+        buck_files.extend(changes.apply(&ctx));
+    }
+
+    if ctx.repo_config.checksums_manifest {
+        generate_checksums_manifest(&ctx);
+    }
 
-    // Flush the new cache
-    new_cache.save();
+    if let Some(report_path) = &args.report {
+        BuckalReport::new(&changes, &ctx, &buck_files)
+            .write(report_path)
+            .unwrap_or_exit_ctx(format!("failed to write report to `{}`", report_path));
+    }
+
+    if args.stats {
+        BuckifyStats::of_written(&buck_files).print();
+    }
+
+    if args.explain_drop {
+        print_dropped_deps();
+    }
+
+    // Written once the BUCK files are, as the proc-macros are built from them
+    if let Some(project_path) = &args.emit_compile_commands {
+        let toolchain = ProjectToolchain::detect(&ctx)
+            .unwrap_or_exit_ctx("failed to query the toolchain for rust-project.json");
+        let project = serde_json::to_string_pretty(&rust_project_json(&ctx, &toolchain))
+            .unwrap_or_exit_ctx("failed to serialize rust-project.json");
+        std::fs::write(project_path, project + "\n").unwrap_or_exit_ctx(format!(
+            "failed to write rust-project.json to `{}`",
+            project_path
+        ));
+    }
+
+    if !failures.is_empty() {
+        for failure in &failures {
+            buckal_error!("failed to buckify `{}`: {}", failure.package, failure.error);
+        }
+        // The snapshot is kept, so the failed packages are retried by the next sync
+        buckal_error!("{} package(s) failed to buckify", failures.len());
+        std::process::exit(1);
+    }
+
+    // Flush the new cache, even when the previous one was ignored. A separate sync leaves
+    // first-party changes unapplied, and `--since` skips changes made before the revision, so
+    // the snapshot is kept for the next full sync.
+    if !ctx.separate && args.since.is_none() {
+        new_cache.save();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::ChangeType;
+    use crate::testing::{REGISTRY, mock_dep, mock_node};
+    use cargo_metadata::Node;
+    use std::collections::HashMap;
+
+    fn mock_nodes(ids: &[&str]) -> HashMap<cargo_metadata::PackageId, Node> {
+        ids.iter()
+            .map(|id| {
+                let node = mock_node(id, serde_json::json!([]), &[]);
+                (node.id.clone(), node)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fail_on_warn() {
+        let args = MigrateArgs::try_parse_from(["migrate", "--fail-on-warn"]).unwrap();
+        assert!(args.fail_on_warn);
+
+        crate::buckal_warn!("something looks off");
+        assert!(warning_count() > 0);
+        assert!(check_warnings(true).is_err());
+        assert!(check_warnings(false).is_ok());
+    }
+
+    #[test]
+    fn test_force_is_alias_for_no_cache() {
+        let args = MigrateArgs::try_parse_from(["migrate", "--force"]).unwrap();
+        assert!(args.no_cache);
+        assert!(MigrateArgs::try_parse_from(["migrate", "--no-cache", "--keep-cache"]).is_err());
+    }
+
+    #[test]
+    fn test_jobs_must_be_positive() {
+        let args = MigrateArgs::try_parse_from(["migrate", "--jobs", "1"]).unwrap();
+        assert_eq!(args.jobs, Some(1));
+        assert!(MigrateArgs::try_parse_from(["migrate", "--jobs", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_alias_flags_conflict() {
+        let args = MigrateArgs::try_parse_from(["migrate", "--no-aliases"]).unwrap();
+        assert!(args.no_aliases && !args.aliases_only);
+        let args = MigrateArgs::try_parse_from(["migrate", "--aliases-only"]).unwrap();
+        assert!(args.aliases_only);
+        assert!(
+            MigrateArgs::try_parse_from(["migrate", "--no-aliases", "--aliases-only"]).is_err()
+        );
+        assert!(
+            MigrateArgs::try_parse_from(["migrate", "--aliases-only", "--dependents-of", "foo"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_manifest_only_conflicts_with_full_sync() {
+        let args = MigrateArgs::try_parse_from(["migrate", "--manifest-only", "foo"]).unwrap();
+        assert_eq!(args.manifest_only.as_deref(), Some("foo"));
+        assert!(
+            MigrateArgs::try_parse_from(["migrate", "--manifest-only", "foo", "--no-cache"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_emit_compile_commands_default_path() {
+        let args = MigrateArgs::try_parse_from(["migrate", "--emit-compile-commands"]).unwrap();
+        assert_eq!(
+            args.emit_compile_commands.as_deref(),
+            Some(Utf8PathBuf::from("rust-project.json").as_path())
+        );
+        let args = MigrateArgs::try_parse_from(["migrate"]).unwrap();
+        assert!(args.emit_compile_commands.is_none());
+    }
+
+    #[test]
+    fn test_format_defaults_to_files() {
+        let args = MigrateArgs::try_parse_from(["migrate"]).unwrap();
+        assert_eq!(args.format, OutputFormat::Files);
+        let args = MigrateArgs::try_parse_from(["migrate", "--format", "buildozer"]).unwrap();
+        assert_eq!(args.format, OutputFormat::Buildozer);
+    }
+
+    #[test]
+    fn test_since_skips_third_party_on_first_party_change() {
+        let serde_id = format!("{REGISTRY}#serde@1.0.0");
+        let node = |root: &str, features: &[&str]| -> Node {
+            mock_node(
+                &format!("path+file://{root}/app#0.1.0"),
+                serde_json::json!([mock_dep("serde", &serde_id)]),
+                features,
+            )
+        };
+        let serde = mock_nodes(&[&serde_id]);
+        let workspace_root = Utf8PathBuf::from("/ws");
+        let ctx = BuckalContext {
+            workspace_root: workspace_root.clone(),
+            ..Default::default()
+        };
+
+        // The graph at the revision, resolved in a worktree elsewhere
+        let mut old_nodes: Vec<Node> = serde.values().cloned().collect();
+        old_nodes.push(node("/tmp/worktree", &[]));
+        let old_nodes =
+            crate::cache::rebase_nodes(old_nodes, "/tmp/worktree".into(), workspace_root.as_path());
+        let since = BuckalCache::new(&old_nodes, &ctx);
+
+        let mut new_nodes = serde.clone();
+        let app = node("/ws", &["extra"]);
+        new_nodes.insert(app.id.clone(), app.clone());
+        let changes = BuckalCache::new(&new_nodes, &ctx).diff(&since, &workspace_root);
+
+        assert_eq!(changes.changes.len(), 1);
+        assert!(matches!(changes.changes[&app.id], ChangeType::Changed));
+    }
+
+    #[test]
+    fn test_new_dependent_changes_fingerprint() {
+        let workspace_root = Utf8PathBuf::from("/ws");
+        let ctx = BuckalContext {
+            workspace_root: workspace_root.clone(),
+            ..Default::default()
+        };
+        let cc = &format!("{REGISTRY}#cc@1.0.0");
+        let mut nodes = mock_nodes(&[cc]);
+        let before = BuckalCache::new(&nodes, &ctx);
+
+        // `cc` itself is unchanged, but the rules granting visibility to its dependents aren't
+        let dependent = mock_node(
+            &format!("{REGISTRY}#ring@0.17.0"),
+            serde_json::json!([{
+                "name": "cc",
+                "pkg": cc,
+                "dep_kinds": [{ "kind": "build", "target": null }]
+            }]),
+            &[],
+        );
+        nodes.insert(dependent.id.clone(), dependent.clone());
+        let changes = BuckalCache::new(&nodes, &ctx).diff(&before, &workspace_root);
+
+        assert_eq!(changes.changes.len(), 2);
+        assert!(matches!(
+            changes.changes[&cargo_metadata::PackageId {
+                repr: cc.to_owned()
+            }],
+            ChangeType::Changed
+        ));
+        assert!(matches!(changes.changes[&dependent.id], ChangeType::Added));
+    }
+
+    #[test]
+    fn test_used_oses_change_fingerprint() {
+        let node = |id: &str, dep: &str, target: Option<&str>| -> Node {
+            mock_node(
+                id,
+                serde_json::json!([{
+                    "name": dep,
+                    "pkg": dep,
+                    "dep_kinds": [{ "kind": null, "target": target }]
+                }]),
+                &[],
+            )
+        };
+        let app = node("app", "winapi-util", Some("cfg(windows)"));
+        let mut nodes = mock_nodes(&["winapi"]);
+        nodes.insert(app.id.clone(), app.clone());
+        let util = node("winapi-util", "winapi", None);
+        nodes.insert(util.id.clone(), util);
+        let ctx = BuckalContext {
+            workspace_root: Utf8PathBuf::from("/ws"),
+            workspace_members: vec![app.id.clone()],
+            ..Default::default()
+        };
+        let before = BuckalCache::new(&nodes, &ctx);
+
+        // `winapi` and its edges are unchanged, but it is now used on every OS
+        let app = node("app", "winapi-util", None);
+        nodes.insert(app.id.clone(), app.clone());
+        let changes = BuckalCache::new(&nodes, &ctx).diff(&before, &ctx.workspace_root);
+
+        let changed: Vec<_> = changes.changes.keys().map(|id| id.repr.as_str()).collect();
+        assert_eq!(changed, ["app", "winapi", "winapi-util"]);
+    }
+
+    #[test]
+    fn test_config_change_reemits_all_packages() {
+        let mut ctx = BuckalContext {
+            workspace_root: Utf8PathBuf::from("/ws"),
+            ..Default::default()
+        };
+        let nodes = mock_nodes(&[
+            "registry+https://github.com/rust-lang/crates.io-index#foo@0.1.0",
+            "registry+https://github.com/rust-lang/crates.io-index#bar@0.2.0",
+        ]);
+        let before = BuckalCache::new(&nodes, &ctx);
+
+        ctx.repo_config.naming.manifest = "-cargo-manifest".to_owned();
+        let changes = BuckalCache::new(&nodes, &ctx).diff(&before, &ctx.workspace_root);
+        assert_eq!(changes.changes.len(), nodes.len());
+        assert!(
+            changes
+                .changes
+                .values()
+                .all(|c| matches!(c, ChangeType::Changed))
+        );
+    }
+
+    #[test]
+    fn test_no_cache_reemits_all_packages() {
+        let workspace_root = Utf8PathBuf::from("/ws");
+        let ctx = BuckalContext {
+            workspace_root: workspace_root.clone(),
+            ..Default::default()
+        };
+        let nodes = mock_nodes(&[
+            "registry+https://github.com/rust-lang/crates.io-index#foo@0.1.0",
+            "registry+https://github.com/rust-lang/crates.io-index#bar@0.2.0",
+        ]);
+        let new_cache = BuckalCache::new(&nodes, &ctx);
+
+        // With an up-to-date snapshot nothing is re-emitted
+        let prior = BuckalCache::new(&nodes, &ctx);
+        assert!(new_cache.diff(&prior, &workspace_root).changes.is_empty());
+
+        // `--no-cache` diffs against an empty snapshot instead
+        let changes = new_cache.diff(&BuckalCache::new_empty(), &workspace_root);
+        assert_eq!(changes.changes.len(), nodes.len());
+        assert!(
+            changes
+                .changes
+                .values()
+                .all(|c| matches!(c, ChangeType::Added))
+        );
+    }
 }
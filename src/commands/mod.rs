@@ -1,10 +1,16 @@
 pub mod add;
 pub mod autoremove;
 pub mod build;
+pub mod bundle_update;
 pub mod clean;
+pub mod config;
+pub mod diff;
 pub mod init;
 pub mod migrate;
 pub mod new;
+pub mod query;
 pub mod remove;
 pub mod test;
 pub mod update;
+pub mod vendor;
+pub mod verify;
@@ -101,7 +101,7 @@ pub fn execute(args: &NewArgs) {
         // Configure the buckal cell in .buckconfig
         let cwd = std::env::current_dir().unwrap_or_exit();
         let repo_path = cwd.join(&args.path);
-        init_buckal_cell(&repo_path).unwrap_or_exit();
+        init_buckal_cell(&repo_path, false).unwrap_or_exit();
 
         extract_buck2_assets(&repo_path).unwrap_or_exit_ctx("failed to extract buck2 assets");
 
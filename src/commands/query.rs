@@ -0,0 +1,139 @@
+use std::{io::Write, process::exit};
+
+use clap::Parser;
+
+use super::build::filter_root_third_party;
+use crate::{
+    buck2::Buck2Command,
+    utils::{UnwrapOrExit, check_buck2_package, ensure_prerequisites},
+};
+
+#[derive(Parser, Debug)]
+pub struct QueryArgs {
+    /// Query expression passed to buck2 (e.g. "deps(//:app)")
+    #[arg(value_name = "EXPR")]
+    pub expr: String,
+
+    /// Run a configured query (buck2 cquery) instead of an unconfigured one
+    #[arg(long)]
+    pub cquery: bool,
+
+    /// Keep third-party, toolchain and platform targets in the query result
+    #[arg(long)]
+    pub include_third_party: bool,
+
+    /// Output the result as JSON
+    #[arg(long)]
+    pub json: bool,
+
+    /// Target platform used by configured queries (passed to buck2 --target-platforms)
+    #[arg(long, value_name = "PLATFORM", requires = "cquery")]
+    pub target_platforms: Option<String>,
+
+    /// Additional arguments passed through to buck2
+    #[arg(last = true)]
+    pub args: Vec<String>,
+}
+
+pub fn execute(args: &QueryArgs) {
+    // Ensure all prerequisites are installed before proceeding
+    ensure_prerequisites().unwrap_or_exit();
+
+    // Check if the current directory is a valid Buck2 package
+    check_buck2_package().unwrap_or_exit();
+
+    let mut cmd = if args.cquery {
+        Buck2Command::cquery()
+    } else {
+        Buck2Command::uquery()
+    };
+
+    cmd = cmd.arg(&args.expr);
+
+    if args.json {
+        cmd = cmd.arg("--json");
+    }
+
+    if let Some(platform) = &args.target_platforms {
+        cmd = cmd.arg("--target-platforms").arg(platform);
+    }
+
+    for arg in &args.args {
+        cmd = cmd.arg(arg);
+    }
+
+    let output = cmd.output().unwrap_or_exit_ctx("failed to execute buck2");
+    std::io::stderr().write_all(&output.stderr).unwrap_or_exit();
+    if !output.status.success() {
+        exit(output.status.code().unwrap_or(1));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let result = if args.include_third_party {
+        stdout.into_owned()
+    } else if args.json {
+        filter_json_output(&stdout)
+    } else {
+        filter_text_output(&stdout)
+    };
+    print!("{}", result);
+}
+
+/// Whether `filter_root_third_party` keeps the target labelled `label`.
+fn keep_label(label: &str) -> bool {
+    !filter_root_third_party(vec![label.to_owned()], "").is_empty()
+}
+
+/// Drop the lines of a query result listing third-party targets.
+fn filter_text_output(output: &str) -> String {
+    output
+        .lines()
+        .filter(|line| keep_label(line.trim()))
+        .map(|line| format!("{line}\n"))
+        .collect()
+}
+
+/// Drop third-party targets from a JSON query result, either a list of labels or an object keyed
+/// by label (with `--output-attribute`). Other results are left untouched.
+fn filter_json_output(output: &str) -> String {
+    let Ok(mut result) = serde_json::from_str::<serde_json::Value>(output) else {
+        return output.to_owned();
+    };
+    match &mut result {
+        serde_json::Value::Array(labels) => {
+            labels.retain(|label| label.as_str().is_none_or(keep_label));
+        }
+        serde_json::Value::Object(targets) => targets.retain(|label, _| keep_label(label)),
+        _ => return output.to_owned(),
+    }
+    serde_json::to_string_pretty(&result).map_or_else(|_| output.to_owned(), |json| json + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_text_output_excludes_third_party() {
+        let output = "root//:app\nroot//third-party/rust/crates/serde/1.0.219:serde\n//third-party/rust:serde\n";
+        assert_eq!(filter_text_output(output), "root//:app\n");
+    }
+
+    #[test]
+    fn test_filter_json_output_excludes_third_party() {
+        let labels = r#"["root//:app", "root//third-party/rust:serde"]"#;
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&filter_json_output(labels)).unwrap(),
+            serde_json::json!(["root//:app"])
+        );
+
+        let attributes =
+            r#"{"root//:app": {"name": "app"}, "root//third-party/rust:serde": {"name": "serde"}}"#;
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&filter_json_output(attributes)).unwrap(),
+            serde_json::json!({"root//:app": {"name": "app"}})
+        );
+
+        assert_eq!(filter_json_output("not json"), "not json");
+    }
+}
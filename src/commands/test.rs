@@ -1,13 +1,14 @@
 use crate::{
     buck2::Buck2Command,
-    buckal_error,
+    buckal_error, buckal_log,
+    buckify::UNITTESTS_CONFIG,
     utils::{
-        UnwrapOrExit, check_buck2_package, ensure_prerequisites, get_buck2_root, get_target,
-        platform_exists, validate_target_triple,
+        UnwrapOrExit, cargo_metadata, check_buck2_package, ensure_prerequisites, get_buck2_root,
+        get_target, platform_exists, validate_target_platform, validate_target_triple,
     },
 };
-use anyhow::{Context, Result, anyhow};
-use cargo_metadata::MetadataCommand;
+use anyhow::{Context, Result, anyhow, bail};
+use cargo_metadata::camino::{Utf8Path, Utf8PathBuf};
 use clap::Parser;
 use std::collections::HashSet;
 use std::process::exit;
@@ -82,6 +83,11 @@ pub struct TestArgs {
     #[arg(short, long)]
     pub release: bool,
 
+    /// Instrument the crates with `-C instrument-coverage` and collect the `.profraw` files
+    /// the tests write under `buck-out/coverage`
+    #[arg(long)]
+    pub coverage: bool,
+
     /// If specified, only run tests containing this string in their names
     #[arg(value_name = "TESTNAME")]
     pub test_name: Option<String>,
@@ -91,12 +97,17 @@ pub struct TestArgs {
     pub args: Vec<String>,
 }
 
+/// Buck2 config (section, key) read by the Rust toolchain to add `-C instrument-coverage`.
+const COVERAGE_CONFIG: (&str, &str) = ("buckal", "coverage");
+
+/// Directory, relative to the Buck2 root, that the instrumented tests write their profiles to.
+const COVERAGE_DIR: &str = "buck-out/coverage";
+
 pub fn execute(args: &TestArgs) {
     ensure_prerequisites().unwrap_or_exit();
     check_buck2_package().unwrap_or_exit();
 
-    let metadata = MetadataCommand::new()
-        .exec()
+    let metadata = cargo_metadata()
         .context("Failed to fetch cargo metadata")
         .unwrap_or_exit();
 
@@ -120,22 +131,10 @@ pub fn execute(args: &TestArgs) {
         cmd = cmd.arg(target);
     }
 
-    for excluded_pkg in &args.exclude {
-        if let Some(pkg) = metadata
-            .packages
-            .iter()
-            .find(|p| p.name.as_str() == excluded_pkg)
-        {
-            let pkg_path = pkg
-                .manifest_path
-                .parent()
-                .ok_or_else(|| anyhow!("Package {} manifest has no parent directory", excluded_pkg))
-                .unwrap_or_exit();
-
-            let relative = pkg_path.strip_prefix(&buck2_root).unwrap_or_exit();
-            let pattern = format_buck2_pattern(relative.as_str());
-            cmd = cmd.arg("--exclude").arg(pattern);
-        }
+    let exclude_patterns = resolve_exclude_patterns(&args.exclude, &metadata, &buck2_root)
+        .unwrap_or_exit_ctx("failed to resolve excluded packages");
+    for pattern in exclude_patterns {
+        cmd = cmd.arg("--exclude").arg(pattern);
     }
 
     cmd = cmd.arg("--exclude").arg("//third-party/...");
@@ -155,7 +154,13 @@ pub fn execute(args: &TestArgs) {
             }
         }
     } else if let Some(platform) = &args.target_platforms {
-        Some(platform.clone())
+        match validate_target_platform(platform) {
+            Ok(platform) => Some(platform),
+            Err(e) => {
+                buckal_error!(e);
+                std::process::exit(1);
+            }
+        }
     } else {
         let platform = format!("//platforms:{}", get_target());
         if platform_exists(&platform) {
@@ -172,6 +177,26 @@ pub fn execute(args: &TestArgs) {
         cmd = cmd.arg("-m").arg("release");
     }
 
+    // Inline unit tests are only compatible under this config with `unittests_test_only`
+    let (section, key) = UNITTESTS_CONFIG;
+    cmd = cmd.arg("-c").arg(format!("{section}.{key}=true"));
+
+    let coverage_dir = if args.coverage {
+        check_coverage_support(&buck2_root).unwrap_or_exit();
+        let (section, key) = COVERAGE_CONFIG;
+        cmd = cmd.arg("-c").arg(format!("{section}.{key}=true"));
+        if args.no_run {
+            None
+        } else {
+            Some(
+                prepare_coverage_dir(&buck2_root)
+                    .unwrap_or_exit_ctx("failed to prepare the coverage directory"),
+            )
+        }
+    } else {
+        None
+    };
+
     if args.no_fail_fast {
         cmd = cmd.arg("--keep-going");
     }
@@ -179,6 +204,10 @@ pub fn execute(args: &TestArgs) {
     if !args.no_run {
         let mut raw_args = Vec::new();
 
+        if let Some(dir) = &coverage_dir {
+            raw_args.push("--env".to_string());
+            raw_args.push(profile_file_env(dir));
+        }
         raw_args.extend_from_slice(&args.args);
 
         if !raw_args.is_empty() {
@@ -191,6 +220,23 @@ pub fn execute(args: &TestArgs) {
 
     let status = cmd.status().unwrap_or_exit_ctx("failed to execute buck2");
 
+    if let Some(dir) = &coverage_dir {
+        let profiles =
+            collect_profiles(dir).unwrap_or_exit_ctx("failed to collect the coverage profiles");
+        if !profiles.is_empty() {
+            buckal_log!(
+                "Coverage",
+                format!("{} profile(s) written to {}", profiles.len(), dir)
+            );
+        } else if status.success() {
+            buckal_error!(format!(
+                "the tests wrote no `.profraw` files to {}; check that `LLVM_PROFILE_FILE` reaches the test executor",
+                dir
+            ));
+            exit(1);
+        }
+    }
+
     if !status.success() {
         exit(status.code().unwrap_or(1));
     }
@@ -429,7 +475,7 @@ fn resolve_targets(
                 if let Some(pkg) = metadata
                     .packages
                     .iter()
-                    .find(|p| p.name.as_str() == pkg_name)
+                    .find(|p| p.source.is_none() && p.name.as_str() == pkg_name)
                 {
                     let pkg_path = pkg.manifest_path.parent().ok_or_else(|| {
                         anyhow!("Package {} manifest has no parent directory", pkg_name)
@@ -516,6 +562,34 @@ fn glob_match(pattern: &str, text: &str) -> bool {
     p_idx == p_chars.len()
 }
 
+/// Resolve excluded first-party package names to the Buck2 patterns covering their directories.
+///
+/// Names that don't match a workspace package are ignored.
+pub(super) fn resolve_exclude_patterns(
+    exclude: &[String],
+    metadata: &cargo_metadata::Metadata,
+    buck2_root: &cargo_metadata::camino::Utf8Path,
+) -> Result<Vec<String>> {
+    let mut patterns = Vec::new();
+    for excluded_pkg in exclude {
+        if let Some(pkg) = metadata
+            .packages
+            .iter()
+            .find(|p| p.source.is_none() && p.name.as_str() == excluded_pkg)
+        {
+            let pkg_path = pkg.manifest_path.parent().ok_or_else(|| {
+                anyhow!("Package {} manifest has no parent directory", excluded_pkg)
+            })?;
+
+            let relative = pkg_path.strip_prefix(buck2_root).with_context(|| {
+                format!("Package {} is not inside the Buck2 project", excluded_pkg)
+            })?;
+            patterns.push(format_buck2_pattern(relative.as_str()));
+        }
+    }
+    Ok(patterns)
+}
+
 fn format_buck2_pattern(rel_path: &str) -> String {
     // Normalize path separators for Buck2 (always use forward slashes)
     let normalized = rel_path.replace('\\', "/");
@@ -582,6 +656,70 @@ fn query_buck2_test_owner_std(
     Ok(best_owner.trim().to_string())
 }
 
+/// Fails unless a Rust toolchain under `toolchains/` reads the coverage config, since buck2
+/// ignores unknown configs and the tests would otherwise silently run uninstrumented.
+fn check_coverage_support(buck2_root: &Utf8Path) -> Result<()> {
+    let toolchains_dir = buck2_root.join("toolchains");
+    let mut stack = vec![toolchains_dir.clone()];
+    while let Some(current_dir) = stack.pop() {
+        let Ok(entries) = std::fs::read_dir(&current_dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                stack.push(Utf8PathBuf::try_from(path)?);
+            } else if path.extension().is_some_and(|e| e == "bzl")
+                || path.file_name().is_some_and(|n| n == "BUCK")
+            {
+                let content = std::fs::read_to_string(&path).unwrap_or_default();
+                if toolchain_reads_coverage(&content) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    let (section, key) = COVERAGE_CONFIG;
+    bail!(
+        "the Rust toolchain under {} does not read `{section}.{key}`, so `--coverage` would not instrument the tests\n\
+         Add `rustc_flags = [\"-Cinstrument-coverage\"] if read_root_config(\"{section}\", \"{key}\", \"false\") == \"true\" else []` to it",
+        toolchains_dir
+    )
+}
+
+fn toolchain_reads_coverage(content: &str) -> bool {
+    let (section, key) = COVERAGE_CONFIG;
+    content.contains(&format!("\"{section}\", \"{key}\""))
+}
+
+/// Creates the coverage directory and removes the profiles left by a previous run.
+fn prepare_coverage_dir(buck2_root: &Utf8Path) -> Result<Utf8PathBuf> {
+    let dir = buck2_root.join(COVERAGE_DIR);
+    std::fs::create_dir_all(&dir)?;
+    for profile in collect_profiles(&dir)? {
+        std::fs::remove_file(&profile)?;
+    }
+    Ok(dir)
+}
+
+/// `%p` and `%m` keep the processes and binaries of one run from overwriting each other's profile.
+fn profile_file_env(dir: &Utf8Path) -> String {
+    format!("LLVM_PROFILE_FILE={}", dir.join("%p-%m.profraw"))
+}
+
+fn collect_profiles(dir: &Utf8Path) -> Result<Vec<Utf8PathBuf>> {
+    let mut profiles = Vec::new();
+    for entry in dir.read_dir_utf8()? {
+        let path = entry?.into_path();
+        if path.extension() == Some("profraw") {
+            profiles.push(path);
+        }
+    }
+    profiles.sort();
+    Ok(profiles)
+}
+
 fn find_file_recursive(dir: &std::path::Path, name: &str) -> Option<std::path::PathBuf> {
     let mut stack = vec![dir.to_path_buf()];
     while let Some(current_dir) = stack.pop() {
@@ -603,3 +741,61 @@ fn find_file_recursive(dir: &std::path::Path, name: &str) -> Option<std::path::P
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coverage_support_check() {
+        let root = crate::testing::temp_dir("coverage_support");
+        let rust_dir = root.join("toolchains").join("rust");
+        std::fs::create_dir_all(&rust_dir).unwrap();
+
+        // A custom toolchain that ignores the config is rejected
+        std::fs::write(
+            rust_dir.join("custom.bzl"),
+            "system_rust_toolchain(name = \"rust\")\n",
+        )
+        .unwrap();
+        let err = check_coverage_support(&root).unwrap_err().to_string();
+        assert!(err.contains("does not read `buckal.coverage`"));
+
+        // The toolchain that `init` generates reads it
+        std::fs::write(
+            rust_dir.join("demo_rust.bzl"),
+            include_str!("../../assets/toolchains/rust/demo_rust.bzl"),
+        )
+        .unwrap();
+        check_coverage_support(&root).unwrap();
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_coverage_profiles_are_collected() {
+        let root = crate::testing::temp_dir("coverage_profiles");
+
+        let dir = prepare_coverage_dir(&root).unwrap();
+        assert_eq!(dir, root.join(COVERAGE_DIR));
+        assert_eq!(
+            profile_file_env(&dir),
+            format!("LLVM_PROFILE_FILE={dir}/%p-%m.profraw")
+        );
+
+        std::fs::write(dir.join("2-b.profraw"), "").unwrap();
+        std::fs::write(dir.join("1-a.profraw"), "").unwrap();
+        std::fs::write(dir.join("merged.profdata"), "").unwrap();
+        assert_eq!(
+            collect_profiles(&dir).unwrap(),
+            vec![dir.join("1-a.profraw"), dir.join("2-b.profraw")]
+        );
+
+        // Profiles of a previous run don't count toward the next one
+        prepare_coverage_dir(&root).unwrap();
+        assert!(collect_profiles(&dir).unwrap().is_empty());
+        assert!(dir.join("merged.profdata").exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,12 +1,12 @@
 use std::process::{Command, Stdio};
 
 use anyhow::{Context, Result, anyhow};
-use cargo_metadata::MetadataCommand;
+use cargo_metadata::{MetadataCommand, camino::Utf8PathBuf};
 use clap::Parser;
 use log::debug;
 
 use crate::{
-    buckify::flush_root,
+    buckify::{BuckalReport, flush_root},
     cache::BuckalCache,
     context::BuckalContext,
     utils::{UnwrapOrExit, check_buck2_package, ensure_prerequisites, get_last_cache, section},
@@ -25,6 +25,10 @@ pub struct UpdateArgs {
     /// Don't actually write the lockfile
     #[arg(long)]
     pub dry_run: bool,
+
+    /// Write a JSON summary of added, changed and removed crates to this path
+    #[arg(long, value_name = "PATH", conflicts_with = "dry_run")]
+    pub report: Option<Utf8PathBuf>,
 }
 
 pub fn execute(args: &UpdateArgs) {
@@ -46,12 +50,19 @@ pub fn execute(args: &UpdateArgs) {
     let _ = MetadataCommand::new().exec();
 
     let ctx = BuckalContext::new();
-    flush_root(&ctx);
+    let (mut buck_files, _) = flush_root(&ctx, false);
 
-    let new_cache = BuckalCache::new(&ctx.nodes_map, &ctx.workspace_root);
+    let new_cache = BuckalCache::new(&ctx.nodes_map, &ctx);
     let changes = new_cache.diff(&last_cache, &ctx.workspace_root);
 
-    changes.apply(&ctx);
+    buck_files.extend(changes.apply(&ctx));
+
+    if let Some(report_path) = &args.report {
+        BuckalReport::new(&changes, &ctx, &buck_files)
+            .write(report_path)
+            .unwrap_or_exit_ctx(format!("failed to write report to `{}`", report_path));
+    }
+
     new_cache.save();
 }
 
@@ -0,0 +1,106 @@
+use clap::Parser;
+
+use crate::{
+    RUST_CRATES_ROOT,
+    buck::{Rule, parse_buck_file},
+    buckal_error, buckal_log, buckal_note,
+    buckify::check_crate_root,
+    context::BuckalContext,
+    utils::{
+        UnwrapOrExit, cargo_metadata, ensure_prerequisites, find_orphaned_vendor_dirs,
+        get_buck2_root, remove_vendor_dir,
+    },
+};
+
+#[derive(Parser, Debug)]
+pub struct VendorArgs {
+    /// Remove vendored crates that are no longer in the dependency graph
+    #[arg(long)]
+    pub prune: bool,
+
+    /// Only print the crates that would be pruned
+    #[arg(name = "dry-run", long, requires = "prune")]
+    pub dry_run: bool,
+
+    /// Check the `crate_root` of each generated library against its Cargo target and sources
+    #[arg(long, conflicts_with = "prune")]
+    pub check_crate_roots: bool,
+}
+
+pub fn execute(args: &VendorArgs) {
+    ensure_prerequisites().unwrap_or_exit();
+
+    if args.check_crate_roots {
+        check_crate_roots();
+        return;
+    }
+
+    let buck2_root = get_buck2_root().unwrap_or_exit();
+    let cargo_metadata = cargo_metadata().unwrap_or_exit();
+    let crates_dir = buck2_root.join(RUST_CRATES_ROOT);
+    let orphans = find_orphaned_vendor_dirs(&crates_dir, &cargo_metadata.packages)
+        .unwrap_or_exit_ctx("failed to scan vendored crates");
+
+    if orphans.is_empty() {
+        buckal_note!("No orphaned vendored crates found.");
+        return;
+    }
+
+    if !args.prune || args.dry_run {
+        buckal_note!("The following vendored crates are no longer resolved:");
+        for orphan in &orphans {
+            println!("  {}", orphan.strip_prefix(&buck2_root).unwrap_or(orphan));
+        }
+        if !args.prune {
+            buckal_note!("Run with `--prune` to remove them.");
+        }
+        return;
+    }
+
+    for orphan in &orphans {
+        buckal_log!(
+            "Removing",
+            orphan.strip_prefix(&buck2_root).unwrap_or(orphan)
+        );
+        remove_vendor_dir(orphan).unwrap_or_exit_ctx("failed to remove vendor directory");
+    }
+}
+
+/// Validate the `crate_root` of the generated libraries against their Cargo targets and the
+/// sources their `vendor` rule provides.
+fn check_crate_roots() {
+    let ctx = BuckalContext::new();
+    let mut failed = false;
+    for package in ctx.packages_map.values() {
+        let buck_path = if package.source.is_none() {
+            package.manifest_path.parent().unwrap().join("BUCK")
+        } else {
+            ctx.vendor_dir(package)
+                .unwrap_or_exit_ctx("failed to get vendor directory")
+                .join("BUCK")
+        };
+        // Crates not buckified yet have nothing to check
+        let Ok(rules) = parse_buck_file(&buck_path) else {
+            continue;
+        };
+        let Some(vendor) = rules
+            .values()
+            .find(|rule| matches!(rule, Rule::FileGroup(_) | Rule::HttpArchive(_)))
+        else {
+            continue;
+        };
+        for rule in rules.values() {
+            if let Rule::RustLibrary(rust_library) = rule
+                && let Err(e) = check_crate_root(rust_library, package, vendor)
+            {
+                buckal_error!("{}", e);
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+    buckal_note!("All crate roots match their library targets.");
+}
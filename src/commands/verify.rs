@@ -0,0 +1,172 @@
+use clap::Parser;
+
+use crate::{
+    RUST_CRATES_ROOT,
+    buck2::Buck2Command,
+    buckal_error, buckal_log, buckal_note,
+    utils::{UnwrapOrExit, ensure_prerequisites, get_buck2_root},
+};
+
+#[derive(Parser, Debug)]
+pub struct VerifyArgs {
+    /// Number of threads buck2 builds the crates with
+    #[arg(long, short = 'j', value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub jobs: Option<usize>,
+
+    /// Keep building the remaining crates after one fails
+    #[arg(long)]
+    pub keep_going: bool,
+}
+
+pub fn execute(args: &VerifyArgs) {
+    ensure_prerequisites().unwrap_or_exit();
+
+    // Queries and builds are relative to the project root
+    let buck2_root = get_buck2_root().unwrap_or_exit();
+    std::env::set_current_dir(&buck2_root)
+        .unwrap_or_exit_ctx(format!("failed to change directory to `{}`", buck2_root));
+
+    let output = Buck2Command::uquery()
+        .arg(format!("kind('rust_library', //{RUST_CRATES_ROOT}/...)"))
+        .output()
+        .unwrap_or_exit_ctx("failed to query third-party crates");
+    if !output.status.success() {
+        buckal_error!(
+            "failed to query third-party crates:\n{}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+        std::process::exit(1);
+    }
+    let targets = crate_targets(&String::from_utf8_lossy(&output.stdout));
+    if targets.is_empty() {
+        buckal_note!("No third-party crates to verify.");
+        return;
+    }
+
+    // One batched build rather than a buck2 invocation per crate; crates that are incompatible
+    // with the host platform (e.g. windows-only ones on Linux) are skipped instead of erroring
+    buckal_log!("Verifying", format!("{} third-party crates", targets.len()));
+    let report = buck2_root
+        .join("buck-out")
+        .join("buckal-verify-report.json");
+    let mut build = Buck2Command::new()
+        .arg("build")
+        .arg("--skip-incompatible-targets")
+        .arg(format!("--build-report={}", report));
+    if let Some(jobs) = args.jobs {
+        build = build.arg("-j").arg(jobs.to_string());
+    }
+    if args.keep_going {
+        build = build.arg("--keep-going");
+    }
+    for (target, _, _) in &targets {
+        build = build.arg(target);
+    }
+    let status = build
+        .status()
+        .unwrap_or_exit_ctx("failed to build the third-party crates");
+    if status.success() {
+        buckal_note!("All {} third-party crates compile.", targets.len());
+        return;
+    }
+
+    let failed = std::fs::read_to_string(&report)
+        .ok()
+        .and_then(|report| failed_crates(&report))
+        .unwrap_or_default();
+    if failed.is_empty() {
+        buckal_error!("failed to build the third-party crates");
+    } else {
+        let failed: Vec<_> = failed
+            .iter()
+            .map(|(_, name, version)| format!("{} v{}", name, version))
+            .collect();
+        buckal_error!("crates failing to compile: {}", failed.join(", "));
+    }
+    std::process::exit(1);
+}
+
+/// The crates whose targets a buck2 `--build-report` records as not built successfully.
+fn failed_crates(report: &str) -> Option<Vec<(String, String, String)>> {
+    let report: serde_json::Value = serde_json::from_str(report).ok()?;
+    let failed: Vec<&str> = report
+        .get("results")?
+        .as_object()?
+        .iter()
+        .filter(|(_, result)| result.get("success").and_then(|s| s.as_str()) != Some("SUCCESS"))
+        .map(|(label, _)| label.as_str())
+        .collect();
+    Some(crate_targets(&failed.join("\n")))
+}
+
+/// The third-party targets listed by a query, with the crate name and version their package
+/// path encodes (`//third-party/rust/crates/<name>/<version>:<target>`).
+fn crate_targets(query_output: &str) -> Vec<(String, String, String)> {
+    let prefix = format!("//{RUST_CRATES_ROOT}/");
+    let mut targets: Vec<_> = query_output
+        .lines()
+        .map(str::trim)
+        // Labels may carry a cell prefix, e.g. `root//third-party/...`
+        .filter_map(|label| {
+            let start = label.find(&prefix)?;
+            let (package, _) = label[start + prefix.len()..].split_once(':')?;
+            let (name, version) = package.split_once('/')?;
+            Some((label.to_owned(), name.to_owned(), version.to_owned()))
+        })
+        .collect();
+    targets.sort();
+    targets.dedup();
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crate_targets() {
+        let output = format!(
+            "root//{RUST_CRATES_ROOT}/serde/1.0.219:serde\n\
+             //{RUST_CRATES_ROOT}/libc/0.2.174:libc\n\
+             \n\
+             //third-party/rust:serde\n"
+        );
+        assert_eq!(
+            crate_targets(&output),
+            [
+                (
+                    format!("//{RUST_CRATES_ROOT}/libc/0.2.174:libc"),
+                    "libc".to_owned(),
+                    "0.2.174".to_owned()
+                ),
+                (
+                    format!("root//{RUST_CRATES_ROOT}/serde/1.0.219:serde"),
+                    "serde".to_owned(),
+                    "1.0.219".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_failed_crates() {
+        let report = format!(
+            r#"{{
+                "success": false,
+                "results": {{
+                    "root//{RUST_CRATES_ROOT}/libc/0.2.174:libc": {{"success": "SUCCESS"}},
+                    "root//{RUST_CRATES_ROOT}/openssl-sys/0.9.109:openssl-sys": {{"success": "FAIL"}}
+                }}
+            }}"#
+        );
+        assert_eq!(
+            failed_crates(&report).unwrap(),
+            [(
+                format!("root//{RUST_CRATES_ROOT}/openssl-sys/0.9.109:openssl-sys"),
+                "openssl-sys".to_owned(),
+                "0.9.109".to_owned()
+            )]
+        );
+        assert!(failed_crates("not json").is_none());
+    }
+}
@@ -1,10 +1,11 @@
-use std::collections::BTreeSet as Set;
-use std::{fs, path::PathBuf};
+use std::collections::{BTreeMap as Map, BTreeSet as Set};
+use std::{fs, path::PathBuf, sync::OnceLock};
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    buckal_warn,
+    buck::PATCHABLE_FIELDS,
+    buckal_error, buckal_warn,
     utils::{UnwrapOrExit, get_buck2_root},
 };
 
@@ -73,6 +74,180 @@ pub struct RepoConfig {
     pub align_cells: bool,
     pub ignore_tests: bool,
     pub patch_fields: Set<String>,
+    /// Target chosen for a renamed dependency alias when it resolves to conflicting targets
+    pub alias_resolution: Map<String, String>,
+    // The `buildscript_*` options below rely on subtargets and attributes of the buckal bundles
+    // that older bundles lack, so they're off by default.
+    /// Forward `cargo:rustc-env` outputs of build scripts to the crates they build, through the
+    /// `[rustc_env]` subtarget of `buildscript_run`
+    pub buildscript_rustc_env: bool,
+    /// Forward `cargo:rustc-check-cfg` outputs of build scripts to the crates they build, through
+    /// the `[check_cfg]` subtarget of `buildscript_run`. The flags are only passed when the
+    /// buckconfig also sets `buckal.check_cfg = true`.
+    pub buildscript_check_cfg: bool,
+    /// Emit the build-dependencies of build scripts as `exec_deps` rather than `deps`. Only
+    /// unconditional, unrenamed ones move, as `os_deps` and `named_deps` have no exec
+    /// counterpart.
+    pub buildscript_exec_deps: bool,
+    /// Visibility of `buildscript_build` and `buildscript_run` targets
+    pub buildscript_visibility: BuildscriptVisibility,
+    /// Run doctests of first-party libraries with `--cfg docsrs` and extra doc-only features
+    pub doc_cfg: Option<DocCfg>,
+    /// Environment set on every emitted `rust_test`, e.g. `RUST_TEST_THREADS = "1"`
+    pub test_env: Map<String, String>,
+    /// Arguments passed to the test runner of every emitted `rust_test`
+    pub test_args: Vec<String>,
+    /// Per-package `env`/`args`, applied on top of `test_env` and `test_args`
+    pub test_overrides: Map<String, TestRun>,
+    /// Per-package `mapped_srcs` of the library, from a label (e.g. a generated file) to its
+    /// path within the crate, for layouts the vendored sources don't cover
+    pub mapped_srcs: Map<String, Map<String, String>>,
+    /// First-party packages whose binaries only run on the host (e.g. `xtask`), pinned to the
+    /// host platform so they're never cross-compiled
+    pub host_only_packages: Set<String>,
+    /// Suffixes of the auxiliary targets generated next to each crate's own targets
+    pub naming: Naming,
+    /// Write the sha256 of every vendored crate to `third-party/rust/checksums.bzl`, keyed by
+    /// source and then `<name>-<version>`, so the checksums of the `http_archive` rules are
+    /// versioned along with them
+    pub checksums_manifest: bool,
+    /// Share a single vendored copy between the sources of a crate version whose `Cargo.lock`
+    /// checksums match (e.g. crates.io and a mirror of it), rather than vendoring each source in
+    /// its own directory
+    pub dedup_vendored: bool,
+    /// Per-package flags passed to rustdoc only (the library's `[doc]` subtarget and doctests),
+    /// e.g. `--cfg=docsrs`, keeping them out of the normal compile
+    pub rustdoc_flags: Map<String, Vec<String>>,
+    /// Label of the `rust_test` rules of libraries' inline unit tests (e.g. `unittest`), so that
+    /// queries can tell them apart, e.g. to leave them out of what `buck2 build` compiles
+    pub unittest_label: Option<String>,
+    /// Leave the inline unit tests out of `buck2 build //...`: their `rust_test` rules are
+    /// incompatible unless the `buckal.unittests` buckconfig is `true`, which `cargo buckal test`
+    /// sets (pass `-c buckal.unittests=true` to a plain `buck2 test`)
+    pub unittests_test_only: bool,
+    /// Set the `default_target_platform` of first-party binaries to the host's
+    /// `//platforms:<triple>`, if it exists, so `buck2 run` needs no `--target-platforms`
+    pub default_target_platform: bool,
+    /// Label every `rust_test` with its crate (`crate:<name>`, `version:<version>`) and kind
+    /// (`test:unit` or `test:integration`), so a custom test runner can shard them by query.
+    /// Doctests run as part of their `rust_library` and aren't labeled.
+    pub test_labels: bool,
+    /// Per-package env of build scripts, e.g. `openssl-sys = { OPENSSL_NO_VENDOR = "1" }`
+    /// for crates wrapping a native library; overrides forwarded values
+    pub sys_crate_env: Map<String, Map<String, String>>,
+    /// Forward the env known `*-sys` crates read (e.g. `OPENSSL_DIR`, `PKG_CONFIG_PATH`) to their
+    /// build scripts from the `[sys_crate_env]` buckconfig section, e.g. in `.buckconfig.local`,
+    /// when set there
+    pub forward_sys_crate_env: bool,
+    /// Name the sources of a crate `<package>-<version>-vendor` instead of `<package>-vendor`.
+    /// Only the sources rule is versioned; the library, manifest and build script rules keep
+    /// their names, so each version still needs its own BUCK file
+    pub versioned_vendor_names: bool,
+    /// With `inherit_workspace_deps`, also refer to third-party crates through the
+    /// `//third-party/rust:<name>` aliases from first-party path dependencies outside the
+    /// workspace, not only from workspace members
+    pub alias_path_deps: bool,
+    /// Globs left out of the sources of first-party crates, on top of `target/**`, `.git/**`
+    /// and `buck-out/**`, e.g. `docs/**`
+    pub filegroup_excludes: Set<String>,
+    /// Also map dependencies for iOS (`aarch64-apple-ios`), under the `ios` key of `os_deps`;
+    /// unix-only crates then become compatible with `iphoneos` as well
+    pub ios: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BuildscriptVisibility {
+    /// Private to the crate's package, plus the dependents reading its `links` metadata
+    #[default]
+    Package,
+    /// Visible to every package
+    Public,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Naming {
+    /// Inline tests of a library: `<lib>-unittest`
+    pub unittest: String,
+    /// Sources of a crate: `<package>-vendor`
+    pub vendor: String,
+    /// `cargo_manifest` of a crate: `<package>-manifest`
+    pub manifest: String,
+    /// `buildscript_run` of a build script: `<package>-build-script-run`
+    pub run: String,
+}
+
+impl Default for Naming {
+    fn default() -> Self {
+        Self {
+            unittest: "-unittest".to_owned(),
+            vendor: "-vendor".to_owned(),
+            manifest: "-manifest".to_owned(),
+            run: "-run".to_owned(),
+        }
+    }
+}
+
+impl Naming {
+    pub fn unittest_name(&self, lib_name: &str) -> String {
+        format!("{lib_name}{}", self.unittest)
+    }
+
+    pub fn vendor_name(&self, package_name: &str) -> String {
+        format!("{package_name}{}", self.vendor)
+    }
+
+    pub fn manifest_name(&self, package_name: &str) -> String {
+        format!("{package_name}{}", self.manifest)
+    }
+
+    /// `build_name` is the build script target's name without its `-build` suffix.
+    pub fn run_name(&self, package_name: &str, build_name: &str) -> String {
+        format!("{package_name}-{build_name}{}", self.run)
+    }
+
+    /// Reject empty suffixes, which would name a target like the crate itself, and suffixes
+    /// shared by two kinds of targets, `-lib` included.
+    fn validate(&self) -> Result<(), String> {
+        let suffixes = [
+            ("unittest", &self.unittest),
+            ("vendor", &self.vendor),
+            ("manifest", &self.manifest),
+            ("run", &self.run),
+        ];
+        let mut seen: Map<&str, &str> = Map::new();
+        for (key, suffix) in suffixes {
+            if suffix.is_empty() {
+                return Err(format!("`naming.{key}` must not be empty"));
+            }
+            if suffix == "-lib" {
+                return Err(format!(
+                    "`naming.{key}` must not be `-lib`, the suffix of libraries sharing a bin's name"
+                ));
+            }
+            if let Some(other) = seen.insert(suffix, key) {
+                return Err(format!(
+                    "`naming.{key}` and `naming.{other}` both use the suffix `{suffix}`"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DocCfg {
+    /// Features enabled for rustdoc only, on top of the library's own features
+    pub features: Set<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TestRun {
+    pub env: Map<String, String>,
+    pub args: Vec<String>,
 }
 
 impl Default for RepoConfig {
@@ -82,6 +257,31 @@ impl Default for RepoConfig {
             align_cells: false,
             ignore_tests: true,
             patch_fields: Set::new(),
+            alias_resolution: Map::new(),
+            buildscript_rustc_env: false,
+            buildscript_check_cfg: false,
+            buildscript_exec_deps: false,
+            buildscript_visibility: BuildscriptVisibility::default(),
+            doc_cfg: None,
+            test_env: Map::new(),
+            test_args: Vec::new(),
+            test_overrides: Map::new(),
+            mapped_srcs: Map::new(),
+            host_only_packages: Set::new(),
+            naming: Naming::default(),
+            checksums_manifest: false,
+            dedup_vendored: false,
+            rustdoc_flags: Map::new(),
+            unittest_label: None,
+            unittests_test_only: false,
+            default_target_platform: false,
+            test_labels: false,
+            sys_crate_env: Map::new(),
+            forward_sys_crate_env: false,
+            versioned_vendor_names: false,
+            alias_path_deps: false,
+            filegroup_excludes: Set::new(),
+            ios: false,
         }
     }
 }
@@ -90,33 +290,356 @@ impl RepoConfig {
     pub fn load() -> Self {
         let repo_config_path = Self::repo_config_path();
 
-        if !repo_config_path.exists() {
-            return Self::default();
-        }
-
-        match fs::read_to_string(&repo_config_path) {
-            Ok(content) => match toml::from_str::<RepoConfig>(&content) {
-                Ok(config) => config,
+        // Without a `buckal.toml`, `--config` overrides still apply to the defaults
+        let content = if repo_config_path.exists() {
+            match fs::read_to_string(&repo_config_path) {
+                Ok(content) => content,
                 Err(_) => {
                     buckal_warn!(
-                        "Failed to parse repo config file at {}, using defaults",
+                        "Failed to read repo config file at {}, using defaults",
                         repo_config_path.display()
                     );
-                    Self::default()
+                    String::new()
                 }
-            },
-            Err(_) => {
-                buckal_warn!(
-                    "Failed to read repo config file at {}, using defaults",
+            }
+        } else {
+            String::new()
+        };
+
+        match Self::parse(&content, config_overrides(), |var| std::env::var(var).ok()) {
+            Ok(config) => config,
+            Err(ConfigError::Env(e) | ConfigError::Invalid(e)) => {
+                buckal_error!(
+                    "{} in repo config file at {}",
+                    e,
                     repo_config_path.display()
                 );
+                std::process::exit(1);
+            }
+            Err(ConfigError::Toml(e)) => {
+                buckal_warn!(
+                    "Failed to parse repo config file at {}, using defaults: {}",
+                    repo_config_path.display(),
+                    e
+                );
                 Self::default()
             }
         }
     }
 
+    /// Parse `buckal.toml`, layer `overrides` over it, then expand `${VAR}` and
+    /// `${VAR:-default}` in string values with `env`.
+    fn parse(
+        content: &str,
+        overrides: &[toml::Table],
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigError> {
+        let mut table = toml::from_str::<toml::Table>(content).map_err(ConfigError::Toml)?;
+        apply_overrides(&mut table, overrides);
+        let mut value = toml::Value::Table(table);
+        expand_env_in_value(&mut value, &env).map_err(ConfigError::Env)?;
+        let config: Self = value.try_into().map_err(ConfigError::Toml)?;
+        config.naming.validate().map_err(ConfigError::Invalid)?;
+        validate_patch_fields(&config.patch_fields).map_err(ConfigError::Invalid)?;
+        Ok(config)
+    }
+
+    /// Runtime env and runner arguments for the tests of `package`.
+    pub fn test_run(&self, package: &str) -> TestRun {
+        let mut test_run = TestRun {
+            env: self.test_env.clone(),
+            args: self.test_args.clone(),
+        };
+        if let Some(overrides) = self.test_overrides.get(package) {
+            test_run.env.extend(overrides.env.clone());
+            test_run.args.extend(overrides.args.iter().cloned());
+        }
+        test_run
+    }
+
+    /// The configuration as TOML, including fields left at their defaults.
+    pub fn to_toml(&self) -> Result<String, toml::ser::Error> {
+        toml::to_string(self)
+    }
+
     pub fn repo_config_path() -> PathBuf {
         let buck2_root = get_buck2_root().unwrap_or_exit();
         buck2_root.join("buckal.toml").into()
     }
 }
+
+/// Reject `patch_fields` entries that aren't patchable attributes, which would silently drop
+/// the manual edits they were meant to keep.
+fn validate_patch_fields(patch_fields: &Set<String>) -> Result<(), String> {
+    let Some(unknown) = patch_fields
+        .iter()
+        .find(|field| !PATCHABLE_FIELDS.contains(&field.as_str()))
+    else {
+        return Ok(());
+    };
+    let suggestion = PATCHABLE_FIELDS
+        .iter()
+        .map(|known| (edit_distance(unknown, known), known))
+        .filter(|(distance, _)| *distance <= 2)
+        .min()
+        .map(|(_, known)| format!(", did you mean `{known}`?"))
+        .unwrap_or_default();
+    Err(format!(
+        "unknown field `{unknown}` in `patch_fields`{suggestion} (expected one of {})",
+        PATCHABLE_FIELDS.join(", ")
+    ))
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+static CONFIG_OVERRIDES: OnceLock<Vec<toml::Table>> = OnceLock::new();
+
+/// Layer `--config KEY=VALUE` overrides over `buckal.toml` when it is loaded.
+pub fn set_config_overrides(overrides: Vec<toml::Table>) {
+    let _ = CONFIG_OVERRIDES.set(overrides);
+}
+
+fn config_overrides() -> &'static [toml::Table] {
+    CONFIG_OVERRIDES
+        .get()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+}
+
+/// Parse a `--config KEY=VALUE` override, where `KEY` may be dotted (e.g. `test_env.RUST_LOG`)
+/// and `VALUE` is a TOML value, so strings must be quoted.
+pub fn parse_config_override(s: &str) -> Result<toml::Table, String> {
+    if !s.contains('=') {
+        return Err(format!("expected `KEY=VALUE`, found `{s}`"));
+    }
+    toml::from_str::<toml::Table>(s)
+        .map_err(|e| format!("invalid config override `{s}`: {}", e.message()))
+}
+
+/// Merge `overrides` into `table` in order, warning about and skipping keys that aren't
+/// `RepoConfig` fields.
+fn apply_overrides(table: &mut toml::Table, overrides: &[toml::Table]) {
+    let known = serde_json::to_value(RepoConfig::default()).unwrap_or_default();
+    for overlay in overrides {
+        for (key, value) in overlay {
+            if known.get(key).is_none() {
+                buckal_warn!("Unknown config key `{}`, ignoring the override", key);
+                continue;
+            }
+            merge_value(table, key, value.clone());
+        }
+    }
+}
+
+/// Set `key` to `value`, merging tables key by key rather than replacing them.
+fn merge_value(table: &mut toml::Table, key: &str, value: toml::Value) {
+    match table.entry(key) {
+        toml::map::Entry::Occupied(mut entry) => match (entry.get_mut(), value) {
+            (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+                for (key, value) in overlay {
+                    merge_value(base, &key, value);
+                }
+            }
+            (slot, value) => *slot = value,
+        },
+        toml::map::Entry::Vacant(entry) => {
+            entry.insert(value);
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ConfigError {
+    Toml(toml::de::Error),
+    Env(String),
+    Invalid(String),
+}
+
+fn expand_env_in_value(
+    value: &mut toml::Value,
+    env: &impl Fn(&str) -> Option<String>,
+) -> Result<(), String> {
+    match value {
+        toml::Value::String(s) => *s = expand_env_vars(s, env)?,
+        toml::Value::Array(items) => {
+            for item in items {
+                expand_env_in_value(item, env)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, item) in table.iter_mut() {
+                expand_env_in_value(item, env)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Expand `${VAR}` and `${VAR:-default}` references; undefined variables without a default are
+/// an error.
+fn expand_env_vars(s: &str, env: &impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let reference = &rest[start + 2..];
+        let end = reference
+            .find('}')
+            .ok_or_else(|| format!("unterminated `${{` in `{s}`"))?;
+        let (var, default) = match reference[..end].split_once(":-") {
+            Some((var, default)) => (var, Some(default)),
+            None => (&reference[..end], None),
+        };
+        match env(var).or_else(|| default.map(str::to_owned)) {
+            Some(value) => out.push_str(&value),
+            None => return Err(format!("environment variable `{var}` is not defined")),
+        }
+        rest = &reference[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(var: &str) -> Option<String> {
+        (var == "MIRROR_URL").then(|| "https://mirror.example.com".to_owned())
+    }
+
+    #[test]
+    fn test_expand_env_vars() {
+        assert_eq!(
+            expand_env_vars("${MIRROR_URL}/crates", &env).unwrap(),
+            "https://mirror.example.com/crates"
+        );
+        assert_eq!(
+            expand_env_vars("${UNSET:-fallback}-${MIRROR_URL:-unused}", &env).unwrap(),
+            "fallback-https://mirror.example.com"
+        );
+        assert_eq!(expand_env_vars("no refs", &env).unwrap(), "no refs");
+        assert!(expand_env_vars("${MIRROR_URL", &env).is_err());
+    }
+
+    #[test]
+    fn test_parse_repo_config_expands_env() {
+        let config = RepoConfig::parse(
+            "[alias_resolution]\nfoo = \"${MIRROR_URL}/foo\"\n",
+            &[],
+            env,
+        )
+        .unwrap();
+        assert_eq!(
+            config.alias_resolution["foo"],
+            "https://mirror.example.com/foo"
+        );
+
+        let err = RepoConfig::parse("patch_fields = [\"${MISSING}\"]\n", &[], env).unwrap_err();
+        assert!(matches!(err, ConfigError::Env(e) if e.contains("`MISSING`")));
+    }
+
+    #[test]
+    fn test_to_toml_reflects_overrides() {
+        let overrides = [parse_config_override("ignore_tests=false").unwrap()];
+        let config = RepoConfig::parse("ignore_tests = true\n", &overrides, env).unwrap();
+        let printed = config.to_toml().unwrap();
+        assert!(printed.contains("ignore_tests = false"), "{printed}");
+        // Unset fields show their defaults
+        assert!(printed.contains("align_cells = false"), "{printed}");
+        assert!(printed.contains("[naming]"), "{printed}");
+
+        // The output reads back as the same configuration
+        let reparsed = RepoConfig::parse(&printed, &[], env).unwrap();
+        assert_eq!(reparsed.to_toml().unwrap(), printed);
+    }
+
+    #[test]
+    fn test_test_run_merges_package_overrides() {
+        let config = RepoConfig::parse(
+            r#"
+test_env = { RUST_BACKTRACE = "1", RUST_LOG = "info" }
+test_args = ["--test-threads=1"]
+
+[test_overrides.foo]
+env = { RUST_LOG = "debug" }
+args = ["--nocapture"]
+"#,
+            &[],
+            env,
+        )
+        .unwrap();
+
+        let foo = config.test_run("foo");
+        assert_eq!(foo.env["RUST_BACKTRACE"], "1");
+        assert_eq!(foo.env["RUST_LOG"], "debug");
+        assert_eq!(foo.args, ["--test-threads=1", "--nocapture"]);
+        assert_eq!(config.test_run("bar").args, ["--test-threads=1"]);
+    }
+
+    #[test]
+    fn test_config_override_wins_over_file() {
+        let overrides = [
+            parse_config_override("ignore_tests=true").unwrap(),
+            parse_config_override("test_env.RUST_LOG=\"debug\"").unwrap(),
+            parse_config_override("no_such_field=1").unwrap(),
+        ];
+        let config = RepoConfig::parse(
+            "ignore_tests = false\ntest_env = { RUST_BACKTRACE = \"1\", RUST_LOG = \"info\" }\n",
+            &overrides,
+            env,
+        )
+        .unwrap();
+
+        assert!(config.ignore_tests);
+        assert_eq!(config.test_env["RUST_LOG"], "debug");
+        // Tables are merged rather than replaced
+        assert_eq!(config.test_env["RUST_BACKTRACE"], "1");
+
+        assert!(parse_config_override("ignore_tests").is_err());
+        assert!(parse_config_override("alias_resolution.foo=unquoted").is_err());
+    }
+
+    #[test]
+    fn test_misspelled_patch_field_is_rejected() {
+        let config = RepoConfig::parse("patch_fields = [\"deps\", \"env\"]\n", &[], env).unwrap();
+        assert_eq!(config.patch_fields.len(), 2);
+
+        let err = RepoConfig::parse("patch_fields = [\"rustc-flags\"]\n", &[], env).unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::Invalid(e) if e.contains("`rustc-flags`") && e.contains("did you mean `rustc_flags`?")
+        ));
+        let err = RepoConfig::parse("patch_fields = [\"srcs\"]\n", &[], env).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(e) if !e.contains("did you mean")));
+    }
+
+    #[test]
+    fn test_naming_rejects_empty_and_colliding_suffixes() {
+        let config = RepoConfig::parse("[naming]\nunittest = \"-tests\"\n", &[], env).unwrap();
+        assert_eq!(config.naming.unittest_name("foo"), "foo-tests");
+        assert_eq!(config.naming.vendor_name("foo"), "foo-vendor");
+
+        let err = RepoConfig::parse("[naming]\nrun = \"\"\n", &[], env).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(e) if e.contains("`naming.run`")));
+        let err = RepoConfig::parse("[naming]\nmanifest = \"-vendor\"\n", &[], env).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(e) if e.contains("`-vendor`")));
+        let err = RepoConfig::parse("[naming]\nunittest = \"-lib\"\n", &[], env).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(e) if e.contains("`-lib`")));
+    }
+}
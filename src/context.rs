@@ -1,36 +1,124 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    sync::OnceLock,
+};
 
 use cargo_lock::{Checksum, Lockfile};
-use cargo_metadata::{MetadataCommand, Node, Package, PackageId, camino::Utf8PathBuf};
+use cargo_metadata::{Node, NodeDep, Package, PackageId, camino::Utf8PathBuf};
+use itertools::Itertools;
 
-use crate::{config::RepoConfig, utils::UnwrapOrExit};
+use crate::{
+    buckal_warn,
+    config::RepoConfig,
+    platform::set_map_ios,
+    utils::{
+        UnwrapOrExit, cargo_metadata, get_buck2_root, resolved_nodes, source_segment,
+        vendor_package_path,
+    },
+};
 
 pub struct BuckalContext {
-    /// The root package of the workspace, if any
-    pub root: Option<Package>,
     pub nodes_map: HashMap<PackageId, Node>,
     pub packages_map: HashMap<PackageId, Package>,
-    pub checksums_map: HashMap<String, Checksum>,
+    pub checksums_map: HashMap<ChecksumKey, Checksum>,
+    /// Where the third-party packages sharing their crate version with another source are
+    /// vendored, see [`vendor_layout`]
+    pub vendor_layout: VendorLayout,
     pub workspace_root: Utf8PathBuf,
     pub workspace_members: Vec<PackageId>,
     /// Whether to skip merging manual changes in BUCK files
     pub no_merge: bool,
+    /// Whether to only write third-party packages, leaving first-party BUCK files untouched
+    pub separate: bool,
+    /// Number of packages buckified in parallel
+    pub jobs: usize,
     /// Repository configuration
     pub repo_config: RepoConfig,
+    /// Edges into each package, built from `nodes_map` on first use
+    pub reverse_deps: OnceLock<ReverseDeps>,
+}
+
+/// An empty graph with the default config, for tests to fill in.
+#[cfg(test)]
+impl Default for BuckalContext {
+    fn default() -> Self {
+        Self {
+            nodes_map: HashMap::new(),
+            packages_map: HashMap::new(),
+            checksums_map: HashMap::new(),
+            vendor_layout: VendorLayout::default(),
+            workspace_root: Utf8PathBuf::from("/tmp"),
+            workspace_members: vec![],
+            no_merge: false,
+            separate: false,
+            jobs: 1,
+            repo_config: RepoConfig::default(),
+            reverse_deps: OnceLock::new(),
+        }
+    }
+}
+
+/// Edges into each package: its dependents, each with its edge to the package, sorted by
+/// dependent.
+pub type ReverseDeps = HashMap<PackageId, Vec<(PackageId, NodeDep)>>;
+
+/// Invert the dependency edges of a graph.
+pub fn reverse_deps(nodes_map: &HashMap<PackageId, Node>) -> ReverseDeps {
+    let mut reverse = ReverseDeps::new();
+    for node in nodes_map.values() {
+        for dep in &node.deps {
+            reverse
+                .entry(dep.pkg.clone())
+                .or_default()
+                .push((node.id.clone(), dep.clone()));
+        }
+    }
+    for edges in reverse.values_mut() {
+        edges.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+    reverse
+}
+
+/// Identity of a locked package: its name, version and source (e.g.
+/// `registry+https://github.com/rust-lang/crates.io-index`), so that crates of the same name and
+/// version from different registries don't share a checksum.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ChecksumKey {
+    pub name: String,
+    pub version: String,
+    pub source: String,
+}
+
+impl ChecksumKey {
+    pub fn new(name: &str, version: &str, source: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            version: version.to_owned(),
+            source: source.to_owned(),
+        }
+    }
+
+    /// The key of a resolved package, as Cargo records it in `Cargo.lock`.
+    pub fn of(package: &Package) -> Self {
+        let source = package.source.as_ref().map_or("", |s| s.repr.as_str());
+        Self::new(package.name.as_str(), &package.version.to_string(), source)
+    }
 }
 
 impl BuckalContext {
     pub fn new() -> Self {
-        let cargo_metadata = MetadataCommand::new().exec().unwrap();
-        let root = cargo_metadata.root_package().map(|p| p.to_owned());
+        // Loaded first, as it decides the platforms dependencies are mapped for
+        let repo_config = RepoConfig::load();
+        set_map_ios(repo_config.ios);
+        let cargo_metadata = cargo_metadata().unwrap_or_exit();
         let packages_map = cargo_metadata
             .packages
             .into_iter()
             .map(|p| (p.id.to_owned(), p))
             .collect::<HashMap<_, _>>();
-        let resolve = cargo_metadata.resolve.unwrap();
-        let nodes_map = resolve
-            .nodes
+        let mut nodes_map = resolved_nodes(cargo_metadata.resolve)
+            .unwrap_or_exit()
             .into_iter()
             .map(|n| (n.id.to_owned(), n))
             .collect::<HashMap<_, _>>();
@@ -41,18 +129,304 @@ impl BuckalContext {
             .packages
             .into_iter()
             .filter(|p| p.checksum.is_some())
-            .map(|p| (format!("{}-{}", p.name, p.version), p.checksum.unwrap()))
+            .map(|p| {
+                let source = p
+                    .source
+                    .as_ref()
+                    .map(ToString::to_string)
+                    .unwrap_or_default();
+                let key = ChecksumKey::new(p.name.as_str(), &p.version.to_string(), &source);
+                (key, p.checksum.unwrap())
+            })
             .collect::<HashMap<_, _>>();
-        let repo_config = RepoConfig::load();
+        let vendor_layout = vendor_layout(
+            &nodes_map,
+            &packages_map,
+            &checksums_map,
+            repo_config.dedup_vendored,
+        );
+        unify_features(&mut nodes_map, &packages_map, &vendor_layout);
         Self {
-            root,
             nodes_map,
             packages_map,
             checksums_map,
+            vendor_layout,
             workspace_root: cargo_metadata.workspace_root.clone(),
             no_merge: false,
+            separate: false,
+            jobs: std::thread::available_parallelism().map_or(1, |n| n.get()),
             repo_config,
             workspace_members: cargo_metadata.workspace_members,
+            reverse_deps: OnceLock::new(),
+        }
+    }
+
+    /// The dependents of each package, see [`reverse_deps`].
+    pub fn reverse_deps(&self) -> &ReverseDeps {
+        self.reverse_deps
+            .get_or_init(|| reverse_deps(&self.nodes_map))
+    }
+
+    /// Buck package of a vendored third-party package, e.g. `third-party/rust/crates/foo/1.0.0`.
+    pub fn vendor_path(&self, package: &Package) -> String {
+        let id = self
+            .vendor_layout
+            .links
+            .get(&package.id)
+            .unwrap_or(&package.id);
+        self.vendor_layout
+            .dirs
+            .get(id)
+            .cloned()
+            .unwrap_or_else(|| vendor_package_path(&package.name, &package.version.to_string()))
+    }
+
+    /// Vendor directory of a third-party package, see [`Self::vendor_path`].
+    pub fn vendor_dir(&self, package: &Package) -> io::Result<Utf8PathBuf> {
+        Ok(get_buck2_root()?.join(self.vendor_path(package)))
+    }
+
+    /// Whether a package shares the vendored copy of another source, which writes its BUCK file.
+    pub fn is_linked_vendor(&self, package: &Package) -> bool {
+        self.vendor_layout.links.contains_key(&package.id)
+    }
+}
+
+/// Vendor directories of the third-party crate versions resolved from several sources.
+#[derive(Debug, Default)]
+pub struct VendorLayout {
+    /// Directory of each source vendored next to another one of the same crate version
+    pub dirs: HashMap<PackageId, String>,
+    /// Package whose vendored copy each duplicate with identical content shares
+    pub links: HashMap<PackageId, PackageId>,
+}
+
+/// Lay out the vendor directories of the crate versions resolved from several sources (e.g.
+/// crates.io and a mirror).
+///
+/// The first source (crates.io, then in order of package ID) keeps `crates/<name>/<version>`,
+/// and each other one gets a directory of its own within it, named by [`source_segment`]. With
+/// `dedup`, a source whose `Cargo.lock` checksum matches an earlier one's shares its copy instead.
+fn vendor_layout(
+    nodes_map: &HashMap<PackageId, Node>,
+    packages_map: &HashMap<PackageId, Package>,
+    checksums_map: &HashMap<ChecksumKey, Checksum>,
+    dedup: bool,
+) -> VendorLayout {
+    let mut groups: BTreeMap<(String, String), Vec<&Package>> = BTreeMap::new();
+    for (id, package) in packages_map {
+        if package.source.is_some() && nodes_map.contains_key(id) {
+            groups
+                .entry((package.name.to_string(), package.version.to_string()))
+                .or_default()
+                .push(package);
+        }
+    }
+
+    let mut layout = VendorLayout::default();
+    for ((name, version), mut packages) in groups {
+        if packages.len() < 2 {
+            continue;
+        }
+        packages.sort_by_key(|p| {
+            let crates_io = p.source.as_ref().is_some_and(|s| s.is_crates_io());
+            (!crates_io, &p.id)
+        });
+        let checksum = |p: &Package| checksums_map.get(&ChecksumKey::of(p));
+        for (i, package) in packages.iter().enumerate().skip(1) {
+            let copy = packages[..i]
+                .iter()
+                .filter(|earlier| !layout.links.contains_key(&earlier.id))
+                .find(|earlier| {
+                    dedup && checksum(package).is_some() && checksum(earlier) == checksum(package)
+                });
+            if let Some(copy) = copy {
+                layout.links.insert(package.id.clone(), copy.id.clone());
+            } else {
+                let dir = format!(
+                    "{}/{}",
+                    vendor_package_path(&name, &version),
+                    source_segment(&package.id)
+                );
+                layout.dirs.insert(package.id.clone(), dir);
+            }
+        }
+    }
+    layout
+}
+
+/// Give every node sharing a vendored copy the union of their features.
+///
+/// Such nodes (the same crate version pulled from several sources by different members, see
+/// [`vendor_layout`]) share a single set of rules, which must build everything any of their
+/// dependents needs.
+fn unify_features(
+    nodes_map: &mut HashMap<PackageId, Node>,
+    packages_map: &HashMap<PackageId, Package>,
+    layout: &VendorLayout,
+) {
+    let mut groups: BTreeMap<&PackageId, Vec<&PackageId>> = BTreeMap::new();
+    for (id, copy) in &layout.links {
+        groups.entry(copy).or_insert_with(|| vec![copy]).push(id);
+    }
+
+    for (copy, ids) in groups {
+        let (name, version) = (&packages_map[copy].name, &packages_map[copy].version);
+        let mut union: Vec<_> = ids
+            .iter()
+            .flat_map(|id| nodes_map[*id].features.iter().cloned())
+            .collect();
+        union.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        union.dedup_by(|a, b| a.as_str() == b.as_str());
+
+        if ids
+            .iter()
+            .any(|id| nodes_map[*id].features.len() != union.len())
+        {
+            buckal_warn!(
+                "{} v{} is resolved with different features by its dependents, enabling all of them: {}",
+                name,
+                version,
+                union.iter().map(|f| f.as_str()).join(", ")
+            );
+        }
+        for id in ids {
+            nodes_map.get_mut(id).unwrap().features = union.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::{REGISTRY, mock_dep, mock_node};
+    const GIT: &str = "git+https://github.com/foo/foo?rev=abc#abc";
+    const MIRROR: &str = "registry+https://mirror.example.com/index";
+
+    fn mock_package(name: &str, id: &str, source: Option<&str>) -> Package {
+        crate::testing::mock_package(name, "1.0.0", id, source)
+    }
+
+    /// `foo` 1.0.0 from crates.io, a mirror with the same content and git.
+    fn foo_from_three_sources() -> BuckalContext {
+        let packages = [
+            mock_package("foo", &format!("{REGISTRY}#foo@1.0.0"), Some(REGISTRY)),
+            mock_package("foo", &format!("{MIRROR}#foo@1.0.0"), Some(MIRROR)),
+            mock_package("foo", &format!("{GIT}#foo@1.0.0"), Some(GIT)),
+        ];
+        let checksum: Checksum = "00".repeat(32).parse().unwrap();
+        BuckalContext {
+            nodes_map: packages
+                .iter()
+                .map(|p| {
+                    (
+                        p.id.clone(),
+                        mock_node(&p.id.repr, serde_json::json!([]), &[]),
+                    )
+                })
+                .collect(),
+            checksums_map: packages
+                .iter()
+                .filter(|p| p.source.as_ref().is_some_and(|s| s.repr != GIT))
+                .map(|p| (ChecksumKey::of(p), checksum.clone()))
+                .collect(),
+            packages_map: packages.into_iter().map(|p| (p.id.clone(), p)).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_vendor_layout() {
+        let mut ctx = foo_from_three_sources();
+        let path = |ctx: &BuckalContext, source: &str| {
+            ctx.vendor_path(
+                &ctx.packages_map[&PackageId {
+                    repr: format!("{source}#foo@1.0.0"),
+                }],
+            )
+        };
+
+        // Each source is vendored on its own, crates.io keeping the usual directory
+        for dedup in [false, true] {
+            ctx.vendor_layout =
+                vendor_layout(&ctx.nodes_map, &ctx.packages_map, &ctx.checksums_map, dedup);
+            assert_eq!(path(&ctx, REGISTRY), "third-party/rust/crates/foo/1.0.0");
+            let git = path(&ctx, GIT);
+            assert!(
+                git.starts_with("third-party/rust/crates/foo/1.0.0/"),
+                "{git}"
+            );
+            assert_ne!(path(&ctx, MIRROR), git);
+
+            // With dedup, the mirror's identical copy shares the storage of crates.io's
+            let mirror = path(&ctx, MIRROR);
+            if dedup {
+                assert_eq!(mirror, "third-party/rust/crates/foo/1.0.0");
+            } else {
+                assert!(
+                    mirror.starts_with("third-party/rust/crates/foo/1.0.0/"),
+                    "{mirror}"
+                );
+            }
+        }
+        let mirror = &ctx.packages_map[&PackageId {
+            repr: format!("{MIRROR}#foo@1.0.0"),
+        }];
+        assert!(ctx.is_linked_vendor(mirror));
+    }
+
+    #[test]
+    fn test_unify_features_across_members() {
+        // Two members each pull `foo` 1.0.0, from crates.io and an identical mirror, with disjoint
+        // features
+        let foo_registry = format!("{REGISTRY}#foo@1.0.0");
+        let foo_mirror = format!("{MIRROR}#foo@1.0.0");
+        let mut ctx = foo_from_three_sources();
+        ctx.packages_map.extend(
+            [
+                mock_package("app1", "path+file:///ws/app1#0.1.0", None),
+                mock_package("app2", "path+file:///ws/app2#0.1.0", None),
+            ]
+            .map(|p| (p.id.clone(), p)),
+        );
+        ctx.nodes_map.extend(
+            [
+                mock_node(
+                    "path+file:///ws/app1#0.1.0",
+                    serde_json::json!([mock_dep("foo", &foo_registry)]),
+                    &[],
+                ),
+                mock_node(
+                    "path+file:///ws/app2#0.1.0",
+                    serde_json::json!([mock_dep("foo", &foo_mirror)]),
+                    &[],
+                ),
+                mock_node(&foo_registry, serde_json::json!([]), &["std", "derive"]),
+                mock_node(&foo_mirror, serde_json::json!([]), &["alloc"]),
+                mock_node(
+                    &format!("{GIT}#foo@1.0.0"),
+                    serde_json::json!([]),
+                    &["default"],
+                ),
+            ]
+            .map(|n| (n.id.clone(), n)),
+        );
+        let (mut nodes_map, packages_map) = (ctx.nodes_map, ctx.packages_map);
+        let layout = vendor_layout(&nodes_map, &packages_map, &ctx.checksums_map, true);
+
+        unify_features(&mut nodes_map, &packages_map, &layout);
+
+        for node in nodes_map.values() {
+            let features: Vec<&str> = node.features.iter().map(|f| f.as_str()).collect();
+            if node.id.repr.starts_with(GIT) {
+                // Vendored on its own, so left as is
+                assert_eq!(features, ["default"]);
+            } else if packages_map[&node.id].name.as_str() == "foo" {
+                assert_eq!(features, ["alloc", "derive", "std"]);
+            } else {
+                assert!(features.is_empty());
+            }
         }
     }
 }
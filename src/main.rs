@@ -9,6 +9,8 @@ mod commands;
 mod config;
 mod context;
 mod platform;
+#[cfg(test)]
+mod testing;
 mod utils;
 
 use std::sync::OnceLock;
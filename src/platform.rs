@@ -1,6 +1,5 @@
 use std::{
     collections::{BTreeSet, HashMap},
-    process::Command,
     str::FromStr,
     sync::OnceLock,
 };
@@ -8,16 +7,31 @@ use std::{
 use bitflags::bitflags;
 use cargo_platform::{Cfg, CfgExpr, Platform};
 
-use crate::buckal_warn;
+use crate::{
+    buckal_warn,
+    utils::{rustc_command, warn_uncounted},
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Os {
     Windows,
     Macos,
     Linux,
+    Ios,
 }
 
 impl Os {
+    pub const ALL: [Os; 3] = [Os::Windows, Os::Macos, Os::Linux];
+
+    /// The OSes dependencies are mapped for: [`Os::ALL`], plus iOS when enabled.
+    pub fn mapped() -> BTreeSet<Os> {
+        let mut oses = BTreeSet::from(Os::ALL);
+        if map_ios() {
+            oses.insert(Os::Ios);
+        }
+        oses
+    }
+
     pub fn buck_label(self) -> &'static str {
         match self {
             // Use canonical prelude constraint values so selects work with
@@ -25,6 +39,7 @@ impl Os {
             Os::Windows => "prelude//os/constraints:windows",
             Os::Macos => "prelude//os/constraints:macos",
             Os::Linux => "prelude//os/constraints:linux",
+            Os::Ios => "prelude//os/constraints:iphoneos",
         }
     }
 
@@ -33,10 +48,65 @@ impl Os {
             Os::Windows => "windows",
             Os::Macos => "macos",
             Os::Linux => "linux",
+            Os::Ios => "ios",
         }
     }
 }
 
+/// CPU architectures crates can be restricted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Cpu {
+    X86_64,
+    Arm64,
+    X86,
+    Arm,
+}
+
+impl Cpu {
+    pub fn buck_label(self) -> &'static str {
+        match self {
+            Cpu::X86_64 => "prelude//cpu/constraints:x86_64",
+            Cpu::Arm64 => "prelude//cpu/constraints:arm64",
+            Cpu::X86 => "prelude//cpu/constraints:x86_32",
+            Cpu::Arm => "prelude//cpu/constraints:arm32",
+        }
+    }
+}
+
+/// Buck constraint labels describing a host from its rustc cfgs (OS and CPU).
+///
+/// Used to pin build scripts to an execution platform matching the machine that runs
+/// `cargo buckal`. Unknown OSes or CPUs are left out rather than guessed.
+pub fn host_constraints(host_cfgs: &[Cfg]) -> BTreeSet<String> {
+    let key_value = |key: &str| {
+        host_cfgs.iter().find_map(|cfg| match cfg {
+            Cfg::KeyPair(k, v) if k == key => Some(v.as_str()),
+            _ => None,
+        })
+    };
+
+    let mut constraints = BTreeSet::new();
+    let os = match key_value("target_os") {
+        Some("windows") => Some(Os::Windows),
+        Some("macos") => Some(Os::Macos),
+        Some("linux") => Some(Os::Linux),
+        Some("ios") => Some(Os::Ios),
+        _ => None,
+    };
+    if let Some(os) = os {
+        constraints.insert(os.buck_label().to_owned());
+    }
+    let cpu = match key_value("target_arch") {
+        Some("x86_64") => Some("x86_64"),
+        Some("aarch64") => Some("arm64"),
+        _ => None,
+    };
+    if let Some(cpu) = cpu {
+        constraints.insert(format!("prelude//cpu/constraints:{cpu}"));
+    }
+    constraints
+}
+
 /// Tier1 host platforms used for cfg evaluation.
 /// Ref: https://doc.rust-lang.org/nightly/rustc/platform-support.html#tier-1-with-host-tools
 static SUPPORTED_TARGETS: &[(Os, &str)] = &[
@@ -45,6 +115,54 @@ static SUPPORTED_TARGETS: &[(Os, &str)] = &[
     (Os::Linux, "x86_64-unknown-linux-gnu"),
 ];
 
+/// Mapped on top of `SUPPORTED_TARGETS` with `ios = true` in `buckal.toml` only: `cfg(unix)`
+/// matches it, so unix-only crates would otherwise become compatible with `iphoneos` too.
+const IOS_TARGET: (Os, &str) = (Os::Ios, "aarch64-apple-ios");
+
+static MAP_IOS: OnceLock<bool> = OnceLock::new();
+
+/// Whether to map iOS, from the repo config; must be set before any platform is evaluated.
+pub fn set_map_ios(enabled: bool) {
+    let _ = MAP_IOS.set(enabled);
+}
+
+fn map_ios() -> bool {
+    MAP_IOS.get().copied().unwrap_or(false)
+}
+
+/// The targets dependencies are mapped for.
+fn mapped_targets(ios: bool) -> Vec<(Os, &'static str)> {
+    let mut targets = SUPPORTED_TARGETS.to_vec();
+    if ios {
+        targets.push(IOS_TARGET);
+    }
+    targets
+}
+
+/// Why platform mapping may be off on `host`, if it isn't among `SUPPORTED_TARGETS`.
+fn unsupported_host_message(host: &str) -> Option<String> {
+    if SUPPORTED_TARGETS.iter().any(|(_, triple)| *triple == host) {
+        return None;
+    }
+    let supported: Vec<&str> = SUPPORTED_TARGETS.iter().map(|(_, t)| *t).collect();
+    Some(format!(
+        "Host triple `{host}` is not a supported target ({}): platform-conditional dependencies \
+         are mapped for those targets only and may be incomplete, and `//platforms:{host}` may \
+         not exist, so `--target-platforms` might be needed",
+        supported.join(", ")
+    ))
+}
+
+/// Warn if the host triple isn't among the targets dependencies are mapped for.
+///
+/// The warning is about the host rather than the generated rules, so it doesn't count toward
+/// `--fail-on-warn`, which would otherwise fail every run on such hosts.
+pub fn check_host_triple(host: &str) {
+    if let Some(message) = unsupported_host_message(host) {
+        warn_uncounted(&message);
+    }
+}
+
 /// Cache of `rustc --print=cfg --target <triple>` output for supported triples.
 static CFG_CACHE: OnceLock<HashMap<&'static str, Vec<Cfg>>> = OnceLock::new();
 
@@ -83,7 +201,7 @@ static CFG_CACHE: OnceLock<HashMap<&'static str, Vec<Cfg>>> = OnceLock::new();
 /// }
 /// ```
 fn get_rustc_cfgs_for_triple(triple: &'static str) -> Option<Vec<Cfg>> {
-    match Command::new("rustc")
+    match rustc_command()
         .args(["--print=cfg", "--target", triple])
         .output()
     {
@@ -113,20 +231,45 @@ fn get_rustc_cfgs_for_triple(triple: &'static str) -> Option<Vec<Cfg>> {
     }
 }
 
+/// Env var bounding how many `rustc --print=cfg` processes run at once (default: one per triple).
+const CFG_JOBS_ENV: &str = "BUCKAL_CFG_JOBS";
+
+fn cfg_jobs() -> usize {
+    std::env::var(CFG_JOBS_ENV)
+        .ok()
+        .and_then(|jobs| jobs.parse::<usize>().ok())
+        .filter(|jobs| *jobs > 0)
+        .unwrap_or(mapped_targets(map_ios()).len())
+}
+
 fn cfg_cache() -> &'static HashMap<&'static str, Vec<Cfg>> {
     CFG_CACHE.get_or_init(|| {
-        // We spawn one thread per target triple. This is acceptable because:
-        // 1. This initialization runs exactly once per program execution (OnceLock).
-        // 2. The work is I/O-bound (waiting on rustc subprocess execution), not CPU-bound,
-        //    so having more threads than cores improves throughput rather than causing
-        //    contention - threads spend most of their time blocked on I/O.
-        // 3. The bounded number of targets (tier-1 platforms) keeps thread count reasonable.
+        let triples: Vec<&'static str> = mapped_targets(map_ios())
+            .into_iter()
+            .map(|(_, t)| t)
+            .collect();
+        query_triples(&triples, cfg_jobs(), get_rustc_cfgs_for_triple)
+    })
+}
+
+/// Run `query` for every triple, with at most `jobs` threads in flight, and collect the
+/// successful results.
+fn query_triples<T, F>(triples: &[&'static str], jobs: usize, query: F) -> HashMap<&'static str, T>
+where
+    T: Send,
+    F: Fn(&'static str) -> Option<T> + Sync,
+{
+    // Threads are spawned per triple within a batch. The work is I/O-bound (waiting on rustc
+    // subprocess execution), so more threads than cores is fine; the batch size only exists to
+    // keep constrained CI machines from running too many rustc processes at once.
+    let mut map = HashMap::new();
+    for batch in triples.chunks(jobs.max(1)) {
         let results = std::thread::scope(|scope| {
-            let handles = SUPPORTED_TARGETS
+            let handles = batch
                 .iter()
-                .map(|(_, triple)| {
-                    let triple = *triple;
-                    scope.spawn(move || (triple, get_rustc_cfgs_for_triple(triple)))
+                .map(|&triple| {
+                    let query = &query;
+                    scope.spawn(move || (triple, query(triple)))
                 })
                 .collect::<Vec<_>>();
 
@@ -140,14 +283,13 @@ fn cfg_cache() -> &'static HashMap<&'static str, Vec<Cfg>> {
                 .collect::<Vec<_>>()
         });
 
-        let mut map = HashMap::new();
-        for (triple, cfgs) in results {
-            if let Some(cfgs) = cfgs {
-                map.insert(triple, cfgs);
+        for (triple, result) in results {
+            if let Some(result) = result {
+                map.insert(triple, result);
             }
         }
-        map
-    })
+    }
+    map
 }
 
 pub fn buck_labels(oses: &BTreeSet<Os>) -> BTreeSet<String> {
@@ -156,8 +298,8 @@ pub fn buck_labels(oses: &BTreeSet<Os>) -> BTreeSet<String> {
 
 /// Returns the set of host OSes that satisfy a Cargo [`Platform`].
 ///
-/// This evaluates `platform` against a fixed set of Rust tier-1 host targets (`SUPPORTED_TARGETS`)
-/// by asking `rustc` for each target's cfg values (`rustc --print=cfg --target <triple>`) and then
+/// This evaluates `platform` against a fixed set of Rust tier-1 host targets (`SUPPORTED_TARGETS`,
+/// plus iOS when enabled) by asking `rustc` for each target's cfg values (`rustc --print=cfg --target <triple>`) and then
 /// using [`Platform::matches`] to determine which target triples match.
 ///
 /// # Notes
@@ -171,12 +313,12 @@ pub fn buck_labels(oses: &BTreeSet<Os>) -> BTreeSet<String> {
 ///   tier-1 target triples.
 pub fn oses_from_platform(platform: &Platform) -> BTreeSet<Os> {
     let cfgs = cfg_cache();
-    SUPPORTED_TARGETS
-        .iter()
+    mapped_targets(map_ios())
+        .into_iter()
         .filter_map(|(os, triple)| {
             cfgs.get(triple).and_then(|cfgs| {
                 if platform.matches(triple, cfgs) {
-                    Some(*os)
+                    Some(os)
                 } else {
                     None
                 }
@@ -224,10 +366,97 @@ bitflags! {
         const WINDOWS = 0b0001;
         const MACOS   = 0b0010;
         const LINUX   = 0b0100;
+        const IOS     = 0b1000_0000;
+        const X86_64  = 0b0000_1000;
+        const ARM64   = 0b0001_0000;
+        const X86     = 0b0010_0000;
+        const ARM     = 0b0100_0000;
+
+        const OS      = Self::WINDOWS.bits() | Self::MACOS.bits() | Self::LINUX.bits() | Self::IOS.bits();
+        const CPU     = Self::X86_64.bits() | Self::ARM64.bits() | Self::X86.bits() | Self::ARM.bits();
+        const BITS_64 = Self::X86_64.bits() | Self::ARM64.bits();
+    }
+}
+
+/// Where a crate is known to build.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlatformSupport {
+    pub oses: BTreeSet<Os>,
+    /// `None` when the crate builds on any CPU
+    pub cpus: Option<BTreeSet<Cpu>>,
+}
+
+/// Buck constraints restricting a target to the platforms a crate supports.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Compatibility {
+    /// `compatible_with`: any one of the labels
+    AnyOf(BTreeSet<String>),
+    /// `target_compatible_with`: all of the labels
+    AllOf(BTreeSet<String>),
+}
+
+impl PlatformSupport {
+    /// The constraints to emit. Buck can't combine an OS and a CPU within one `compatible_with`
+    /// entry, so a CPU restriction is only expressed when it applies to every OS, or when a
+    /// single OS and CPU remain; otherwise compatibility stays at the OS level.
+    pub fn compatibility(&self) -> Compatibility {
+        let cpu_labels = |cpus: &BTreeSet<Cpu>| -> BTreeSet<String> {
+            cpus.iter().map(|cpu| cpu.buck_label().to_owned()).collect()
+        };
+        match &self.cpus {
+            Some(cpus) if self.oses.len() == Os::mapped().len() && cpus.len() == 1 => {
+                Compatibility::AllOf(cpu_labels(cpus))
+            }
+            Some(cpus) if self.oses.len() == Os::mapped().len() => {
+                Compatibility::AnyOf(cpu_labels(cpus))
+            }
+            Some(cpus) if self.oses.len() == 1 && cpus.len() == 1 => {
+                let mut labels = buck_labels(&self.oses);
+                labels.extend(cpu_labels(cpus));
+                Compatibility::AllOf(labels)
+            }
+            _ => Compatibility::AnyOf(buck_labels(&self.oses)),
+        }
+    }
+}
+
+impl Compatibility {
+    /// Set the matching attribute of a rule.
+    pub fn apply(
+        self,
+        compatible_with: &mut BTreeSet<String>,
+        target_compatible_with: &mut BTreeSet<String>,
+    ) {
+        match self {
+            Compatibility::AnyOf(labels) => *compatible_with = labels,
+            Compatibility::AllOf(labels) => *target_compatible_with = labels,
+        }
     }
 }
 
 impl PlatformMask {
+    /// The platforms of the mask; a mask without OS or CPU bits doesn't restrict that dimension.
+    pub fn to_support(self) -> PlatformSupport {
+        let oses = if self.intersects(Self::OS) {
+            self.to_oses()
+        } else {
+            Os::mapped()
+        };
+        let cpus = self.intersects(Self::CPU).then(|| {
+            [
+                (Self::X86_64, Cpu::X86_64),
+                (Self::ARM64, Cpu::Arm64),
+                (Self::X86, Cpu::X86),
+                (Self::ARM, Cpu::Arm),
+            ]
+            .into_iter()
+            .filter(|(bit, _)| self.contains(*bit))
+            .map(|(_, cpu)| cpu)
+            .collect()
+        });
+        PlatformSupport { oses, cpus }
+    }
+
     pub fn to_oses(self) -> BTreeSet<Os> {
         let mut set = BTreeSet::new();
         if self.contains(Self::WINDOWS) {
@@ -239,6 +468,9 @@ impl PlatformMask {
         if self.contains(Self::LINUX) {
             set.insert(Os::Linux);
         }
+        if self.contains(Self::IOS) {
+            set.insert(Os::Ios);
+        }
         set
     }
 }
@@ -254,10 +486,10 @@ static PACKAGE_PLATFORMS: phf::Map<&'static str, PlatformMask> = phf::phf_map! {
     "winreg" => PlatformMask::WINDOWS,
 };
 
-pub fn lookup_platforms(package_name: &str) -> Option<BTreeSet<Os>> {
+pub fn lookup_platforms(package_name: &str) -> Option<PlatformSupport> {
     PACKAGE_PLATFORMS
         .get(package_name)
-        .map(|mask| mask.to_oses())
+        .map(|mask| mask.to_support())
 }
 
 #[cfg(test)]
@@ -376,6 +608,33 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_host_constraints() {
+        let cfgs: Vec<Cfg> = ["target_os=\"linux\"", "target_arch=\"x86_64\"", "unix"]
+            .iter()
+            .map(|line| Cfg::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            host_constraints(&cfgs),
+            BTreeSet::from([
+                "prelude//cpu/constraints:x86_64".to_owned(),
+                "prelude//os/constraints:linux".to_owned(),
+            ])
+        );
+
+        let cfgs: Vec<Cfg> = ["target_os=\"macos\"", "target_arch=\"aarch64\""]
+            .iter()
+            .map(|line| Cfg::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            host_constraints(&cfgs),
+            BTreeSet::from([
+                "prelude//cpu/constraints:arm64".to_owned(),
+                "prelude//os/constraints:macos".to_owned(),
+            ])
+        );
+    }
+
     #[test]
     fn test_platform_mask_operations() {
         // Test PlatformMask operations
@@ -409,17 +668,61 @@ mod tests {
         let windows_pkgs = lookup_platforms("windows-future").unwrap();
         let mut expected = BTreeSet::new();
         expected.insert(Os::Windows);
-        assert_eq!(windows_pkgs, expected);
+        assert_eq!(windows_pkgs.oses, expected);
+        assert_eq!(windows_pkgs.cpus, None);
 
         let macos_pkgs = lookup_platforms("system-configuration").unwrap();
         let mut expected = BTreeSet::new();
         expected.insert(Os::Macos);
-        assert_eq!(macos_pkgs, expected);
+        assert_eq!(macos_pkgs.oses, expected);
 
         // Test unknown package returns None
         assert!(lookup_platforms("unknown-package").is_none());
     }
 
+    #[test]
+    fn test_64_bit_only_compatibility() {
+        let support = PlatformMask::BITS_64.to_support();
+        assert_eq!(support.oses, BTreeSet::from(Os::ALL));
+        assert_eq!(
+            support.cpus,
+            Some(BTreeSet::from([Cpu::X86_64, Cpu::Arm64]))
+        );
+        assert_eq!(
+            support.compatibility(),
+            Compatibility::AnyOf(BTreeSet::from([
+                "prelude//cpu/constraints:arm64".to_owned(),
+                "prelude//cpu/constraints:x86_64".to_owned(),
+            ]))
+        );
+
+        // A single OS and CPU are both required
+        let linux_x86_64 = (PlatformMask::LINUX | PlatformMask::X86_64).to_support();
+        assert_eq!(
+            linux_x86_64.compatibility(),
+            Compatibility::AllOf(BTreeSet::from([
+                "prelude//cpu/constraints:x86_64".to_owned(),
+                "prelude//os/constraints:linux".to_owned(),
+            ]))
+        );
+
+        // OS and CPU sets that can't be combined fall back to the OSes
+        let unix_64 =
+            (PlatformMask::LINUX | PlatformMask::MACOS | PlatformMask::BITS_64).to_support();
+        assert_eq!(
+            unix_64.compatibility(),
+            Compatibility::AnyOf(buck_labels(&BTreeSet::from([Os::Linux, Os::Macos])))
+        );
+
+        let mut compatible_with = BTreeSet::new();
+        let mut target_compatible_with = BTreeSet::new();
+        linux_x86_64
+            .compatibility()
+            .apply(&mut compatible_with, &mut target_compatible_with);
+        assert!(compatible_with.is_empty());
+        assert_eq!(target_compatible_with.len(), 2);
+    }
+
     #[test]
     fn test_buck_labels_utility() {
         // Test the buck_labels utility function
@@ -445,4 +748,60 @@ mod tests {
             assert!(!triple.is_empty());
         }
     }
+
+    #[test]
+    fn test_unsupported_host_message() {
+        assert!(unsupported_host_message("x86_64-unknown-linux-gnu").is_none());
+        let message = unsupported_host_message("riscv64gc-unknown-linux-gnu").unwrap();
+        assert!(
+            message.contains("`riscv64gc-unknown-linux-gnu`"),
+            "{message}"
+        );
+        assert!(message.contains("--target-platforms"), "{message}");
+        assert!(unsupported_host_message("x86_64-unknown-linux-musl").is_some());
+    }
+
+    #[test]
+    fn test_ios_is_opt_in() {
+        assert!(!Os::ALL.contains(&Os::Ios));
+        assert!(!mapped_targets(false).iter().any(|(os, _)| *os == Os::Ios));
+        assert!(mapped_targets(true).contains(&IOS_TARGET));
+        assert_eq!(mapped_targets(false).len(), Os::ALL.len());
+
+        // Printing the cfgs of a builtin target doesn't need it installed
+        let cfgs = get_rustc_cfgs_for_triple(IOS_TARGET.1).expect("rustc knows aarch64-apple-ios");
+        let ios = Platform::from_str("cfg(target_os = \"ios\")").unwrap();
+        assert!(ios.matches(IOS_TARGET.1, &cfgs));
+        assert!(host_constraints(&cfgs).contains(Os::Ios.buck_label()));
+    }
+
+    #[test]
+    fn test_query_triples_respects_concurrency_limit() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        const TRIPLES: &[&str] = &[
+            "aarch64-apple-darwin",
+            "x86_64-pc-windows-msvc",
+            "x86_64-unknown-linux-gnu",
+            "wasm32-unknown-unknown",
+            "not-installed",
+        ];
+
+        for jobs in [0, 1, 2, 3, 16] {
+            let running = AtomicUsize::new(0);
+            let peak = AtomicUsize::new(0);
+            let results = query_triples(TRIPLES, jobs, |triple| {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(std::time::Duration::from_millis(5));
+                running.fetch_sub(1, Ordering::SeqCst);
+                (triple != "not-installed").then_some(triple.len())
+            });
+
+            assert_eq!(results.len(), TRIPLES.len() - 1, "jobs = {jobs}");
+            assert_eq!(results["wasm32-unknown-unknown"], 22);
+            assert!(!results.contains_key("not-installed"));
+            assert!(peak.load(Ordering::SeqCst) <= jobs.max(1), "jobs = {jobs}");
+        }
+    }
 }
@@ -0,0 +1,88 @@
+//! Fixtures shared by the unit tests.
+
+use cargo_metadata::{Node, Package, Target, TargetKind, camino::Utf8PathBuf};
+
+pub const REGISTRY: &str = "registry+https://github.com/rust-lang/crates.io-index";
+
+/// A package without targets or dependencies, first-party if `source` is `None`, whose manifest
+/// is `/ws/<name>/Cargo.toml`.
+pub fn mock_package(name: &str, version: &str, id: &str, source: Option<&str>) -> Package {
+    serde_json::from_value(serde_json::json!({
+        "name": name,
+        "version": version,
+        "id": id,
+        "license": null,
+        "license_file": null,
+        "description": null,
+        "source": source,
+        "dependencies": [],
+        "targets": [],
+        "features": {},
+        "manifest_path": format!("/ws/{name}/Cargo.toml"),
+        "metadata": null,
+        "publish": null,
+        "authors": [],
+        "categories": [],
+        "keywords": [],
+        "readme": null,
+        "repository": null,
+        "homepage": null,
+        "documentation": null,
+        "edition": "2021",
+        "links": null,
+        "default_run": null,
+        "rust_version": null
+    }))
+    .unwrap()
+}
+
+/// A node of the resolved graph with the given `deps` (as in `cargo metadata`, see [`mock_dep`])
+/// and enabled `features`.
+pub fn mock_node(id: &str, deps: serde_json::Value, features: &[&str]) -> Node {
+    serde_json::from_value(serde_json::json!({
+        "id": id,
+        "deps": deps,
+        "dependencies": [],
+        "features": features
+    }))
+    .unwrap()
+}
+
+/// A normal dependency on the package `pkg`, referred to as `name`, for [`mock_node`].
+pub fn mock_dep(name: &str, pkg: &str) -> serde_json::Value {
+    serde_json::json!({
+        "name": name,
+        "pkg": pkg,
+        "dep_kinds": [{ "kind": null, "target": null }]
+    })
+}
+
+/// A target of the given kind, whose source doesn't exist.
+pub fn mock_target(name: &str, kind: TargetKind) -> Target {
+    serde_json::from_value(serde_json::json!({
+        "name": name,
+        "kind": [kind],
+        "crate_types": [],
+        "required_features": [],
+        "src_path": "/nonexistent/dummy.rs",
+        "edition": "2021",
+        "doctest": true,
+        "test": true
+    }))
+    .unwrap()
+}
+
+/// A path under the system temp dir that no other test or run uses, e.g.
+/// `/tmp/cargo-buckal-<name>-<pid>-<nanos>`. It isn't created.
+pub fn temp_dir(name: &str) -> Utf8PathBuf {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    Utf8PathBuf::from_path_buf(std::env::temp_dir())
+        .unwrap()
+        .join(format!(
+            "cargo-buckal-{name}-{}-{nanos}",
+            std::process::id()
+        ))
+}
@@ -1,18 +1,28 @@
-use cargo_metadata::MetadataCommand;
-use cargo_metadata::camino::Utf8PathBuf;
+use cargo_metadata::camino::{Utf8Path, Utf8PathBuf};
+use cargo_metadata::{Metadata, MetadataCommand, Node, Package, PackageId, Resolve};
 use cargo_platform::Cfg;
 use colored::Colorize;
 use inquire::Select;
-use std::collections::HashMap;
-use std::{io, process::Command, str::FromStr};
+use std::collections::BTreeSet;
+use std::{
+    io::{self, Read},
+    process::Command,
+    str::FromStr,
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
 
 use crate::RUST_CRATES_ROOT;
 use crate::buck2::Buck2Command;
 use crate::cache::BuckalCache;
+use crate::context::BuckalContext;
 
 #[macro_export]
 macro_rules! buckal_log {
     ($action:expr, $msg:expr) => {{
+        let msg = $msg;
         let colored = match $action {
             "Adding" => ::colored::Colorize::green($action),
             "Creating" => ::colored::Colorize::green($action),
@@ -21,61 +31,138 @@ macro_rules! buckal_log {
             "Fetching" => ::colored::Colorize::cyan($action),
             _ => ::colored::Colorize::blue($action),
         };
-        println!("{:>12} {}", ::colored::Colorize::bold(colored), $msg);
+        println!("{:>12} {}", ::colored::Colorize::bold(colored), msg);
+        $crate::utils::write_log_file("INFO", format_args!("{} {}", $action, msg));
     }};
 }
 
 #[macro_export]
 macro_rules! buckal_error {
-    ($msg:expr) => {{
-        let error_prefix = ::colored::Colorize::red("error:");
-        eprintln!("{} {}", ::colored::Colorize::bold(error_prefix), $msg);
-    }};
+    ($msg:expr) => {
+        $crate::buckal_error!("{}", $msg)
+    };
 
     ($fmt:expr, $($arg:tt)*) => {{
         let error_prefix = ::colored::Colorize::red("error:");
-        eprintln!(
-            "{} {}",
-            ::colored::Colorize::bold(error_prefix),
-            format_args!($fmt, $($arg)*)
-        );
+        let msg = format!($fmt, $($arg)*);
+        eprintln!("{} {}", ::colored::Colorize::bold(error_prefix), msg);
+        $crate::utils::write_log_file("ERROR", format_args!("{}", msg));
     }};
 }
 
 #[macro_export]
 macro_rules! buckal_note {
-    ($msg:expr) => {{
-        let note_prefix = ::colored::Colorize::cyan("note:");
-        eprintln!("{} {}", ::colored::Colorize::bold(note_prefix), $msg);
-    }};
+    ($msg:expr) => {
+        $crate::buckal_note!("{}", $msg)
+    };
 
     ($fmt:expr, $($arg:tt)*) => {{
         let note_prefix = ::colored::Colorize::cyan("note:");
-        eprintln!(
-            "{} {}",
-            ::colored::Colorize::bold(note_prefix),
-            format_args!($fmt, $($arg)*)
-        );
+        let msg = format!($fmt, $($arg)*);
+        eprintln!("{} {}", ::colored::Colorize::bold(note_prefix), msg);
+        $crate::utils::write_log_file("NOTE", format_args!("{}", msg));
     }};
 }
 
 #[macro_export]
 macro_rules! buckal_warn {
-    ($msg:expr) => {{
-        let warn_prefix = ::colored::Colorize::yellow("warn:");
-        eprintln!("{} {}", ::colored::Colorize::bold(warn_prefix), $msg);
-    }};
+    ($msg:expr) => {
+        $crate::buckal_warn!("{}", $msg)
+    };
 
     ($fmt:expr, $($arg:tt)*) => {{
         let warn_prefix = ::colored::Colorize::yellow("warn:");
-        eprintln!(
-            "{} {}",
-            ::colored::Colorize::bold(warn_prefix),
-            format_args!($fmt, $($arg)*)
-        );
+        let msg = format!($fmt, $($arg)*);
+        eprintln!("{} {}", ::colored::Colorize::bold(warn_prefix), msg);
+        $crate::utils::write_log_file("WARN", format_args!("{}", msg));
+        $crate::utils::record_warning();
     }};
 }
 
+/// Print a warning like `buckal_warn!` without counting it toward `--fail-on-warn`.
+pub fn warn_uncounted(msg: &str) {
+    let warn_prefix = colored::Colorize::yellow("warn:");
+    eprintln!("{} {}", colored::Colorize::bold(warn_prefix), msg);
+    write_log_file("WARN", format_args!("{}", msg));
+}
+
+/// Warnings printed by `buckal_warn!` so far, for `--fail-on-warn`.
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+
+/// Count a warning. Used by `buckal_warn!`.
+pub fn record_warning() {
+    WARNINGS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Number of warnings printed so far.
+pub fn warning_count() -> usize {
+    WARNINGS.load(Ordering::Relaxed)
+}
+
+/// File the `buckal_*` macros tee their output to, set by `--log-file`.
+static LOG_FILE: Mutex<Option<std::fs::File>> = Mutex::new(None);
+
+/// Tee the output of the `buckal_*` macros to `path` (appending), or stop with `None`.
+pub fn set_log_file(path: Option<&Utf8Path>) -> io::Result<()> {
+    let file = match path {
+        Some(path) => Some(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?,
+        ),
+        None => None,
+    };
+    *LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()) = file;
+    Ok(())
+}
+
+/// Append a timestamped line to the log file, if any. Used by the `buckal_*` macros.
+pub fn write_log_file(level: &str, msg: std::fmt::Arguments) {
+    let mut log_file = LOG_FILE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(file) = log_file.as_mut() {
+        let line = format!(
+            "{} {:<5} {}\n",
+            utc_timestamp(std::time::SystemTime::now()),
+            level,
+            msg
+        );
+        // Logging must never abort a sync
+        let _ = io::Write::write_all(file, line.as_bytes());
+    }
+}
+
+/// RFC 3339 UTC timestamp with millisecond precision, e.g. `2025-01-31T12:00:00.000Z`.
+fn utc_timestamp(time: std::time::SystemTime) -> String {
+    let since_epoch = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3_600,
+        secs_of_day % 3_600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
 pub fn check_buck2_installed() -> bool {
     Buck2Command::new()
         .arg("--help")
@@ -313,12 +400,59 @@ pub fn get_buck2_root() -> io::Result<Utf8PathBuf> {
         let path_str = String::from_utf8_lossy(&out_put.stdout).trim().to_string();
         Ok(Utf8PathBuf::from(path_str))
     } else {
-        Err(io::Error::other(
-            String::from_utf8_lossy(&out_put.stderr).to_string(),
+        let cwd = std::env::current_dir()?;
+        Err(buck2_root_error(
+            &String::from_utf8_lossy(&out_put.stderr),
+            &cwd,
         ))
     }
 }
 
+/// Explain a failed `buck2 root`, spelling out the common case of running outside a project.
+fn buck2_root_error(stderr: &str, cwd: &std::path::Path) -> io::Error {
+    let in_project = cwd.ancestors().any(|dir| dir.join(".buckconfig").is_file());
+    if in_project {
+        io::Error::other(format!("`buck2 root` failed:\n{}", stderr.trim_end()))
+    } else {
+        io::Error::other(format!(
+            "`{}` is not inside a Buck2 project (no `.buckconfig` found in it or its parents)\n\
+             help: run `cargo buckal init` to set one up, or `cargo buckal migrate --init <root>` for an existing Cargo project",
+            cwd.display()
+        ))
+    }
+}
+
+/// Refuse to overwrite BUCK files under `root` that have uncommitted changes, listing them.
+///
+/// Only tracked files count, so the untracked output of a first sync doesn't block the next one.
+/// Outside a git repository (or without git) there is nothing to check.
+pub fn ensure_buck_files_clean(root: &Utf8Path) -> io::Result<()> {
+    let output = match Command::new("git")
+        .arg("-C")
+        .arg(root)
+        .args(["status", "--porcelain", "--untracked-files=no", "--"])
+        .args([":(glob)BUCK", ":(glob)**/BUCK"])
+        .output()
+    {
+        Ok(output) if output.status.success() => output,
+        _ => return Ok(()),
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let dirty: Vec<String> = stdout
+        .lines()
+        .filter_map(|line| line.get(3..))
+        .map(|path| format!("  {}", path.rsplit(" -> ").next().unwrap_or(path)))
+        .collect();
+    if dirty.is_empty() {
+        return Ok(());
+    }
+    Err(io::Error::other(format!(
+        "BUCK files have uncommitted changes that a sync may overwrite:\n{}\n\
+         help: commit or stash them, or pass `--allow-dirty` to sync anyway",
+        dirty.join("\n")
+    )))
+}
+
 /// Check if a platform target exists using buck2 uquery
 pub fn platform_exists(platform_target: &str) -> bool {
     let output = crate::buck2::Buck2Command::uquery()
@@ -344,8 +478,108 @@ pub fn check_buck2_package() -> io::Result<()> {
     Ok(())
 }
 
+/// Default cap on the size of a download, 256 MiB.
+pub const DEFAULT_MAX_DOWNLOAD_SIZE: u64 = 256 * 1024 * 1024;
+
+static MAX_DOWNLOAD_SIZE: OnceLock<u64> = OnceLock::new();
+
+/// Cap the size of every download (from `--max-download-size`).
+pub fn set_max_download_size(size: u64) {
+    let _ = MAX_DOWNLOAD_SIZE.set(size);
+}
+
+pub fn max_download_size() -> u64 {
+    *MAX_DOWNLOAD_SIZE
+        .get()
+        .unwrap_or(&DEFAULT_MAX_DOWNLOAD_SIZE)
+}
+
+/// The cap given with `--max-download-size`, if any.
+pub fn explicit_max_download_size() -> Option<u64> {
+    MAX_DOWNLOAD_SIZE.get().copied()
+}
+
+/// Parse a size in bytes, with an optional `K`/`M`/`G` suffix in powers of 1024 (e.g. `256MiB`).
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size `{}`", size))?;
+    let shift = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 0,
+        "k" | "kb" | "kib" => 10,
+        "m" | "mb" | "mib" => 20,
+        "g" | "gb" | "gib" => 30,
+        _ => return Err(format!("invalid size unit in `{}`", size)),
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size `{}` is too large", size))
+}
+
+/// Read a download whose server announced `content_length`, failing as soon as either the
+/// announced or the streamed size exceeds `limit` bytes.
+pub fn read_limited(
+    reader: impl Read,
+    content_length: Option<u64>,
+    limit: u64,
+) -> io::Result<Vec<u8>> {
+    let too_large = || {
+        io::Error::other(format!(
+            "download exceeds the maximum size of {} bytes (see `--max-download-size`)",
+            limit
+        ))
+    };
+    if content_length.is_some_and(|length| length > limit) {
+        return Err(too_large());
+    }
+    let mut body = Vec::new();
+    reader.take(limit + 1).read_to_end(&mut body)?;
+    if body.len() as u64 > limit {
+        return Err(too_large());
+    }
+    Ok(body)
+}
+
+static TOOLCHAIN: OnceLock<Option<String>> = OnceLock::new();
+
+/// Select the toolchain used for every `rustc` invocation (from `--toolchain`).
+///
+/// Falls back to `RUSTUP_TOOLCHAIN` when not set, and otherwise lets rustup resolve the
+/// toolchain (including `rust-toolchain.toml`) on its own.
+pub fn set_toolchain(toolchain: Option<String>) {
+    let _ = TOOLCHAIN.set(toolchain.or_else(toolchain_from_env));
+}
+
+fn toolchain_from_env() -> Option<String> {
+    std::env::var("RUSTUP_TOOLCHAIN")
+        .ok()
+        .filter(|t| !t.is_empty())
+}
+
+fn rustc_toolchain_arg(toolchain: Option<&str>) -> Option<String> {
+    toolchain.map(|t| format!("+{}", t.trim_start_matches('+')))
+}
+
+fn rustc_command_for(toolchain: Option<&str>) -> Command {
+    let mut command = Command::new("rustc");
+    if let Some(arg) = rustc_toolchain_arg(toolchain) {
+        command.arg(arg);
+    }
+    command
+}
+
+/// Create a `rustc` command bound to the selected toolchain.
+pub fn rustc_command() -> Command {
+    rustc_command_for(TOOLCHAIN.get_or_init(toolchain_from_env).as_deref())
+}
+
 pub fn get_target() -> String {
-    let output = Command::new("rustc")
+    let output = rustc_command()
         .arg("-Vv")
         .output()
         .expect("rustc failed to run");
@@ -360,10 +594,7 @@ pub fn get_target() -> String {
 
 /// Check if a target triple is valid for rustc
 pub fn is_valid_rustc_target(triple: &str) -> bool {
-    let output = Command::new("rustc")
-        .arg("--print")
-        .arg("target-list")
-        .output();
+    let output = rustc_command().arg("--print").arg("target-list").output();
 
     match output {
         Ok(o) if o.status.success() => {
@@ -399,8 +630,52 @@ pub fn validate_target_triple(triple: &str) -> Result<String, String> {
     Ok(platform)
 }
 
+/// Check that a `--target-platforms` label resolves before handing it to buck2, listing the
+/// available `//platforms:*` targets otherwise.
+pub fn validate_target_platform(platform: &str) -> Result<String, String> {
+    check_target_platform(platform, platform_exists, available_platforms)
+}
+
+fn check_target_platform(
+    platform: &str,
+    exists: impl Fn(&str) -> bool,
+    available: impl FnOnce() -> Vec<String>,
+) -> Result<String, String> {
+    if exists(platform) {
+        return Ok(platform.to_owned());
+    }
+    let available = available();
+    let hint = if available.is_empty() {
+        "No platforms are defined in //platforms/BUCK.".to_owned()
+    } else {
+        format!("Available platforms: {}", available.join(", "))
+    };
+    Err(format!(
+        "platform '{}' does not exist in Buck2. {}",
+        platform, hint
+    ))
+}
+
+/// The targets of the `//platforms` package.
+fn available_platforms() -> Vec<String> {
+    crate::buck2::Buck2Command::uquery()
+        .arg("//platforms:")
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| {
+            String::from_utf8_lossy(&o.stdout)
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 pub fn get_cfgs() -> Vec<Cfg> {
-    let output = Command::new("rustc")
+    let output = rustc_command()
         .arg("--print=cfg")
         .output()
         .expect("rustc failed to run");
@@ -416,7 +691,203 @@ pub fn get_cache_path() -> io::Result<Utf8PathBuf> {
 }
 
 pub fn get_vendor_dir(name: &str, version: &str) -> io::Result<Utf8PathBuf> {
-    Ok(get_buck2_root()?.join(format!("{RUST_CRATES_ROOT}/{}/{}", name, version)))
+    Ok(get_buck2_root()?.join(vendor_package_path(name, version)))
+}
+
+/// Buck package of a vendored third-party crate, e.g. `third-party/rust/crates/foo/1.0.0`.
+pub fn vendor_package_path(name: &str, version: &str) -> String {
+    format!("{RUST_CRATES_ROOT}/{}/{}", name, version_segment(version))
+}
+
+/// Directory segment telling apart the sources of a crate version vendored from several of them:
+/// a short hash of the source part of its package ID (e.g.
+/// `registry+https://github.com/rust-lang/crates.io-index#foo@1.0.0`).
+pub fn source_segment(id: &PackageId) -> String {
+    let source = id
+        .repr
+        .rsplit_once('#')
+        .map_or(id.repr.as_str(), |(source, _)| source);
+    hex::encode(&blake3::hash(source.as_bytes()).as_bytes()[..4])
+}
+
+/// A version as a directory and label segment.
+///
+/// Buck rejects the `+` of build metadata (`1.0.0+build`) in target patterns, so it becomes `_`,
+/// which semver never uses; [`version_from_segment`] maps it back.
+pub fn version_segment(version: &str) -> String {
+    version.replace('+', "_")
+}
+
+/// The version a directory or label segment was made from by [`version_segment`].
+pub fn version_from_segment(segment: &str) -> String {
+    segment.replace('_', "+")
+}
+
+/// Where a package comes from, classified from its `source` or package ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackageSource {
+    /// crates.io, through either the git index or the sparse index
+    CratesIo,
+    /// Any other registry
+    Registry,
+    Git,
+    /// Path dependencies, including workspace members
+    Path,
+}
+
+/// `source` values of crates.io: the git index, and the sparse index used by newer Cargo.
+const CRATES_IO_SOURCES: [&str; 2] = [
+    "registry+https://github.com/rust-lang/crates.io-index",
+    "sparse+https://index.crates.io/",
+];
+
+impl PackageSource {
+    /// Classify a `source` string or a package ID, in either the `<source>#<name>@<version>` or
+    /// the legacy `<name> <version> (<source>)` format.
+    pub fn classify(source: &str) -> Self {
+        let source = source
+            .strip_suffix(')')
+            .and_then(|s| s.rsplit_once(" ("))
+            .map_or(source, |(_, source)| source);
+        if CRATES_IO_SOURCES.iter().any(|s| source.starts_with(s)) {
+            Self::CratesIo
+        } else if source.starts_with("registry+") || source.starts_with("sparse+") {
+            Self::Registry
+        } else if source.starts_with("git+") {
+            Self::Git
+        } else {
+            Self::Path
+        }
+    }
+
+    pub fn of(package: &Package) -> Self {
+        package
+            .source
+            .as_ref()
+            .map_or(Self::Path, |source| Self::classify(&source.repr))
+    }
+}
+
+/// Remove a vendored `name/version` directory, along with its `name` parent if it became empty.
+pub fn remove_vendor_dir(vendor_dir: &Utf8Path) -> io::Result<()> {
+    if vendor_dir.exists() {
+        std::fs::remove_dir_all(vendor_dir)?;
+    }
+    if let Some(package_dir) = vendor_dir.parent()
+        && package_dir.exists()
+        && package_dir.read_dir()?.next().is_none()
+    {
+        std::fs::remove_dir_all(package_dir)?;
+    }
+    Ok(())
+}
+
+/// Find vendored `name/version` directories under `crates_dir` that none of the resolved
+/// `packages` is vendored to, for `autoremove` and `vendor --prune`.
+pub fn find_orphaned_vendor_dirs(
+    crates_dir: &Utf8Path,
+    packages: &[Package],
+) -> io::Result<Vec<Utf8PathBuf>> {
+    let keep: BTreeSet<String> = packages
+        .iter()
+        .map(|p| format!("{}/{}", p.name, p.version))
+        .collect();
+    let mut orphans = Vec::new();
+    if !crates_dir.exists() {
+        return Ok(orphans);
+    }
+    for package_dir in crates_dir.read_dir_utf8()? {
+        let package_dir = package_dir?;
+        if !package_dir.file_type()?.is_dir() {
+            continue;
+        }
+        for version_dir in package_dir.path().read_dir_utf8()? {
+            let version_dir = version_dir?;
+            if !version_dir.file_type()?.is_dir() {
+                continue;
+            }
+            let label = format!(
+                "{}/{}",
+                package_dir.file_name(),
+                version_from_segment(version_dir.file_name())
+            );
+            if !keep.contains(&label) {
+                orphans.push(version_dir.into_path());
+            }
+        }
+    }
+    orphans.sort();
+    Ok(orphans)
+}
+
+#[derive(Debug, Default)]
+struct MetadataFiles {
+    dump: Option<Utf8PathBuf>,
+    load: Option<Utf8PathBuf>,
+}
+
+static METADATA_FILES: OnceLock<MetadataFiles> = OnceLock::new();
+
+/// Write the resolved metadata to `dump` (from `--dump-metadata`), or read it back from `load`
+/// instead of running `cargo metadata` (from `--metadata`).
+pub fn set_metadata_files(dump: Option<Utf8PathBuf>, load: Option<Utf8PathBuf>) {
+    let _ = METADATA_FILES.set(MetadataFiles { dump, load });
+}
+
+/// The `cargo metadata` of the current workspace, read from the `--metadata` file if one was
+/// given.
+pub fn cargo_metadata() -> anyhow::Result<Metadata> {
+    let files = METADATA_FILES.get_or_init(MetadataFiles::default);
+    if let Some(path) = &files.load {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("failed to read metadata file `{}`: {}", path, e))?;
+        let metadata: Metadata = serde_json::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("invalid metadata file `{}`: {}", path, e))?;
+        let cwd = std::env::current_dir().unwrap_or_default();
+        if let Some(reason) = stale_metadata_reason(&metadata, path, &cwd) {
+            buckal_warn!("Metadata file `{}` may be stale: {}", path, reason);
+        }
+        return Ok(metadata);
+    }
+
+    let metadata = MetadataCommand::new().exec()?;
+    if let Some(path) = &files.dump {
+        std::fs::write(path, serde_json::to_string(&metadata)?)
+            .map_err(|e| anyhow::anyhow!("failed to write metadata file `{}`: {}", path, e))?;
+    }
+    Ok(metadata)
+}
+
+/// The nodes of the dependency graph of `cargo metadata`, which buckal can't work without.
+pub fn resolved_nodes(resolve: Option<Resolve>) -> anyhow::Result<Vec<Node>> {
+    resolve.map(|resolve| resolve.nodes).ok_or_else(|| {
+        anyhow::anyhow!(
+            "cargo metadata has no dependency graph (`resolve`): buckal needs resolved \
+             dependencies, so metadata can't come from `cargo metadata --no-deps`"
+        )
+    })
+}
+
+/// Why metadata dumped to `path` may not describe the workspace around `cwd`: it was dumped
+/// from another workspace, or the workspace manifest or lockfile changed since.
+fn stale_metadata_reason(
+    metadata: &Metadata,
+    path: &Utf8Path,
+    cwd: &std::path::Path,
+) -> Option<String> {
+    if !cwd.starts_with(&metadata.workspace_root) {
+        return Some(format!(
+            "it was dumped from the workspace at `{}`",
+            metadata.workspace_root
+        ));
+    }
+    let dumped_at = std::fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    ["Cargo.toml", "Cargo.lock"].into_iter().find_map(|file| {
+        let modified = std::fs::metadata(metadata.workspace_root.join(file))
+            .and_then(|m| m.modified())
+            .ok()?;
+        (modified > dumped_at).then(|| format!("`{}` changed after it was dumped", file))
+    })
 }
 
 pub fn get_last_cache() -> BuckalCache {
@@ -425,14 +896,8 @@ pub fn get_last_cache() -> BuckalCache {
     if let Ok(last_cache) = BuckalCache::load() {
         last_cache
     } else {
-        let cargo_metadata = MetadataCommand::new().exec().unwrap_or_exit();
-        let resolve = cargo_metadata.resolve.unwrap();
-        let nodes_map = resolve
-            .nodes
-            .into_iter()
-            .map(|n| (n.id.to_owned(), n))
-            .collect::<HashMap<_, _>>();
-        BuckalCache::new(&nodes_map, &cargo_metadata.workspace_root)
+        let ctx = BuckalContext::new();
+        BuckalCache::new(&ctx.nodes_map, &ctx)
     }
 }
 
@@ -455,6 +920,51 @@ pub fn section(title: &str) {
     println!("{}{}{}", left_pad, content, right_pad);
 }
 
+/// When to colorize `buckal_*` output
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Colorize when writing to a terminal, honoring `NO_COLOR` and `CLICOLOR_FORCE`
+    #[default]
+    Auto,
+    /// Always colorize
+    Always,
+    /// Never colorize
+    Never,
+}
+
+/// Resolve the color override for `choice`, given the values of `NO_COLOR` and `CLICOLOR_FORCE`.
+///
+/// Returns `None` when the decision should be left to terminal detection.
+fn resolve_color_override(
+    choice: ColorChoice,
+    no_color: Option<&str>,
+    clicolor_force: Option<&str>,
+) -> Option<bool> {
+    match choice {
+        ColorChoice::Always => Some(true),
+        ColorChoice::Never => Some(false),
+        ColorChoice::Auto => {
+            // https://no-color.org: any non-empty value disables color
+            if no_color.is_some_and(|v| !v.is_empty()) {
+                Some(false)
+            } else if clicolor_force.is_some_and(|v| !v.is_empty() && v != "0") {
+                Some(true)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+pub fn set_color_choice(choice: ColorChoice) {
+    let no_color = std::env::var("NO_COLOR").ok();
+    let clicolor_force = std::env::var("CLICOLOR_FORCE").ok();
+    match resolve_color_override(choice, no_color.as_deref(), clicolor_force.as_deref()) {
+        Some(enabled) => colored::control::set_override(enabled),
+        None => colored::control::unset_override(),
+    }
+}
+
 pub fn check_python3_installed() -> bool {
     Command::new("python3")
         .arg("--version")
@@ -465,7 +975,7 @@ pub fn check_python3_installed() -> bool {
 
 /// Quick check if rustc is available before spawning multiple threads.
 pub fn check_rustc_installed() -> bool {
-    Command::new("rustc")
+    rustc_command()
         .arg("--version")
         .output()
         .map(|o| o.status.success())
@@ -493,7 +1003,10 @@ pub fn ensure_python3_installed() -> io::Result<()> {
 pub fn ensure_prerequisites() -> io::Result<()> {
     ensure_rustc_installed()?;
     ensure_buck2_installed()?;
-    ensure_python3_installed()?;
+    // Python is only used to read existing BUCK files back for merging
+    if !cfg!(feature = "starlark-parser") {
+        ensure_python3_installed()?;
+    }
     Ok(())
 }
 
@@ -528,6 +1041,184 @@ impl<T, E: std::fmt::Display> UnwrapOrExit<T> for Result<T, E> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_rustc_toolchain_arg() {
+        assert_eq!(rustc_toolchain_arg(None), None);
+        assert_eq!(
+            rustc_toolchain_arg(Some("nightly")).as_deref(),
+            Some("+nightly")
+        );
+        assert_eq!(
+            rustc_toolchain_arg(Some("+1.85.0")).as_deref(),
+            Some("+1.85.0")
+        );
+    }
+
+    #[test]
+    fn test_rustc_command_uses_selected_toolchain() {
+        // Only meaningful with rustup-managed toolchains installed
+        let has_stable = Command::new("rustup")
+            .args(["run", "stable", "rustc", "--version"])
+            .output()
+            .is_ok_and(|o| o.status.success());
+        if !has_stable {
+            return;
+        }
+
+        let command = rustc_command_for(Some("stable"));
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args.first().and_then(|a| a.to_str()), Some("+stable"));
+
+        let output = rustc_command_for(Some("stable"))
+            .arg("-V")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+    }
+
+    #[test]
+    fn test_classify_package_source() {
+        for id in [
+            "registry+https://github.com/rust-lang/crates.io-index#serde@1.0.0",
+            "sparse+https://index.crates.io/#serde@1.0.0",
+            "serde 1.0.0 (sparse+https://index.crates.io/)",
+        ] {
+            assert_eq!(PackageSource::classify(id), PackageSource::CratesIo, "{id}");
+        }
+        assert_eq!(
+            PackageSource::classify("sparse+https://example.com/index/#foo@0.1.0"),
+            PackageSource::Registry
+        );
+        assert_eq!(
+            PackageSource::classify("git+https://github.com/foo/bar?branch=main#bar@0.1.0"),
+            PackageSource::Git
+        );
+        assert_eq!(
+            PackageSource::classify("path+file:///ws/foo#0.1.0"),
+            PackageSource::Path
+        );
+    }
+
+    #[test]
+    fn test_prune_orphaned_vendor_dirs() {
+        let crates_dir = crate::testing::temp_dir("vendor");
+        for dir in ["foo/1.0.0", "foo/0.9.0", "bar/0.1.0"] {
+            std::fs::create_dir_all(crates_dir.join(dir)).unwrap();
+            std::fs::write(crates_dir.join(dir).join("BUCK"), "").unwrap();
+        }
+
+        let foo = crate::testing::mock_package(
+            "foo",
+            "1.0.0",
+            "foo 1.0.0",
+            Some(crate::testing::REGISTRY),
+        );
+        let keep = [foo];
+        let orphans = find_orphaned_vendor_dirs(&crates_dir, &keep).unwrap();
+        assert_eq!(
+            orphans,
+            vec![crates_dir.join("bar/0.1.0"), crates_dir.join("foo/0.9.0")]
+        );
+
+        for orphan in &orphans {
+            remove_vendor_dir(orphan).unwrap();
+        }
+        assert!(crates_dir.join("foo/1.0.0").is_dir());
+        assert!(!crates_dir.join("foo/0.9.0").exists());
+        // The now-empty package directory is cleaned up as well
+        assert!(!crates_dir.join("bar").exists());
+        assert!(
+            find_orphaned_vendor_dirs(&crates_dir, &keep)
+                .unwrap()
+                .is_empty()
+        );
+
+        std::fs::remove_dir_all(&crates_dir).unwrap();
+    }
+
+    #[test]
+    fn test_metadata_without_resolve() {
+        let metadata: Metadata = serde_json::from_value(serde_json::json!({
+            "packages": [],
+            "workspace_members": [],
+            "workspace_root": "/ws",
+            "target_directory": "/ws/target",
+            "version": 1,
+            "metadata": null
+        }))
+        .unwrap();
+        assert!(metadata.resolve.is_none());
+
+        let err = resolved_nodes(metadata.resolve).unwrap_err().to_string();
+        assert!(err.contains("no dependency graph"), "{err}");
+        assert!(err.contains("--no-deps"), "{err}");
+    }
+
+    #[test]
+    fn test_stale_metadata_reason() {
+        let root = crate::testing::temp_dir("metadata");
+        std::fs::create_dir_all(root.join("app")).unwrap();
+        std::fs::write(root.join("Cargo.toml"), "").unwrap();
+        let dump = root.join("metadata.json");
+        std::fs::write(&dump, "").unwrap();
+        let metadata: Metadata = serde_json::from_value(serde_json::json!({
+            "packages": [],
+            "workspace_members": [],
+            "resolve": null,
+            "workspace_root": root,
+            "target_directory": root.join("target"),
+            "version": 1,
+            "metadata": null
+        }))
+        .unwrap();
+
+        assert_eq!(
+            stale_metadata_reason(&metadata, &dump, root.join("app").as_std_path()),
+            None
+        );
+        assert!(
+            stale_metadata_reason(&metadata, &dump, std::path::Path::new("/elsewhere"))
+                .unwrap()
+                .contains("dumped from the workspace")
+        );
+
+        // A lockfile written after the dump makes it stale
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        let lock = std::fs::File::create(root.join("Cargo.lock")).unwrap();
+        lock.set_modified(later).unwrap();
+        assert_eq!(
+            stale_metadata_reason(&metadata, &dump, root.as_std_path()).as_deref(),
+            Some("`Cargo.lock` changed after it was dumped")
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_color_override() {
+        assert_eq!(
+            resolve_color_override(ColorChoice::Always, Some("1"), None),
+            Some(true)
+        );
+        assert_eq!(
+            resolve_color_override(ColorChoice::Never, None, Some("1")),
+            Some(false)
+        );
+        assert_eq!(resolve_color_override(ColorChoice::Auto, None, None), None);
+        assert_eq!(
+            resolve_color_override(ColorChoice::Auto, Some("1"), Some("1")),
+            Some(false)
+        );
+        assert_eq!(
+            resolve_color_override(ColorChoice::Auto, Some(""), Some("1")),
+            Some(true)
+        );
+        assert_eq!(
+            resolve_color_override(ColorChoice::Auto, None, Some("0")),
+            None
+        );
+    }
+
     #[test]
     fn test_is_valid_rustc_target_valid_targets() {
         // These are common, always-available targets
@@ -566,4 +1257,154 @@ mod tests {
             assert_eq!(platform, "//platforms:x86_64-unknown-linux-gnu");
         }
     }
+
+    #[test]
+    fn test_check_target_platform_rejects_unknown_label() {
+        let exists = |platform: &str| platform == "//platforms:x86_64-unknown-linux-gnu";
+        let available = || {
+            vec![
+                "root//platforms:aarch64-apple-darwin".to_owned(),
+                "root//platforms:x86_64-unknown-linux-gnu".to_owned(),
+            ]
+        };
+
+        assert_eq!(
+            check_target_platform("//platforms:x86_64-unknown-linux-gnu", exists, available),
+            Ok("//platforms:x86_64-unknown-linux-gnu".to_owned())
+        );
+        let err = check_target_platform("//platforms:x86_64-unknown-linux-gnux", exists, available)
+            .unwrap_err();
+        assert!(err.contains("'//platforms:x86_64-unknown-linux-gnux' does not exist"));
+        assert!(err.contains(
+            "root//platforms:aarch64-apple-darwin, root//platforms:x86_64-unknown-linux-gnu"
+        ));
+        assert!(
+            check_target_platform("//platforms:foo", exists, Vec::new)
+                .unwrap_err()
+                .contains("No platforms are defined")
+        );
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("256MiB"), Ok(DEFAULT_MAX_DOWNLOAD_SIZE));
+        assert_eq!(parse_size("2k"), Ok(2048));
+        assert_eq!(parse_size("1 GB"), Ok(1 << 30));
+        assert!(parse_size("MiB").is_err());
+        assert!(parse_size("10 bananas").is_err());
+    }
+
+    #[test]
+    fn test_read_limited_rejects_oversized_downloads() {
+        let body = vec![b'x'; 64];
+
+        assert_eq!(read_limited(&body[..], Some(64), 64).unwrap(), body);
+        assert_eq!(read_limited(&body[..], None, 100).unwrap(), body);
+
+        // An announced length over the limit is rejected before reading
+        let err = read_limited(&body[..], Some(1 << 40), 64).unwrap_err();
+        assert!(err.to_string().contains("maximum size of 64 bytes"));
+
+        // A server that omits or understates the length is cut off while streaming
+        assert!(read_limited(&body[..], None, 63).is_err());
+        assert!(read_limited(&body[..], Some(10), 63).is_err());
+    }
+
+    #[test]
+    fn test_buck2_root_error_outside_project() {
+        let dir = crate::testing::temp_dir("root").into_std_path_buf();
+        let nested = dir.join("crates/foo");
+        std::fs::create_dir_all(&nested).unwrap();
+
+        let stderr = "Error: could not find a project root\n";
+        // Assumes no `.buckconfig` above the temp directory
+        let outside = buck2_root_error(stderr, &nested).to_string();
+        assert!(outside.contains("is not inside a Buck2 project"));
+        assert!(outside.contains("cargo buckal init"));
+
+        std::fs::write(dir.join(".buckconfig"), "").unwrap();
+        let inside = buck2_root_error(stderr, &nested).to_string();
+        assert!(inside.contains("could not find a project root"));
+        assert!(!inside.contains("cargo buckal init"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_dirty_buck_file_blocks_sync() {
+        let root = crate::testing::temp_dir("dirty");
+        std::fs::create_dir_all(root.join("foo")).unwrap();
+        let git = |args: &[&str]| {
+            Command::new("git")
+                .arg("-C")
+                .arg(&root)
+                .args([
+                    "-c",
+                    "user.name=buckal",
+                    "-c",
+                    "user.email=buckal@example.com",
+                ])
+                .args(args)
+                .output()
+                .is_ok_and(|output| output.status.success())
+        };
+        if !git(&["init", "-q"]) {
+            // No git available: nothing to check
+            std::fs::remove_dir_all(&root).unwrap();
+            return;
+        }
+        std::fs::write(root.join("foo/BUCK"), "# @generated by `cargo buckal`\n").unwrap();
+        std::fs::write(root.join("README.md"), "").unwrap();
+        assert!(git(&["add", "."]));
+        assert!(git(&["commit", "-qm", "init"]));
+        assert!(ensure_buck_files_clean(&root).is_ok());
+
+        // Untracked BUCK files and other edits don't count
+        std::fs::write(root.join("BUCK"), "").unwrap();
+        std::fs::write(root.join("README.md"), "edited").unwrap();
+        assert!(ensure_buck_files_clean(&root).is_ok());
+
+        // A manual edit to a generated file does
+        std::fs::write(root.join("foo/BUCK"), "# manual edit\n").unwrap();
+        let err = ensure_buck_files_clean(&root).unwrap_err().to_string();
+        assert!(err.contains("foo/BUCK"));
+        assert!(err.contains("--allow-dirty"));
+        assert!(!err.contains("README.md"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_utc_timestamp() {
+        let at = |millis: u64| {
+            utc_timestamp(std::time::UNIX_EPOCH + std::time::Duration::from_millis(millis))
+        };
+        assert_eq!(at(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(at(951_782_400_123), "2000-02-29T00:00:00.123Z");
+        assert_eq!(at(1_700_000_000_000), "2023-11-14T22:13:20.000Z");
+    }
+
+    #[test]
+    fn test_log_file_receives_buckal_output() {
+        let path = crate::testing::temp_dir("log").with_extension("log");
+
+        set_log_file(Some(&path)).unwrap();
+        crate::buckal_log!("Flushing", "log-file-test v0.1.0");
+        crate::buckal_warn!("log-file-test {}", "warning");
+        set_log_file(None).unwrap();
+        crate::buckal_note!("log-file-test after close");
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<_> = log
+            .lines()
+            .filter(|l| l.contains("log-file-test"))
+            .collect();
+        assert_eq!(lines.len(), 2, "unexpected log content:\n{log}");
+        assert!(lines[0].ends_with(" INFO  Flushing log-file-test v0.1.0"));
+        assert!(lines[1].ends_with(" WARN  log-file-test warning"));
+        assert!(lines[0].starts_with(|c: char| c.is_ascii_digit()));
+    }
 }